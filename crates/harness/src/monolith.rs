@@ -104,6 +104,7 @@ impl Monolith {
                         port: http_port,
                         region: state.lock().unwrap().region.clone(),
                         id: monolith_id,
+                        challenge: None,
                     };
                     let msg = serde_json::to_string(&MsgM2B::from(init)).unwrap();
                     ws.send(Message::Text(msg)).await.unwrap();
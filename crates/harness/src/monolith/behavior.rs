@@ -87,20 +87,18 @@ impl Behavior for BehaviorLoadRooms {
                 };
                 return vec![unloaded.into()];
             }
-            MsgB2M::Join(msg) => {
-                if !state.rooms.contains_key(&msg.room) {
-                    let room = RoomMetadata::default_with_name(msg.room.clone());
-                    let load_epoch = state.room_load_epoch.fetch_add(1, Ordering::Relaxed);
-                    state.rooms.insert(
-                        room.name.clone(),
-                        GossipRoom {
-                            room: room.clone(),
-                            load_epoch,
-                        },
-                    );
-                    let loaded = M2BLoaded { room, load_epoch };
-                    return vec![loaded.into()];
-                }
+            MsgB2M::Join(msg) if !state.rooms.contains_key(&msg.room) => {
+                let room = RoomMetadata::default_with_name(msg.room.clone());
+                let load_epoch = state.room_load_epoch.fetch_add(1, Ordering::Relaxed);
+                state.rooms.insert(
+                    room.name.clone(),
+                    GossipRoom {
+                        room: room.clone(),
+                        load_epoch,
+                    },
+                );
+                let loaded = M2BLoaded { room, load_epoch };
+                return vec![loaded.into()];
             }
             _ => {}
         }
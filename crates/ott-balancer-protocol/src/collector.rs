@@ -1,17 +1,27 @@
+use std::collections::{HashMap, HashSet};
+
 use serde::{Deserialize, Serialize};
 use typeshare::typeshare;
 
+use crate::monolith::UnloadReason;
 use crate::{BalancerId, ClientId, MonolithId, Region, RoomName};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[typeshare]
 pub struct BalancerState {
     pub id: BalancerId,
     pub region: Region,
     pub monoliths: Vec<MonolithState>,
+    /// When the collector last got this state from the balancer, in milliseconds since the Unix epoch. Only set by the collector.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[typeshare(serialized_as = "Option<number>")]
+    pub collected_at: Option<u64>,
+    /// Why the collector's latest attempt to get state from the balancer failed. When set, this state is the last one that the collector got, and may be out of date. If the collector has never got state from the balancer, this is a placeholder without `collected_at` or monoliths.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[typeshare]
 pub struct MonolithState {
     pub id: MonolithId,
@@ -19,30 +29,450 @@ pub struct MonolithState {
     pub rooms: Vec<RoomState>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[typeshare]
 pub struct RoomState {
     pub name: RoomName,
     pub clients: Vec<ClientState>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[typeshare]
 pub struct ClientState {
     pub id: ClientId,
     pub edge_region: Region,
 }
 
+/// A lifecycle event that a balancer sends on its state stream.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[typeshare]
+pub struct BalancerEvent {
+    /// The balancer that the event happened on.
+    pub balancer_id: BalancerId,
+    /// When the event happened, in milliseconds since the Unix epoch.
+    #[typeshare(serialized_as = "number")]
+    pub timestamp: u64,
+    pub event: Event,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+#[typeshare]
 pub enum Event {
-    ClientMsg(EClientMsg),
+    RoomLoaded(ERoomLoaded),
+    RoomUnloaded(ERoomUnloaded),
+    ClientJoined(EClientJoined),
+    ClientLeft(EClientLeft),
+    MonolithConnected(EMonolithConnected),
+    MonolithDisconnected(EMonolithDisconnected),
+    RoomConflictResolved(ERoomConflictResolved),
+}
+
+/// A monolith reported that it loaded a room.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[typeshare]
+pub struct ERoomLoaded {
+    pub room: RoomName,
+    pub monolith_id: MonolithId,
+    pub load_epoch: u32,
+}
+
+/// A monolith reported that it unloaded a room.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[typeshare]
+pub struct ERoomUnloaded {
+    pub room: RoomName,
+    pub monolith_id: MonolithId,
+    pub reason: UnloadReason,
 }
 
-pub enum Direction {
-    Tx,
-    Rx,
+/// A client was authenticated and handed off to a monolith.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[typeshare]
+pub struct EClientJoined {
+    pub client_id: ClientId,
+    pub room: RoomName,
+    pub monolith_id: MonolithId,
+    pub edge_region: Region,
 }
 
-pub struct EClientMsg {
+/// A client disconnected from the balancer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[typeshare]
+pub struct EClientLeft {
     pub client_id: ClientId,
-    pub direction: Direction,
+    pub room: RoomName,
+}
+
+/// The balancer connected to a monolith.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[typeshare]
+pub struct EMonolithConnected {
+    pub monolith_id: MonolithId,
+    pub region: Region,
+}
+
+/// The balancer lost its connection to a monolith, or dropped it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[typeshare]
+pub struct EMonolithDisconnected {
+    pub monolith_id: MonolithId,
+}
+
+/// A room was loaded on two monoliths at once, and one of them was told to unload it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[typeshare]
+pub struct ERoomConflictResolved {
+    pub room: RoomName,
+    /// The monolith that keeps the room, because it loaded the room first.
+    pub kept_monolith_id: MonolithId,
+    /// The monolith that was told to unload the room.
+    pub unloaded_monolith_id: MonolithId,
+}
+
+/// Sent on the state stream so that subscribers can keep an exact copy of a balancer's state.
+///
+/// The stream starts with a snapshot, which is followed by diffs. Each diff has a version one higher than the one before it. Diffs with a version at or below the version of the last snapshot should be ignored.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[typeshare]
+pub struct StateSync {
+    pub balancer_id: BalancerId,
+    /// The version of the state after this message has been applied.
+    #[typeshare(serialized_as = "number")]
+    pub version: u64,
+    pub state: StateUpdate,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+#[typeshare]
+pub enum StateUpdate {
+    /// The whole state. Replaces any copy that the subscriber had.
+    Snapshot(BalancerState),
+    /// The changes since the previous version, in the order that they should be applied.
+    Diff(Vec<StateChange>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+#[typeshare]
+pub enum StateChange {
+    MonolithAdded(DMonolithAdded),
+    /// Also removes all of the monolith's rooms.
+    MonolithRemoved(DMonolithRemoved),
+    RoomAdded(DRoomAdded),
+    /// Also removes all of the room's clients.
+    RoomRemoved(DRoomRemoved),
+    ClientAdded(DClientAdded),
+    ClientRemoved(DClientRemoved),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[typeshare]
+pub struct DMonolithAdded {
+    pub monolith_id: MonolithId,
+    pub region: Region,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[typeshare]
+pub struct DMonolithRemoved {
+    pub monolith_id: MonolithId,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[typeshare]
+pub struct DRoomAdded {
+    pub monolith_id: MonolithId,
+    pub room: RoomName,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[typeshare]
+pub struct DRoomRemoved {
+    pub monolith_id: MonolithId,
+    pub room: RoomName,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[typeshare]
+pub struct DClientAdded {
+    pub monolith_id: MonolithId,
+    pub room: RoomName,
+    pub client_id: ClientId,
+    pub edge_region: Region,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[typeshare]
+pub struct DClientRemoved {
+    pub monolith_id: MonolithId,
+    pub room: RoomName,
+    pub client_id: ClientId,
+}
+
+impl BalancerState {
+    /// The changes that turn `self` into `new`.
+    pub fn diff(&self, new: &BalancerState) -> Vec<StateChange> {
+        let mut changes = Vec::new();
+        let old_monoliths: HashMap<_, _> = self.monoliths.iter().map(|m| (m.id, m)).collect();
+        let new_monoliths: HashSet<_> = new.monoliths.iter().map(|m| m.id).collect();
+        for old_monolith in &self.monoliths {
+            if !new_monoliths.contains(&old_monolith.id) {
+                changes.push(StateChange::MonolithRemoved(DMonolithRemoved {
+                    monolith_id: old_monolith.id,
+                }));
+            }
+        }
+        for monolith in &new.monoliths {
+            let old_monolith = old_monoliths.get(&monolith.id);
+            if old_monolith.is_none() {
+                changes.push(StateChange::MonolithAdded(DMonolithAdded {
+                    monolith_id: monolith.id,
+                    region: monolith.region.clone(),
+                }));
+            }
+            let old_rooms: HashMap<_, _> = old_monolith
+                .map(|m| m.rooms.iter().map(|r| (&r.name, r)).collect())
+                .unwrap_or_default();
+            let new_rooms: HashSet<_> = monolith.rooms.iter().map(|r| &r.name).collect();
+            for old_room in old_monolith.map(|m| m.rooms.as_slice()).unwrap_or_default() {
+                if !new_rooms.contains(&old_room.name) {
+                    changes.push(StateChange::RoomRemoved(DRoomRemoved {
+                        monolith_id: monolith.id,
+                        room: old_room.name.clone(),
+                    }));
+                }
+            }
+            for room in &monolith.rooms {
+                let old_room = old_rooms.get(&room.name);
+                if old_room.is_none() {
+                    changes.push(StateChange::RoomAdded(DRoomAdded {
+                        monolith_id: monolith.id,
+                        room: room.name.clone(),
+                    }));
+                }
+                let old_clients = old_room.map(|r| r.clients.as_slice()).unwrap_or_default();
+                let old_client_set: HashSet<_> = old_clients.iter().collect();
+                let new_client_set: HashSet<_> = room.clients.iter().collect();
+                for old_client in old_clients {
+                    if !new_client_set.contains(old_client) {
+                        changes.push(StateChange::ClientRemoved(DClientRemoved {
+                            monolith_id: monolith.id,
+                            room: room.name.clone(),
+                            client_id: old_client.id,
+                        }));
+                    }
+                }
+                for client in &room.clients {
+                    if !old_client_set.contains(client) {
+                        changes.push(StateChange::ClientAdded(DClientAdded {
+                            monolith_id: monolith.id,
+                            room: room.name.clone(),
+                            client_id: client.id,
+                            edge_region: client.edge_region.clone(),
+                        }));
+                    }
+                }
+            }
+        }
+        changes
+    }
+
+    /// Apply changes from a [`StateUpdate::Diff`]. Changes to monoliths or rooms that don't exist are ignored.
+    pub fn apply_changes(&mut self, changes: &[StateChange]) {
+        for change in changes {
+            match change {
+                StateChange::MonolithAdded(c) => self.monoliths.push(MonolithState {
+                    id: c.monolith_id,
+                    region: c.region.clone(),
+                    rooms: vec![],
+                }),
+                StateChange::MonolithRemoved(c) => {
+                    self.monoliths.retain(|m| m.id != c.monolith_id);
+                }
+                StateChange::RoomAdded(c) => {
+                    if let Some(monolith) = self.monolith_mut(c.monolith_id) {
+                        monolith.rooms.push(RoomState {
+                            name: c.room.clone(),
+                            clients: vec![],
+                        });
+                    }
+                }
+                StateChange::RoomRemoved(c) => {
+                    if let Some(monolith) = self.monolith_mut(c.monolith_id) {
+                        monolith.rooms.retain(|r| r.name != c.room);
+                    }
+                }
+                StateChange::ClientAdded(c) => {
+                    if let Some(room) = self.room_mut(c.monolith_id, &c.room) {
+                        room.clients.push(ClientState {
+                            id: c.client_id,
+                            edge_region: c.edge_region.clone(),
+                        });
+                    }
+                }
+                StateChange::ClientRemoved(c) => {
+                    if let Some(room) = self.room_mut(c.monolith_id, &c.room) {
+                        room.clients.retain(|client| client.id != c.client_id);
+                    }
+                }
+            }
+        }
+    }
+
+    fn monolith_mut(&mut self, id: MonolithId) -> Option<&mut MonolithState> {
+        self.monoliths.iter_mut().find(|m| m.id == id)
+    }
+
+    fn room_mut(&mut self, monolith_id: MonolithId, room: &RoomName) -> Option<&mut RoomState> {
+        self.monolith_mut(monolith_id)?
+            .rooms
+            .iter_mut()
+            .find(|r| r.name == *room)
+    }
+}
+
+/// Limits which events a subscriber of the state stream receives.
+///
+/// Each list that isn't empty must match the event, and an event matches a list if it matches any item in it. For example, `{ "events": ["ws"], "rooms": ["foo", "bar"] }` only lets through websocket messages in the rooms `foo` and `bar`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[typeshare]
+pub struct StreamFilter {
+    /// Event types, like `ws`, `proxy`, `broadcast`, or the `type` of an [`Event`], like `room_loaded`.
+    pub events: Vec<String>,
+    pub rooms: Vec<RoomName>,
+    pub monoliths: Vec<MonolithId>,
+    pub clients: Vec<ClientId>,
+}
+
+/// Messages that a subscriber can send on the state stream.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+#[typeshare]
+pub enum StreamControl {
+    /// Replace the current filter.
+    Subscribe(StreamFilter),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn event_schema_should_be_stable() {
+        let event = BalancerEvent {
+            balancer_id: uuid::Uuid::nil().into(),
+            timestamp: 1700000000000,
+            event: Event::RoomUnloaded(ERoomUnloaded {
+                room: "foo".into(),
+                monolith_id: uuid::Uuid::nil().into(),
+                reason: UnloadReason::Keepalive,
+            }),
+        };
+        let expected = serde_json::json!({
+            "balancer_id": "00000000-0000-0000-0000-000000000000",
+            "timestamp": 1700000000000u64,
+            "event": {
+                "type": "room_unloaded",
+                "payload": {
+                    "room": "foo",
+                    "monolith_id": "00000000-0000-0000-0000-000000000000",
+                    "reason": "Keepalive",
+                },
+            },
+        });
+        assert_eq!(serde_json::to_value(&event).unwrap(), expected);
+        assert_eq!(
+            serde_json::from_value::<BalancerEvent>(expected).unwrap(),
+            event
+        );
+    }
+
+    #[test]
+    fn should_deserialize_subscribe_with_partial_filter() {
+        let msg: StreamControl = serde_json::from_value(serde_json::json!({
+            "type": "subscribe",
+            "payload": { "rooms": ["foo"] },
+        }))
+        .unwrap();
+        assert_eq!(
+            msg,
+            StreamControl::Subscribe(StreamFilter {
+                rooms: vec!["foo".into()],
+                ..Default::default()
+            })
+        );
+    }
+
+    fn client(id: u128, edge_region: &str) -> ClientState {
+        ClientState {
+            id: uuid::Uuid::from_u128(id).into(),
+            edge_region: edge_region.into(),
+        }
+    }
+
+    fn monolith(id: u128, rooms: Vec<RoomState>) -> MonolithState {
+        MonolithState {
+            id: uuid::Uuid::from_u128(id).into(),
+            region: "ord".into(),
+            rooms,
+        }
+    }
+
+    fn room(name: &str, clients: Vec<ClientState>) -> RoomState {
+        RoomState {
+            name: name.into(),
+            clients,
+        }
+    }
+
+    #[test]
+    fn applying_diff_should_produce_new_state() {
+        let old = BalancerState {
+            id: uuid::Uuid::nil().into(),
+            region: "ord".into(),
+            monoliths: vec![
+                monolith(
+                    1,
+                    vec![room("foo", vec![client(10, "ord"), client(11, "iad")])],
+                ),
+                monolith(2, vec![room("bar", vec![client(12, "ord")])]),
+            ],
+            collected_at: None,
+            error: None,
+        };
+        let new = BalancerState {
+            monoliths: vec![
+                monolith(
+                    1,
+                    vec![
+                        room("foo", vec![client(11, "iad"), client(13, "ams")]),
+                        room("baz", vec![client(14, "ord")]),
+                    ],
+                ),
+                monolith(3, vec![room("bar", vec![client(12, "ord")])]),
+            ],
+            ..old.clone()
+        };
+
+        let changes = old.diff(&new);
+        assert_eq!(
+            changes
+                .iter()
+                .filter(|c| matches!(c, StateChange::MonolithRemoved(_)))
+                .count(),
+            1
+        );
+        let mut applied = old.clone();
+        applied.apply_changes(&changes);
+        assert_eq!(applied, new);
+
+        assert!(new.diff(&new).is_empty());
+        let mut reverted = new.clone();
+        reverted.apply_changes(&new.diff(&old));
+        assert_eq!(reverted.monoliths.len(), 2);
+        assert_eq!(reverted.diff(&old), vec![]);
+    }
 }
@@ -1,7 +1,9 @@
 //! Defines the communication protocol between the monoliths and the balancer.
 
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
+use sha2::Sha256;
 use typeshare::typeshare;
 
 use crate::{BalancerId, ClientId, MonolithId, Region, RoomName};
@@ -16,6 +18,7 @@ pub enum MsgB2M {
     Leave(B2MLeave),
     ClientMsg(B2MClientMsg),
     Init(B2MInit),
+    Auth(LinkAuth),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -36,6 +39,9 @@ pub struct B2MJoin {
     pub room: RoomName,
     pub client: ClientId,
     pub token: String,
+    /// The trace that the join is part of, if the balancer is exporting traces.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<TraceContext>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -51,12 +57,118 @@ pub struct B2MClientMsg<T = Box<RawValue>> {
     pub client_id: ClientId,
     /// The message that was received from the client, verbatim.
     pub payload: T,
+    /// The trace that the message is part of, if the balancer is exporting traces.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<TraceContext>,
+}
+
+/// [W3C trace context](https://www.w3.org/TR/trace-context/), so that a monolith can continue a trace that was started by the balancer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[typeshare]
+pub struct TraceContext {
+    /// The value of the `traceparent` header, eg. `00-<trace id>-<parent span id>-01`.
+    pub traceparent: String,
+    /// The value of the `tracestate` header, if there is any vendor specific state.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tracestate: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[typeshare]
 pub struct B2MInit {
     pub id: BalancerId,
+    /// A random value that the monolith must include in its [`LinkAuth`]. Only sent if the balancer is configured with a shared secret.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub challenge: Option<String>,
+}
+
+impl B2MInit {
+    pub fn new(id: BalancerId) -> Self {
+        Self {
+            id,
+            challenge: None,
+        }
+    }
+
+    /// Prove to the monolith that sent `monolith` that this balancer knows the shared secret.
+    pub fn authenticate(&self, monolith: &M2BInit, secret: &[u8]) -> LinkAuth {
+        LinkAuth::new(secret, "b2m", self, monolith)
+    }
+
+    /// Check the proof that the balancer that sent this init answered `monolith` with.
+    pub fn verify(
+        &self,
+        monolith: &M2BInit,
+        auth: &LinkAuth,
+        secret: &[u8],
+    ) -> Result<(), LinkAuthError> {
+        auth.verify(secret, "b2m", self, monolith)
+    }
+}
+
+/// Generate a random value for the `challenge` of an init message.
+pub fn new_challenge() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+/// Proves that the sender knows the secret that is shared between the balancers and the monoliths. Sent in response to the peer's init message.
+///
+/// `signature` is the hex encoded HMAC-SHA256 of `{direction}:{balancer id}:{balancer challenge}:{monolith id}:{port}:{region}:{monolith challenge}`, keyed with the shared secret. `direction` is `b2m` or `m2b`, and a missing challenge is an empty string. Both challenges are fresh for every connection, so a captured [`LinkAuth`] can't be used on another connection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[typeshare]
+pub struct LinkAuth {
+    pub signature: String,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+impl LinkAuth {
+    fn new(secret: &[u8], direction: &str, balancer: &B2MInit, monolith: &M2BInit) -> Self {
+        let mac = Self::mac(secret, direction, balancer, monolith);
+        Self {
+            signature: hex::encode(mac.finalize().into_bytes()),
+        }
+    }
+
+    fn mac(secret: &[u8], direction: &str, balancer: &B2MInit, monolith: &M2BInit) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC can take a key of any size");
+        mac.update(
+            format!(
+                "{direction}:{}:{}:{}:{}:{}:{}",
+                balancer.id,
+                balancer.challenge.as_deref().unwrap_or_default(),
+                monolith.id,
+                monolith.port,
+                monolith.region,
+                monolith.challenge.as_deref().unwrap_or_default(),
+            )
+            .as_bytes(),
+        );
+        mac
+    }
+
+    fn verify(
+        &self,
+        secret: &[u8],
+        direction: &str,
+        balancer: &B2MInit,
+        monolith: &M2BInit,
+    ) -> Result<(), LinkAuthError> {
+        let signature =
+            hex::decode(&self.signature).map_err(|_| LinkAuthError::InvalidSignature)?;
+        // verify_slice compares in constant time
+        Self::mac(secret, direction, balancer, monolith)
+            .verify_slice(&signature)
+            .map_err(|_| LinkAuthError::InvalidSignature)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum LinkAuthError {
+    #[error("peer did not authenticate")]
+    Missing,
+    #[error("peer sent an invalid signature")]
+    InvalidSignature,
 }
 
 impl From<B2MLoad> for MsgB2M {
@@ -105,6 +217,7 @@ pub enum MsgM2B<T = Box<RawValue>> {
     Gossip(M2BGossip),
     RoomMsg(M2BRoomMsg<T>),
     Kick(M2BKick),
+    Auth(LinkAuth),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -114,6 +227,26 @@ pub struct M2BInit {
     pub port: u16,
     pub region: Region,
     pub id: MonolithId,
+    /// A random value that the balancer must include in its [`LinkAuth`]. Only sent if the monolith is configured with a shared secret.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub challenge: Option<String>,
+}
+
+impl M2BInit {
+    /// Prove to the balancer that sent `balancer` that this monolith knows the shared secret.
+    pub fn authenticate(&self, balancer: &B2MInit, secret: &[u8]) -> LinkAuth {
+        LinkAuth::new(secret, "m2b", balancer, self)
+    }
+
+    /// Check the proof that the monolith that sent this init answered `balancer` with.
+    pub fn verify(
+        &self,
+        balancer: &B2MInit,
+        auth: &LinkAuth,
+        secret: &[u8],
+    ) -> Result<(), LinkAuthError> {
+        auth.verify(secret, "m2b", balancer, self)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -132,7 +265,7 @@ pub struct M2BUnloaded {
 }
 
 /// The reason that a room was unloaded.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[typeshare]
 pub enum UnloadReason {
     /// The room was deemed inactive and was unloaded to free up resources.
@@ -143,6 +276,8 @@ pub enum UnloadReason {
     Commanded,
     /// The room was unloaded because the Monolith is shutting down.
     Shutdown,
+    /// The Balancer stopped tracking the room because the Monolith's gossip no longer included it, or it could not be synced.
+    Gossip,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -296,4 +431,124 @@ mod test {
         let json_ser = serde_json::to_string(&MsgM2B::from(json)).unwrap();
         assert_eq!(raw_ser, json_ser);
     }
+
+    #[test]
+    fn trace_context_should_be_optional() {
+        let join = B2MJoin {
+            room: "foo".into(),
+            client: uuid::Uuid::new_v4().into(),
+            token: "token".to_owned(),
+            trace: None,
+        };
+        let ser = serde_json::to_value(MsgB2M::from(join)).unwrap();
+        assert!(ser["payload"].get("trace").is_none());
+
+        let msg: B2MClientMsg<serde_json::Value> = serde_json::from_value(serde_json::json!({
+            "client_id": uuid::Uuid::new_v4(),
+            "payload": {},
+            "trace": { "traceparent": "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01" },
+        }))
+        .unwrap();
+        assert_eq!(
+            msg.trace,
+            Some(TraceContext {
+                traceparent: "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_owned(),
+                tracestate: None,
+            })
+        );
+    }
+
+    fn inits() -> (B2MInit, M2BInit) {
+        let balancer = B2MInit {
+            id: uuid::Uuid::new_v4().into(),
+            challenge: Some(new_challenge()),
+        };
+        let monolith = M2BInit {
+            port: 3000,
+            region: "ord".into(),
+            id: uuid::Uuid::new_v4().into(),
+            challenge: Some(new_challenge()),
+        };
+        (balancer, monolith)
+    }
+
+    #[test]
+    fn link_auth_should_verify_with_same_secret() {
+        let (balancer, monolith) = inits();
+        let b2m = balancer.authenticate(&monolith, b"secret");
+        balancer
+            .verify(&monolith, &b2m, b"secret")
+            .expect("b2m auth should verify");
+
+        let m2b = monolith.authenticate(&balancer, b"secret");
+        monolith
+            .verify(&balancer, &m2b, b"secret")
+            .expect("m2b auth should verify");
+    }
+
+    #[test]
+    fn link_auth_should_reject_wrong_secret_or_tampering() {
+        let (balancer, monolith) = inits();
+        let auth = monolith.authenticate(&balancer, b"secret");
+
+        assert_eq!(
+            monolith.verify(&balancer, &auth, b"other").unwrap_err(),
+            LinkAuthError::InvalidSignature
+        );
+
+        let mut tampered = monolith.clone();
+        tampered.port = 4000;
+        assert_eq!(
+            tampered.verify(&balancer, &auth, b"secret").unwrap_err(),
+            LinkAuthError::InvalidSignature
+        );
+
+        // a signature for one direction must not be accepted for the other
+        assert_eq!(
+            balancer.verify(&monolith, &auth, b"secret").unwrap_err(),
+            LinkAuthError::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn link_auth_should_be_bound_to_the_connection() {
+        let (balancer, monolith) = inits();
+        let auth = monolith.authenticate(&balancer, b"secret");
+
+        // replaying the monolith's answer on a connection with a new challenge fails
+        let mut other_connection = balancer.clone();
+        other_connection.challenge = Some(new_challenge());
+        assert_eq!(
+            monolith
+                .verify(&other_connection, &auth, b"secret")
+                .unwrap_err(),
+            LinkAuthError::InvalidSignature
+        );
+
+        // and so does replaying it to another balancer
+        let other_balancer = B2MInit {
+            id: uuid::Uuid::new_v4().into(),
+            challenge: balancer.challenge.clone(),
+        };
+        assert_eq!(
+            monolith
+                .verify(&other_balancer, &auth, b"secret")
+                .unwrap_err(),
+            LinkAuthError::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn init_without_auth_should_be_backwards_compatible() {
+        let init: MsgB2M = serde_json::from_str(
+            r#"{"type":"init","payload":{"id":"a7b6e7e2-1c4a-4a1e-9a0b-4bfbb1d6c9a1"}}"#,
+        )
+        .expect("failed to parse init without auth");
+        let MsgB2M::Init(init) = init else {
+            panic!("expected init");
+        };
+        assert!(init.challenge.is_none());
+        let ser = serde_json::to_string(&MsgB2M::Init(init)).unwrap();
+        assert!(!ser.contains("challenge"));
+    }
 }
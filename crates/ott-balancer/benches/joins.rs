@@ -39,6 +39,7 @@ fn send_messages(c: &mut Criterion) {
                     port: 0,
                 },
                 proxy_port: 0,
+                weight: Default::default(),
             })
             .await
             .expect("failed to add monolith");
@@ -80,6 +81,7 @@ fn send_messages(c: &mut Criterion) {
                     port: 0,
                 },
                 proxy_port: 0,
+                weight: Default::default(),
             })
             .await
             .expect("failed to add monolith");
@@ -121,6 +123,7 @@ fn send_messages(c: &mut Criterion) {
                         port: 0,
                     },
                     proxy_port: 0,
+                    weight: Default::default(),
                 })
                 .await
                 .expect("failed to add monolith");
@@ -150,9 +153,7 @@ fn send_messages(c: &mut Criterion) {
         "mass join 20 rooms, on 5 monoliths, with regions set",
         |b| {
             b.to_async(&rt).iter_custom(|iters| async move {
-                unsafe {
-                    BalancerConfig::get_mut().region = "foo".into();
-                }
+                BalancerConfig::update(|config| config.region = "foo".into());
 
                 let rooms: Vec<RoomName> = (0..20)
                     .map(|i| format!("foo{}", i))
@@ -170,6 +171,7 @@ fn send_messages(c: &mut Criterion) {
                             port: 0,
                         },
                         proxy_port: 0,
+                        weight: Default::default(),
                     })
                     .await
                     .expect("failed to add monolith");
@@ -68,6 +68,7 @@ async fn set_up_1m1c(
                 port: 0,
             },
             proxy_port: 0,
+            weight: Default::default(),
         })
         .await
         .expect("failed to send monolith");
@@ -18,6 +18,7 @@ fn build_monolith() -> BalancerMonolith {
             port: 3002,
         },
         proxy_port: 3000,
+        weight: Default::default(),
     };
     let (monolith_outbound_tx, _monolith_outbound_rx) = tokio::sync::mpsc::channel(100);
     let monolith_outbound_tx = Arc::new(monolith_outbound_tx);
@@ -98,9 +99,7 @@ fn select_monoliths(c: &mut Criterion) {
     });
 
     c.bench_function("hash ring selector, 2 monoliths", |b| {
-        let strategy = HashRingSelector {
-            config: Default::default(),
-        };
+        let strategy = HashRingSelector::default();
         let mut m1 = build_monolith();
         m1.add_room(&"foo1".into()).expect("failed to add room");
         m1.add_room(&"foo2".into()).expect("failed to add room");
@@ -114,9 +113,7 @@ fn select_monoliths(c: &mut Criterion) {
     });
 
     c.bench_function("hash ring selector, 5 monoliths", |b| {
-        let strategy = HashRingSelector {
-            config: Default::default(),
-        };
+        let strategy = HashRingSelector::default();
         let mut m1 = build_monolith();
         m1.add_room(&"foo1".into()).expect("failed to add room");
         m1.add_room(&"foo2".into()).expect("failed to add room");
@@ -136,9 +133,7 @@ fn select_monoliths(c: &mut Criterion) {
     });
 
     c.bench_function("hash ring selector, 10 monoliths", |b| {
-        let strategy = HashRingSelector {
-            config: Default::default(),
-        };
+        let strategy = HashRingSelector::default();
         let mut m1 = build_monolith();
         m1.add_room(&"foo1".into()).expect("failed to add room");
         m1.add_room(&"foo2".into()).expect("failed to add room");
@@ -2,9 +2,12 @@ use std::{collections::HashMap, sync::Arc};
 
 use futures_util::stream::FuturesUnordered;
 use futures_util::StreamExt;
-use ott_balancer_protocol::collector::{BalancerState, MonolithState, RoomState};
+use ott_balancer_protocol::collector::{
+    BalancerState, EClientJoined, EClientLeft, EMonolithConnected, EMonolithDisconnected,
+    ERoomConflictResolved, ERoomLoaded, ERoomUnloaded, Event, MonolithState, RoomState,
+};
 use ott_balancer_protocol::monolith::{
-    B2MClientMsg, B2MJoin, B2MLeave, B2MUnload, MsgB2M, MsgM2B, RoomMetadata,
+    B2MClientMsg, B2MJoin, B2MLeave, B2MUnload, MsgB2M, MsgM2B, RoomMetadata, UnloadReason,
 };
 use ott_balancer_protocol::*;
 use serde_json::value::RawValue;
@@ -13,16 +16,18 @@ use tokio::task::JoinHandle;
 use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::Message;
-use tracing::{debug, error, info, instrument, trace, warn};
+use tracing::{debug, error, info, instrument, trace, warn, Instrument};
 
 use crate::balancer::collector::ClientState;
-use crate::client::ClientLink;
+use crate::client::{ClientInbound, ClientLink};
 use crate::config::BalancerConfig;
 use crate::connection::BALANCER_ID;
 use crate::monolith::{MonolithSendError, Room};
 use crate::room::RoomLocator;
 use crate::selection::{MonolithSelection, MonolithSelectionStrategy};
 use crate::service::set_connected_monolith_metrics;
+use crate::state_stream::{emit_event, notify_state_changed};
+use crate::telemetry::current_trace_context;
 use crate::{
     client::{BalancerClient, NewClient},
     messages::*,
@@ -32,9 +37,16 @@ use crate::{
 pub struct Balancer {
     pub(crate) ctx: Arc<RwLock<BalancerContext>>,
 
-    new_client_rx:
-        tokio::sync::mpsc::Receiver<(NewClient, tokio::sync::oneshot::Sender<ClientLink>)>,
-    new_client_tx: tokio::sync::mpsc::Sender<(NewClient, tokio::sync::oneshot::Sender<ClientLink>)>,
+    new_client_rx: tokio::sync::mpsc::Receiver<(
+        NewClient,
+        tracing::Span,
+        tokio::sync::oneshot::Sender<ClientLink>,
+    )>,
+    new_client_tx: tokio::sync::mpsc::Sender<(
+        NewClient,
+        tracing::Span,
+        tokio::sync::oneshot::Sender<ClientLink>,
+    )>,
 
     new_monolith_rx: tokio::sync::mpsc::Receiver<(
         NewMonolith,
@@ -84,8 +96,9 @@ impl Balancer {
         loop {
             tokio::select! {
                 new_client = self.new_client_rx.recv() => {
-                    if let Some((new_client, client_link_tx)) = new_client {
-                        match join_client(&self.ctx, new_client, client_link_tx).await {
+                    if let Some((new_client, client_span, client_link_tx)) = new_client {
+                        // the join is part of the client's connection, even though it happens on the dispatcher task
+                        match join_client(&self.ctx, new_client, client_link_tx).instrument(client_span).await {
                             Ok(_) => {},
                             Err(err) => error!("failed to join client: {:?}", err)
                         };
@@ -146,7 +159,11 @@ pub fn start_dispatcher(mut balancer: Balancer) -> anyhow::Result<tokio::task::J
 
 #[derive(Clone)]
 pub struct BalancerLink {
-    new_client_tx: tokio::sync::mpsc::Sender<(NewClient, tokio::sync::oneshot::Sender<ClientLink>)>,
+    new_client_tx: tokio::sync::mpsc::Sender<(
+        NewClient,
+        tracing::Span,
+        tokio::sync::oneshot::Sender<ClientLink>,
+    )>,
 
     new_monolith_tx: tokio::sync::mpsc::Sender<(
         NewMonolith,
@@ -158,7 +175,9 @@ pub struct BalancerLink {
 impl BalancerLink {
     pub async fn send_client(&self, client: NewClient) -> anyhow::Result<ClientLink> {
         let (receiver_tx, receiver_rx) = tokio::sync::oneshot::channel();
-        self.new_client_tx.send((client, receiver_tx)).await?;
+        self.new_client_tx
+            .send((client, tracing::Span::current(), receiver_tx))
+            .await?;
         let client_link = receiver_rx.await?;
 
         Ok(client_link)
@@ -216,6 +235,7 @@ impl BalancerContext {
                 room: client.room.clone(),
                 client: client.id,
                 token: client.token.clone(),
+                trace: current_trace_context(),
             })
             .await
         {
@@ -240,6 +260,12 @@ impl BalancerContext {
             .get_mut(&monolith_id)
             .ok_or_else(|| anyhow::anyhow!("monolith not found"))?;
         monolith.add_client(&client.room, client.id);
+        emit_event(Event::ClientJoined(EClientJoined {
+            client_id: client.id,
+            room: client.room.clone(),
+            monolith_id,
+            edge_region: client.edge_region.clone(),
+        }));
         self.clients.insert(client.id, client);
 
         Ok(())
@@ -250,6 +276,10 @@ impl BalancerContext {
         let Some(removed) = self.clients.remove(&client_id) else {
             anyhow::bail!("client not found in context");
         };
+        emit_event(Event::ClientLeft(EClientLeft {
+            client_id,
+            room: removed.room.clone(),
+        }));
         let Some(locator) = self.rooms_to_monoliths.get(&removed.room) else {
             // If we can't find the room that they are supposedly in, the client shouldn't be here anyway
             warn!("room not found in rooms_to_monoliths");
@@ -278,6 +308,10 @@ impl BalancerContext {
 
     pub fn add_monolith(&mut self, monolith: BalancerMonolith) {
         let id = monolith.id();
+        emit_event(Event::MonolithConnected(EMonolithConnected {
+            monolith_id: id,
+            region: monolith.region().clone(),
+        }));
         let region = monolith.region().to_string();
         self.monoliths.insert(id, monolith);
         self.monoliths_by_region.entry(region).or_default().push(id);
@@ -286,6 +320,9 @@ impl BalancerContext {
     pub fn remove_monolith(&mut self, monolith_id: MonolithId) -> anyhow::Result<()> {
         let m = self.monoliths.remove(&monolith_id);
         if let Some(m) = m {
+            emit_event(Event::MonolithDisconnected(EMonolithDisconnected {
+                monolith_id,
+            }));
             let region = m.region().to_string();
             self.monoliths_by_region.entry(region).and_modify(|v| {
                 v.retain(|x| *x != monolith_id);
@@ -311,6 +348,7 @@ impl BalancerContext {
             .get_mut(&locator.monolith_id())
             .ok_or(anyhow::anyhow!("monolith not found"))?;
         self.rooms_to_monoliths.insert(room_name.clone(), locator);
+        notify_state_changed();
         let room = monolith.add_room(&room_name)?;
         Ok(room)
     }
@@ -329,6 +367,7 @@ impl BalancerContext {
             }
         }
         monolith.remove_room(room);
+        notify_state_changed();
         Ok(())
     }
 
@@ -365,6 +404,11 @@ impl BalancerContext {
                                 room: metadata.name.clone(),
                             })
                             .await?;
+                        emit_event(Event::RoomConflictResolved(ERoomConflictResolved {
+                            room: metadata.name.clone(),
+                            kept_monolith_id: locator.monolith_id(),
+                            unloaded_monolith_id: monolith_id,
+                        }));
                         return Err(anyhow::anyhow!("room already loaded"));
                     }
                     std::cmp::Ordering::Greater => {
@@ -384,6 +428,11 @@ impl BalancerContext {
                         {
                             warn!(room = %metadata.name, "failed to unload room on old monolith: {:?}", err);
                         }
+                        emit_event(Event::RoomConflictResolved(ERoomConflictResolved {
+                            room: metadata.name.clone(),
+                            kept_monolith_id: monolith_id,
+                            unloaded_monolith_id: old_monolith_id,
+                        }));
                         self.remove_room(&metadata.name, old_monolith_id)?;
                     }
                     std::cmp::Ordering::Equal => {
@@ -498,6 +547,8 @@ impl BalancerContext {
             id: *BALANCER_ID,
             region: BalancerConfig::get().region.clone(),
             monoliths,
+            collected_at: None,
+            error: None,
         }
     }
 }
@@ -510,7 +561,14 @@ pub async fn join_client(
 ) -> anyhow::Result<()> {
     info!("new client");
 
-    let (monolith_id, should_create_room, room_broadcast_rx, client_inbound_tx, send_handle) = {
+    let (
+        monolith_id,
+        should_create_room,
+        room_broadcast_rx,
+        room_message_count,
+        client_inbound_tx,
+        send_handle,
+    ) = {
         let mut ctx_write = ctx.write().await;
 
         let (monolith_id, should_create_room) =
@@ -530,7 +588,7 @@ pub async fn join_client(
                 }
             };
 
-        let room_broadcast_rx = if should_create_room {
+        let room = if should_create_room {
             ctx_write.add_room(
                 new_client.room.clone(),
                 RoomLocator::new(monolith_id, u32::MAX),
@@ -539,14 +597,16 @@ pub async fn join_client(
             let monolith = ctx_write.monoliths.get(&monolith_id).unwrap();
             let room = monolith.rooms().get(&new_client.room).unwrap();
             room
-        }
-        .new_broadcast_rx();
+        };
+        let room_broadcast_rx = room.new_broadcast_rx();
+        let room_message_count = room.message_counter();
 
         let monolith = ctx_write.monoliths.get(&monolith_id).unwrap();
         (
             monolith_id,
             should_create_room,
             room_broadcast_rx,
+            room_message_count,
             monolith.new_inbound_tx(),
             monolith.send_handle(),
         )
@@ -559,6 +619,7 @@ pub async fn join_client(
         client_inbound_tx,
         room_broadcast_rx,
         client_outbound_unicast_rx,
+        room_message_count,
     );
     let client = BalancerClient::new(new_client, client_outbound_unicast_tx);
     match send_handle
@@ -566,6 +627,7 @@ pub async fn join_client(
             room: client.room.clone(),
             client: client.id,
             token: client.token.clone(),
+            trace: current_trace_context(),
         })
         .await
     {
@@ -576,6 +638,12 @@ pub async fn join_client(
                 .get_mut(&monolith_id)
                 .ok_or_else(|| anyhow::anyhow!("monolith not found"))?;
             monolith.add_client(&client.room, client.id);
+            emit_event(Event::ClientJoined(EClientJoined {
+                client_id: client.id,
+                room: client.room.clone(),
+                monolith_id,
+                edge_region: client.edge_region.clone(),
+            }));
             ctx_write.clients.insert(client.id, client);
         }
         Err(MonolithSendError::SendTimeoutError(err)) => {
@@ -617,6 +685,10 @@ pub async fn leave_client(ctx: Arc<RwLock<BalancerContext>>, id: ClientId) -> an
             anyhow::bail!("client not found in context");
         };
         let room_name = removed.room.clone();
+        emit_event(Event::ClientLeft(EClientLeft {
+            client_id: id,
+            room: room_name.clone(),
+        }));
         let Some(locator) = ctx_write.rooms_to_monoliths.get(&removed.room) else {
             warn!("room not found in rooms_to_monoliths");
             return Ok(());
@@ -707,11 +779,14 @@ pub async fn join_monolith(
     Ok(handle)
 }
 
+// Runs on the monolith's task, so it's parented to the client's span explicitly.
+#[instrument(parent = &inbound.span, skip_all, err, fields(client_id = %inbound.msg.id()))]
 async fn handle_client_inbound(
     ctx: Arc<RwLock<BalancerContext>>,
-    msg: Context<ClientId, SocketMessage>,
+    inbound: ClientInbound,
     monolith_outbound_tx: Arc<tokio::sync::mpsc::Sender<SocketMessage>>,
 ) -> anyhow::Result<()> {
+    let msg = inbound.msg;
     match msg.message() {
         SocketMessage::Message(Message::Text(_) | Message::Binary(_)) => {
             let raw_value: Box<RawValue> = msg.message().deserialize()?;
@@ -719,6 +794,7 @@ async fn handle_client_inbound(
             let built_msg: MsgB2M = B2MClientMsg {
                 client_id: *msg.id(),
                 payload: raw_value,
+                trace: current_trace_context(),
             }
             .into();
             let text = serde_json::to_string(&built_msg).expect("failed to serialize message");
@@ -746,6 +822,26 @@ pub async fn leave_monolith(
     Ok(())
 }
 
+/// Stop tracking a room on a Monolith after reconciling its gossip, and let the collector know it's gone.
+async fn remove_gossiped_room(
+    ctx: &Arc<RwLock<BalancerContext>>,
+    room: &RoomName,
+    monolith_id: MonolithId,
+) {
+    let mut ctx_write = ctx.write().await;
+    let was_loaded = ctx_write
+        .monoliths
+        .get(&monolith_id)
+        .is_some_and(|m| m.rooms().contains_key(room));
+    if ctx_write.remove_room(room, monolith_id).is_ok() && was_loaded {
+        emit_event(Event::RoomUnloaded(ERoomUnloaded {
+            room: room.clone(),
+            monolith_id,
+            reason: UnloadReason::Gossip,
+        }));
+    }
+}
+
 async fn add_or_sync_room_ctx(
     ctx: &Arc<RwLock<BalancerContext>>,
     metadata: RoomMetadata,
@@ -755,7 +851,7 @@ async fn add_or_sync_room_ctx(
     let room_name = metadata.name.clone();
 
     enum ConflictAction {
-        Reject(crate::monolith::MonolithSendHandle),
+        Reject(crate::monolith::MonolithSendHandle, MonolithId),
         Replace(crate::monolith::MonolithSendHandle, MonolithId),
     }
 
@@ -782,7 +878,7 @@ async fn add_or_sync_room_ctx(
                             .get(&monolith_id)
                             .ok_or_else(|| anyhow::anyhow!("monolith not found"))?
                             .send_handle();
-                        Some(ConflictAction::Reject(handle))
+                        Some(ConflictAction::Reject(handle, locator.monolith_id()))
                     }
                     std::cmp::Ordering::Greater => {
                         warn!(room = %room_name, "unloading room on old monolith because it's the newer version");
@@ -824,11 +920,25 @@ async fn add_or_sync_room_ctx(
     };
 
     match conflict {
-        Some(ConflictAction::Reject(handle)) => {
-            handle.send(B2MUnload { room: room_name }).await?;
+        Some(ConflictAction::Reject(handle, kept_monolith_id)) => {
+            handle
+                .send(B2MUnload {
+                    room: room_name.clone(),
+                })
+                .await?;
+            emit_event(Event::RoomConflictResolved(ERoomConflictResolved {
+                room: room_name,
+                kept_monolith_id,
+                unloaded_monolith_id: monolith_id,
+            }));
             return Err(anyhow::anyhow!("room already loaded"));
         }
-        Some(ConflictAction::Replace(handle, _old_monolith_id)) => {
+        Some(ConflictAction::Replace(handle, old_monolith_id)) => {
+            emit_event(Event::RoomConflictResolved(ERoomConflictResolved {
+                room: room_name.clone(),
+                kept_monolith_id: monolith_id,
+                unloaded_monolith_id: old_monolith_id,
+            }));
             if let Err(err) = handle
                 .send(B2MUnload {
                     room: room_name.clone(),
@@ -859,7 +969,7 @@ pub async fn dispatch_monolith_message(
             debug!("got message from monolith: {:?}", msg);
 
             match msg {
-                MsgM2B::Init(_) => {
+                MsgM2B::Init(_) | MsgM2B::Auth(_) => {
                     warn!(
                         "monolith {:?} sent init when it wasn't expected, ignoring",
                         monolith_id
@@ -867,7 +977,13 @@ pub async fn dispatch_monolith_message(
                 }
                 MsgM2B::Loaded(msg) => {
                     info!(monolith_id = %monolith_id, room = %msg.room.name, load_epoch = %msg.load_epoch, "room loaded");
+                    let room = msg.room.name.clone();
                     add_or_sync_room_ctx(&ctx, msg.room, *monolith_id, msg.load_epoch).await?;
+                    emit_event(Event::RoomLoaded(ERoomLoaded {
+                        room,
+                        monolith_id: *monolith_id,
+                        load_epoch: msg.load_epoch,
+                    }));
                 }
                 MsgM2B::Unloaded(msg) => {
                     let mut ctx_write = ctx.write().await;
@@ -885,6 +1001,11 @@ pub async fn dispatch_monolith_message(
                         "room unloaded"
                     );
                     ctx_write.remove_room(&msg.name, *monolith_id)?;
+                    emit_event(Event::RoomUnloaded(ERoomUnloaded {
+                        room: msg.name,
+                        monolith_id: *monolith_id,
+                        reason: msg.reason,
+                    }));
                 }
                 MsgM2B::Gossip(msg) => {
                     let to_remove = {
@@ -913,14 +1034,14 @@ pub async fn dispatch_monolith_message(
                             Ok(_) => {}
                             Err(err) => {
                                 warn!("failed to add room: {:?}", err);
-                                let _ = ctx.write().await.remove_room(&room_name, *monolith_id);
+                                remove_gossiped_room(&ctx, &room_name, *monolith_id).await;
                             }
                         }
                     }
 
                     for room in to_remove {
                         info!(room_name = ?room, "removing room from gossip");
-                        let _ = ctx.write().await.remove_room(&room, *monolith_id);
+                        remove_gossiped_room(&ctx, &room, *monolith_id).await;
                     }
                 }
                 MsgM2B::RoomMsg(msg) => {
@@ -930,6 +1051,13 @@ pub async fn dispatch_monolith_message(
                         Some(client_id) => {
                             let client = {
                                 let ctx_read = ctx.read().await;
+                                if let Some(room) = ctx_read
+                                    .monoliths
+                                    .get(monolith_id)
+                                    .and_then(|m| m.rooms().get(&msg.room))
+                                {
+                                    room.count_message();
+                                }
                                 ctx_read
                                     .clients
                                     .get(&client_id)
@@ -951,6 +1079,7 @@ pub async fn dispatch_monolith_message(
                             else {
                                 anyhow::bail!("room not found on monolith");
                             };
+                            room.count_message();
                             // broadcast to all clients
                             debug!("broadcasting to clients in room: {:?}", room.name());
                             room.broadcast(built_msg)?;
@@ -992,7 +1121,8 @@ pub async fn dispatch_monolith_message(
 mod test {
     use std::time::Duration;
 
-    use ott_balancer_protocol::monolith::M2BRoomMsg;
+    use ott_balancer_protocol::collector::BalancerEvent;
+    use ott_balancer_protocol::monolith::{M2BGossip, M2BRoomMsg};
     use ott_common::discovery::{ConnectionConfig, HostOrIp};
     use serde_json::value::RawValue;
     use std::net::Ipv4Addr;
@@ -1022,6 +1152,7 @@ mod test {
                     port: 3002,
                 },
                 proxy_port: 3000,
+                weight: Default::default(),
             },
             monolith_outbound_tx,
             client_inbound_tx,
@@ -1100,6 +1231,7 @@ mod test {
                     port: 3002,
                 },
                 proxy_port: 3000,
+                weight: Default::default(),
             },
             monolith_outbound_tx,
             client_inbound_tx,
@@ -1157,6 +1289,7 @@ mod test {
                     port: 3002,
                 },
                 proxy_port: 3000,
+                weight: Default::default(),
             },
             monolith_outbound_tx_1,
             client_inbound_tx_1,
@@ -1174,6 +1307,7 @@ mod test {
                     port: 3004,
                 },
                 proxy_port: 3000,
+                weight: Default::default(),
             },
             monolith_outbound_tx_2,
             client_inbound_tx_2,
@@ -1240,6 +1374,7 @@ mod test {
                     port: 3002,
                 },
                 proxy_port: 3000,
+                weight: Default::default(),
             },
             monolith_outbound_tx_1,
             client_inbound_tx_1,
@@ -1257,6 +1392,7 @@ mod test {
                     port: 3004,
                 },
                 proxy_port: 3000,
+                weight: Default::default(),
             },
             monolith_outbound_tx_2,
             client_inbound_tx_2,
@@ -1319,6 +1455,7 @@ mod test {
                     port: 3002,
                 },
                 proxy_port: 3000,
+                weight: Default::default(),
             },
             monolith_outbound_tx_1,
             client_inbound_tx_1,
@@ -1336,6 +1473,7 @@ mod test {
                     port: 3004,
                 },
                 proxy_port: 3000,
+                weight: Default::default(),
             },
             monolith_outbound_tx_2,
             client_inbound_tx_2,
@@ -1417,9 +1555,7 @@ mod test {
         BalancerConfig::init_default();
         let ctx = Arc::new(RwLock::new(BalancerContext::new()));
         ctx.write().await.monolith_selection =
-            MonolithSelectionStrategy::HashRing(HashRingSelector {
-                config: Default::default(),
-            });
+            MonolithSelectionStrategy::HashRing(HashRingSelector::default());
         let (monolith_outbound_tx_1, _monolith_outbound_rx_1) = tokio::sync::mpsc::channel(100);
         let monolith_outbound_tx_1 = Arc::new(monolith_outbound_tx_1);
         let (client_inbound_tx_1, _client_inbound_rx_1) = tokio::sync::mpsc::channel(100);
@@ -1433,6 +1569,7 @@ mod test {
                     port: 3002,
                 },
                 proxy_port: 3000,
+                weight: Default::default(),
             },
             monolith_outbound_tx_1,
             client_inbound_tx_1,
@@ -1450,6 +1587,7 @@ mod test {
                     port: 3004,
                 },
                 proxy_port: 3000,
+                weight: Default::default(),
             },
             monolith_outbound_tx_2,
             client_inbound_tx_2,
@@ -1530,6 +1668,7 @@ mod test {
                     port: 3002,
                 },
                 proxy_port: 3000,
+                weight: Default::default(),
             },
             monolith_outbound_tx,
             client_inbound_tx,
@@ -1583,6 +1722,167 @@ mod test {
         assert!(!ctx.read().await.clients.contains_key(&client_id));
     }
 
+    #[tokio::test]
+    async fn should_emit_lifecycle_events() {
+        BalancerConfig::init_default();
+        let mut events_rx = crate::state_stream::EVENT_STREAMER
+            .lock()
+            .unwrap()
+            .event_tx()
+            .subscribe();
+        let room_name = RoomName::from("foo");
+        let ctx = Arc::new(RwLock::new(BalancerContext::new()));
+        let (monolith_outbound_tx, _monolith_outbound_rx) = tokio::sync::mpsc::channel(100);
+        let (client_inbound_tx, _client_inbound_rx) = tokio::sync::mpsc::channel(100);
+        let monolith_id = uuid::Uuid::new_v4().into();
+        let monolith = BalancerMonolith::new(
+            NewMonolith {
+                id: monolith_id,
+                region: "ord".into(),
+                config: ConnectionConfig {
+                    host: HostOrIp::Ip(Ipv4Addr::LOCALHOST.into()),
+                    port: 3002,
+                },
+                proxy_port: 3000,
+                weight: Default::default(),
+            },
+            Arc::new(monolith_outbound_tx),
+            client_inbound_tx,
+        );
+        ctx.write().await.add_monolith(monolith);
+        ctx.write()
+            .await
+            .add_room(room_name.clone(), RoomLocator::new(monolith_id, 0))
+            .expect("failed to add room");
+        let client_id = uuid::Uuid::new_v4().into();
+        let (client_link_tx, client_link_rx) = tokio::sync::oneshot::channel();
+        join_client(
+            &ctx,
+            NewClient {
+                id: client_id,
+                room: room_name.clone(),
+                edge_region: "iad".into(),
+                token: "test".into(),
+            },
+            client_link_tx,
+        )
+        .await
+        .expect("failed to add client");
+        let _client_link = client_link_rx.await.expect("failed to get client link");
+        leave_client(ctx.clone(), client_id)
+            .await
+            .expect("failed to remove client");
+        leave_monolith(ctx.clone(), monolith_id)
+            .await
+            .expect("failed to remove monolith");
+
+        // other tests can emit events at the same time, so only look at the ones for this monolith and client
+        let mut events = vec![];
+        while let Ok(event) = events_rx.try_recv() {
+            let Ok(event) = serde_json::from_str::<BalancerEvent>(&event.text) else {
+                continue;
+            };
+            assert_eq!(event.balancer_id, *BALANCER_ID);
+            let relevant = match &event.event {
+                Event::MonolithConnected(e) => e.monolith_id == monolith_id,
+                Event::MonolithDisconnected(e) => e.monolith_id == monolith_id,
+                Event::ClientJoined(e) => e.client_id == client_id,
+                Event::ClientLeft(e) => e.client_id == client_id,
+                _ => false,
+            };
+            if relevant {
+                events.push(event.event);
+            }
+        }
+        assert_eq!(
+            events,
+            vec![
+                Event::MonolithConnected(EMonolithConnected {
+                    monolith_id,
+                    region: "ord".into(),
+                }),
+                Event::ClientJoined(EClientJoined {
+                    client_id,
+                    room: room_name.clone(),
+                    monolith_id,
+                    edge_region: "iad".into(),
+                }),
+                Event::ClientLeft(EClientLeft {
+                    client_id,
+                    room: room_name,
+                }),
+                Event::MonolithDisconnected(EMonolithDisconnected { monolith_id }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_emit_room_unloaded_for_rooms_missing_from_gossip() {
+        BalancerConfig::init_default();
+        let mut events_rx = crate::state_stream::EVENT_STREAMER
+            .lock()
+            .unwrap()
+            .event_tx()
+            .subscribe();
+        let ctx = Arc::new(RwLock::new(BalancerContext::new()));
+        let (monolith_outbound_tx, _monolith_outbound_rx) = tokio::sync::mpsc::channel(100);
+        let (client_inbound_tx, _client_inbound_rx) = tokio::sync::mpsc::channel(100);
+        let monolith_id = uuid::Uuid::new_v4().into();
+        let monolith = BalancerMonolith::new(
+            NewMonolith {
+                id: monolith_id,
+                region: Default::default(),
+                config: ConnectionConfig {
+                    host: HostOrIp::Ip(Ipv4Addr::LOCALHOST.into()),
+                    port: 3002,
+                },
+                proxy_port: 3000,
+                weight: Default::default(),
+            },
+            Arc::new(monolith_outbound_tx),
+            client_inbound_tx,
+        );
+        ctx.write().await.add_monolith(monolith);
+        ctx.write()
+            .await
+            .add_room("stale".into(), RoomLocator::new(monolith_id, 0))
+            .expect("failed to add room");
+
+        let gossip: MsgM2B = MsgM2B::Gossip(M2BGossip { rooms: vec![] });
+        let text = serde_json::to_string(&gossip).expect("failed to serialize message");
+        dispatch_monolith_message(
+            ctx.clone(),
+            Context::new(monolith_id, Message::Text(text).into()),
+        )
+        .await
+        .expect("failed to dispatch gossip");
+
+        assert!(!ctx
+            .read()
+            .await
+            .rooms_to_monoliths
+            .contains_key(&RoomName::from("stale")));
+        let mut events = vec![];
+        while let Ok(event) = events_rx.try_recv() {
+            let Ok(event) = serde_json::from_str::<BalancerEvent>(&event.text) else {
+                continue;
+            };
+            if let Event::RoomUnloaded(e) = event.event {
+                if e.monolith_id == monolith_id {
+                    events.push(e);
+                }
+            }
+        }
+        assert_eq!(
+            events,
+            vec![ERoomUnloaded {
+                room: "stale".into(),
+                monolith_id,
+                reason: UnloadReason::Gossip,
+            }]
+        );
+    }
+
     #[tokio::test]
     async fn join_client_does_not_hold_context_lock_while_waiting_for_monolith_send() {
         BalancerConfig::init_default();
@@ -1601,6 +1901,7 @@ mod test {
                     port: 3002,
                 },
                 proxy_port: 3000,
+                weight: Default::default(),
             },
             monolith_outbound_tx,
             client_inbound_tx,
@@ -1667,6 +1968,7 @@ mod test {
                     port: 3002,
                 },
                 proxy_port: 3000,
+                weight: Default::default(),
             },
             monolith_outbound_tx,
             client_inbound_tx,
@@ -1726,4 +2028,49 @@ mod test {
         drop(client_rx);
         assert!(task.await.expect("task should complete").is_err());
     }
+
+    #[tokio::test]
+    async fn client_messages_should_continue_the_client_trace() {
+        use opentelemetry::trace::TracerProvider as _;
+        use tracing_subscriber::prelude::*;
+
+        // records spans without exporting them anywhere
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let _guard = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")))
+            .set_default();
+
+        let client_id: ClientId = uuid::Uuid::new_v4().into();
+        let client_span = tracing::info_span!("client_entry");
+        let (inbound, client_trace) = {
+            let _span = client_span.enter();
+            (
+                ClientInbound::new(client_id, Message::Text("{}".to_owned()).into()),
+                current_trace_context().expect("client span should have a trace context"),
+            )
+        };
+
+        // handled outside of the client's span, like on the monolith's task
+        let ctx = Arc::new(RwLock::new(BalancerContext::new()));
+        let (monolith_outbound_tx, mut monolith_outbound_rx) = tokio::sync::mpsc::channel(1);
+        handle_client_inbound(ctx, inbound, Arc::new(monolith_outbound_tx))
+            .await
+            .unwrap();
+
+        let SocketMessage::Message(Message::Text(text)) =
+            monolith_outbound_rx.recv().await.unwrap()
+        else {
+            panic!("expected a text message");
+        };
+        let MsgB2M::ClientMsg(msg) = serde_json::from_str(&text).unwrap() else {
+            panic!("expected a client message");
+        };
+        let trace = msg.trace.expect("message should carry the trace context");
+        let trace_id = |traceparent: &str| traceparent.split('-').nth(1).unwrap().to_owned();
+        assert_eq!(
+            trace_id(&trace.traceparent),
+            trace_id(&client_trace.traceparent)
+        );
+        assert_ne!(trace.traceparent, client_trace.traceparent);
+    }
 }
@@ -1,3 +1,5 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use futures_util::{SinkExt, StreamExt};
@@ -70,32 +72,59 @@ pub struct NewClient {
     pub token: String,
 }
 
+/// A message from a client, on its way to the Monolith that has the client's Room.
+#[derive(Debug)]
+pub struct ClientInbound {
+    pub msg: Context<ClientId, SocketMessage>,
+    /// The span of the client's connection, so that handling the message is part of the client's trace.
+    pub span: tracing::Span,
+}
+
+impl ClientInbound {
+    /// Wrap a message that is being sent from within the client's connection span.
+    pub fn new(id: ClientId, msg: SocketMessage) -> Self {
+        Self {
+            msg: Context::new(id, msg),
+            span: tracing::Span::current(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ClientLink {
     id: ClientId,
     /// Messages to send to the Room this client is in.
-    room_tx: tokio::sync::mpsc::Sender<Context<ClientId, SocketMessage>>,
+    room_tx: tokio::sync::mpsc::Sender<ClientInbound>,
     /// Messages sent by the Balancer that need to be sent to all clients in the same room as this client.
     broadcast_rx: tokio::sync::broadcast::Receiver<SocketMessage>,
     /// Messages sent by the Balancer that need to be sent to this client.
     unicast_rx: tokio::sync::mpsc::Receiver<SocketMessage>,
+    /// The message counter of the Room this client is in, for the room metrics.
+    room_message_count: Arc<AtomicU64>,
 }
 
 impl ClientLink {
     pub fn new(
         id: ClientId,
-        room_tx: tokio::sync::mpsc::Sender<Context<ClientId, SocketMessage>>,
+        room_tx: tokio::sync::mpsc::Sender<ClientInbound>,
         broadcast_rx: tokio::sync::broadcast::Receiver<SocketMessage>,
         unicast_rx: tokio::sync::mpsc::Receiver<SocketMessage>,
+        room_message_count: Arc<AtomicU64>,
     ) -> Self {
         Self {
             id,
             room_tx,
             broadcast_rx,
             unicast_rx,
+            room_message_count,
         }
     }
 
+    /// Count a message that this client sent to its Room.
+    pub fn count_room_message(&self) {
+        self.room_message_count.fetch_add(1, Ordering::Relaxed);
+    }
+
     /// Receive the next message from the Balancer that needs to be sent to this client.
     pub async fn outbound_recv(&mut self) -> Result<SocketMessage, RecvError> {
         let msg = tokio::select! {
@@ -134,7 +163,9 @@ impl ClientLink {
 
     /// Send a message to the Room this client is in via the Balancer
     pub async fn inbound_send(&mut self, msg: impl Into<SocketMessage>) -> anyhow::Result<()> {
-        self.room_tx.send(Context::new(self.id, msg.into())).await?;
+        self.room_tx
+            .send(ClientInbound::new(self.id, msg.into()))
+            .await?;
 
         Ok(())
     }
@@ -271,7 +302,7 @@ pub async fn client_entry<'r>(
             msg = client_link.outbound_recv() => {
                 match msg {
                     Ok(SocketMessage::Message(msg)) => {
-                        debug!(event = "ws", balancer_id = %*BALANCER_ID,  node_id = %client_id, room = %room_name, direction = "tx");
+                        debug!(event = "ws", balancer_id = %*BALANCER_ID,  node_id = %client_id, client_id = %client_id, room = %room_name, direction = "tx");
 
                         let mut close_code = None;
                         if let Message::Close(Some(frame)) = &msg {
@@ -313,7 +344,8 @@ pub async fn client_entry<'r>(
                         continue;
                     }
 
-                    debug!(event = "ws", balancer_id = %*BALANCER_ID,  node_id = %client_id, room = %room_name, direction = "rx");
+                    debug!(event = "ws", balancer_id = %*BALANCER_ID,  node_id = %client_id, client_id = %client_id, room = %room_name, direction = "rx");
+                    client_link.count_room_message();
                     if let Err(err) = client_link.inbound_send(msg).await {
                         error!("Error sending client message to balancer: {:?}", err);
                         break;
@@ -354,7 +386,7 @@ pub async fn client_entry<'r>(
     if !already_sent_close && !client_link.room_tx.is_closed() {
         client_link
             .room_tx
-            .send(Context::new(
+            .send(ClientInbound::new(
                 client_id,
                 SocketMessage::Message(Message::Close(Some(CloseFrame {
                     code: CloseCode::Normal,
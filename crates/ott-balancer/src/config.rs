@@ -1,28 +1,56 @@
-use std::{borrow::BorrowMut, path::PathBuf, sync::Once};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::SystemTime;
 
-use clap::{Parser, ValueEnum};
-use figment::providers::Format;
+use anyhow::Context;
+use clap::{Parser, Subcommand, ValueEnum};
+use figment::providers::{Env, Format, Toml};
+use figment::value::{Dict, Map};
+use figment::{Figment, Metadata, Profile, Provider};
 use ott_balancer_protocol::Region;
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
 use ott_common::discovery::DiscoveryConfig;
 
+use crate::link::MonolithLinkConfig;
+use crate::metrics::MetricsConfig;
 use crate::selection::MonolithSelectionConfig;
+use crate::telemetry::OtlpConfig;
+use crate::tls::TlsConfig;
 
-static mut CONFIG: Option<BalancerConfig> = None;
+static CONFIG: RwLock<Option<Arc<BalancerConfig>>> = RwLock::new(None);
 
-static CONFIG_INIT: Once = Once::new();
+/// The file that the config was loaded from, so that it can be reloaded later.
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
 
-#[derive(Debug, Deserialize)]
-#[serde(default)]
+/// Config fields that are only read at startup, so changing them has no effect until the balancer is restarted.
+const RESTART_REQUIRED: &[&str] = &["port", "discovery", "tls", "monolith_link", "otlp"];
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
 pub struct BalancerConfig {
     /// The port to listen on for HTTP requests.
     pub port: u16,
     pub discovery: DiscoveryConfig,
+    #[schemars(with = "String")]
     pub region: Region,
     /// The API key that clients can use to access restricted endpoints.
+    ///
+    /// This key is granted every scope. Prefer `api_keys` for new deployments.
     pub api_key: Option<String>,
+    /// Named API keys, each limited to a set of scopes.
+    pub api_keys: Vec<ApiKeyConfig>,
     pub selection_strategy: Option<MonolithSelectionConfig>,
+    /// Serve HTTPS instead of plain HTTP on `port`.
+    pub tls: Option<TlsConfig>,
+    /// How to secure and authenticate the connections to monoliths.
+    pub monolith_link: MonolithLinkConfig,
+    /// Export traces to an OpenTelemetry collector.
+    pub otlp: Option<OtlpConfig>,
+    /// Limits on the per-room and per-region metrics.
+    pub metrics: MetricsConfig,
 }
 
 impl Default for BalancerConfig {
@@ -32,53 +60,387 @@ impl Default for BalancerConfig {
             discovery: DiscoveryConfig::default(),
             region: Default::default(),
             api_key: None,
+            api_keys: vec![],
             selection_strategy: None,
+            tls: None,
+            monolith_link: MonolithLinkConfig::default(),
+            otlp: None,
+            metrics: MetricsConfig::default(),
+        }
+    }
+}
+
+/// A named API key that grants access to a subset of the restricted endpoints.
+///
+/// Multiple keys can be valid at the same time, which allows keys to be rotated without downtime.
+#[derive(Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    /// A human readable name for this key. Used in logs and metrics, so it must not be secret.
+    pub name: String,
+    pub key: String,
+    pub scopes: Vec<ApiKeyScope>,
+    /// When this key stops being accepted, as an RFC 3339 timestamp. If not set, the key never expires.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub expires: Option<SystemTime>,
+}
+
+impl std::fmt::Debug for ApiKeyConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // the key itself is left out so that it doesn't end up in the logs
+        f.debug_struct("ApiKeyConfig")
+            .field("name", &self.name)
+            .field("scopes", &self.scopes)
+            .field(
+                "expires",
+                &self
+                    .expires
+                    .map(humantime_serde::re::humantime::format_rfc3339),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl ApiKeyConfig {
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApiKeyScope {
+    /// Read the balancer's current state.
+    ReadState,
+    /// Subscribe to the balancer's event stream.
+    StreamEvents,
+    /// Make changes to the balancer at runtime.
+    AdminWrite,
+}
+
+impl ApiKeyScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiKeyScope::ReadState => "read-state",
+            ApiKeyScope::StreamEvents => "stream-events",
+            ApiKeyScope::AdminWrite => "admin-write",
         }
     }
 }
 
+/// Config fields that hold secrets, and must never be printed.
+const SECRET_FIELDS: &[&str] = &["api_key", "key", "secret", "token"];
+
+/// The prefix of environment variables that override the config file. Nested fields are separated with `__`, like `BALANCER_DISCOVERY__METHOD`.
+const ENV_PREFIX: &str = "BALANCER_";
+const ENV_SPLIT: &str = "__";
+
 impl BalancerConfig {
-    pub fn load(path: &PathBuf) -> Result<(), anyhow::Error> {
-        let mut config: BalancerConfig = figment::Figment::new()
-            .merge(figment::providers::Toml::file(path))
-            .merge(figment::providers::Env::prefixed("BALANCER_"))
-            .extract()?;
-
-        if let Some(region) = figment::providers::Env::var("FLY_REGION") {
-            config.region = region.into();
-        }
-        // SAFETY: CONFIG is only mutated once, and only from this thread. All other accesses are read-only.
-        #[allow(static_mut_refs)]
-        CONFIG_INIT.call_once(|| unsafe { *CONFIG.borrow_mut() = Some(config) });
+    /// Environment variables that start with [`ENV_PREFIX`] are only read if they name a top level config field, so
+    /// unrelated ones (like the `BALANCER_SERVICE_HOST` that Kubernetes sets for a service named `balancer`) are
+    /// ignored. Unknown fields in the config file, and in the sections that variables do name, are still rejected.
+    fn figment(path: &Path) -> Figment {
+        let fields: Arc<[String]> = Self::json_schema()
+            .schema
+            .object
+            .map(|object| object.properties.into_keys().collect())
+            .unwrap_or_default();
+        let env = Env::prefixed(ENV_PREFIX)
+            .split(ENV_SPLIT)
+            .filter(move |key| {
+                let field = key.as_str().split('.').next().unwrap_or_default();
+                fields.iter().any(|known| known.eq_ignore_ascii_case(field))
+            });
+        Figment::new()
+            .merge(Toml::file(path))
+            .merge(env)
+            .merge(FlyRegion)
+    }
+
+    /// Read and validate the config from the given file and the environment.
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let config: BalancerConfig = Self::figment(path).extract()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Render the effective config, one value per line, along with where each value came from. Secrets are redacted.
+    pub fn explain(path: &Path) -> anyhow::Result<String> {
+        let figment = Self::figment(path);
+        let config: BalancerConfig = figment.extract()?;
+        config.validate()?;
+
+        let effective = serde_json::to_value(&config)?;
+        let provided = figment.find_value("").ok();
+        let mut lines = vec![];
+        explain_value(
+            &figment,
+            &mut vec![],
+            &effective,
+            provided.as_ref(),
+            &mut lines,
+        );
+
+        let width = lines
+            .iter()
+            .map(|(key, value, _)| key.len() + value.len())
+            .max()
+            .unwrap_or(0);
+        Ok(lines
+            .into_iter()
+            .map(|(key, value, source)| {
+                let padding = width - key.len() - value.len();
+                format!("{key} = {value}{:padding$}  # {source}", "")
+            })
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    /// A JSON Schema for the config file, for editor tooling.
+    pub fn json_schema() -> schemars::schema::RootSchema {
+        schemars::schema_for!(BalancerConfig)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut names = HashSet::new();
+        for key in &self.api_keys {
+            if key.key.is_empty() {
+                anyhow::bail!("api key {:?} is empty", key.name);
+            }
+            if !names.insert(key.name.as_str()) {
+                anyhow::bail!("api key name {:?} is used more than once", key.name);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<(), anyhow::Error> {
+        let config = Self::read(path)?;
+        let _ = CONFIG_PATH.set(path.to_owned());
+        Self::set(config);
         Ok(())
     }
 
-    /// Initialize the config with default values.
+    /// Re-read the config from the same sources as [`BalancerConfig::load`], and swap it in if it's valid.
+    ///
+    /// Returns the previous config and the new one. Use [`crate::reload::reload_config`], which makes sure that only
+    /// one reload runs at a time.
+    pub fn reload() -> anyhow::Result<(Arc<Self>, Arc<Self>)> {
+        let path = CONFIG_PATH
+            .get()
+            .context("config was not loaded from a file")?;
+        let config = Arc::new(Self::read(path)?);
+        let old = Self::set(config.clone()).context("config not initialized")?;
+        Ok((old, config))
+    }
+
+    /// Initialize the config with default values, if it hasn't been initialized yet.
     pub fn init_default() {
-        // SAFETY: CONFIG is only mutated once, and only from this thread. All other accesses are read-only.
-        #[allow(static_mut_refs)]
-        CONFIG_INIT.call_once(|| unsafe { *CONFIG.borrow_mut() = Some(BalancerConfig::default()) });
+        let mut config = CONFIG.write().unwrap();
+        if config.is_none() {
+            *config = Some(Arc::new(BalancerConfig::default()));
+        }
+    }
+
+    /// Get the current config. The returned config won't change, even if the config is reloaded.
+    pub fn get() -> Arc<Self> {
+        CONFIG
+            .read()
+            .unwrap()
+            .clone()
+            .expect("config not initialized")
+    }
+
+    /// Atomically replace the current config, returning the previous one.
+    fn set(config: impl Into<Arc<Self>>) -> Option<Arc<Self>> {
+        CONFIG.write().unwrap().replace(config.into())
+    }
+
+    /// Modify the current config in place. Should only be used for tests and benchmarks.
+    pub fn update(f: impl FnOnce(&mut Self)) {
+        let mut config = CONFIG.write().unwrap();
+        let config = config.as_mut().expect("config not initialized");
+        f(Arc::make_mut(config));
     }
 
-    pub fn get() -> &'static Self {
-        debug_assert!(CONFIG_INIT.is_completed(), "config not initialized");
-        // SAFETY: get is never called before CONFIG is initialized.
-        #[allow(static_mut_refs)]
-        unsafe {
-            CONFIG.as_ref().expect("config not initialized")
+    /// Describe what changed between this config and `new`, without revealing any secrets.
+    pub fn diff(&self, new: &Self) -> Vec<ConfigChange> {
+        let mut changes = vec![];
+        macro_rules! diff_field {
+            ($field:ident) => {
+                let (old_value, new_value) =
+                    (format!("{:?}", self.$field), format!("{:?}", new.$field));
+                if old_value != new_value {
+                    changes.push(ConfigChange::new(
+                        stringify!($field),
+                        format!("{} -> {}", old_value, new_value),
+                    ));
+                }
+            };
+            ($field:ident, redacted) => {
+                if self.$field != new.$field {
+                    changes.push(ConfigChange::new(
+                        stringify!($field),
+                        "<redacted>".to_owned(),
+                    ));
+                }
+            };
         }
+
+        diff_field!(port);
+        diff_field!(discovery);
+        diff_field!(region);
+        diff_field!(api_key, redacted);
+        diff_field!(api_keys);
+        diff_field!(selection_strategy);
+        diff_field!(tls);
+        diff_field!(monolith_link);
+        diff_field!(otlp);
+        diff_field!(metrics);
+
+        // Secrets are left out of the Debug output, so a change to only a secret needs to be checked separately.
+        let api_key_values = |config: &Self| {
+            config
+                .api_keys
+                .iter()
+                .map(|k| k.key.clone())
+                .collect::<Vec<_>>()
+        };
+        if !changes.iter().any(|c| c.field == "api_keys")
+            && api_key_values(self) != api_key_values(new)
+        {
+            changes.push(ConfigChange::new("api_keys", "<redacted>".to_owned()));
+        }
+        if !changes.iter().any(|c| c.field == "monolith_link")
+            && self.monolith_link.secret != new.monolith_link.secret
+        {
+            changes.push(ConfigChange::new("monolith_link", "<redacted>".to_owned()));
+        }
+        changes
     }
+}
 
-    /// Get a mutable reference to the config. Should only be used for tests and benchmarks.
-    ///
-    /// # Safety
-    ///
-    /// This function makes absolutely no attempts to ensure atomicity of access to the config.
-    pub unsafe fn get_mut() -> &'static mut Self {
-        debug_assert!(CONFIG_INIT.is_completed(), "config not initialized");
-        // SAFETY: get_mut is only used for benchmarks
-        #[allow(static_mut_refs)]
-        CONFIG.as_mut().expect("config not initialized")
+/// Sets `region` from the `FLY_REGION` environment variable, which fly.io sets on every machine.
+struct FlyRegion;
+
+const FLY_REGION_SOURCE: &str = "FLY_REGION";
+
+impl Provider for FlyRegion {
+    fn metadata(&self) -> Metadata {
+        Metadata::named(FLY_REGION_SOURCE)
+    }
+
+    fn data(&self) -> Result<Map<Profile, Dict>, figment::Error> {
+        let mut dict = Dict::new();
+        if let Some(region) = Env::var(FLY_REGION_SOURCE) {
+            dict.insert("region".into(), region.into());
+        }
+        Ok(Profile::Default.collect(dict))
+    }
+}
+
+/// Walk the effective config, and look up where each leaf value came from in the figment.
+///
+/// `provided` is the value at the same path in the figment, if any provider set it. Otherwise, the value is a default.
+fn explain_value(
+    figment: &Figment,
+    path: &mut Vec<String>,
+    value: &serde_json::Value,
+    provided: Option<&figment::value::Value>,
+    lines: &mut Vec<(String, String, String)>,
+) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (key, value) in fields {
+                path.push(key.clone());
+                let provided = provided.and_then(|p| p.as_dict()).and_then(|d| d.get(key));
+                explain_value(figment, path, value, provided, lines);
+                path.pop();
+            }
+        }
+        serde_json::Value::Array(items) if !items.is_empty() => {
+            for (i, value) in items.iter().enumerate() {
+                path.push(i.to_string());
+                let provided = provided.and_then(|p| p.as_array()).and_then(|a| a.get(i));
+                explain_value(figment, path, value, provided, lines);
+                path.pop();
+            }
+        }
+        serde_json::Value::Null => {}
+        _ => {
+            let key = path
+                .iter()
+                .map(|part| match part.parse::<usize>() {
+                    Ok(i) => format!("[{i}]"),
+                    Err(_) => format!(".{part}"),
+                })
+                .collect::<String>();
+            let key = key.trim_start_matches('.').to_owned();
+            let is_secret = path
+                .last()
+                .is_some_and(|field| SECRET_FIELDS.contains(&field.as_str()));
+            let rendered = if is_secret {
+                "\"<redacted>\"".to_owned()
+            } else {
+                value.to_string()
+            };
+            let source = match provided {
+                Some(provided) => describe_source(figment, provided, path),
+                None => "default".to_owned(),
+            };
+            lines.push((key, rendered, source));
+        }
+    }
+}
+
+fn describe_source(figment: &Figment, value: &figment::value::Value, path: &[String]) -> String {
+    let Some(metadata) = figment.get_metadata(value.tag()) else {
+        return "unknown".to_owned();
+    };
+    match &metadata.source {
+        Some(figment::Source::File(file)) => file.display().to_string(),
+        _ if metadata.name.ends_with("environment variable(s)") => env_var_name(path),
+        _ => metadata.name.to_string(),
+    }
+}
+
+/// Find the environment variable that set the field at `path`. It's either the one for the field itself, or for one of its parents, like `BALANCER_DISCOVERY={method="fly"}`.
+fn env_var_name(path: &[String]) -> String {
+    let name = |len: usize| {
+        format!(
+            "{ENV_PREFIX}{}",
+            path[..len].join(ENV_SPLIT).to_ascii_uppercase()
+        )
+    };
+    (1..=path.len())
+        .rev()
+        .map(name)
+        .find(|name| std::env::var_os(name).is_some())
+        .unwrap_or_else(|| name(path.len()))
+}
+
+/// A top level config field that changed during a reload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigChange {
+    pub field: &'static str,
+    pub description: String,
+}
+
+impl ConfigChange {
+    fn new(field: &'static str, description: String) -> Self {
+        Self { field, description }
+    }
+
+    /// Whether this change only takes effect after a restart.
+    pub fn requires_restart(&self) -> bool {
+        RESTART_REQUIRED.contains(&self.field)
+    }
+}
+
+impl std::fmt::Display for ConfigChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.description)
     }
 }
 
@@ -103,6 +465,17 @@ pub struct Cli {
     /// Validate the configuration file.
     #[clap(long, short)]
     pub validate: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Print the effective config after merging the config file and environment variables, and where each value came from.
+    PrintConfig,
+    /// Print a JSON Schema for the config file.
+    Schema,
 }
 
 impl Cli {
@@ -147,3 +520,231 @@ impl From<LogLevel> for tracing::Level {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Run `test` in its own environment, so that variables that one test sets can't leak into others.
+    fn with_env(test: impl FnOnce(&mut figment::Jail)) {
+        #[allow(clippy::result_large_err)]
+        figment::Jail::expect_with(|jail| {
+            test(jail);
+            Ok(())
+        });
+    }
+
+    fn api_key(name: &str, key: &str) -> ApiKeyConfig {
+        ApiKeyConfig {
+            name: name.to_owned(),
+            key: key.to_owned(),
+            scopes: vec![ApiKeyScope::ReadState],
+            expires: None,
+        }
+    }
+
+    #[test]
+    fn should_parse_api_keys() {
+        let config = serde_json::json!({
+            "api_keys": [
+                {
+                    "name": "collector",
+                    "key": "COLLECTOR_KEY",
+                    "scopes": ["read-state", "stream-events"],
+                    "expires": "2030-01-01T00:00:00Z",
+                },
+                {
+                    "name": "admin",
+                    "key": "ADMIN_KEY",
+                    "scopes": ["admin-write"],
+                },
+            ]
+        });
+
+        let config: BalancerConfig =
+            serde_json::from_value(config).expect("failed to parse api keys");
+        assert_eq!(config.api_keys.len(), 2);
+        assert_eq!(
+            config.api_keys[0].scopes,
+            vec![ApiKeyScope::ReadState, ApiKeyScope::StreamEvents]
+        );
+        assert!(config.api_keys[0].expires.is_some());
+        assert!(config.api_keys[1].expires.is_none());
+        assert!(!format!("{:?}", config.api_keys[0]).contains("COLLECTOR_KEY"));
+    }
+
+    #[test]
+    fn should_read_and_validate_config_file() {
+        with_env(|_| {
+            let path =
+                std::env::temp_dir().join(format!("ott-balancer-{}.toml", uuid::Uuid::new_v4()));
+            std::fs::write(
+                &path,
+                r#"
+                region = "ord"
+                [[api_keys]]
+                name = "collector"
+                key = "a"
+                scopes = ["read-state"]
+                [[api_keys]]
+                name = "collector"
+                key = "b"
+                scopes = ["read-state"]
+                "#,
+            )
+            .unwrap();
+            let err =
+                BalancerConfig::read(&path).expect_err("duplicate key names should be rejected");
+            assert!(err.to_string().contains("more than once"));
+
+            std::fs::write(
+                &path,
+                "region = \"ord\"\nselection_strategy = { strategy = \"Random\" }",
+            )
+            .unwrap();
+            let config = BalancerConfig::read(&path).expect("failed to read config");
+            assert_eq!(
+                config.selection_strategy,
+                Some(MonolithSelectionConfig::Random)
+            );
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+
+    #[test]
+    fn should_reject_unknown_keys() {
+        with_env(|_| {
+            let path =
+                std::env::temp_dir().join(format!("ott-balancer-{}.toml", uuid::Uuid::new_v4()));
+            for (contents, typo) in [
+                ("selction_strategy = { strategy = \"Random\" }", "selction_strategy"),
+                ("[discovery]\nmethod = \"fly\"\nfly_app = \"ott\"\nservice_port = 3002\npoling_interval = \"1s\"", "poling_interval"),
+                ("[monolith_link]\nsecrat = \"s\"", "secrat"),
+            ] {
+                std::fs::write(&path, contents).unwrap();
+                let err = BalancerConfig::read(&path).expect_err("unknown keys should be rejected");
+                assert!(err.to_string().contains(typo), "{}", err);
+            }
+            let _ = std::fs::remove_file(&path);
+        });
+    }
+
+    #[test]
+    fn should_ignore_unrelated_environment_variables() {
+        with_env(|jail| {
+            jail.create_file("balancer.toml", "region = \"ord\"")
+                .unwrap();
+            jail.set_env("BALANCER_SERVICE_HOST", "10.0.0.1");
+            jail.set_env("BALANCER_PORT_8081_TCP_ADDR", "10.0.0.1");
+            jail.set_env("BALANCER_METRICS__TOP_ROOMS", 7);
+            let config = BalancerConfig::read(Path::new("balancer.toml"))
+                .expect("unrelated variables should be ignored");
+            assert_eq!(config.metrics.top_rooms, 7);
+
+            jail.set_env("BALANCER_METRICS__TOP_ROMS", 7);
+            let err = BalancerConfig::read(Path::new("balancer.toml"))
+                .expect_err("unknown keys in config sections should be rejected");
+            assert!(err.to_string().contains("top_roms"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn explain_should_show_sources_and_redact_secrets() {
+        with_env(|_| {
+            let path =
+                std::env::temp_dir().join(format!("ott-balancer-{}.toml", uuid::Uuid::new_v4()));
+            std::fs::write(
+                &path,
+                "api_key = \"hunter2\"\n[monolith_link]\nsecret = \"hunter3\"",
+            )
+            .unwrap();
+            let rendered = BalancerConfig::explain(&path).expect("failed to explain config");
+            let _ = std::fs::remove_file(&path);
+
+            let line = |key: &str| {
+                rendered
+                    .lines()
+                    .find(|line| line.starts_with(&format!("{key} =")))
+                    .unwrap_or_else(|| panic!("missing {key} in:\n{rendered}"))
+                    .to_owned()
+            };
+            assert!(line("port").ends_with("# default"));
+            assert!(line("discovery.method").contains(r#""manual""#));
+            assert!(line("api_key").ends_with(&format!("# {}", path.display())));
+            assert!(line("monolith_link.secret").contains("<redacted>"));
+            assert!(!rendered.contains("hunter"), "{}", rendered);
+        });
+    }
+
+    #[test]
+    fn explain_should_name_nested_environment_variables() {
+        with_env(|jail| {
+            jail.create_file("balancer.toml", "").unwrap();
+            jail.set_env("BALANCER_METRICS__TOP_ROOMS", 7);
+            let rendered = BalancerConfig::explain(Path::new("balancer.toml"))
+                .expect("failed to explain config");
+
+            let line = rendered
+                .lines()
+                .find(|line| line.starts_with("metrics.top_rooms ="))
+                .unwrap_or_else(|| panic!("missing metrics.top_rooms in:\n{rendered}"));
+            assert!(line.contains(" 7 "), "{}", line);
+            assert!(line.ends_with("# BALANCER_METRICS__TOP_ROOMS"), "{}", line);
+        });
+    }
+
+    #[test]
+    fn json_schema_should_include_nested_configs() {
+        let schema = serde_json::to_value(BalancerConfig::json_schema()).unwrap();
+        let definitions = schema["definitions"].as_object().unwrap();
+        for name in ["DiscoveryConfig", "MonolithSelectionConfig", "ApiKeyConfig"] {
+            assert!(definitions.contains_key(name), "missing {name}");
+        }
+        assert_eq!(schema["additionalProperties"], serde_json::json!(false));
+    }
+
+    #[test]
+    fn diff_should_list_changed_fields() {
+        let old = BalancerConfig::default();
+        let new = BalancerConfig {
+            port: 9000,
+            region: "ord".into(),
+            selection_strategy: Some(MonolithSelectionConfig::Random),
+            ..Default::default()
+        };
+
+        let changes = old.diff(&new);
+        let fields: Vec<_> = changes.iter().map(|c| c.field).collect();
+        assert_eq!(fields, ["port", "region", "selection_strategy"]);
+        assert!(changes[0].requires_restart());
+        assert!(!changes[1].requires_restart());
+        assert_eq!(
+            changes[1].to_string(),
+            r#"region: Region("unknown") -> Region("ord")"#
+        );
+        assert!(old.diff(&old).is_empty());
+    }
+
+    #[test]
+    fn diff_should_not_reveal_secrets() {
+        let old = BalancerConfig {
+            api_key: Some("old-legacy-key".to_owned()),
+            api_keys: vec![api_key("collector", "old-scoped-key")],
+            ..Default::default()
+        };
+        let new = BalancerConfig {
+            api_key: Some("new-legacy-key".to_owned()),
+            api_keys: vec![api_key("collector", "new-scoped-key")],
+            ..Default::default()
+        };
+
+        let changes = old.diff(&new);
+        let fields: Vec<_> = changes.iter().map(|c| c.field).collect();
+        assert_eq!(fields, ["api_key", "api_keys"]);
+        for change in changes {
+            let rendered = change.to_string();
+            assert!(!rendered.contains("legacy-key"), "{}", rendered);
+            assert!(!rendered.contains("scoped-key"), "{}", rendered);
+        }
+    }
+}
@@ -1,12 +1,13 @@
 //! Manages connections to Monoliths.
 
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use std::time::Duration;
 
 use futures_util::{SinkExt, StreamExt};
-use ott_balancer_protocol::monolith::{MsgB2M, MsgM2B};
+use ott_balancer_protocol::monolith::{LinkAuth, MsgB2M, MsgM2B};
 use ott_common::discovery::{ConnectionConfig, ServiceDiscoveryMsg};
-use tokio_tungstenite::{connect_async, WebSocketStream};
+use tokio_tungstenite::WebSocketStream;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 use tungstenite::protocol::frame::coding::CloseCode;
@@ -14,10 +15,10 @@ use tungstenite::protocol::CloseFrame;
 use tungstenite::Message;
 
 use crate::balancer::BalancerLink;
+use crate::link::LinkSecurity;
 use crate::messages::SocketMessage;
-use crate::monolith::NewMonolith;
+use crate::monolith::{MonolithWeight, NewMonolith};
 use crate::service::set_discovery_metrics;
-use ott_balancer_protocol::monolith::B2MInit;
 use ott_balancer_protocol::*;
 use uuid::Uuid;
 
@@ -28,6 +29,7 @@ pub static BALANCER_ID: Lazy<BalancerId> = Lazy::new(|| Uuid::new_v4().into());
 pub struct MonolithConnectionManager {
     discovery_rx: tokio::sync::mpsc::Receiver<ServiceDiscoveryMsg>,
     link: BalancerLink,
+    security: Arc<LinkSecurity>,
 
     monoliths: HashSet<ConnectionConfig>,
     connection_tasks: HashMap<ConnectionConfig, ActiveConnection>,
@@ -37,10 +39,12 @@ impl MonolithConnectionManager {
     pub fn new(
         discovery_rx: tokio::sync::mpsc::Receiver<ServiceDiscoveryMsg>,
         link: BalancerLink,
+        security: Arc<LinkSecurity>,
     ) -> Self {
         Self {
             discovery_rx,
             link,
+            security,
             monoliths: Default::default(),
             connection_tasks: Default::default(),
         }
@@ -61,22 +65,43 @@ impl MonolithConnectionManager {
             }
         }
 
-        for conf in msg.added {
-            info!("Connecting to monolith at {}", conf.uri());
+        let weight = |conf: &ConnectionConfig| msg.metadata.get(conf).and_then(|meta| meta.weight);
+        for (conf, active) in &self.connection_tasks {
+            if let Some(weight) = weight(conf) {
+                active.weight.set(weight);
+            }
+        }
+
+        for conf in &msg.added {
+            info!("Connecting to monolith at {}", self.security.uri(conf));
             let link = self.link.clone();
+            let security = self.security.clone();
             self.monoliths.insert(conf.clone());
 
             let cancel = CancellationToken::new();
+            let weight = weight(conf).map(MonolithWeight::new).unwrap_or_default();
             let conf_clone = conf.clone();
             let cancel_clone = cancel.clone();
+            let weight_clone = weight.clone();
             let handle = tokio::task::Builder::new()
                 .name("monolith connection")
                 .spawn(async move {
-                    connect_and_maintain(conf_clone, link.clone(), cancel_clone).await
+                    connect_and_maintain(
+                        conf_clone,
+                        link.clone(),
+                        security,
+                        cancel_clone,
+                        weight_clone,
+                    )
+                    .await
                 })?;
-            let active = ActiveConnection { handle, cancel };
+            let active = ActiveConnection {
+                handle,
+                cancel,
+                weight,
+            };
 
-            self.connection_tasks.insert(conf, active);
+            self.connection_tasks.insert(conf.clone(), active);
         }
 
         set_discovery_metrics(self.monoliths.len());
@@ -88,21 +113,23 @@ impl MonolithConnectionManager {
 async fn connect_and_maintain(
     conf: ConnectionConfig,
     link: BalancerLink,
+    security: Arc<LinkSecurity>,
     cancel: CancellationToken,
+    weight: MonolithWeight,
 ) {
     let mut stream: WebSocketStream<_>;
     loop {
         // start the initial connection as if this is a brand new connection
         loop {
             tokio::select! {
-                result = connect_async(conf.uri()) => {
+                result = security.connect(&conf) => {
                     match result {
-                        Ok((s, _)) => {
+                        Ok(s) => {
                             stream = s;
                             break;
                         },
                         Err(err) => {
-                            error!("Failed to connect to monolith: {}", err);
+                            error!("Failed to connect to monolith: {:?}", err);
                             tokio::time::sleep(Duration::from_secs(5)).await;
                             continue;
                         }
@@ -116,10 +143,10 @@ async fn connect_and_maintain(
             }
         }
 
-        let init = B2MInit { id: *BALANCER_ID };
+        let init = security.build_init(*BALANCER_ID);
         stream
             .send(Message::Text(
-                serde_json::to_string(&MsgB2M::Init(init)).unwrap(),
+                serde_json::to_string(&MsgB2M::Init(init.clone())).unwrap(),
             ))
             .await
             .unwrap_or_else(|err| {
@@ -162,14 +189,41 @@ async fn connect_and_maintain(
                 };
 
                 match message {
-                    MsgM2B::Init(init) => {
-                        monolith_id = init.id;
+                    MsgM2B::Init(monolith_init) => {
+                        if let Some(auth) = security.authenticate(&init, &monolith_init) {
+                            stream
+                                .send(Message::Text(
+                                    serde_json::to_string(&MsgB2M::Auth(auth)).unwrap(),
+                                ))
+                                .await
+                                .unwrap_or_else(|err| {
+                                    error!("Failed to send auth message to monolith: {}", err);
+                                });
+                        }
+                        let auth = if security.requires_auth() {
+                            receive_auth(&mut stream).await
+                        } else {
+                            None
+                        };
+                        if let Err(err) = security.verify_init(&init, &monolith_init, auth.as_ref())
+                        {
+                            warn!(monolith_id = %monolith_init.id, "Monolith failed authentication: {}", err);
+                            let _ = stream
+                                .close(Some(CloseFrame {
+                                    code: CloseCode::Policy,
+                                    reason: "authentication failed".into(),
+                                }))
+                                .await;
+                            return;
+                        }
+                        monolith_id = monolith_init.id;
                         debug!("monolith sent init, handing off to balancer");
                         let monolith = NewMonolith {
                             id: monolith_id,
-                            region: init.region,
+                            region: monolith_init.region,
                             config: conf.clone(),
-                            proxy_port: init.port,
+                            proxy_port: monolith_init.port,
+                            weight: weight.clone(),
                         };
 
                         let Ok(rx) = link.send_monolith(monolith).await else {
@@ -206,7 +260,7 @@ async fn connect_and_maintain(
             tokio::select! {
                 msg = outbound_rx.recv() => {
                     if let Some(SocketMessage::Message(msg)) = msg {
-                        debug!(event = "ws", balancer_id = %*BALANCER_ID,  node_id = %monolith_id, direction = "tx");
+                        debug!(event = "ws", balancer_id = %*BALANCER_ID,  node_id = %monolith_id, monolith_id = %monolith_id, direction = "tx");
                         if let Err(err) = stream.send(msg).await {
                             error!("Error sending ws message to monolith: {:?}", err);
                             break;
@@ -234,7 +288,7 @@ async fn connect_and_maintain(
                             _ => msg,
                         };
 
-                        debug!(event = "ws", balancer_id = %*BALANCER_ID,  node_id = %monolith_id, direction = "rx");
+                        debug!(event = "ws", balancer_id = %*BALANCER_ID,  node_id = %monolith_id, monolith_id = %monolith_id, direction = "rx");
                         if let Err(err) = link
                             .send_monolith_message(monolith_id, SocketMessage::Message(msg))
                             .await {
@@ -276,7 +330,23 @@ async fn connect_and_maintain(
     info!("Monolith connection task ended: {}", conf.uri());
 }
 
+/// Wait for the monolith to answer the balancer's challenge.
+async fn receive_auth<S>(stream: &mut WebSocketStream<S>) -> Option<LinkAuth>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let result = tokio::time::timeout(Duration::from_secs(20), stream.next()).await;
+    let Ok(Some(Ok(Message::Text(text)))) = result else {
+        return None;
+    };
+    match serde_json::from_str::<MsgM2B>(&text) {
+        Ok(MsgM2B::Auth(auth)) => Some(auth),
+        _ => None,
+    }
+}
+
 struct ActiveConnection {
     handle: tokio::task::JoinHandle<()>,
     cancel: CancellationToken,
+    weight: MonolithWeight,
 }
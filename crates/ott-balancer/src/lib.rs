@@ -10,7 +10,7 @@ use hyper_util::rt::TokioExecutor;
 use hyper_util::server::conn::auto;
 use tokio::net::TcpListener;
 use tokio::sync::RwLock;
-use tracing::{error, info};
+use tracing::{debug, error, info};
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{EnvFilter, Layer};
@@ -18,20 +18,24 @@ use tracing_subscriber::{EnvFilter, Layer};
 use crate::config::BalancerConfig;
 use crate::service::BalancerService;
 use crate::state_stream::{EventFilter, EventSink, EVENT_STREAMER};
-use ott_common::discovery::{
-    start_discovery_task, DiscoveryConfig, DnsServiceDiscoverer, FlyServiceDiscoverer,
-    HarnessServiceDiscoverer, ManualServiceDiscoverer,
-};
+use ott_common::discovery::start_discovery_task;
 pub mod balancer;
 pub mod client;
 pub mod config;
 pub mod connection;
+pub mod link;
 pub mod messages;
+pub mod metrics;
 pub mod monolith;
+pub mod reload;
 pub mod room;
 pub mod selection;
 pub mod service;
 pub mod state_stream;
+pub mod telemetry;
+#[cfg(test)]
+mod test_util;
+pub mod tls;
 
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
@@ -39,6 +43,27 @@ static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 pub async fn run() -> anyhow::Result<()> {
     let args = config::Cli::parse();
 
+    match args.command {
+        Some(config::Command::PrintConfig) => match BalancerConfig::explain(&args.config_path) {
+            Ok(rendered) => {
+                println!("{}", rendered);
+                std::process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("Error loading configuration: {:?}", err);
+                std::process::exit(1);
+            }
+        },
+        Some(config::Command::Schema) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&BalancerConfig::json_schema())?
+            );
+            std::process::exit(0);
+        }
+        None => {}
+    }
+
     let loaded_config = BalancerConfig::load(&args.config_path);
 
     if args.validate {
@@ -90,10 +115,19 @@ pub async fn run() -> anyhow::Result<()> {
         })
         .with_filter(EnvFilter::new("debug"))
         .with_filter(streamer_filter);
+    let otlp_layer = match &config.otlp {
+        Some(otlp_config) => Some(
+            telemetry::build_otlp_layer(otlp_config)
+                .context("configuring trace export")?
+                .with_filter(EnvFilter::new("ott_balancer=info,ott_common=info")),
+        ),
+        None => None,
+    };
     tracing_subscriber::registry()
         .with(console_layer)
         .with(streamer_layer)
         .with(fmt_layer)
+        .with(otlp_layer)
         .init();
     info!("Args: {:?}", args);
     info!("Loaded config: {:?}", config);
@@ -115,6 +149,10 @@ pub async fn run() -> anyhow::Result<()> {
         },
     ));
 
+    let _reload_handle = reload::start_sighup_task(ctx.clone())?;
+    let _room_metrics_handle = metrics::start_room_rate_task(ctx.clone())?;
+    let _state_publisher_handle = state_stream::start_state_publisher(ctx.clone())?;
+
     let balancer = Balancer::new(ctx.clone());
     let service_link = balancer.new_link();
     let conman_link = balancer.new_link();
@@ -122,28 +160,15 @@ pub async fn run() -> anyhow::Result<()> {
     info!("Dispatcher started");
 
     info!("Starting monolith discovery");
-    let _discovery_handle = match &config.discovery {
-        DiscoveryConfig::Dns(config) => {
-            let discovery = DnsServiceDiscoverer::new(config.clone());
-            start_discovery_task(discovery, discovery_tx)
-        }
-        DiscoveryConfig::Fly(config) => {
-            let discovery = FlyServiceDiscoverer::new(config.clone());
-            start_discovery_task(discovery, discovery_tx)
-        }
-        DiscoveryConfig::Manual(config) => {
-            let discovery = ManualServiceDiscoverer::new(config.clone());
-            start_discovery_task(discovery, discovery_tx)
-        }
-        DiscoveryConfig::Harness(config) => {
-            let discovery = HarnessServiceDiscoverer::new(config.clone());
-            start_discovery_task(discovery, discovery_tx)
-        }
-    };
+    let _discovery_handle = start_discovery_task(config.discovery.discoverer(), discovery_tx);
     info!("Monolith discovery started");
 
     info!("Starting connection manager");
-    let mut conman = connection::MonolithConnectionManager::new(discovery_rx, conman_link);
+    let link_security = Arc::new(
+        link::LinkSecurity::new(&config.monolith_link).context("configuring monolith links")?,
+    );
+    let mut conman =
+        connection::MonolithConnectionManager::new(discovery_rx, conman_link, link_security);
     let _conman_handle = tokio::task::Builder::new()
         .name("connection manager")
         .spawn(async move {
@@ -164,12 +189,27 @@ pub async fn run() -> anyhow::Result<()> {
         task_handle_tx,
     };
 
+    let tls_acceptor = match &config.tls {
+        Some(tls_config) => {
+            let resolver = Arc::new(
+                tls::ReloadingCertResolver::new(tls_config).context("loading TLS certificates")?,
+            );
+            let _tls_reload_handle = tls::start_reload_task(tls_config.clone(), resolver.clone())?;
+            Some(tls::build_acceptor(resolver))
+        }
+        None => None,
+    };
+
     // on linux, binding ipv6 will also bind ipv4
     let listener6 = TcpListener::bind(bind_addr6)
         .await
         .context("binding primary inbound socket")?;
 
-    info!("Serving on {}", bind_addr6);
+    if tls_acceptor.is_some() {
+        info!("Serving TLS on {}", bind_addr6);
+    } else {
+        info!("Serving on {}", bind_addr6);
+    }
     let mut tasks = FuturesUnordered::new();
     loop {
         let accept_fut = Box::pin(listener6.accept());
@@ -198,15 +238,40 @@ pub async fn run() -> anyhow::Result<()> {
         };
 
         let service = service.clone();
-        let io = hyper_util::rt::TokioIo::new(stream);
+        let tls_acceptor = tls_acceptor.clone();
 
         // Spawn a tokio task to serve multiple connections concurrently
         let task = tokio::task::Builder::new()
             .name("serve http")
             .spawn(async move {
                 let serve = auto::Builder::new(TokioExecutor::new());
-                let conn = serve.serve_connection_with_upgrades(io, service);
-                if let Err(err) = conn.await {
+                let result = match tls_acceptor {
+                    Some(acceptor) => {
+                        let stream = match tokio::time::timeout(
+                            tls::HANDSHAKE_TIMEOUT,
+                            acceptor.accept(stream),
+                        )
+                        .await
+                        {
+                            Ok(Ok(stream)) => stream,
+                            Ok(Err(err)) => {
+                                debug!("TLS handshake failed: {:?}", err);
+                                return;
+                            }
+                            Err(_) => {
+                                debug!("TLS handshake timed out");
+                                return;
+                            }
+                        };
+                        let io = hyper_util::rt::TokioIo::new(stream);
+                        serve.serve_connection_with_upgrades(io, service).await
+                    }
+                    None => {
+                        let io = hyper_util::rt::TokioIo::new(stream);
+                        serve.serve_connection_with_upgrades(io, service).await
+                    }
+                };
+                if let Err(err) = result {
                     error!("Error serving connection: {:?}", err);
                 }
             });
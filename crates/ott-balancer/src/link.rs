@@ -0,0 +1,445 @@
+//! Secures the connections that the balancer makes to monoliths.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use ott_balancer_protocol::monolith::{new_challenge, B2MInit, LinkAuth, LinkAuthError, M2BInit};
+use ott_balancer_protocol::BalancerId;
+use ott_common::discovery::{ConnectionConfig, HostOrIp};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+
+use crate::tls::{load_certs, load_private_key};
+
+#[derive(Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct MonolithLinkConfig {
+    /// Connect to monoliths with `wss://` instead of `ws://`.
+    pub tls: Option<LinkTlsConfig>,
+    /// A secret shared with the monoliths. When set, the balancer and each monolith challenge each other to prove that they know it, and the balancer disconnects from monoliths that don't.
+    pub secret: Option<String>,
+}
+
+impl std::fmt::Debug for MonolithLinkConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // the secret itself is left out so that it doesn't end up in the logs
+        f.debug_struct("MonolithLinkConfig")
+            .field("tls", &self.tls)
+            .field("secret", &self.secret.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct LinkTlsConfig {
+    /// PEM encoded CA certificates used to verify monolith certificates. If not set, the Mozilla root certificates are used.
+    pub ca_path: Option<PathBuf>,
+    /// A PEM encoded certificate chain to present to monoliths, for mutual TLS. Requires `client_key_path`.
+    pub client_cert_path: Option<PathBuf>,
+    /// The PEM encoded private key for `client_cert_path`.
+    pub client_key_path: Option<PathBuf>,
+    /// The name that monolith certificates must be valid for. If not set, the host that the monolith was discovered at is used.
+    pub server_name: Option<String>,
+}
+
+impl LinkTlsConfig {
+    fn build_client_config(&self) -> anyhow::Result<rustls::ClientConfig> {
+        let mut roots = rustls::RootCertStore::empty();
+        match &self.ca_path {
+            Some(ca_path) => {
+                for cert in load_certs(ca_path)? {
+                    roots
+                        .add(cert)
+                        .with_context(|| format!("adding CA certificate {}", ca_path.display()))?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        let builder = rustls::ClientConfig::builder().with_root_certificates(roots);
+
+        let config = match (&self.client_cert_path, &self.client_key_path) {
+            (Some(cert_path), Some(key_path)) => builder
+                .with_client_auth_cert(load_certs(cert_path)?, load_private_key(key_path)?)
+                .context("invalid client certificate")?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => anyhow::bail!("client_cert_path and client_key_path must be set together"),
+        };
+        Ok(config)
+    }
+}
+
+/// Everything needed to open and authenticate connections to monoliths, built once from [`MonolithLinkConfig`].
+pub struct LinkSecurity {
+    tls: Option<(Arc<rustls::ClientConfig>, Option<String>)>,
+    secret: Option<String>,
+}
+
+impl LinkSecurity {
+    pub fn new(config: &MonolithLinkConfig) -> anyhow::Result<Self> {
+        let tls = match &config.tls {
+            Some(tls) => {
+                if let Some(server_name) = &tls.server_name {
+                    validate_server_name(server_name)?;
+                }
+                Some((
+                    Arc::new(tls.build_client_config()?),
+                    tls.server_name.clone(),
+                ))
+            }
+            None => None,
+        };
+        Ok(Self {
+            tls,
+            secret: config.secret.clone(),
+        })
+    }
+
+    pub fn uri(&self, conf: &ConnectionConfig) -> url::Url {
+        match &self.tls {
+            Some((_, server_name)) => {
+                let mut url = conf.uri_with_scheme("wss");
+                if let Some(server_name) = server_name {
+                    url.set_host(Some(server_name))
+                        .expect("server name is validated in LinkSecurity::new");
+                }
+                url
+            }
+            None => conf.uri(),
+        }
+    }
+
+    pub async fn connect(
+        &self,
+        conf: &ConnectionConfig,
+    ) -> anyhow::Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+        // Connect to the discovered address, even if the TLS server name is overridden.
+        let tcp = match &conf.host {
+            HostOrIp::Host(host) => TcpStream::connect((host.as_str(), conf.port)).await?,
+            HostOrIp::Ip(ip) => TcpStream::connect((*ip, conf.port)).await?,
+        };
+        let connector = self
+            .tls
+            .as_ref()
+            .map(|(config, _)| Connector::Rustls(config.clone()));
+        let (stream, _) = tokio_tungstenite::client_async_tls_with_config(
+            self.uri(conf).as_str(),
+            tcp,
+            None,
+            connector,
+        )
+        .await?;
+        Ok(stream)
+    }
+
+    pub fn build_init(&self, id: BalancerId) -> B2MInit {
+        let mut init = B2MInit::new(id);
+        if self.secret.is_some() {
+            init.challenge = Some(new_challenge());
+        }
+        init
+    }
+
+    /// Answer the monolith's challenge, if it sent one and a shared secret is configured.
+    pub fn authenticate(&self, init: &B2MInit, monolith: &M2BInit) -> Option<LinkAuth> {
+        let secret = self.secret.as_ref()?;
+        monolith.challenge.as_ref()?;
+        Some(init.authenticate(monolith, secret.as_bytes()))
+    }
+
+    /// Whether monoliths have to answer the balancer's challenge before they are used.
+    pub fn requires_auth(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    /// Check that the monolith answered the challenge in `init` with the shared secret, if one is configured.
+    pub fn verify_init(
+        &self,
+        init: &B2MInit,
+        monolith: &M2BInit,
+        auth: Option<&LinkAuth>,
+    ) -> Result<(), LinkAuthError> {
+        let Some(secret) = &self.secret else {
+            return Ok(());
+        };
+        let auth = auth.ok_or(LinkAuthError::Missing)?;
+        monolith.verify(init, auth, secret.as_bytes())
+    }
+}
+
+/// Make sure that `server_name` can be used both as the host of a `wss://` URL and as a TLS server name.
+fn validate_server_name(server_name: &str) -> anyhow::Result<()> {
+    let mut url = url::Url::parse("wss://localhost").expect("valid url");
+    url.set_host(Some(server_name))
+        .with_context(|| format!("invalid monolith_link.tls.server_name {server_name:?}"))?;
+    rustls::pki_types::ServerName::try_from(server_name)
+        .with_context(|| format!("invalid monolith_link.tls.server_name {server_name:?}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures_util::{SinkExt, StreamExt};
+    use ott_balancer_protocol::monolith::MsgB2M;
+    use tungstenite::Message;
+
+    use crate::test_util::local_listener;
+
+    struct TestPki {
+        dir: PathBuf,
+        ca: rcgen::Certificate,
+    }
+
+    impl TestPki {
+        fn new() -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("ott-balancer-link-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let mut params = rcgen::CertificateParams::new(vec![]);
+            params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+            let ca = rcgen::Certificate::from_params(params).unwrap();
+            std::fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+            Self { dir, ca }
+        }
+
+        /// Issue a certificate signed by the CA, and write it to `{name}.pem` and `{name}.key`.
+        fn issue(&self, name: &str, subject_alt_names: &[&str]) {
+            let cert = rcgen::Certificate::from_params(rcgen::CertificateParams::new(
+                subject_alt_names
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect::<Vec<_>>(),
+            ))
+            .unwrap();
+            std::fs::write(
+                self.dir.join(format!("{name}.pem")),
+                cert.serialize_pem_with_signer(&self.ca).unwrap(),
+            )
+            .unwrap();
+            std::fs::write(
+                self.dir.join(format!("{name}.key")),
+                cert.serialize_private_key_pem(),
+            )
+            .unwrap();
+        }
+
+        fn path(&self, file: &str) -> PathBuf {
+            self.dir.join(file)
+        }
+    }
+
+    impl Drop for TestPki {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Accept a single websocket connection over TLS, requiring a client certificate signed by the test CA, and return the first message.
+    async fn spawn_monolith(
+        pki: &TestPki,
+    ) -> (u16, tokio::task::JoinHandle<anyhow::Result<String>>) {
+        let mut client_roots = rustls::RootCertStore::empty();
+        for cert in load_certs(&pki.path("ca.pem")).unwrap() {
+            client_roots.add(cert).unwrap();
+        }
+        let verifier = rustls::server::WebPkiClientVerifier::builder(Arc::new(client_roots))
+            .build()
+            .unwrap();
+        let server_config = rustls::ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                load_certs(&pki.path("monolith.pem")).unwrap(),
+                load_private_key(&pki.path("monolith.key")).unwrap(),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+        let (listener, addr) = local_listener().await;
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let stream = acceptor.accept(stream).await?;
+            let mut ws = tokio_tungstenite::accept_async(stream).await?;
+            let Some(Ok(Message::Text(msg))) = ws.next().await else {
+                anyhow::bail!("expected a text message");
+            };
+            ws.close(None).await?;
+            Ok(msg)
+        });
+        (addr.port(), handle)
+    }
+
+    fn link_config(pki: &TestPki, secret: Option<&str>) -> MonolithLinkConfig {
+        MonolithLinkConfig {
+            tls: Some(LinkTlsConfig {
+                ca_path: Some(pki.path("ca.pem")),
+                client_cert_path: Some(pki.path("balancer.pem")),
+                client_key_path: Some(pki.path("balancer.key")),
+                server_name: Some("monolith.internal".to_owned()),
+            }),
+            secret: secret.map(|s| s.to_owned()),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_connect_with_mutual_tls_and_challenge() {
+        let pki = TestPki::new();
+        pki.issue("monolith", &["monolith.internal"]);
+        pki.issue("balancer", &["balancer.internal"]);
+        let (port, monolith) = spawn_monolith(&pki).await;
+
+        let security = LinkSecurity::new(&link_config(&pki, Some("secret"))).unwrap();
+        let conf = ConnectionConfig {
+            host: HostOrIp::Ip("127.0.0.1".parse().unwrap()),
+            port,
+        };
+        assert_eq!(security.uri(&conf).scheme(), "wss");
+        assert_eq!(security.uri(&conf).host_str(), Some("monolith.internal"));
+
+        let mut ws = security.connect(&conf).await.expect("failed to connect");
+        let id = uuid::Uuid::new_v4().into();
+        let init = serde_json::to_string(&MsgB2M::Init(security.build_init(id))).unwrap();
+        ws.send(Message::Text(init)).await.unwrap();
+
+        let received = monolith.await.unwrap().expect("monolith failed");
+        let MsgB2M::Init(init) = serde_json::from_str(&received).unwrap() else {
+            panic!("expected init");
+        };
+        assert!(init.challenge.is_some());
+    }
+
+    #[tokio::test]
+    async fn should_reject_monolith_with_untrusted_certificate() {
+        let pki = TestPki::new();
+        pki.issue("balancer", &["balancer.internal"]);
+        // The monolith's certificate is valid for the right name, but is signed by a different CA.
+        let other = TestPki::new();
+        other.issue("monolith", &["monolith.internal"]);
+        std::fs::copy(other.path("monolith.pem"), pki.path("monolith.pem")).unwrap();
+        std::fs::copy(other.path("monolith.key"), pki.path("monolith.key")).unwrap();
+        let (port, _monolith) = spawn_monolith(&pki).await;
+
+        let security = LinkSecurity::new(&link_config(&pki, None)).unwrap();
+        let conf = ConnectionConfig {
+            host: HostOrIp::Ip("127.0.0.1".parse().unwrap()),
+            port,
+        };
+        security
+            .connect(&conf)
+            .await
+            .expect_err("should not trust the monolith's certificate");
+    }
+
+    fn monolith_init() -> M2BInit {
+        M2BInit {
+            port: 3000,
+            region: "ord".into(),
+            id: uuid::Uuid::new_v4().into(),
+            challenge: Some(new_challenge()),
+        }
+    }
+
+    #[test]
+    fn should_verify_monolith_init() {
+        let config = MonolithLinkConfig {
+            secret: Some("secret".to_owned()),
+            ..Default::default()
+        };
+        let security = LinkSecurity::new(&config).unwrap();
+        let init = security.build_init(uuid::Uuid::new_v4().into());
+        let monolith = monolith_init();
+        assert!(security.requires_auth());
+        assert_eq!(
+            security.verify_init(&init, &monolith, None).unwrap_err(),
+            LinkAuthError::Missing
+        );
+
+        let auth = monolith.authenticate(&init, b"wrong");
+        assert_eq!(
+            security
+                .verify_init(&init, &monolith, Some(&auth))
+                .unwrap_err(),
+            LinkAuthError::InvalidSignature
+        );
+
+        let auth = monolith.authenticate(&init, b"secret");
+        security
+            .verify_init(&init, &monolith, Some(&auth))
+            .expect("init should verify");
+
+        // an answer to another balancer's challenge is rejected
+        let other = security.build_init(init.id);
+        assert_ne!(other.challenge, init.challenge);
+        assert_eq!(
+            security
+                .verify_init(&other, &monolith, Some(&auth))
+                .unwrap_err(),
+            LinkAuthError::InvalidSignature
+        );
+
+        let answer = security
+            .authenticate(&init, &monolith)
+            .expect("should answer the monolith's challenge");
+        init.verify(&monolith, &answer, b"secret")
+            .expect("the monolith should accept the answer");
+    }
+
+    #[test]
+    fn should_not_require_auth_without_secret() {
+        let security = LinkSecurity::new(&MonolithLinkConfig::default()).unwrap();
+        let init = security.build_init(uuid::Uuid::new_v4().into());
+        assert!(init.challenge.is_none());
+        assert!(!security.requires_auth());
+        let monolith = monolith_init();
+        security
+            .verify_init(&init, &monolith, None)
+            .expect("auth should be optional");
+        assert!(security.authenticate(&init, &monolith).is_none());
+    }
+
+    #[test]
+    fn should_reject_invalid_server_name() {
+        let config = MonolithLinkConfig {
+            tls: Some(LinkTlsConfig {
+                server_name: Some("monolith internal".to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let err = LinkSecurity::new(&config)
+            .err()
+            .expect("invalid server name should be rejected");
+        assert!(err.to_string().contains("server_name"), "{}", err);
+    }
+
+    #[test]
+    fn should_require_client_cert_and_key_together() {
+        let config = LinkTlsConfig {
+            client_cert_path: Some("/etc/ott/balancer.pem".into()),
+            ..Default::default()
+        };
+        config
+            .build_client_config()
+            .expect_err("client key should be required");
+    }
+
+    #[test]
+    fn parse_link_config() {
+        let config = serde_json::json!({
+            "tls": {
+                "ca_path": "/etc/ott/ca.pem",
+                "client_cert_path": "/etc/ott/balancer.pem",
+                "client_key_path": "/etc/ott/balancer.key",
+            },
+            "secret": "hunter2",
+        });
+        let config: MonolithLinkConfig =
+            serde_json::from_value(config).expect("failed to parse link config");
+        assert!(config.tls.is_some());
+        assert!(!format!("{:?}", config).contains("hunter2"));
+    }
+}
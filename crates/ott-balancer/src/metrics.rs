@@ -0,0 +1,397 @@
+//! Metrics that are broken down by room and by region.
+//!
+//! Room names are chosen by users, so only the busiest rooms get a label of their own. The rest
+//! are lumped together under [`OTHER_LABEL`], which keeps the number of series bounded.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use ott_balancer_protocol::RoomName;
+use prometheus::{register_histogram_vec, register_int_gauge_vec, HistogramVec, IntGaugeVec};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::balancer::BalancerContext;
+use crate::config::BalancerConfig;
+
+/// The label used for everything that didn't make the cut for a label of its own.
+pub const OTHER_LABEL: &str = "(other)";
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// How many of the busiest rooms get their own label on the room message rate histogram.
+    pub top_rooms: usize,
+    /// How many edge regions get their own label on the edge region client gauge.
+    pub max_edge_regions: usize,
+    /// How often room message rates are sampled.
+    #[serde(with = "humantime_serde")]
+    #[schemars(with = "String")]
+    pub sample_interval: Duration,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            top_rooms: 20,
+            max_edge_regions: 50,
+            sample_interval: Duration::from_secs(15),
+        }
+    }
+}
+
+/// Take the messages counted by each room since the last sample.
+///
+/// Rooms count their own messages, so that the hot path doesn't need a lock or a copy of the room name.
+pub fn take_room_message_counts(ctx: &BalancerContext) -> HashMap<RoomName, u64> {
+    ctx.monoliths
+        .values()
+        .flat_map(|monolith| monolith.rooms().values())
+        .map(|room| (room.name().clone(), room.take_message_count()))
+        .collect()
+}
+
+/// Set the room and client gauges from the current state of the balancer.
+pub fn update_state_gauges(ctx: &BalancerContext, config: &BalancerConfig) {
+    GAUGE_MONOLITH_ROOMS.reset();
+    GAUGE_MONOLITH_CLIENTS.reset();
+    for monolith in ctx.monoliths.values() {
+        let (id, region) = (monolith.id().to_string(), monolith.region().to_string());
+        let labels = [id.as_str(), region.as_str()];
+        let clients: usize = monolith.rooms().values().map(|r| r.clients().len()).sum();
+        GAUGE_MONOLITH_ROOMS
+            .with_label_values(&labels)
+            .set(monolith.rooms().len() as i64);
+        GAUGE_MONOLITH_CLIENTS
+            .with_label_values(&labels)
+            .set(clients as i64);
+    }
+
+    let mut edge_regions: HashMap<String, u64> = HashMap::new();
+    for client in ctx.clients.values() {
+        *edge_regions
+            .entry(client.edge_region.to_string())
+            .or_default() += 1;
+    }
+    GAUGE_EDGE_REGION_CLIENTS.reset();
+    for (edge_region, clients) in top_n(edge_regions, config.metrics.max_edge_regions) {
+        GAUGE_EDGE_REGION_CLIENTS
+            .with_label_values(&[&edge_region])
+            .set(clients as i64);
+    }
+}
+
+/// Keep the `n` entries with the largest values, and add up the rest under [`OTHER_LABEL`].
+///
+/// Ties are broken by the label, so that the same labels are picked every time.
+fn top_n<V>(values: HashMap<String, V>, n: usize) -> Vec<(String, V)>
+where
+    V: PartialOrd + std::ops::AddAssign + Default + Copy,
+{
+    let mut values: Vec<_> = values.into_iter().collect();
+    values.sort_by(|a, b| {
+        b.1.partial_cmp(&a.1)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a.0.cmp(&b.0))
+    });
+    if values.len() > n {
+        let mut other = V::default();
+        for (_, value) in values.drain(n..) {
+            other += value;
+        }
+        values.push((OTHER_LABEL.to_owned(), other));
+    }
+    values
+}
+
+/// Turns room message counts into observations on a histogram of message rates.
+pub struct RoomRateSampler {
+    histogram: HistogramVec,
+    /// The rooms that currently have their own label.
+    labeled: HashSet<String>,
+}
+
+impl RoomRateSampler {
+    pub fn new(histogram: HistogramVec) -> Self {
+        Self {
+            histogram,
+            labeled: HashSet::new(),
+        }
+    }
+
+    /// Observe the message rate of every room, giving the `top_rooms` busiest rooms their own label.
+    ///
+    /// Rooms that are no longer among the busiest have their label removed.
+    pub fn sample(&mut self, counts: HashMap<RoomName, u64>, elapsed: Duration, top_rooms: usize) {
+        let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+        let mut rates: Vec<_> = counts
+            .into_iter()
+            .map(|(room, count)| (room.to_string(), count as f64 / secs))
+            .collect();
+        rates.sort_by(|a, b| {
+            b.1.partial_cmp(&a.1)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| a.0.cmp(&b.0))
+        });
+
+        let split = rates.len().min(top_rooms);
+        let labeled: HashSet<String> = rates[..split].iter().map(|(r, _)| r.clone()).collect();
+        for stale in self.labeled.difference(&labeled) {
+            let _ = self.histogram.remove_label_values(&[stale]);
+        }
+        for (room, rate) in &rates[..split] {
+            self.histogram.with_label_values(&[room]).observe(*rate);
+        }
+        for (_, rate) in &rates[split..] {
+            self.histogram
+                .with_label_values(&[OTHER_LABEL])
+                .observe(*rate);
+        }
+        self.labeled = labeled;
+    }
+}
+
+/// Periodically sample the message rate of each room.
+pub fn start_room_rate_task(
+    ctx: Arc<RwLock<BalancerContext>>,
+) -> anyhow::Result<tokio::task::JoinHandle<()>> {
+    Ok(tokio::task::Builder::new()
+        .name("room metrics")
+        .spawn(async move {
+            let mut sampler = RoomRateSampler::new(HISTOGRAM_ROOM_MESSAGE_RATE.clone());
+            let mut last_sample = Instant::now();
+            loop {
+                tokio::time::sleep(BalancerConfig::get().metrics.sample_interval).await;
+                let counts = take_room_message_counts(&*ctx.read().await);
+                let now = Instant::now();
+                sampler.sample(
+                    counts,
+                    now - last_sample,
+                    BalancerConfig::get().metrics.top_rooms,
+                );
+                last_sample = now;
+            }
+        })?)
+}
+
+static GAUGE_MONOLITH_ROOMS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "balancer_monolith_rooms",
+        "Number of rooms loaded on each monolith",
+        &["monolith_id", "region"]
+    )
+    .unwrap()
+});
+
+static GAUGE_MONOLITH_CLIENTS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "balancer_monolith_clients",
+        "Number of clients in rooms on each monolith",
+        &["monolith_id", "region"]
+    )
+    .unwrap()
+});
+
+static GAUGE_EDGE_REGION_CLIENTS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "balancer_edge_region_clients",
+        "Number of clients connected through each edge region",
+        &["edge_region"]
+    )
+    .unwrap()
+});
+
+static HISTOGRAM_ROOM_MESSAGE_RATE: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "balancer_room_message_rate",
+        "Messages per second sent to and from the busiest rooms",
+        &["room"],
+        vec![0.1, 0.5, 1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0]
+    )
+    .unwrap()
+});
+
+#[cfg(test)]
+mod test {
+    use prometheus::core::Collector;
+    use prometheus::HistogramOpts;
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::Ordering;
+
+    use ott_balancer_protocol::MonolithId;
+    use ott_common::discovery::{ConnectionConfig, HostOrIp};
+
+    use crate::client::{BalancerClient, NewClient};
+    use crate::monolith::{BalancerMonolith, NewMonolith};
+    use crate::room::RoomLocator;
+
+    use super::*;
+
+    fn sampled_rooms(histogram: &HistogramVec) -> HashMap<String, u64> {
+        histogram.collect()[0]
+            .get_metric()
+            .iter()
+            .map(|m| {
+                (
+                    m.get_label()[0].get_value().to_owned(),
+                    m.get_histogram().get_sample_count(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_only_label_busiest_rooms() {
+        let histogram =
+            HistogramVec::new(HistogramOpts::new("test_rate", "test"), &["room"]).unwrap();
+        let mut sampler = RoomRateSampler::new(histogram.clone());
+
+        let counts = HashMap::from([
+            ("busy".into(), 100),
+            ("medium".into(), 50),
+            ("quiet".into(), 1),
+            ("silent".into(), 0),
+        ]);
+        sampler.sample(counts, Duration::from_secs(10), 2);
+        assert_eq!(
+            sampled_rooms(&histogram),
+            HashMap::from([
+                ("busy".to_owned(), 1),
+                ("medium".to_owned(), 1),
+                (OTHER_LABEL.to_owned(), 2),
+            ])
+        );
+    }
+
+    #[test]
+    fn should_remove_rooms_that_are_no_longer_busiest() {
+        let histogram =
+            HistogramVec::new(HistogramOpts::new("test_rate", "test"), &["room"]).unwrap();
+        let mut sampler = RoomRateSampler::new(histogram.clone());
+
+        sampler.sample(
+            HashMap::from([("foo".into(), 100), ("bar".into(), 1)]),
+            Duration::from_secs(1),
+            1,
+        );
+        sampler.sample(
+            HashMap::from([("foo".into(), 1), ("bar".into(), 100)]),
+            Duration::from_secs(1),
+            1,
+        );
+        assert_eq!(
+            sampled_rooms(&histogram),
+            HashMap::from([("bar".to_owned(), 1), (OTHER_LABEL.to_owned(), 2)])
+        );
+    }
+
+    #[tokio::test]
+    async fn should_set_state_gauges_from_context() {
+        BalancerConfig::init_default();
+        let mut ctx = BalancerContext::new();
+        let (monolith_outbound_tx, _monolith_outbound_rx) = tokio::sync::mpsc::channel(100);
+        let (client_inbound_tx, _client_inbound_rx) = tokio::sync::mpsc::channel(100);
+        let monolith_id: MonolithId = uuid::Uuid::new_v4().into();
+        ctx.add_monolith(BalancerMonolith::new(
+            NewMonolith {
+                id: monolith_id,
+                region: "gauge-test".into(),
+                config: ConnectionConfig {
+                    host: HostOrIp::Ip(Ipv4Addr::LOCALHOST.into()),
+                    port: 3002,
+                },
+                proxy_port: 3000,
+                weight: Default::default(),
+            },
+            Arc::new(monolith_outbound_tx),
+            client_inbound_tx,
+        ));
+        for room in ["foo", "bar"] {
+            ctx.add_room(room.into(), RoomLocator::new(monolith_id, 0))
+                .unwrap();
+        }
+        for _ in 0..3 {
+            let (client_unicast_tx, _client_unicast_rx) = tokio::sync::mpsc::channel(100);
+            let client = BalancerClient::new(
+                NewClient {
+                    id: uuid::Uuid::new_v4().into(),
+                    room: "foo".into(),
+                    edge_region: "gauge-test-edge".into(),
+                    token: "test".into(),
+                },
+                client_unicast_tx,
+            );
+            ctx.add_client(client, monolith_id).await.unwrap();
+        }
+
+        update_state_gauges(&ctx, &BalancerConfig::get());
+
+        let labels = [monolith_id.to_string(), "gauge-test".to_owned()];
+        let labels = [labels[0].as_str(), labels[1].as_str()];
+        assert_eq!(GAUGE_MONOLITH_ROOMS.with_label_values(&labels).get(), 2);
+        assert_eq!(GAUGE_MONOLITH_CLIENTS.with_label_values(&labels).get(), 3);
+        assert_eq!(
+            GAUGE_EDGE_REGION_CLIENTS
+                .with_label_values(&["gauge-test-edge"])
+                .get(),
+            3
+        );
+    }
+
+    #[test]
+    fn should_take_room_message_counts() {
+        let mut ctx = BalancerContext::new();
+        let (monolith_outbound_tx, _monolith_outbound_rx) = tokio::sync::mpsc::channel(100);
+        let (client_inbound_tx, _client_inbound_rx) = tokio::sync::mpsc::channel(100);
+        let monolith_id: MonolithId = uuid::Uuid::new_v4().into();
+        ctx.add_monolith(BalancerMonolith::new(
+            NewMonolith {
+                id: monolith_id,
+                region: Default::default(),
+                config: ConnectionConfig {
+                    host: HostOrIp::Ip(Ipv4Addr::LOCALHOST.into()),
+                    port: 3002,
+                },
+                proxy_port: 3000,
+                weight: Default::default(),
+            },
+            Arc::new(monolith_outbound_tx),
+            client_inbound_tx,
+        ));
+        let room = ctx
+            .add_room("foo".into(), RoomLocator::new(monolith_id, 0))
+            .unwrap();
+        room.count_message();
+        room.message_counter().fetch_add(2, Ordering::Relaxed);
+
+        assert_eq!(
+            take_room_message_counts(&ctx),
+            HashMap::from([("foo".into(), 3)])
+        );
+        assert_eq!(
+            take_room_message_counts(&ctx),
+            HashMap::from([("foo".into(), 0)])
+        );
+    }
+
+    #[test]
+    fn top_n_should_sum_the_rest() {
+        let values = HashMap::from([
+            ("ord".to_owned(), 5),
+            ("iad".to_owned(), 3),
+            ("ams".to_owned(), 3),
+            ("syd".to_owned(), 1),
+        ]);
+        assert_eq!(
+            top_n(values, 2),
+            vec![
+                ("ord".to_owned(), 5),
+                ("ams".to_owned(), 3),
+                (OTHER_LABEL.to_owned(), 4)
+            ]
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use std::fmt::Display;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::{collections::HashMap, time::Duration};
 
@@ -13,6 +14,7 @@ use tokio::sync::mpsc::error::SendTimeoutError;
 use tokio_tungstenite::tungstenite::Message;
 use tracing::{debug, error, info, instrument, warn};
 
+use crate::client::ClientInbound;
 use crate::messages::*;
 
 /// A cloneable handle for sending balancer messages to a monolith without
@@ -123,9 +125,10 @@ pub struct BalancerMonolith {
     /// The Sender used to send messages to this Monolith.
     monolith_outbound_tx: Arc<tokio::sync::mpsc::Sender<SocketMessage>>,
     /// The Sender to be used by clients to send messages to this Monolith.
-    client_inbound_tx: tokio::sync::mpsc::Sender<Context<ClientId, SocketMessage>>,
+    client_inbound_tx: tokio::sync::mpsc::Sender<ClientInbound>,
     config: ConnectionConfig,
     proxy_port: u16,
+    weight: MonolithWeight,
     http_client: reqwest::Client,
 }
 
@@ -133,7 +136,7 @@ impl BalancerMonolith {
     pub fn new(
         m: NewMonolith,
         monolith_outbound_tx: Arc<tokio::sync::mpsc::Sender<SocketMessage>>,
-        client_inbound_tx: tokio::sync::mpsc::Sender<Context<ClientId, SocketMessage>>,
+        client_inbound_tx: tokio::sync::mpsc::Sender<ClientInbound>,
     ) -> Self {
         Self {
             id: m.id,
@@ -143,6 +146,7 @@ impl BalancerMonolith {
             client_inbound_tx,
             config: m.config,
             proxy_port: m.proxy_port,
+            weight: m.weight,
             http_client: reqwest::Client::builder()
                 .redirect(reqwest::redirect::Policy::none())
                 .build()
@@ -171,6 +175,11 @@ impl BalancerMonolith {
         self.proxy_port
     }
 
+    /// How many rooms this Monolith should get relative to others.
+    pub fn weight(&self) -> u32 {
+        self.weight.get()
+    }
+
     pub fn http_client(&self) -> &reqwest::Client {
         &self.http_client
     }
@@ -257,7 +266,7 @@ impl BalancerMonolith {
         Ok(())
     }
 
-    pub fn new_inbound_tx(&self) -> tokio::sync::mpsc::Sender<Context<ClientId, SocketMessage>> {
+    pub fn new_inbound_tx(&self) -> tokio::sync::mpsc::Sender<ClientInbound> {
         self.client_inbound_tx.clone()
     }
 }
@@ -285,6 +294,8 @@ pub struct Room {
 
     /// The Sender used to broadcast to all clients in this room.
     broadcast_tx: tokio::sync::broadcast::Sender<SocketMessage>,
+    /// Messages sent to and from this room since the room metrics were last sampled. Shared with the clients in the room, so that they can count without locking the context.
+    message_count: Arc<AtomicU64>,
 }
 
 impl Room {
@@ -295,6 +306,7 @@ impl Room {
             clients: Vec::new(),
             metadata: None,
             broadcast_tx,
+            message_count: Arc::new(AtomicU64::new(0)),
         }
     }
 
@@ -327,9 +339,23 @@ impl Room {
         self.broadcast_tx.subscribe()
     }
 
+    pub fn message_counter(&self) -> Arc<AtomicU64> {
+        self.message_count.clone()
+    }
+
+    /// Count a message that was sent to or from this room.
+    pub fn count_message(&self) {
+        self.message_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The number of messages counted since the last call.
+    pub fn take_message_count(&self) -> u64 {
+        self.message_count.swap(0, Ordering::Relaxed)
+    }
+
     /// Broadcast a message to all clients in this room.
     pub fn broadcast(&self, msg: impl Into<SocketMessage>) -> anyhow::Result<()> {
-        debug!(event = "broadcast", node_id = %self.name, direction = "tx");
+        debug!(event = "broadcast", node_id = %self.name, room = %self.name, direction = "tx");
         self.broadcast_tx.send(msg.into())?;
         Ok(())
     }
@@ -341,4 +367,31 @@ pub struct NewMonolith {
     pub region: Region,
     pub config: ConnectionConfig,
     pub proxy_port: u16,
+    pub weight: MonolithWeight,
+}
+
+/// The weight that service discovery gave a monolith. It's shared with the connection to the monolith, so that it
+/// can be updated without reconnecting.
+#[derive(Debug, Clone)]
+pub struct MonolithWeight(Arc<AtomicU32>);
+
+impl MonolithWeight {
+    /// Weights are at least 1, so that every monolith can get rooms.
+    pub fn new(weight: u32) -> Self {
+        Self(Arc::new(AtomicU32::new(weight.max(1))))
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, weight: u32) {
+        self.0.store(weight.max(1), Ordering::Relaxed);
+    }
+}
+
+impl Default for MonolithWeight {
+    fn default() -> Self {
+        Self::new(1)
+    }
 }
@@ -0,0 +1,122 @@
+//! Reloads the config while the balancer is running.
+
+use std::sync::Arc;
+
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{error, info, warn};
+
+use crate::balancer::BalancerContext;
+use crate::config::{BalancerConfig, ConfigChange};
+
+/// Held for the whole of a reload, so that reloads from SIGHUP and the admin endpoint can't interleave and apply an
+/// older config after a newer one was swapped in.
+static RELOAD_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Re-read the config, swap it in, and apply the changes that can take effect without a restart.
+///
+/// If the new config is invalid, the current config is kept. Only one reload runs at a time.
+pub async fn reload_config(
+    ctx: &Arc<RwLock<BalancerContext>>,
+) -> anyhow::Result<Vec<ConfigChange>> {
+    let _guard = RELOAD_LOCK.lock().await;
+    let (old, new) = match BalancerConfig::reload() {
+        Ok(configs) => configs,
+        Err(err) => {
+            COUNTER_CONFIG_RELOADS.with_label_values(&["error"]).inc();
+            return Err(err);
+        }
+    };
+    COUNTER_CONFIG_RELOADS.with_label_values(&["ok"]).inc();
+    let changes = old.diff(&new);
+    apply_config(ctx, &changes, &new).await;
+    Ok(changes)
+}
+
+async fn apply_config(
+    ctx: &Arc<RwLock<BalancerContext>>,
+    changes: &[ConfigChange],
+    config: &BalancerConfig,
+) {
+    if changes.is_empty() {
+        info!("Config reloaded, nothing changed");
+        return;
+    }
+    for change in changes {
+        if change.requires_restart() {
+            warn!(
+                "Config changed, but requires a restart to take effect: {}",
+                change
+            );
+        } else {
+            info!("Config changed: {}", change);
+        }
+    }
+
+    if changes.iter().any(|c| c.field == "selection_strategy") {
+        let strategy = config.selection_strategy.unwrap_or_default();
+        info!("Using selection strategy: {:?}", strategy);
+        ctx.write().await.monolith_selection = strategy.into();
+    }
+}
+
+/// Reload the config every time the process receives SIGHUP.
+pub fn start_sighup_task(
+    ctx: Arc<RwLock<BalancerContext>>,
+) -> anyhow::Result<tokio::task::JoinHandle<()>> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    Ok(tokio::task::Builder::new()
+        .name("config reloader")
+        .spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading config");
+                if let Err(err) = reload_config(&ctx).await {
+                    error!(
+                        "Failed to reload config, keeping the current one: {:?}",
+                        err
+                    );
+                }
+            }
+        })?)
+}
+
+static COUNTER_CONFIG_RELOADS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "balancer_config_reloads_total",
+        "Number of times the config was reloaded, by result",
+        &["result"]
+    )
+    .unwrap()
+});
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::selection::{MonolithSelectionConfig, MonolithSelectionStrategy};
+
+    #[tokio::test]
+    async fn should_recreate_selection_strategy() {
+        let ctx = Arc::new(RwLock::new(BalancerContext::new()));
+        let old = BalancerConfig::default();
+        let new = BalancerConfig {
+            selection_strategy: Some(MonolithSelectionConfig::Random),
+            ..Default::default()
+        };
+
+        let changes = old.diff(&new);
+        apply_config(&ctx, &changes, &new).await;
+        assert!(matches!(
+            ctx.read().await.monolith_selection,
+            MonolithSelectionStrategy::Random(_)
+        ));
+
+        let changes = new.diff(&old);
+        apply_config(&ctx, &changes, &old).await;
+        assert!(matches!(
+            ctx.read().await.monolith_selection,
+            MonolithSelectionStrategy::MinRooms(_)
+        ));
+    }
+}
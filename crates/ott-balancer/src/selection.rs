@@ -1,11 +1,13 @@
 use std::hash::Hash;
+use std::sync::{Arc, Mutex};
 
 use crate::monolith::BalancerMonolith;
 use enum_dispatch::enum_dispatch;
 use hashring::HashRing;
-use ott_balancer_protocol::RoomName;
-use rand::seq::IteratorRandom;
-use serde::Deserialize;
+use ott_balancer_protocol::{MonolithId, RoomName};
+use rand::seq::SliceRandom;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
 #[enum_dispatch(MonolithSelectionStrategy)]
 pub trait MonolithSelection: std::fmt::Debug {
@@ -19,15 +21,15 @@ pub trait MonolithSelection: std::fmt::Debug {
         &'a self,
         monoliths: Vec<&'a BalancerMonolith>,
     ) -> anyhow::Result<&'a BalancerMonolith> {
-        let selected = monoliths
-            .iter()
-            .choose(&mut rand::thread_rng())
-            .ok_or_else(|| anyhow::anyhow!("no monoliths available"))?;
+        if monoliths.is_empty() {
+            anyhow::bail!("no monoliths available");
+        }
+        let selected = monoliths.choose_weighted(&mut rand::thread_rng(), |m| m.weight())?;
         Ok(selected)
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 #[enum_dispatch]
 pub enum MonolithSelectionStrategy {
     MinRooms(MinRoomsSelector),
@@ -41,7 +43,7 @@ impl Default for MonolithSelectionStrategy {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize, Serialize, JsonSchema)]
 #[serde(tag = "strategy")]
 pub enum MonolithSelectionConfig {
     #[default]
@@ -73,8 +75,11 @@ impl MonolithSelection for MinRoomsSelector {
         _room: &RoomName,
         monoliths: Vec<&'a BalancerMonolith>,
     ) -> anyhow::Result<&'a BalancerMonolith> {
+        /// Compare rooms per unit of weight, so that heavier monoliths get more rooms.
         fn cmp(x: &BalancerMonolith, y: &BalancerMonolith) -> std::cmp::Ordering {
-            x.rooms().len().cmp(&y.rooms().len())
+            let x_load = x.rooms().len() as u64 * y.weight() as u64;
+            let y_load = y.rooms().len() as u64 * x.weight() as u64;
+            x_load.cmp(&y_load)
         }
 
         let selected = monoliths.iter().min_by(|x, y| cmp(x, y));
@@ -85,7 +90,8 @@ impl MonolithSelection for MinRoomsSelector {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct HashRingSelectorConfig {
     #[serde(default)]
     pub weight: usize,
@@ -99,53 +105,111 @@ impl Default for HashRingSelectorConfig {
 
 impl From<HashRingSelectorConfig> for HashRingSelector {
     fn from(config: HashRingSelectorConfig) -> Self {
-        HashRingSelector { config }
+        HashRingSelector {
+            config,
+            ring: Default::default(),
+        }
     }
 }
 
-#[derive(Debug, Default, Copy, Clone)]
+/// The most that a monolith's weight can multiply its places on the ring by, so that the ring stays small no matter
+/// what weights discovery hands out.
+const MAX_RING_WEIGHT: u32 = 10;
+
+/// Places monoliths on a consistent hash ring.
+///
+/// Monoliths with a higher weight get more places on the ring. Weights are reduced to their smallest ratio and
+/// clamped to [`MAX_RING_WEIGHT`], so balancers that see different weights for the same monoliths (eg. while an
+/// update is rolling out) will hash some rooms to different monoliths.
+#[derive(Debug, Default, Clone)]
 pub struct HashRingSelector {
     pub config: HashRingSelectorConfig,
+    /// The ring is only rebuilt when the monoliths or their weights change.
+    ring: Arc<Mutex<Option<CachedRing>>>,
 }
 
-impl MonolithSelection for HashRingSelector {
-    fn select_monolith<'a>(
-        &'a self,
-        room: &RoomName,
-        monoliths: Vec<&'a BalancerMonolith>,
-    ) -> anyhow::Result<&'a BalancerMonolith> {
-        let weight = self.config.weight.max(1);
+struct CachedRing {
+    /// Each monolith and its weight on the ring, sorted by id.
+    members: Vec<(MonolithId, u32)>,
+    ring: HashRing<RingNode>,
+}
+
+impl std::fmt::Debug for CachedRing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CachedRing")
+            .field("members", &self.members)
+            .finish_non_exhaustive()
+    }
+}
+
+impl HashRingSelector {
+    fn build_ring(&self, members: &[(MonolithId, u32)]) -> HashRing<RingNode> {
+        let replicas = self.config.weight.max(1);
         let mut ring = HashRing::new();
         ring.batch_add(
-            monoliths
+            members
                 .iter()
-                // This makes it so that each monolith is added to the ring 5 times with different hashes to spread the load more evenly
-                .flat_map(|m| std::iter::repeat_n(m, weight).enumerate())
-                .map(|(i, m)| RingNode {
-                    monolith: m,
-                    idx: i,
+                // This makes it so that each monolith is added to the ring 5 times with different hashes to spread the load more evenly,
+                // and more times for monoliths that discovery gave a higher weight
+                .flat_map(|(id, weight)| {
+                    (0..replicas * *weight as usize).map(|idx| RingNode { id: *id, idx })
                 })
                 .collect(),
         );
-
-        let node = ring.get(room).ok_or(anyhow::anyhow!("ring hash empty"))?;
-
-        Ok(node.monolith)
+        ring
     }
 }
 
-struct RingNode<'a> {
-    monolith: &'a BalancerMonolith,
-    idx: usize,
+/// Reduce weights to their smallest ratio, and clamp them to [`MAX_RING_WEIGHT`].
+fn ring_weights(weights: impl Iterator<Item = u32> + Clone) -> impl Iterator<Item = u32> {
+    fn gcd(a: u32, b: u32) -> u32 {
+        if b == 0 {
+            a
+        } else {
+            gcd(b, a % b)
+        }
+    }
+
+    let divisor = weights.clone().fold(0, gcd).max(1);
+    weights.map(move |weight| (weight / divisor).clamp(1, MAX_RING_WEIGHT))
 }
 
-impl Hash for RingNode<'_> {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.monolith.id().hash(state);
-        self.idx.hash(state);
+impl MonolithSelection for HashRingSelector {
+    fn select_monolith<'a>(
+        &'a self,
+        room: &RoomName,
+        monoliths: Vec<&'a BalancerMonolith>,
+    ) -> anyhow::Result<&'a BalancerMonolith> {
+        let mut members: Vec<_> = monoliths
+            .iter()
+            .map(|m| m.id())
+            .zip(ring_weights(monoliths.iter().map(|m| m.weight())))
+            .collect();
+        members.sort();
+
+        let mut cached = self.ring.lock().unwrap();
+        if cached.as_ref().is_none_or(|c| c.members != members) {
+            let ring = self.build_ring(&members);
+            *cached = Some(CachedRing { members, ring });
+        }
+        let node = cached
+            .as_ref()
+            .and_then(|c| c.ring.get(room))
+            .ok_or(anyhow::anyhow!("ring hash empty"))?;
+
+        monoliths
+            .into_iter()
+            .find(|m| m.id() == node.id)
+            .ok_or(anyhow::anyhow!("monolith on ring is missing"))
     }
 }
 
+#[derive(Hash)]
+struct RingNode {
+    id: MonolithId,
+    idx: usize,
+}
+
 #[derive(Debug, Default, Copy, Clone)]
 pub struct RandomSelector;
 
@@ -166,7 +230,7 @@ mod test {
     use std::net::Ipv4Addr;
     use std::sync::Arc;
 
-    use crate::monolith::{BalancerMonolith, NewMonolith};
+    use crate::monolith::{BalancerMonolith, MonolithWeight, NewMonolith};
     use ott_common::discovery::{ConnectionConfig, HostOrIp};
 
     #[test]
@@ -201,6 +265,7 @@ mod test {
                     port: 3002,
                 },
                 proxy_port: 3000,
+                weight: Default::default(),
             },
             monolith_outbound_tx_one,
             client_inbound_tx_one,
@@ -227,6 +292,7 @@ mod test {
                     port: 3002,
                 },
                 proxy_port: 3000,
+                weight: Default::default(),
             },
             monolith_outbound_tx_two,
             client_inbound_tx_two,
@@ -245,4 +311,84 @@ mod test {
 
         assert_eq!(selected.id(), monolith_two.id())
     }
+    fn monolith(weight: u32, rooms: &[&str]) -> BalancerMonolith {
+        let (monolith_outbound_tx, _monolith_outbound_rx) = tokio::sync::mpsc::channel(100);
+        let (client_inbound_tx, _client_inbound_rx) = tokio::sync::mpsc::channel(100);
+        let mut monolith = BalancerMonolith::new(
+            NewMonolith {
+                id: uuid::Uuid::new_v4().into(),
+                region: Default::default(),
+                config: ConnectionConfig {
+                    host: HostOrIp::Ip(Ipv4Addr::LOCALHOST.into()),
+                    port: 3002,
+                },
+                proxy_port: 3000,
+                weight: MonolithWeight::new(weight),
+            },
+            Arc::new(monolith_outbound_tx),
+            client_inbound_tx,
+        );
+        for room in rooms {
+            monolith
+                .add_room(&RoomName::from(*room))
+                .expect("failed to add room");
+        }
+        monolith
+    }
+
+    #[tokio::test]
+    async fn min_rooms_should_account_for_weight() {
+        let heavy = monolith(3, &["one", "two"]);
+        let light = monolith(1, &["three"]);
+
+        let selected = MinRoomsSelector
+            .select_monolith(&"foo".into(), vec![&heavy, &light])
+            .expect("failed to select monolith");
+        assert_eq!(selected.id(), heavy.id());
+
+        let unweighted = monolith(1, &["one", "two"]);
+        let selected = MinRoomsSelector
+            .select_monolith(&"foo".into(), vec![&unweighted, &light])
+            .expect("failed to select monolith");
+        assert_eq!(selected.id(), light.id());
+    }
+
+    #[test]
+    fn ring_weights_should_be_normalized_and_clamped() {
+        let weights: Vec<_> = ring_weights([10, 20, 30].into_iter()).collect();
+        assert_eq!(weights, [1, 2, 3]);
+        let weights: Vec<_> = ring_weights([1, 50, u32::MAX].into_iter()).collect();
+        assert_eq!(weights, [1, 10, 10]);
+    }
+
+    #[tokio::test]
+    async fn hash_ring_should_handle_large_weights() {
+        let heavy = monolith(u32::MAX, &[]);
+        let light = monolith(1, &[]);
+        let selector = HashRingSelector::from(HashRingSelectorConfig::default());
+
+        let selected = selector
+            .select_monolith(&"foo".into(), vec![&heavy, &light])
+            .expect("failed to select monolith")
+            .id();
+        // the cached ring should pick the same monolith
+        let again = selector
+            .select_monolith(&"foo".into(), vec![&light, &heavy])
+            .expect("failed to select monolith")
+            .id();
+        assert_eq!(selected, again);
+
+        let selected = selector
+            .select_monolith(&"foo".into(), vec![&light])
+            .expect("failed to select monolith");
+        assert_eq!(selected.id(), light.id());
+    }
+
+    #[test]
+    fn monolith_weight_should_be_at_least_one() {
+        let weight = MonolithWeight::new(0);
+        assert_eq!(weight.get(), 1);
+        weight.set(5);
+        assert_eq!(weight.get(), 5);
+    }
 }
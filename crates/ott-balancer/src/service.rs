@@ -1,5 +1,6 @@
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use anyhow::{anyhow, Context};
 use bytes::Bytes;
@@ -18,15 +19,17 @@ use prometheus::{
 };
 use reqwest::Url;
 use route_recognizer::Router;
+use subtle::ConstantTimeEq;
 use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
-use tracing::{debug, error, field, info, span, trace, warn, Level};
+use tracing::{debug, error, field, info, instrument, span, trace, warn, Instrument, Level};
 
 use crate::balancer::{BalancerContext, BalancerLink};
 use crate::client::client_entry;
-use crate::config::BalancerConfig;
+use crate::config::{ApiKeyScope, BalancerConfig};
 use crate::connection::BALANCER_ID;
 use crate::monolith::MonolithProxyTarget;
+use crate::telemetry;
 
 static NOTFOUND: &[u8] = b"Not Found";
 
@@ -36,6 +39,7 @@ static ROUTER: Lazy<Router<&'static str>> = Lazy::new(|| {
     router.add("/api/balancing", "status");
     router.add("/api/state", "state");
     router.add("/api/state/stream", "state_stream");
+    router.add("/api/balancing/config/reload", "config_reload");
     router.add("/api/status/metrics", "metrics");
     router.add("/api/room/:room_name", "room");
     router.add("/api/room/:room_name/", "room");
@@ -70,7 +74,7 @@ impl Service<Request<IncomingBody>> for BalancerService {
             .and_then(Result::ok)
             .map(Region::from)
             .unwrap_or_default();
-        let _request_span = span!(
+        let request_span = span!(
             Level::INFO,
             "http_request",
             request_id = request_id,
@@ -79,8 +83,9 @@ impl Service<Request<IncomingBody>> for BalancerService {
             edge_region = ?edge_region,
             path = %req.uri().path(),
             handler = field::Empty,
-        )
-        .entered();
+        );
+        telemetry::set_parent_from_headers(&request_span, req.headers());
+        let _entered = request_span.clone().entered();
 
         fn mk_response(s: String) -> anyhow::Result<Response<Full<Bytes>>, hyper::Error> {
             Ok(Response::builder().body(Full::new(Bytes::from(s))).unwrap())
@@ -105,7 +110,7 @@ impl Service<Request<IncomingBody>> for BalancerService {
             let res = match handler {
                 "health" => mk_response("OK".to_owned()),
                 "status" => {
-                    if !is_authorized(&req) {
+                    if !is_authorized(&req, ApiKeyScope::ReadState) {
                         return Ok(Response::builder()
                             .status(StatusCode::UNAUTHORIZED)
                             .body(Full::new("unauthorized".into()))
@@ -122,7 +127,7 @@ impl Service<Request<IncomingBody>> for BalancerService {
                     mk_response(rendered)
                 }
                 "state" => {
-                    if !is_authorized(&req) {
+                    if !is_authorized(&req, ApiKeyScope::ReadState) {
                         return Ok(Response::builder()
                             .status(StatusCode::UNAUTHORIZED)
                             .body(Full::new("unauthorized".into()))
@@ -141,13 +146,24 @@ impl Service<Request<IncomingBody>> for BalancerService {
                         .unwrap())
                 }
                 "state_stream" => {
-                    if !is_authorized(&req) {
+                    if !is_authorized(&req, ApiKeyScope::StreamEvents) {
                         return Ok(Response::builder()
                             .status(StatusCode::UNAUTHORIZED)
                             .body(Full::new("unauthorized".into()))
                             .unwrap());
                     }
                     if is_websocket_upgrade(&req) {
+                        let filter = match crate::state_stream::parse_filter_query(
+                            req.uri().query().unwrap_or_default(),
+                        ) {
+                            Ok(filter) => filter,
+                            Err(err) => {
+                                return Ok(Response::builder()
+                                    .status(StatusCode::BAD_REQUEST)
+                                    .body(Full::new(format!("{:#}", err).into()))
+                                    .unwrap());
+                            }
+                        };
                         let (response, websocket) = match upgrade(req, None) {
                             Ok((response, websocket)) => (response, websocket),
                             Err(err) => {
@@ -158,7 +174,10 @@ impl Service<Request<IncomingBody>> for BalancerService {
 
                         let handle = tokio::spawn(async move {
                             if let Err(err) =
-                                crate::state_stream::handle_stream_websocket(websocket).await
+                                crate::state_stream::handle_stream_websocket(
+                                    websocket, filter, ctx,
+                                )
+                                .await
                             {
                                 error!("error handling event stream websocket: {}", err);
                             }
@@ -173,7 +192,55 @@ impl Service<Request<IncomingBody>> for BalancerService {
                             .unwrap())
                     }
                 }
+                "config_reload" => {
+                    if !is_authorized(&req, ApiKeyScope::AdminWrite) {
+                        return Ok(Response::builder()
+                            .status(StatusCode::UNAUTHORIZED)
+                            .body(Full::new("unauthorized".into()))
+                            .unwrap());
+                    }
+                    if req.method() != hyper::Method::POST {
+                        return Ok(Response::builder()
+                            .status(StatusCode::METHOD_NOT_ALLOWED)
+                            .body(Full::new("expected POST".into()))
+                            .unwrap());
+                    }
+                    match crate::reload::reload_config(&ctx).await {
+                        Ok(changes) if changes.is_empty() => mk_response("no changes".to_owned()),
+                        Ok(changes) => {
+                            let rendered = changes
+                                .iter()
+                                .map(|change| {
+                                    if change.requires_restart() {
+                                        format!("{} (requires restart)", change)
+                                    } else {
+                                        change.to_string()
+                                    }
+                                })
+                                .collect::<Vec<_>>()
+                                .join("\n");
+                            mk_response(rendered)
+                        }
+                        Err(err) => {
+                            error!(
+                                "Failed to reload config, keeping the current one: {:?}",
+                                err
+                            );
+                            Ok(Response::builder()
+                                .status(StatusCode::BAD_REQUEST)
+                                .body(Full::new(Bytes::from(format!(
+                                    "failed to reload config: {:#}",
+                                    err
+                                ))))
+                                .unwrap())
+                        }
+                    }
+                }
                 "metrics" => {
+                    crate::metrics::update_state_gauges(
+                        &*ctx.read().await,
+                        &BalancerConfig::get(),
+                    );
                     let bytes = match gather_metrics() {
                         Ok(bytes) => bytes,
                         Err(e) => {
@@ -254,7 +321,8 @@ impl Service<Request<IncomingBody>> for BalancerService {
                                     error!("Error in websocket connection: {}", e);
                                 }
                                 GAUGE_CLIENTS.dec();
-                            },
+                            }
+                            .in_current_span(),
                         );
                         match handle {
                             Ok(handle) => {
@@ -270,7 +338,7 @@ impl Service<Request<IncomingBody>> for BalancerService {
                         Ok(response)
                     } else if let Some(monolith) = selected_monolith {
                         info!("proxying request to monolith {}", monolith.id());
-                        debug!(event = "proxy", balancer_id = %*BALANCER_ID,  direction = "tx", room = %room_name, node_id = %monolith.id());
+                        debug!(event = "proxy", balancer_id = %*BALANCER_ID,  direction = "tx", room = %room_name, node_id = %monolith.id(), monolith_id = %monolith.id());
                         match proxy_request(req, monolith).await {
                             Ok(res) => Ok(res),
                             Err(err) => {
@@ -299,7 +367,7 @@ impl Service<Request<IncomingBody>> for BalancerService {
                             message = "proxying request to monolith",
                             monolith = %monolith.id(),
                         );
-                        debug!(event = "proxy", balancer_id = %*BALANCER_ID,  direction = "tx", node_id = %monolith.id());
+                        debug!(event = "proxy", balancer_id = %*BALANCER_ID,  direction = "tx", node_id = %monolith.id(), monolith_id = %monolith.id());
                         match proxy_request(req, monolith).await {
                             Ok(res) => Ok(res),
                             Err(err) => {
@@ -317,7 +385,8 @@ impl Service<Request<IncomingBody>> for BalancerService {
                 _ => Ok(not_found()),
             };
             res
-        })
+        }
+        .instrument(request_span))
     }
 }
 
@@ -360,6 +429,7 @@ impl Drop for ProxyRequestInFlightGuard {
     }
 }
 
+#[instrument(skip_all, fields(monolith_id = %target.id(), path = %in_req.uri().path()))]
 async fn proxy_request(
     in_req: Request<IncomingBody>,
     target: MonolithProxyTarget,
@@ -370,7 +440,7 @@ async fn proxy_request(
     let _in_flight_guard = ProxyRequestInFlightGuard::new();
     let response = async {
         let client = target.http_client();
-        let (parts, body) = in_req.into_parts();
+        let (mut parts, body) = in_req.into_parts();
         let mut url: Url = target.config().uri().clone();
         url.set_scheme("http")
             .map_err(|_| anyhow!("failed to set proxy request scheme to http"))?;
@@ -381,6 +451,8 @@ async fn proxy_request(
         // TODO: update X-Forwarded-For header
         // TODO: stream the body instead of loading it all into memory?
 
+        telemetry::inject_headers(&mut parts.headers);
+
         let body: Bytes = body.collect().await?.to_bytes();
         let out_body: reqwest::Body = reqwest::Body::from(body);
         let req = client
@@ -469,19 +541,85 @@ pub(crate) fn set_connected_monolith_metrics(connected_monolith_count: usize) {
     update_health_gauge();
 }
 
-fn is_authorized<B>(req: &Request<B>) -> bool {
-    if let Some(api_key) = BalancerConfig::get().api_key.as_ref() {
-        let headers = req.headers();
-        let auth = headers.get("Authorization");
-        if let Some(auth) = auth {
-            if auth.as_bytes().starts_with(b"Bearer ")
-                && &auth.as_bytes()[7..] == api_key.as_bytes()
-            {
-                return true;
-            }
+fn is_authorized<B>(req: &Request<B>, scope: ApiKeyScope) -> bool {
+    let Some(token) = req
+        .headers()
+        .get("Authorization")
+        .and_then(|auth| auth.as_bytes().strip_prefix(b"Bearer "))
+    else {
+        return false;
+    };
+
+    authorize_token(&BalancerConfig::get(), token, scope, SystemTime::now())
+}
+
+/// Check whether `token` is a valid API key that grants `scope`.
+///
+/// Every configured key is compared in constant time, and the comparisons don't stop early when
+/// a match is found, so the response time doesn't reveal which key (if any) was matched.
+fn authorize_token(
+    config: &BalancerConfig,
+    token: &[u8],
+    scope: ApiKeyScope,
+    now: SystemTime,
+) -> bool {
+    let mut key_name = "unknown";
+    let mut check = ApiKeyCheck::Invalid;
+    if let Some(api_key) = config.api_key.as_ref() {
+        if bool::from(api_key.as_bytes().ct_eq(token)) {
+            key_name = "api_key";
+            check = ApiKeyCheck::Allowed;
+        }
+    }
+    for api_key in &config.api_keys {
+        let is_match = bool::from(api_key.key.as_bytes().ct_eq(token));
+        if is_match && check == ApiKeyCheck::Invalid {
+            key_name = &api_key.name;
+            check = if api_key.is_expired(now) {
+                ApiKeyCheck::Expired
+            } else if !api_key.scopes.contains(&scope) {
+                ApiKeyCheck::Forbidden
+            } else {
+                ApiKeyCheck::Allowed
+            };
+        }
+    }
+
+    COUNTER_API_KEY_REQUESTS
+        .with_label_values(&[key_name, scope.as_str(), check.as_str()])
+        .inc();
+    if check != ApiKeyCheck::Allowed {
+        warn!(
+            key = key_name,
+            scope = scope.as_str(),
+            result = check.as_str(),
+            "rejected api key"
+        );
+    }
+
+    check == ApiKeyCheck::Allowed
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ApiKeyCheck {
+    Allowed,
+    /// The key exists, but it has expired.
+    Expired,
+    /// The key exists, but it doesn't have the required scope.
+    Forbidden,
+    /// The key doesn't exist.
+    Invalid,
+}
+
+impl ApiKeyCheck {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ApiKeyCheck::Allowed => "allowed",
+            ApiKeyCheck::Expired => "expired",
+            ApiKeyCheck::Forbidden => "forbidden",
+            ApiKeyCheck::Invalid => "invalid",
         }
     }
-    false
 }
 
 static GAUGE_CLIENTS: Lazy<IntGauge> = Lazy::new(|| {
@@ -533,6 +671,15 @@ static COUNTER_PROXY_REQUEST_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
     .unwrap()
 });
 
+static COUNTER_API_KEY_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "balancer_api_key_requests_total",
+        "Count of requests to restricted endpoints, by API key name",
+        &["key", "scope", "result"]
+    )
+    .unwrap()
+});
+
 static GAUGE_PROXY_REQUESTS_IN_FLIGHT: Lazy<IntGauge> = Lazy::new(|| {
     register_int_gauge!(
         "balancer_proxy_requests_in_flight",
@@ -553,7 +700,9 @@ static HISTOGRAM_PROXY_REQUEST_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config::ApiKeyConfig;
     use std::future::pending;
+    use std::time::Duration;
 
     fn reset_readiness_metrics() {
         GAUGE_DISCOVERY_COMPLETED.set(0);
@@ -580,6 +729,13 @@ mod test {
             ROUTER.recognize("/api/status/metrics").unwrap().handler(),
             &&"metrics"
         );
+        assert_eq!(
+            ROUTER
+                .recognize("/api/balancing/config/reload")
+                .unwrap()
+                .handler(),
+            &&"config_reload"
+        );
     }
 
     #[test]
@@ -619,68 +775,190 @@ mod test {
     #[test]
     fn test_is_authorized_with_valid_api_key() {
         BalancerConfig::init_default();
-        unsafe {
-            BalancerConfig::get_mut().api_key = Some("YOUR_API_KEY".to_owned());
-        }
+        BalancerConfig::update(|config| config.api_key = Some("YOUR_API_KEY".to_owned()));
         let req = Request::builder()
             .header("Authorization", "Bearer YOUR_API_KEY")
             .body(Full::new(Bytes::new()))
             .unwrap();
 
-        assert!(is_authorized(&req));
+        assert!(is_authorized(&req, ApiKeyScope::ReadState));
     }
 
     #[test]
     fn test_is_authorized_with_invalid_api_key() {
         BalancerConfig::init_default();
-        unsafe {
-            BalancerConfig::get_mut().api_key = Some("YOUR_API_KEY".to_owned());
-        }
+        BalancerConfig::update(|config| config.api_key = Some("YOUR_API_KEY".to_owned()));
         let req = Request::builder()
             .header("Authorization", "Bearer INVALID_API_KEY")
             .body(Full::new(Bytes::new()))
             .unwrap();
 
-        assert!(!is_authorized(&req));
+        assert!(!is_authorized(&req, ApiKeyScope::ReadState));
     }
 
     #[test]
     fn test_is_authorized_without_authorization_header() {
         BalancerConfig::init_default();
-        unsafe {
-            BalancerConfig::get_mut().api_key = Some("YOUR_API_KEY".to_owned());
-        }
+        BalancerConfig::update(|config| config.api_key = Some("YOUR_API_KEY".to_owned()));
         let req = Request::builder().body(Full::new(Bytes::new())).unwrap();
 
-        assert!(!is_authorized(&req));
+        assert!(!is_authorized(&req, ApiKeyScope::ReadState));
     }
 
     #[test]
     fn test_is_authorized_with_different_authorization_scheme() {
         BalancerConfig::init_default();
-        unsafe {
-            BalancerConfig::get_mut().api_key = Some("YOUR_API_KEY".to_owned());
-        }
+        BalancerConfig::update(|config| config.api_key = Some("YOUR_API_KEY".to_owned()));
         let req = Request::builder()
             .header("Authorization", "Basic YOUR_API_KEY")
             .body(Full::new(Bytes::new()))
             .unwrap();
 
-        assert!(!is_authorized(&req));
+        assert!(!is_authorized(&req, ApiKeyScope::ReadState));
     }
 
     #[test]
     fn test_is_authorized_without_api_key_set() {
         BalancerConfig::init_default();
-        unsafe {
-            BalancerConfig::get_mut().api_key = None;
-        }
+        BalancerConfig::update(|config| config.api_key = None);
         let req = Request::builder()
             .header("Authorization", "Basic YOUR_API_KEY")
             .body(Full::new(Bytes::new()))
             .unwrap();
 
-        assert!(!is_authorized(&req));
+        assert!(!is_authorized(&req, ApiKeyScope::ReadState));
+    }
+
+    fn scoped_key(name: &str, key: &str, scopes: &[ApiKeyScope]) -> ApiKeyConfig {
+        ApiKeyConfig {
+            name: name.to_owned(),
+            key: key.to_owned(),
+            scopes: scopes.to_vec(),
+            expires: None,
+        }
+    }
+
+    #[test]
+    fn test_authorize_token_with_scoped_key() {
+        let config = BalancerConfig {
+            api_keys: vec![scoped_key(
+                "collector",
+                "COLLECTOR_KEY",
+                &[ApiKeyScope::ReadState, ApiKeyScope::StreamEvents],
+            )],
+            ..Default::default()
+        };
+        let now = SystemTime::now();
+
+        assert!(authorize_token(
+            &config,
+            b"COLLECTOR_KEY",
+            ApiKeyScope::ReadState,
+            now
+        ));
+        assert!(authorize_token(
+            &config,
+            b"COLLECTOR_KEY",
+            ApiKeyScope::StreamEvents,
+            now
+        ));
+        assert!(!authorize_token(
+            &config,
+            b"COLLECTOR_KEY",
+            ApiKeyScope::AdminWrite,
+            now
+        ));
+        assert!(!authorize_token(
+            &config,
+            b"COLLECTOR",
+            ApiKeyScope::ReadState,
+            now
+        ));
+    }
+
+    #[test]
+    fn test_authorize_token_during_rotation() {
+        let now = SystemTime::now();
+        let config = BalancerConfig {
+            api_keys: vec![
+                ApiKeyConfig {
+                    expires: Some(now + Duration::from_secs(60)),
+                    ..scoped_key("old", "OLD_KEY", &[ApiKeyScope::ReadState])
+                },
+                scoped_key("new", "NEW_KEY", &[ApiKeyScope::ReadState]),
+            ],
+            ..Default::default()
+        };
+
+        assert!(authorize_token(
+            &config,
+            b"OLD_KEY",
+            ApiKeyScope::ReadState,
+            now
+        ));
+        assert!(authorize_token(
+            &config,
+            b"NEW_KEY",
+            ApiKeyScope::ReadState,
+            now
+        ));
+
+        let later = now + Duration::from_secs(120);
+        assert!(!authorize_token(
+            &config,
+            b"OLD_KEY",
+            ApiKeyScope::ReadState,
+            later
+        ));
+        assert!(authorize_token(
+            &config,
+            b"NEW_KEY",
+            ApiKeyScope::ReadState,
+            later
+        ));
+    }
+
+    #[test]
+    fn test_authorize_token_legacy_key_has_all_scopes() {
+        let config = BalancerConfig {
+            api_key: Some("LEGACY_KEY".to_owned()),
+            ..Default::default()
+        };
+        let now = SystemTime::now();
+
+        for scope in [
+            ApiKeyScope::ReadState,
+            ApiKeyScope::StreamEvents,
+            ApiKeyScope::AdminWrite,
+        ] {
+            assert!(authorize_token(&config, b"LEGACY_KEY", scope, now));
+        }
+    }
+
+    #[test]
+    fn test_authorize_token_counts_usage_per_key() {
+        let config = BalancerConfig {
+            api_keys: vec![scoped_key(
+                "usage-test",
+                "USAGE_KEY",
+                &[ApiKeyScope::ReadState],
+            )],
+            ..Default::default()
+        };
+        let now = SystemTime::now();
+        let allowed =
+            COUNTER_API_KEY_REQUESTS.with_label_values(&["usage-test", "read-state", "allowed"]);
+        let forbidden =
+            COUNTER_API_KEY_REQUESTS.with_label_values(&["usage-test", "admin-write", "forbidden"]);
+        let allowed_before = allowed.get();
+        let forbidden_before = forbidden.get();
+
+        authorize_token(&config, b"USAGE_KEY", ApiKeyScope::ReadState, now);
+        authorize_token(&config, b"USAGE_KEY", ApiKeyScope::ReadState, now);
+        authorize_token(&config, b"USAGE_KEY", ApiKeyScope::AdminWrite, now);
+
+        assert_eq!(allowed.get(), allowed_before + 2);
+        assert_eq!(forbidden.get(), forbidden_before + 1);
     }
 
     #[test]
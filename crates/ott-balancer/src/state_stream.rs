@@ -1,29 +1,298 @@
+use anyhow::Context as _;
 use futures_util::SinkExt;
 use futures_util::StreamExt;
 use once_cell::sync::Lazy;
+use ott_balancer_protocol::collector::{
+    BalancerEvent, BalancerState, Event, StateSync, StateUpdate, StreamControl, StreamFilter,
+};
+use ott_balancer_protocol::{ClientId, MonolithId, RoomName};
 use ott_common::websocket::HyperWebsocket;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{Notify, RwLock};
 use tracing::Metadata;
 use tracing_subscriber::layer::Context;
 use tracing_subscriber::layer::Filter;
+use uuid::Uuid;
 
 use tungstenite::Message;
 
+use crate::balancer::BalancerContext;
+use crate::connection::BALANCER_ID;
+
 pub static EVENT_STREAMER: Lazy<Arc<Mutex<EventStreamer>>> =
     Lazy::new(|| Arc::new(Mutex::new(EventStreamer::new())));
 
+/// The event type that [`StateSync`] messages can be filtered by.
+pub const STATE_EVENT: &str = "state";
+
+/// How often the state is checked for changes that didn't come with a lifecycle event.
+const STATE_RESYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The state that was last sent to subscribers, which diffs are computed against.
+///
+/// `state` is `None` while nobody is subscribed, so that no work is done to keep it up to date.
+#[derive(Default)]
+struct PublishedState {
+    version: u64,
+    state: Option<BalancerState>,
+    /// When `state` was copied from the context, from [`STATE_COPIES`].
+    copied_at: u64,
+}
+
+impl PublishedState {
+    fn snapshot(&self) -> Option<StateSync> {
+        Some(StateSync {
+            balancer_id: *BALANCER_ID,
+            version: self.version,
+            state: StateUpdate::Snapshot(self.state.clone()?),
+        })
+    }
+}
+
+static PUBLISHED_STATE: Lazy<Mutex<PublishedState>> = Lazy::new(Default::default);
+static STATE_CHANGED: Lazy<Notify> = Lazy::new(Notify::new);
+/// Orders copies of the state, so that an older copy never replaces a newer one that was published in the meantime.
+static STATE_COPIES: AtomicU64 = AtomicU64::new(0);
+
+/// Copy the balancer's state. The read lock is only held while copying, not while diffing or serializing it.
+async fn copy_state(ctx: &RwLock<BalancerContext>) -> (BalancerState, u64) {
+    let ctx_read = ctx.read().await;
+    let copied_at = STATE_COPIES.fetch_add(1, Ordering::SeqCst) + 1;
+    (ctx_read.current_state(), copied_at)
+}
+
 /// Handle a WebSocket connection for streaming state events.
 /// Does not block the current task
-pub async fn handle_stream_websocket(ws: HyperWebsocket) -> anyhow::Result<()> {
-    EVENT_STREAMER.lock().unwrap().handle_new_connection(ws)?;
+pub async fn handle_stream_websocket(
+    ws: HyperWebsocket,
+    filter: StreamFilter,
+    ctx: Arc<RwLock<BalancerContext>>,
+) -> anyhow::Result<()> {
+    let (recv, snapshot) = subscribe(&ctx).await;
+    tokio::task::Builder::new()
+        .name("event streamer websocket")
+        .spawn(stream_to_websocket(ws, filter, recv, snapshot))?;
 
     Ok(())
 }
 
+/// Subscribe to the state stream, along with a snapshot of the state that the diffs sent from now on apply to.
+async fn subscribe(
+    ctx: &RwLock<BalancerContext>,
+) -> (
+    tokio::sync::broadcast::Receiver<Arc<StreamEvent>>,
+    Option<StateSync>,
+) {
+    let event_tx = EVENT_STREAMER.lock().unwrap().event_tx();
+    let recv = event_tx.subscribe();
+    if PUBLISHED_STATE.lock().unwrap().state.is_none() {
+        let (state, copied_at) = copy_state(ctx).await;
+        let mut published = PUBLISHED_STATE.lock().unwrap();
+        if published.state.is_none() {
+            published.version += 1;
+            published.state = Some(state);
+            published.copied_at = copied_at;
+        }
+    }
+    let snapshot = PUBLISHED_STATE.lock().unwrap().snapshot();
+    (recv, snapshot)
+}
+
+/// Let the state publisher know that the balancer's state may have changed.
+pub fn notify_state_changed() {
+    STATE_CHANGED.notify_one();
+}
+
+/// Send the changes to the balancer's state since it was last published to everyone that is subscribed.
+async fn publish_state(ctx: &RwLock<BalancerContext>) {
+    let event_tx = EVENT_STREAMER.lock().unwrap().event_tx();
+    if event_tx.receiver_count() == 0 {
+        PUBLISHED_STATE.lock().unwrap().state = None;
+        return;
+    }
+    let (state, copied_at) = copy_state(ctx).await;
+    let mut published = PUBLISHED_STATE.lock().unwrap();
+    if published.copied_at > copied_at {
+        // A subscriber published a newer copy while this one was being taken.
+        return;
+    }
+    published.copied_at = copied_at;
+    if let Some(old) = &published.state {
+        let changes = old.diff(&state);
+        if changes.is_empty() {
+            return;
+        }
+        published.version += 1;
+        let sync = StateSync {
+            balancer_id: *BALANCER_ID,
+            version: published.version,
+            state: StateUpdate::Diff(changes),
+        };
+        match StreamEvent::from_state_sync(&sync) {
+            Ok(event) => {
+                let _ = event_tx.send(Arc::new(event));
+            }
+            Err(err) => tracing::error!("Error serializing state diff: {}", err),
+        }
+    } else {
+        published.version += 1;
+    }
+    published.state = Some(state);
+}
+
+/// Publish state diffs whenever the balancer's state changes.
+pub fn start_state_publisher(
+    ctx: Arc<RwLock<BalancerContext>>,
+) -> anyhow::Result<tokio::task::JoinHandle<()>> {
+    Ok(tokio::task::Builder::new()
+        .name("state publisher")
+        .spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = STATE_CHANGED.notified() => {}
+                    _ = tokio::time::sleep(STATE_RESYNC_INTERVAL) => {}
+                }
+                publish_state(&ctx).await;
+            }
+        })?)
+}
+
+/// Build a filter from the query string of a state stream request.
+///
+/// Each parameter can be repeated, or hold a comma separated list, eg. `?events=ws,room_loaded&rooms=foo`.
+pub fn parse_filter_query(query: &str) -> anyhow::Result<StreamFilter> {
+    let mut filter = StreamFilter::default();
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        let values = value.split(',').map(str::trim).filter(|v| !v.is_empty());
+        match key.as_ref() {
+            "events" => filter.events.extend(values.map(str::to_owned)),
+            "rooms" => filter.rooms.extend(values.map(RoomName::from)),
+            "monoliths" => {
+                for value in values {
+                    let id = Uuid::parse_str(value)
+                        .with_context(|| format!("invalid monolith id: {}", value))?;
+                    filter.monoliths.push(id.into());
+                }
+            }
+            "clients" => {
+                for value in values {
+                    let id = Uuid::parse_str(value)
+                        .with_context(|| format!("invalid client id: {}", value))?;
+                    filter.clients.push(id.into());
+                }
+            }
+            _ => anyhow::bail!("unknown filter: {}", key),
+        }
+    }
+    Ok(filter)
+}
+
+/// An event on its way to the state stream, along with the fields that subscribers can filter on.
+#[derive(Debug, Default)]
+pub struct StreamEvent {
+    /// The event, serialized as JSON.
+    pub text: String,
+    /// The `event` field of a traffic event, or the `type` of a lifecycle event.
+    pub kind: Option<String>,
+    pub room: Option<RoomName>,
+    pub monoliths: Vec<MonolithId>,
+    pub client: Option<ClientId>,
+    /// The version of a [`StateSync`] message.
+    pub version: Option<u64>,
+}
+
+impl StreamEvent {
+    pub fn from_state_sync(sync: &StateSync) -> serde_json::Result<Self> {
+        Ok(Self {
+            text: serde_json::to_string(sync)?,
+            kind: Some(STATE_EVENT.to_owned()),
+            version: Some(sync.version),
+            ..Default::default()
+        })
+    }
+
+    /// Pick out the fields to filter on from either kind of event: the flat JSON written by the
+    /// `tracing` layer, or a serialized [`BalancerEvent`].
+    pub fn new(text: String) -> Self {
+        let Ok(value) = serde_json::from_str::<serde_json::Value>(&text) else {
+            return Self {
+                text,
+                ..Default::default()
+            };
+        };
+        let (kind, fields) = match value.get("event") {
+            Some(serde_json::Value::String(kind)) => (Some(kind.clone()), &value),
+            Some(event) => (
+                event
+                    .get("type")
+                    .and_then(|t| t.as_str())
+                    .map(str::to_owned),
+                event.get("payload").unwrap_or(event),
+            ),
+            None => (None, &value),
+        };
+        let field = |name: &str| fields.get(name).and_then(|v| v.as_str());
+        let id = |name: &str| field(name).and_then(|v| Uuid::parse_str(v).ok());
+        let room = field("room").map(RoomName::from);
+        let monoliths = ["monolith_id", "kept_monolith_id", "unloaded_monolith_id"]
+            .into_iter()
+            .filter_map(id)
+            .map(MonolithId::from)
+            .collect();
+        let client = id("client_id").map(ClientId::from);
+        Self {
+            text,
+            kind,
+            room,
+            monoliths,
+            client,
+            version: None,
+        }
+    }
+
+    pub fn matches(&self, filter: &StreamFilter) -> bool {
+        fn any_of<T: PartialEq>(allowed: &[T], value: Option<&T>) -> bool {
+            allowed.is_empty() || value.is_some_and(|v| allowed.contains(v))
+        }
+
+        any_of(&filter.events, self.kind.as_ref())
+            && any_of(&filter.rooms, self.room.as_ref())
+            && (filter.monoliths.is_empty()
+                || self.monoliths.iter().any(|m| filter.monoliths.contains(m)))
+            && any_of(&filter.clients, self.client.as_ref())
+    }
+}
+
+/// Send a lifecycle event to everyone that is subscribed to the state stream.
+pub fn emit_event(event: Event) {
+    notify_state_changed();
+    let event_tx = EVENT_STREAMER.lock().unwrap().event_tx();
+    if event_tx.receiver_count() == 0 {
+        return;
+    }
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let event = BalancerEvent {
+        balancer_id: *BALANCER_ID,
+        timestamp,
+        event,
+    };
+    match serde_json::to_string(&event) {
+        Ok(text) => {
+            let _ = event_tx.send(Arc::new(StreamEvent::new(text)));
+        }
+        Err(err) => tracing::error!("Error serializing event: {}", err),
+    }
+}
+
 pub struct EventStreamer {
-    event_tx: tokio::sync::broadcast::Sender<String>,
+    event_tx: tokio::sync::broadcast::Sender<Arc<StreamEvent>>,
 }
 
 impl Default for EventStreamer {
@@ -38,69 +307,114 @@ impl EventStreamer {
         Self { event_tx }
     }
 
-    pub fn handle_new_connection(&self, ws: HyperWebsocket) -> anyhow::Result<()> {
-        let mut recv = self.event_tx.subscribe();
-        tokio::task::Builder::new()
-            .name("event streamer websocket")
-            .spawn(async move {
-                let Ok(mut ws) = ws.await else {
-                    return;
-                };
-
-                loop {
-                    tokio::select! {
-                        result = recv.recv() => {
-                            match result {
-                                Ok(event) => {
-                                    let msg = Message::Text(event);
-                                    if let Err(err) = ws.send(msg).await {
-                                        tracing::error!("Error sending event to WebSocket: {}", err);
-                                        break;
-                                    }
-                                }
-                                Err(RecvError::Lagged(_)) => {
-                                    continue;
-                                }
-                                Err(_) => {
-                                    break;
-                                }
+    pub fn event_tx(&self) -> tokio::sync::broadcast::Sender<Arc<StreamEvent>> {
+        self.event_tx.clone()
+    }
+}
+
+/// Whether subscribers with this filter should receive [`StateSync`] messages.
+fn wants_state(filter: &StreamFilter) -> bool {
+    StreamEvent {
+        kind: Some(STATE_EVENT.to_owned()),
+        ..Default::default()
+    }
+    .matches(filter)
+}
+
+async fn stream_to_websocket(
+    ws: HyperWebsocket,
+    mut filter: StreamFilter,
+    mut recv: tokio::sync::broadcast::Receiver<Arc<StreamEvent>>,
+    snapshot: Option<StateSync>,
+) {
+    let Ok(mut ws) = ws.await else {
+        return;
+    };
+
+    // The version of the last snapshot that was sent, so that diffs that are already part of it get skipped.
+    let mut snapshot_version = None;
+    let mut snapshot = snapshot.filter(|_| wants_state(&filter));
+    loop {
+        if let Some(sync) = snapshot.take() {
+            snapshot_version = Some(sync.version);
+            let text = serde_json::to_string(&sync).expect("failed to serialize snapshot");
+            if let Err(err) = ws.send(Message::Text(text)).await {
+                tracing::error!("Error sending snapshot to WebSocket: {}", err);
+                break;
+            }
+        }
+        tokio::select! {
+            result = recv.recv() => {
+                match result {
+                    Ok(event) => {
+                        if !event.matches(&filter) {
+                            continue;
+                        }
+                        if let (Some(version), Some(snapshot_version)) = (event.version, snapshot_version) {
+                            if version <= snapshot_version {
+                                continue;
                             }
                         }
-                        msg = ws.next() => match msg {
-                            Some(Ok(msg)) => {
-                                if msg.is_close() {
-                                    break;
+                        let msg = Message::Text(event.text.clone());
+                        if let Err(err) = ws.send(msg).await {
+                            tracing::error!("Error sending event to WebSocket: {}", err);
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => {
+                        // Diffs were missed, so start over from a snapshot.
+                        if wants_state(&filter) {
+                            snapshot = PUBLISHED_STATE.lock().unwrap().snapshot();
+                        }
+                        continue;
+                    }
+                    Err(_) => {
+                        break;
+                    }
+                }
+            }
+            msg = ws.next() => match msg {
+                Some(Ok(msg)) => {
+                    if msg.is_close() {
+                        break;
+                    }
+                    if let Message::Text(text) = msg {
+                        match serde_json::from_str::<StreamControl>(&text) {
+                            Ok(StreamControl::Subscribe(new_filter)) => {
+                                tracing::debug!("Event stream filter changed: {:?}", new_filter);
+                                if wants_state(&new_filter) && !wants_state(&filter) {
+                                    snapshot = PUBLISHED_STATE.lock().unwrap().snapshot();
                                 }
+                                filter = new_filter;
                             }
-                            Some(Err(err)) => {
-                                tracing::error!("Error receiving message from WebSocket: {}", err);
-                                break;
+                            Err(err) => {
+                                tracing::warn!("Invalid message on event stream, ignoring: {}", err);
                             }
-                            None => break,
-                        },
-                        else => break,
+                        }
                     }
                 }
-                let _ = ws.close(None).await;
-                let _ = ws.flush().await;
-            })?;
-        Ok(())
-    }
-
-    pub fn event_tx(&self) -> tokio::sync::broadcast::Sender<String> {
-        self.event_tx.clone()
+                Some(Err(err)) => {
+                    tracing::error!("Error receiving message from WebSocket: {}", err);
+                    break;
+                }
+                None => break,
+            },
+            else => break,
+        }
     }
+    let _ = ws.close(None).await;
+    let _ = ws.flush().await;
 }
 
 pub struct EventSink {
-    pub event_tx: tokio::sync::broadcast::Sender<String>,
+    pub event_tx: tokio::sync::broadcast::Sender<Arc<StreamEvent>>,
 }
 
 impl std::io::Write for EventSink {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         let event = String::from_utf8_lossy(buf).to_string();
         self.event_tx
-            .send(event)
+            .send(Arc::new(StreamEvent::new(event)))
             .map_err(|_| std::io::ErrorKind::Other)?;
 
         Ok(buf.len())
@@ -112,11 +426,11 @@ impl std::io::Write for EventSink {
 }
 
 pub struct EventFilter {
-    event_tx: tokio::sync::broadcast::Sender<String>,
+    event_tx: tokio::sync::broadcast::Sender<Arc<StreamEvent>>,
 }
 
 impl EventFilter {
-    pub fn new(event_tx: tokio::sync::broadcast::Sender<String>) -> Self {
+    pub fn new(event_tx: tokio::sync::broadcast::Sender<Arc<StreamEvent>>) -> Self {
         Self { event_tx }
     }
 }
@@ -126,3 +440,163 @@ impl<S> Filter<S> for EventFilter {
         meta.fields().field("event").is_some() && self.event_tx.receiver_count() > 0
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use ott_common::discovery::{ConnectionConfig, HostOrIp};
+
+    use crate::config::BalancerConfig;
+    use crate::monolith::{BalancerMonolith, NewMonolith};
+    use crate::room::RoomLocator;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn diffs_should_follow_snapshot() {
+        BalancerConfig::init_default();
+        let ctx = Arc::new(RwLock::new(BalancerContext::new()));
+        let (mut recv, snapshot) = subscribe(&ctx).await;
+        let snapshot = snapshot.expect("subscriber should get a snapshot");
+        let StateUpdate::Snapshot(mut state) = snapshot.state else {
+            panic!("expected a snapshot, got {:?}", snapshot.state);
+        };
+        assert!(state.monoliths.is_empty());
+
+        let (monolith_outbound_tx, _monolith_outbound_rx) = tokio::sync::mpsc::channel(100);
+        let (client_inbound_tx, _client_inbound_rx) = tokio::sync::mpsc::channel(100);
+        let monolith_id: MonolithId = Uuid::new_v4().into();
+        ctx.write().await.add_monolith(BalancerMonolith::new(
+            NewMonolith {
+                id: monolith_id,
+                region: "ord".into(),
+                config: ConnectionConfig {
+                    host: HostOrIp::Ip(Ipv4Addr::LOCALHOST.into()),
+                    port: 3002,
+                },
+                proxy_port: 3000,
+                weight: Default::default(),
+            },
+            Arc::new(monolith_outbound_tx),
+            client_inbound_tx,
+        ));
+        ctx.write()
+            .await
+            .add_room("foo".into(), RoomLocator::new(monolith_id, 0))
+            .unwrap();
+        publish_state(&ctx).await;
+        ctx.write()
+            .await
+            .add_room("bar".into(), RoomLocator::new(monolith_id, 0))
+            .unwrap();
+        ctx.write()
+            .await
+            .remove_room(&"foo".into(), monolith_id)
+            .unwrap();
+        publish_state(&ctx).await;
+        // nothing changed, so nothing should be sent
+        publish_state(&ctx).await;
+
+        let mut version = snapshot.version;
+        while let Ok(event) = recv.try_recv() {
+            // other tests can emit events at the same time
+            if event.kind.as_deref() != Some(STATE_EVENT) {
+                continue;
+            }
+            let sync: StateSync = serde_json::from_str(&event.text).unwrap();
+            assert_eq!(sync.version, version + 1);
+            version = sync.version;
+            let StateUpdate::Diff(changes) = sync.state else {
+                panic!("expected a diff, got {:?}", sync.state);
+            };
+            state.apply_changes(&changes);
+        }
+        assert_eq!(version, snapshot.version + 2);
+        assert_eq!(state, ctx.read().await.current_state());
+        assert_eq!(state.monoliths[0].rooms[0].name, "bar".into());
+    }
+
+    #[test]
+    fn should_parse_filter_query() {
+        let monolith_id = Uuid::new_v4();
+        let filter = parse_filter_query(&format!(
+            "events=ws,room_loaded&rooms=foo&rooms=bar&monoliths={}",
+            monolith_id
+        ))
+        .unwrap();
+        assert_eq!(filter.events, vec!["ws", "room_loaded"]);
+        assert_eq!(filter.rooms, vec!["foo".into(), "bar".into()]);
+        assert_eq!(filter.monoliths, vec![monolith_id.into()]);
+        assert!(filter.clients.is_empty());
+
+        assert_eq!(parse_filter_query("").unwrap(), StreamFilter::default());
+        assert!(parse_filter_query("clients=not-a-uuid").is_err());
+        assert!(parse_filter_query("foo=bar").is_err());
+    }
+
+    #[test]
+    fn should_match_traffic_events() {
+        let client_id = Uuid::new_v4();
+        let event = StreamEvent::new(
+            serde_json::json!({
+                "event": "ws",
+                "node_id": client_id,
+                "client_id": client_id,
+                "room": "foo",
+                "direction": "rx",
+            })
+            .to_string(),
+        );
+
+        assert!(event.matches(&StreamFilter::default()));
+        assert!(event.matches(&StreamFilter {
+            events: vec!["proxy".to_owned(), "ws".to_owned()],
+            rooms: vec!["foo".into()],
+            clients: vec![client_id.into()],
+            ..Default::default()
+        }));
+        assert!(!event.matches(&StreamFilter {
+            events: vec!["ws".to_owned()],
+            rooms: vec!["bar".into()],
+            ..Default::default()
+        }));
+        assert!(!event.matches(&StreamFilter {
+            monoliths: vec![Uuid::new_v4().into()],
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn should_match_lifecycle_events() {
+        let kept = Uuid::new_v4();
+        let unloaded = Uuid::new_v4();
+        let event = StreamEvent::new(
+            serde_json::to_string(&BalancerEvent {
+                balancer_id: Uuid::new_v4().into(),
+                timestamp: 0,
+                event: Event::RoomConflictResolved(
+                    ott_balancer_protocol::collector::ERoomConflictResolved {
+                        room: "foo".into(),
+                        kept_monolith_id: kept.into(),
+                        unloaded_monolith_id: unloaded.into(),
+                    },
+                ),
+            })
+            .unwrap(),
+        );
+
+        assert_eq!(event.kind.as_deref(), Some("room_conflict_resolved"));
+        for monolith_id in [kept, unloaded] {
+            assert!(event.matches(&StreamFilter {
+                events: vec!["room_conflict_resolved".to_owned()],
+                monoliths: vec![monolith_id.into()],
+                ..Default::default()
+            }));
+        }
+        assert!(!event.matches(&StreamFilter {
+            events: vec!["room_loaded".to_owned()],
+            ..Default::default()
+        }));
+    }
+}
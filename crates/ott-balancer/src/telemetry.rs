@@ -0,0 +1,336 @@
+//! Exports traces over OTLP, and propagates W3C trace context to monoliths.
+
+use std::collections::HashMap;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::trace::{Link, SamplingResult, SpanKind, TraceContextExt, TraceId};
+use opentelemetry::{Context, Key, KeyValue, OrderMap, Value};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, ShouldSample, Tracer};
+use opentelemetry_sdk::{runtime, Resource};
+use ott_balancer_protocol::monolith::TraceContext;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::Subscriber;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtlpConfig {
+    /// The OTLP/gRPC endpoint of the collector to export traces to.
+    pub endpoint: String,
+    /// Reported as the `service.name` resource attribute.
+    pub service_name: String,
+    /// The fraction of new traces to sample, from 0 to 1. Traces continued from an incoming `traceparent` header are sampled at the same ratio, because anyone can send that header.
+    pub sample_ratio: f64,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://localhost:4317".to_owned(),
+            service_name: "ott-balancer".to_owned(),
+            sample_ratio: 1.0,
+        }
+    }
+}
+
+/// Build a layer that exports spans to the OTLP collector in `config`.
+///
+/// Must be called from within a tokio runtime, because spans are exported in batches by a background task.
+pub fn build_otlp_layer<S>(config: &OtlpConfig) -> anyhow::Result<OpenTelemetryLayer<S, Tracer>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(&config.endpoint);
+    let trace_config = opentelemetry_sdk::trace::config()
+        .with_sampler(LocalParentBased(Sampler::TraceIdRatioBased(
+            config.sample_ratio,
+        )))
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            config.service_name.clone(),
+        )]));
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace_config)
+        .install_batch(runtime::Tokio)?;
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Like [`Sampler::ParentBased`], but only follows the sampling decision of parents in this process.
+///
+/// Parents from incoming requests come from untrusted clients, which shouldn't get to decide what is sampled, so traces
+/// that continue them are sampled with the root sampler like new ones.
+#[derive(Debug, Clone)]
+struct LocalParentBased(Sampler);
+
+impl ShouldSample for LocalParentBased {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &OrderMap<Key, Value>,
+        links: &[Link],
+    ) -> SamplingResult {
+        let has_local_parent = parent_context.is_some_and(|cx| {
+            let parent = cx.span().span_context().clone();
+            parent.is_valid() && !parent.is_remote()
+        });
+        if has_local_parent {
+            Sampler::ParentBased(Box::new(self.0.clone())).should_sample(
+                parent_context,
+                trace_id,
+                name,
+                span_kind,
+                attributes,
+                links,
+            )
+        } else {
+            self.0
+                .should_sample(parent_context, trace_id, name, span_kind, attributes, links)
+        }
+    }
+}
+
+/// The trace context of the current span, or `None` if spans aren't being exported.
+pub fn current_trace_context() -> Option<TraceContext> {
+    let cx = tracing::Span::current().context();
+    if !cx.span().span_context().is_valid() {
+        return None;
+    }
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(&cx, &mut carrier);
+    Some(TraceContext {
+        traceparent: carrier.remove("traceparent")?,
+        tracestate: carrier.remove("tracestate").filter(|s| !s.is_empty()),
+    })
+}
+
+/// Make `span` a child of the trace in the `traceparent` header, if the request has one.
+pub fn set_parent_from_headers(span: &tracing::Span, headers: &HeaderMap) {
+    let cx = TraceContextPropagator::new().extract(&HeaderExtractor(headers));
+    if cx.span().span_context().is_valid() {
+        span.set_parent(cx);
+    }
+}
+
+/// Replace the trace context headers with the context of the current span.
+///
+/// Leaves the headers alone if spans aren't being exported.
+pub fn inject_headers(headers: &mut HeaderMap) {
+    let cx = tracing::Span::current().context();
+    if !cx.span().span_context().is_valid() {
+        return;
+    }
+    TraceContextPropagator::new().inject_context(&cx, &mut HeaderInjector(headers));
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{
+        TraceService, TraceServiceServer,
+    };
+    use opentelemetry_proto::tonic::collector::trace::v1::{
+        ExportTraceServiceRequest, ExportTraceServiceResponse,
+    };
+    use opentelemetry_proto::tonic::common::v1::any_value::Value;
+    use tokio::sync::mpsc;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+    use crate::test_util::local_listener;
+
+    /// Stands in for an OTLP collector, passing along every export request it receives.
+    struct MockCollector {
+        requests_tx: mpsc::UnboundedSender<ExportTraceServiceRequest>,
+    }
+
+    #[tonic::async_trait]
+    impl TraceService for MockCollector {
+        async fn export(
+            &self,
+            request: tonic::Request<ExportTraceServiceRequest>,
+        ) -> Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+            let _ = self.requests_tx.send(request.into_inner());
+            Ok(tonic::Response::new(ExportTraceServiceResponse {
+                partial_success: None,
+            }))
+        }
+    }
+
+    async fn start_mock_collector() -> (String, mpsc::UnboundedReceiver<ExportTraceServiceRequest>)
+    {
+        let (listener, addr) = local_listener().await;
+        let endpoint = format!("http://{addr}");
+        let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(TraceServiceServer::new(MockCollector { requests_tx }))
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+        (endpoint, requests_rx)
+    }
+
+    #[test]
+    fn should_not_have_trace_context_without_exporter() {
+        let _guard = tracing_subscriber::registry().set_default();
+        let _span = tracing::info_span!("test").entered();
+        assert_eq!(current_trace_context(), None);
+
+        let mut headers = HeaderMap::new();
+        inject_headers(&mut headers);
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn should_continue_trace_from_headers() {
+        // records spans without exporting them anywhere
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let _guard = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")))
+            .set_default();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            HeaderValue::from_static("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+        );
+
+        let span = tracing::info_span!("test");
+        set_parent_from_headers(&span, &headers);
+        let _span = span.entered();
+
+        let trace = current_trace_context().expect("span should have a trace context");
+        assert!(trace
+            .traceparent
+            .starts_with("00-0af7651916cd43dd8448eb211c80319c-"));
+        assert!(!trace.traceparent.contains("b7ad6b7169203331"));
+
+        inject_headers(&mut headers);
+        assert_eq!(headers["traceparent"], trace.traceparent.as_str());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn should_export_spans_to_collector() {
+        let (endpoint, mut requests_rx) = start_mock_collector().await;
+        let config = OtlpConfig {
+            endpoint,
+            ..Default::default()
+        };
+        let subscriber = tracing_subscriber::registry().with(build_otlp_layer(&config).unwrap());
+
+        let trace = tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("join_client").entered();
+            current_trace_context().expect("span should have a trace context")
+        });
+        tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider)
+            .await
+            .unwrap();
+
+        let request = tokio::time::timeout(Duration::from_secs(5), requests_rx.recv())
+            .await
+            .expect("collector should receive spans")
+            .unwrap();
+        let resource_spans = &request.resource_spans[0];
+        let service_name = resource_spans
+            .resource
+            .as_ref()
+            .unwrap()
+            .attributes
+            .iter()
+            .find(|kv| kv.key == "service.name")
+            .and_then(|kv| kv.value.clone())
+            .and_then(|v| v.value);
+        assert_eq!(
+            service_name,
+            Some(Value::StringValue("ott-balancer".to_owned()))
+        );
+        let span = &resource_spans.scope_spans[0].spans[0];
+        assert_eq!(span.name, "join_client");
+        assert_eq!(
+            trace.traceparent,
+            format!(
+                "00-{}-{}-01",
+                hex_string(&span.trace_id),
+                hex_string(&span.span_id)
+            )
+        );
+    }
+
+    #[test]
+    fn should_only_follow_local_parents_when_sampling() {
+        use opentelemetry::trace::{SamplingDecision, SpanContext, SpanId, TraceFlags, TraceState};
+
+        let sampler = LocalParentBased(Sampler::TraceIdRatioBased(0.0));
+        let trace_id = TraceId::from_hex("0af7651916cd43dd8448eb211c80319c").unwrap();
+        let decide = |parent: Option<SpanContext>| {
+            let cx = parent.map(|parent| Context::new().with_remote_span_context(parent));
+            sampler
+                .should_sample(
+                    cx.as_ref(),
+                    trace_id,
+                    "test",
+                    &SpanKind::Server,
+                    &OrderMap::default(),
+                    &[],
+                )
+                .decision
+        };
+        let parent = |remote: bool| {
+            SpanContext::new(
+                trace_id,
+                SpanId::from_hex("b7ad6b7169203331").unwrap(),
+                TraceFlags::SAMPLED,
+                remote,
+                TraceState::default(),
+            )
+        };
+
+        assert_eq!(decide(None), SamplingDecision::Drop);
+        assert_eq!(decide(Some(parent(true))), SamplingDecision::Drop);
+        assert_eq!(
+            decide(Some(parent(false))),
+            SamplingDecision::RecordAndSample
+        );
+    }
+
+    fn hex_string(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
@@ -0,0 +1,12 @@
+//! Stand-in servers for tests.
+
+use std::net::SocketAddr;
+
+use tokio::net::TcpListener;
+
+/// Bind a listener on a random local port for a stand-in server.
+pub async fn local_listener() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    (listener, addr)
+}
@@ -0,0 +1,392 @@
+//! TLS termination for the balancer's main listener.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info};
+
+/// How long a client gets to finish the TLS handshake before the connection is dropped.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// The PEM encoded certificate chain to use when the client doesn't request a server name that matches any of the `sni` certificates.
+    pub cert_path: PathBuf,
+    /// The PEM encoded private key for `cert_path`.
+    pub key_path: PathBuf,
+    /// Additional certificates, selected by the server name that the client requests.
+    #[serde(default)]
+    pub sni: Vec<SniCertConfig>,
+    /// How often to check the certificate and key files for changes.
+    #[serde(default = "default_reload_interval")]
+    #[serde(with = "humantime_serde")]
+    #[schemars(with = "String")]
+    pub reload_interval: Duration,
+}
+
+fn default_reload_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SniCertConfig {
+    pub server_name: String,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    fn paths(&self) -> impl Iterator<Item = &Path> {
+        [self.cert_path.as_path(), self.key_path.as_path()]
+            .into_iter()
+            .chain(
+                self.sni
+                    .iter()
+                    .flat_map(|sni| [sni.cert_path.as_path(), sni.key_path.as_path()]),
+            )
+    }
+}
+
+/// The certificates that are currently being served.
+#[derive(Debug)]
+struct LoadedCerts {
+    default: Arc<CertifiedKey>,
+    by_server_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl LoadedCerts {
+    fn load(config: &TlsConfig) -> anyhow::Result<Self> {
+        let default = load_certified_key(&config.cert_path, &config.key_path)?;
+        let mut by_server_name = HashMap::new();
+        for sni in &config.sni {
+            let key = load_certified_key(&sni.cert_path, &sni.key_path)?;
+            by_server_name.insert(sni.server_name.to_lowercase(), key);
+        }
+        Ok(Self {
+            default,
+            by_server_name,
+        })
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> anyhow::Result<Arc<CertifiedKey>> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+    let key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .with_context(|| format!("unsupported private key {}", key_path.display()))?;
+
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+/// Load a PEM encoded certificate chain.
+pub(crate) fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let file =
+        std::fs::read(path).with_context(|| format!("reading certificate {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut file.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("parsing certificate {}", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+/// Load a PEM encoded private key.
+pub(crate) fn load_private_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let file =
+        std::fs::read(path).with_context(|| format!("reading private key {}", path.display()))?;
+    rustls_pemfile::private_key(&mut file.as_slice())
+        .with_context(|| format!("parsing private key {}", path.display()))?
+        .ok_or_else(|| anyhow::anyhow!("no private key found in {}", path.display()))
+}
+
+/// Picks a certificate based on SNI, and allows the certificates to be swapped out while the server is running.
+#[derive(Debug)]
+pub struct ReloadingCertResolver {
+    certs: RwLock<Arc<LoadedCerts>>,
+}
+
+impl ReloadingCertResolver {
+    pub fn new(config: &TlsConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            certs: RwLock::new(Arc::new(LoadedCerts::load(config)?)),
+        })
+    }
+
+    /// Reload all certificates from disk. If any of them fail to load, the current certificates are kept.
+    pub fn reload(&self, config: &TlsConfig) -> anyhow::Result<()> {
+        let certs = LoadedCerts::load(config)?;
+        *self.certs.write().unwrap() = Arc::new(certs);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().unwrap().clone();
+        let by_name = client_hello
+            .server_name()
+            .and_then(|name| certs.by_server_name.get(&name.to_lowercase()));
+        Some(by_name.unwrap_or(&certs.default).clone())
+    }
+}
+
+/// Build a [`TlsAcceptor`] that serves HTTP/2 and HTTP/1.1, negotiated via ALPN.
+pub fn build_acceptor(resolver: Arc<ReloadingCertResolver>) -> TlsAcceptor {
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    TlsAcceptor::from(Arc::new(server_config))
+}
+
+/// Watch the certificate and key files, and reload them into `resolver` when they change.
+pub fn start_reload_task(
+    config: TlsConfig,
+    resolver: Arc<ReloadingCertResolver>,
+) -> anyhow::Result<tokio::task::JoinHandle<()>> {
+    Ok(tokio::task::Builder::new()
+        .name("tls reloader")
+        .spawn(async move {
+            let mut last_modified = modified_times(&config).await;
+            loop {
+                tokio::time::sleep(config.reload_interval).await;
+                let modified = modified_times(&config).await;
+                if modified == last_modified {
+                    continue;
+                }
+                debug!("TLS certificate files changed, reloading");
+                match resolver.reload(&config) {
+                    Ok(()) => {
+                        info!("Reloaded TLS certificates");
+                        last_modified = modified;
+                    }
+                    Err(err) => {
+                        error!(
+                            "Failed to reload TLS certificates, keeping the current ones: {:?}",
+                            err
+                        );
+                    }
+                }
+            }
+        })?)
+}
+
+async fn modified_times(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    let mut times = vec![];
+    for path in config.paths() {
+        let modified = tokio::fs::metadata(path)
+            .await
+            .and_then(|meta| meta.modified())
+            .ok();
+        times.push(modified);
+    }
+    times
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TestCert {
+        dir: PathBuf,
+    }
+
+    impl TestCert {
+        fn generate(name: &str, server_names: &[&str]) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "ott-balancer-tls-{}-{}",
+                name,
+                uuid::Uuid::new_v4()
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            let cert = TestCert { dir };
+            cert.regenerate(server_names);
+            cert
+        }
+
+        fn regenerate(&self, server_names: &[&str]) {
+            let cert = rcgen::generate_simple_self_signed(
+                server_names
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap();
+            std::fs::write(self.cert_path(), cert.serialize_pem().unwrap()).unwrap();
+            std::fs::write(self.key_path(), cert.serialize_private_key_pem()).unwrap();
+        }
+
+        fn cert_path(&self) -> PathBuf {
+            self.dir.join("cert.pem")
+        }
+
+        fn key_path(&self) -> PathBuf {
+            self.dir.join("key.pem")
+        }
+
+        fn der(&self) -> Vec<u8> {
+            let pem = std::fs::read(self.cert_path()).unwrap();
+            let cert = rustls_pemfile::certs(&mut pem.as_slice())
+                .next()
+                .unwrap()
+                .unwrap();
+            cert.to_vec()
+        }
+    }
+
+    impl Drop for TestCert {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Resolve the certificate for a real ClientHello, like the one a client sends during the handshake.
+    fn served_cert(resolver: &ReloadingCertResolver, server_name: Option<&str>) -> Vec<u8> {
+        let mut client_config = rustls::ClientConfig::builder()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        client_config.enable_sni = server_name.is_some();
+        let name =
+            rustls::pki_types::ServerName::try_from(server_name.unwrap_or("localhost").to_owned())
+                .unwrap();
+        let mut client = rustls::ClientConnection::new(Arc::new(client_config), name).unwrap();
+        let mut client_hello = vec![];
+        client.write_tls(&mut client_hello).unwrap();
+
+        let mut acceptor = rustls::server::Acceptor::default();
+        acceptor.read_tls(&mut client_hello.as_slice()).unwrap();
+        let accepted = acceptor.accept().unwrap().expect("incomplete client hello");
+        let key = resolver
+            .resolve(accepted.client_hello())
+            .expect("no certificate resolved");
+        key.cert[0].to_vec()
+    }
+
+    #[test]
+    fn should_select_certificate_by_server_name() {
+        let default = TestCert::generate("default", &["localhost"]);
+        let other = TestCert::generate("other", &["other.example.com"]);
+        let config = TlsConfig {
+            cert_path: default.cert_path(),
+            key_path: default.key_path(),
+            sni: vec![SniCertConfig {
+                server_name: "Other.Example.com".to_owned(),
+                cert_path: other.cert_path(),
+                key_path: other.key_path(),
+            }],
+            reload_interval: default_reload_interval(),
+        };
+
+        let resolver = ReloadingCertResolver::new(&config).expect("failed to load certs");
+        assert_eq!(served_cert(&resolver, None), default.der());
+        assert_eq!(
+            served_cert(&resolver, Some("other.example.com")),
+            other.der()
+        );
+        assert_eq!(
+            served_cert(&resolver, Some("OTHER.example.COM")),
+            other.der()
+        );
+        assert_eq!(
+            served_cert(&resolver, Some("unknown.example.com")),
+            default.der()
+        );
+    }
+
+    #[test]
+    fn should_keep_current_certificates_when_reload_fails() {
+        let cert = TestCert::generate("reload", &["localhost"]);
+        let config = TlsConfig {
+            cert_path: cert.cert_path(),
+            key_path: cert.key_path(),
+            sni: vec![],
+            reload_interval: default_reload_interval(),
+        };
+        let resolver = ReloadingCertResolver::new(&config).expect("failed to load certs");
+        let original = cert.der();
+
+        std::fs::write(cert.key_path(), "not a key").unwrap();
+        resolver
+            .reload(&config)
+            .expect_err("reload should fail with an invalid key");
+        assert_eq!(served_cert(&resolver, None), original);
+
+        cert.regenerate(&["localhost"]);
+        resolver.reload(&config).expect("failed to reload certs");
+        assert_ne!(served_cert(&resolver, None), original);
+        assert_eq!(served_cert(&resolver, None), cert.der());
+    }
+
+    #[tokio::test]
+    async fn should_negotiate_http2_with_sni_certificate() {
+        let default = TestCert::generate("alpn-default", &["localhost"]);
+        let other = TestCert::generate("alpn-other", &["other.example.com"]);
+        let config = TlsConfig {
+            cert_path: default.cert_path(),
+            key_path: default.key_path(),
+            sni: vec![SniCertConfig {
+                server_name: "other.example.com".to_owned(),
+                cert_path: other.cert_path(),
+                key_path: other.key_path(),
+            }],
+            reload_interval: default_reload_interval(),
+        };
+        let resolver = Arc::new(ReloadingCertResolver::new(&config).expect("failed to load certs"));
+        let acceptor = build_acceptor(resolver);
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(other.der().into()).unwrap();
+        let mut client_config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![b"h2".to_vec()];
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(async move { acceptor.accept(server_io).await });
+        let server_name = rustls::pki_types::ServerName::try_from("other.example.com").unwrap();
+        let client = connector
+            .connect(server_name, client_io)
+            .await
+            .expect("tls handshake failed");
+        let server = server.await.unwrap().expect("tls accept failed");
+
+        let (_, client_conn) = client.get_ref();
+        assert_eq!(client_conn.alpn_protocol(), Some(b"h2".as_slice()));
+        let (_, server_conn) = server.get_ref();
+        assert_eq!(server_conn.server_name(), Some("other.example.com"));
+    }
+
+    #[test]
+    fn parse_tls_config() {
+        let config = serde_json::json!({
+            "cert_path": "/etc/ott/cert.pem",
+            "key_path": "/etc/ott/key.pem",
+            "sni": [
+                {
+                    "server_name": "balancer.example.com",
+                    "cert_path": "/etc/ott/balancer.pem",
+                    "key_path": "/etc/ott/balancer.key",
+                }
+            ],
+            "reload_interval": "1m",
+        });
+
+        let config: TlsConfig = serde_json::from_value(config).expect("failed to parse tls config");
+        assert_eq!(config.sni.len(), 1);
+        assert_eq!(config.reload_interval, Duration::from_secs(60));
+        assert_eq!(config.paths().count(), 4);
+    }
+}
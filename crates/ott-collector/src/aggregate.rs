@@ -0,0 +1,260 @@
+//! Merges the views of all balancers into a single view of the system.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use ott_balancer_protocol::{BalancerId, ClientId, MonolithId, Region, RoomName};
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use typeshare::typeshare;
+
+use crate::auth::Authenticated;
+use crate::redact::Redactor;
+use crate::SystemState;
+
+/// The state of the whole system, where each monolith and room only shows up once.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[typeshare]
+pub struct AggregatedState {
+    pub balancers: Vec<BalancerId>,
+    pub monoliths: Vec<AggregatedMonolith>,
+    /// Clients connected through each edge region, across all balancers.
+    pub clients_by_edge_region: BTreeMap<Region, u32>,
+    /// Rooms that balancers disagree about the location of.
+    pub conflicts: Vec<RoomConflict>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[typeshare]
+pub struct AggregatedMonolith {
+    pub id: MonolithId,
+    pub region: Region,
+    pub rooms: Vec<AggregatedRoom>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[typeshare]
+pub struct AggregatedRoom {
+    pub name: RoomName,
+    /// The clients in this room on every balancer.
+    pub clients: Vec<AggregatedClient>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[typeshare]
+pub struct AggregatedClient {
+    pub id: ClientId,
+    pub edge_region: Region,
+    /// The balancer that the client is connected to.
+    pub balancer_id: BalancerId,
+}
+
+/// A room that different balancers think is on different monoliths.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[typeshare]
+pub struct RoomConflict {
+    pub room: RoomName,
+    pub locations: Vec<RoomLocation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[typeshare]
+pub struct RoomLocation {
+    pub monolith_id: MonolithId,
+    /// The balancers that think the room is on this monolith.
+    pub balancers: Vec<BalancerId>,
+}
+
+impl AggregatedState {
+    pub fn new(state: &SystemState) -> Self {
+        let mut monoliths: BTreeMap<MonolithId, (Region, BTreeMap<RoomName, AggregatedRoom>)> =
+            BTreeMap::new();
+        let mut clients = BTreeSet::new();
+        let mut clients_by_edge_region = BTreeMap::new();
+
+        for balancer in &state.0 {
+            for monolith in &balancer.monoliths {
+                let (_, rooms) = monoliths
+                    .entry(monolith.id)
+                    .or_insert_with(|| (monolith.region.clone(), BTreeMap::new()));
+                for room in &monolith.rooms {
+                    let aggregated =
+                        rooms
+                            .entry(room.name.clone())
+                            .or_insert_with(|| AggregatedRoom {
+                                name: room.name.clone(),
+                                clients: vec![],
+                            });
+                    for client in &room.clients {
+                        if !clients.insert(client.id) {
+                            continue;
+                        }
+                        *clients_by_edge_region
+                            .entry(client.edge_region.clone())
+                            .or_default() += 1;
+                        aggregated.clients.push(AggregatedClient {
+                            id: client.id,
+                            edge_region: client.edge_region.clone(),
+                            balancer_id: balancer.id,
+                        });
+                    }
+                }
+            }
+        }
+
+        let conflicts = find_room_conflicts(state);
+
+        Self {
+            balancers: state.0.iter().map(|b| b.id).collect(),
+            monoliths: monoliths
+                .into_iter()
+                .map(|(id, (region, rooms))| AggregatedMonolith {
+                    id,
+                    region,
+                    rooms: rooms.into_values().collect(),
+                })
+                .collect(),
+            clients_by_edge_region,
+            conflicts,
+        }
+    }
+}
+
+/// Find rooms that different balancers think are on different monoliths.
+pub fn find_room_conflicts(state: &SystemState) -> Vec<RoomConflict> {
+    let mut locations: BTreeMap<RoomName, BTreeMap<MonolithId, BTreeSet<BalancerId>>> =
+        BTreeMap::new();
+    for balancer in &state.0 {
+        for monolith in &balancer.monoliths {
+            for room in &monolith.rooms {
+                locations
+                    .entry(room.name.clone())
+                    .or_default()
+                    .entry(monolith.id)
+                    .or_default()
+                    .insert(balancer.id);
+            }
+        }
+    }
+
+    locations
+        .into_iter()
+        .filter(|(_, by_monolith)| by_monolith.len() > 1)
+        .map(|(room, by_monolith)| RoomConflict {
+            room,
+            locations: by_monolith
+                .into_iter()
+                .map(|(monolith_id, balancers)| RoomLocation {
+                    monolith_id,
+                    balancers: balancers.into_iter().collect(),
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+/// Serve the state of all balancers, merged together.
+#[get("/state/aggregate")]
+pub async fn serve_aggregate(
+    _auth: Authenticated,
+    state: &State<Arc<Mutex<SystemState>>>,
+    redactor: &State<Redactor>,
+) -> Json<AggregatedState> {
+    let state = redactor.system_state(state.lock().await.clone());
+    Json(AggregatedState::new(&state))
+}
+
+#[cfg(test)]
+mod tests {
+    use ott_balancer_protocol::collector::{BalancerState, ClientState, MonolithState, RoomState};
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn id<T: From<Uuid>>(n: u128) -> T {
+        Uuid::from_u128(n).into()
+    }
+
+    fn balancer(n: u128, monoliths: Vec<(u128, &str, Vec<u128>)>) -> BalancerState {
+        BalancerState {
+            id: id(n),
+            region: "ord".into(),
+            monoliths: monoliths
+                .into_iter()
+                .map(|(monolith, room, clients)| MonolithState {
+                    id: id(monolith),
+                    region: "ord".into(),
+                    rooms: vec![RoomState {
+                        name: room.into(),
+                        clients: clients
+                            .into_iter()
+                            .map(|c| ClientState {
+                                id: id(c),
+                                edge_region: if c % 2 == 0 { "ord" } else { "iad" }.into(),
+                            })
+                            .collect(),
+                    }],
+                })
+                .collect(),
+            collected_at: None,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn should_merge_rooms_and_clients_across_balancers() {
+        let state = SystemState(vec![
+            balancer(1, vec![(10, "foo", vec![100, 101])]),
+            balancer(2, vec![(10, "foo", vec![102])]),
+        ]);
+        let aggregated = AggregatedState::new(&state);
+
+        assert_eq!(aggregated.balancers, vec![id(1), id(2)]);
+        assert_eq!(aggregated.monoliths.len(), 1);
+        let rooms = &aggregated.monoliths[0].rooms;
+        assert_eq!(rooms.len(), 1);
+        let clients: Vec<_> = rooms[0]
+            .clients
+            .iter()
+            .map(|c| (c.id, c.balancer_id))
+            .collect();
+        assert_eq!(
+            clients,
+            vec![(id(100), id(1)), (id(101), id(1)), (id(102), id(2))]
+        );
+        assert_eq!(
+            aggregated.clients_by_edge_region,
+            BTreeMap::from([("iad".into(), 1), ("ord".into(), 2)])
+        );
+        assert!(aggregated.conflicts.is_empty());
+    }
+
+    #[test]
+    fn should_report_rooms_on_different_monoliths() {
+        let state = SystemState(vec![
+            balancer(1, vec![(10, "foo", vec![]), (11, "bar", vec![])]),
+            balancer(2, vec![(11, "Foo", vec![]), (11, "bar", vec![])]),
+            balancer(3, vec![(10, "foo", vec![])]),
+        ]);
+        let aggregated = AggregatedState::new(&state);
+
+        assert_eq!(
+            aggregated.conflicts,
+            vec![RoomConflict {
+                room: "foo".into(),
+                locations: vec![
+                    RoomLocation {
+                        monolith_id: id(10),
+                        balancers: vec![id(1), id(3)],
+                    },
+                    RoomLocation {
+                        monolith_id: id(11),
+                        balancers: vec![id(2)],
+                    },
+                ],
+            }]
+        );
+    }
+}
@@ -0,0 +1,444 @@
+//! Compares the views of all balancers to find places where they disagree.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use ott_balancer_protocol::{BalancerId, ClientId, MonolithId, Region};
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+use typeshare::typeshare;
+
+use crate::aggregate::{find_room_conflicts, RoomConflict};
+use crate::auth::Authenticated;
+use crate::redact::Redactor;
+use crate::SystemState;
+
+pub static CURRENT_ALERTS: Lazy<Arc<Mutex<Vec<Alert>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(vec![])));
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AlertsConfig {
+    /// A URL to POST an [`AlertNotification`] to whenever alerts start or stop firing.
+    pub webhook_url: Option<String>,
+    #[serde(with = "humantime_serde")]
+    pub webhook_timeout: Duration,
+    /// How many collections in a row an alert has to be detected in before it fires. Balancers pick up monoliths
+    /// one at a time during deploys, so a single collection can disagree without anything being wrong.
+    pub fire_after: u32,
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        Self {
+            webhook_url: None,
+            webhook_timeout: Duration::from_secs(5),
+            fire_after: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+#[typeshare]
+pub enum Alert {
+    RoomConflict(RoomConflict),
+    OrphanedClient(OrphanedClient),
+    PartialMonolith(PartialMonolith),
+}
+
+impl Alert {
+    /// Identifies the problem that this alert is about, so that it is only notified once while it lasts.
+    fn key(&self) -> String {
+        match self {
+            Alert::RoomConflict(a) => format!("room_conflict:{}", a.room),
+            Alert::OrphanedClient(a) => format!("orphaned_client:{}", a.client_id),
+            Alert::PartialMonolith(a) => format!("partial_monolith:{}", a.monolith_id),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Alert::RoomConflict(_) => "room_conflict",
+            Alert::OrphanedClient(_) => "orphaned_client",
+            Alert::PartialMonolith(_) => "partial_monolith",
+        }
+    }
+}
+
+/// A client that more than one balancer thinks is connected to it.
+///
+/// Clients only ever connect to one balancer, so the others are holding on to a client that has already left.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[typeshare]
+pub struct OrphanedClient {
+    pub client_id: ClientId,
+    pub balancers: Vec<BalancerId>,
+}
+
+/// A monolith that only some of the balancers are connected to.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[typeshare]
+pub struct PartialMonolith {
+    pub monolith_id: MonolithId,
+    pub region: Region,
+    pub seen_by: Vec<BalancerId>,
+    pub missing_from: Vec<BalancerId>,
+}
+
+/// Sent to the webhook when alerts start or stop firing.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[typeshare]
+pub struct AlertNotification {
+    /// Alerts that started firing.
+    pub firing: Vec<Alert>,
+    /// Alerts that stopped firing.
+    pub resolved: Vec<Alert>,
+}
+
+pub fn detect_alerts(state: &SystemState) -> Vec<Alert> {
+    // The balancer behind a stale state may have caught up since, so comparing it to the others would raise false alarms.
+    let state = &SystemState(
+        state
+            .0
+            .iter()
+            .filter(|b| b.error.is_none())
+            .cloned()
+            .collect(),
+    );
+    let mut alerts: Vec<Alert> = find_room_conflicts(state)
+        .into_iter()
+        .map(Alert::RoomConflict)
+        .collect();
+
+    let mut client_balancers: BTreeMap<ClientId, BTreeSet<BalancerId>> = BTreeMap::new();
+    let mut monolith_balancers: BTreeMap<MonolithId, (Region, BTreeSet<BalancerId>)> =
+        BTreeMap::new();
+    for balancer in &state.0 {
+        for monolith in &balancer.monoliths {
+            monolith_balancers
+                .entry(monolith.id)
+                .or_insert_with(|| (monolith.region.clone(), BTreeSet::new()))
+                .1
+                .insert(balancer.id);
+            for client in monolith.rooms.iter().flat_map(|r| &r.clients) {
+                client_balancers
+                    .entry(client.id)
+                    .or_default()
+                    .insert(balancer.id);
+            }
+        }
+    }
+
+    alerts.extend(
+        client_balancers
+            .into_iter()
+            .filter(|(_, balancers)| balancers.len() > 1)
+            .map(|(client_id, balancers)| {
+                Alert::OrphanedClient(OrphanedClient {
+                    client_id,
+                    balancers: balancers.into_iter().collect(),
+                })
+            }),
+    );
+
+    let all_balancers: BTreeSet<BalancerId> = state.0.iter().map(|b| b.id).collect();
+    alerts.extend(
+        monolith_balancers
+            .into_iter()
+            .filter(|(_, (_, seen_by))| seen_by.len() < all_balancers.len())
+            .map(|(monolith_id, (region, seen_by))| {
+                Alert::PartialMonolith(PartialMonolith {
+                    monolith_id,
+                    region,
+                    missing_from: all_balancers.difference(&seen_by).copied().collect(),
+                    seen_by: seen_by.into_iter().collect(),
+                })
+            }),
+    );
+
+    alerts
+}
+
+/// Keeps track of which alerts are firing, and notifies the webhook when that changes.
+pub struct AlertMonitor {
+    config: AlertsConfig,
+    firing: BTreeMap<String, Alert>,
+    /// How many collections in a row each detected alert has been seen in.
+    detected: BTreeMap<String, u32>,
+    http_client: reqwest::Client,
+}
+
+impl AlertMonitor {
+    pub fn new(config: AlertsConfig) -> Self {
+        Self {
+            config,
+            firing: BTreeMap::new(),
+            detected: BTreeMap::new(),
+            http_client: reqwest::Client::new(),
+        }
+    }
+
+    /// Check `state` for alerts, and publish the ones that have lasted for [`AlertsConfig::fire_after`] collections.
+    pub async fn update(&mut self, state: &SystemState) {
+        let alerts = self.debounce(detect_alerts(state));
+
+        GAUGE_ALERTS.reset();
+        for alert in &alerts {
+            GAUGE_ALERTS.with_label_values(&[alert.kind()]).inc();
+        }
+
+        let notification = self.diff(&alerts);
+        *CURRENT_ALERTS.lock().await = alerts;
+        if notification.firing.is_empty() && notification.resolved.is_empty() {
+            return;
+        }
+        for alert in &notification.firing {
+            warn!("Alert firing: {:?}", alert);
+        }
+        if let Some(url) = &self.config.webhook_url {
+            let request = self
+                .http_client
+                .post(url)
+                .timeout(self.config.webhook_timeout)
+                .json(&notification);
+            tokio::spawn(async move {
+                match request.send().await.and_then(|r| r.error_for_status()) {
+                    Ok(_) => {}
+                    Err(err) => error!("Failed to send alert webhook: {}", err),
+                }
+            });
+        }
+    }
+
+    /// Keep only the alerts that have been detected in enough collections in a row.
+    fn debounce(&mut self, alerts: Vec<Alert>) -> Vec<Alert> {
+        let detected = alerts
+            .iter()
+            .map(|alert| {
+                let key = alert.key();
+                let count = self.detected.get(&key).copied().unwrap_or_default() + 1;
+                (key, count)
+            })
+            .collect();
+        self.detected = detected;
+        alerts
+            .into_iter()
+            .filter(|alert| self.detected[&alert.key()] >= self.config.fire_after)
+            .collect()
+    }
+
+    /// Update which alerts are firing, and return the ones that started or stopped.
+    fn diff(&mut self, alerts: &[Alert]) -> AlertNotification {
+        let firing: BTreeMap<String, Alert> = alerts.iter().map(|a| (a.key(), a.clone())).collect();
+        let resolved = self
+            .firing
+            .iter()
+            .filter(|(key, _)| !firing.contains_key(*key))
+            .map(|(_, alert)| alert.clone())
+            .collect();
+        let started = alerts
+            .iter()
+            .filter(|alert| !self.firing.contains_key(&alert.key()))
+            .cloned()
+            .collect();
+        self.firing = firing;
+        AlertNotification {
+            firing: started,
+            resolved,
+        }
+    }
+}
+
+/// Serve the alerts that are currently firing.
+#[get("/alerts")]
+pub async fn serve_alerts(
+    _auth: Authenticated,
+    alerts: &State<Arc<Mutex<Vec<Alert>>>>,
+    redactor: &State<Redactor>,
+) -> Json<Vec<Alert>> {
+    let alerts = alerts.lock().await.clone();
+    Json(redactor.alerts(alerts))
+}
+
+static GAUGE_ALERTS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "collector_alerts",
+        "Number of alerts that are currently firing",
+        &["type"]
+    )
+    .unwrap()
+});
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Full};
+    use ott_balancer_protocol::collector::{BalancerState, ClientState, MonolithState, RoomState};
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::test_util::serve_http;
+
+    fn id<T: From<Uuid>>(n: u128) -> T {
+        Uuid::from_u128(n).into()
+    }
+
+    fn balancer(n: u128, monoliths: &[(u128, &str, &[u128])]) -> BalancerState {
+        BalancerState {
+            id: id(n),
+            region: "ord".into(),
+            monoliths: monoliths
+                .iter()
+                .map(|(monolith, room, clients)| MonolithState {
+                    id: id(*monolith),
+                    region: "ord".into(),
+                    rooms: vec![RoomState {
+                        name: (*room).into(),
+                        clients: clients
+                            .iter()
+                            .map(|c| ClientState {
+                                id: id(*c),
+                                edge_region: "ord".into(),
+                            })
+                            .collect(),
+                    }],
+                })
+                .collect(),
+            collected_at: None,
+            error: None,
+        }
+    }
+
+    /// Stands in for a webhook receiver, passing along the body of every request it receives.
+    async fn start_webhook_receiver() -> (String, mpsc::UnboundedReceiver<serde_json::Value>) {
+        let (body_tx, body_rx) = mpsc::unbounded_channel();
+        let addr = serve_http(move |req| {
+            let body_tx = body_tx.clone();
+            async move {
+                let body = req.into_body().collect().await.unwrap().to_bytes();
+                let _ = body_tx.send(serde_json::from_slice(&body).unwrap());
+                hyper::Response::new(Full::new(Bytes::new()))
+            }
+        })
+        .await;
+        (format!("http://{addr}/hook"), body_rx)
+    }
+
+    #[test]
+    fn should_detect_orphaned_clients_and_partial_monoliths() {
+        let state = SystemState(vec![
+            balancer(1, &[(10, "foo", &[100, 101]), (11, "bar", &[])]),
+            balancer(2, &[(10, "foo", &[101])]),
+        ]);
+
+        assert_eq!(
+            detect_alerts(&state),
+            vec![
+                Alert::OrphanedClient(OrphanedClient {
+                    client_id: id(101),
+                    balancers: vec![id(1), id(2)],
+                }),
+                Alert::PartialMonolith(PartialMonolith {
+                    monolith_id: id(11),
+                    region: "ord".into(),
+                    seen_by: vec![id(1)],
+                    missing_from: vec![id(2)],
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_not_alert_when_balancers_agree() {
+        let state = SystemState(vec![
+            balancer(1, &[(10, "foo", &[100])]),
+            balancer(2, &[(10, "foo", &[101])]),
+        ]);
+        assert_eq!(detect_alerts(&state), vec![]);
+    }
+
+    #[test]
+    fn should_ignore_stale_balancers() {
+        let mut stale = balancer(2, &[(11, "bar", &[100])]);
+        stale.error = Some("timed out".into());
+        let state = SystemState(vec![balancer(1, &[(10, "foo", &[100])]), stale]);
+        assert_eq!(detect_alerts(&state), vec![]);
+    }
+
+    #[tokio::test]
+    async fn should_notify_webhook_when_alerts_change() {
+        let (url, mut body_rx) = start_webhook_receiver().await;
+        let mut monitor = AlertMonitor::new(AlertsConfig {
+            webhook_url: Some(url),
+            fire_after: 1,
+            ..Default::default()
+        });
+        let conflicted = SystemState(vec![
+            balancer(1, &[(10, "foo", &[])]),
+            balancer(2, &[(11, "foo", &[]), (10, "bar", &[])]),
+        ]);
+
+        monitor.update(&conflicted).await;
+        let body = tokio::time::timeout(Duration::from_secs(5), body_rx.recv())
+            .await
+            .expect("webhook should be notified")
+            .unwrap();
+        let firing: Vec<_> = body["firing"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|a| a["type"].as_str().unwrap())
+            .collect();
+        assert_eq!(firing, vec!["room_conflict", "partial_monolith"]);
+        assert_eq!(body["resolved"], serde_json::json!([]));
+
+        // nothing changed, so the webhook shouldn't be notified again
+        monitor.update(&conflicted).await;
+        monitor
+            .update(&SystemState(vec![
+                balancer(1, &[(10, "foo", &[])]),
+                balancer(2, &[(10, "foo", &[])]),
+            ]))
+            .await;
+        let body = tokio::time::timeout(Duration::from_secs(5), body_rx.recv())
+            .await
+            .expect("webhook should be notified")
+            .unwrap();
+        assert_eq!(body["firing"], serde_json::json!([]));
+        assert_eq!(body["resolved"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn should_only_fire_after_consecutive_detections() {
+        let mut monitor = AlertMonitor::new(AlertsConfig {
+            fire_after: 3,
+            ..Default::default()
+        });
+        let partial = SystemState(vec![
+            balancer(1, &[(10, "foo", &[]), (11, "bar", &[])]),
+            balancer(2, &[(10, "foo", &[])]),
+        ]);
+        let agreeing = SystemState(vec![
+            balancer(1, &[(10, "foo", &[])]),
+            balancer(2, &[(10, "foo", &[])]),
+        ]);
+
+        let mut fired = |state: &SystemState| monitor.debounce(detect_alerts(state)).len();
+        assert_eq!(fired(&partial), 0);
+        assert_eq!(fired(&partial), 0);
+        // the monolith caught up before the alert fired, so counting starts over
+        assert_eq!(fired(&agreeing), 0);
+        assert_eq!(fired(&partial), 0);
+        assert_eq!(fired(&partial), 0);
+        assert_eq!(fired(&partial), 1);
+        assert_eq!(fired(&agreeing), 0);
+    }
+}
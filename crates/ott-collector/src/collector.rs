@@ -1,43 +1,165 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{sync::Arc, time::Duration};
 
 use once_cell::sync::Lazy;
-use ott_balancer_protocol::{collector::BalancerState, RoomName};
+use ott_balancer_protocol::collector::{BalancerEvent, BalancerState, StateSync};
+use ott_balancer_protocol::{BalancerId, RoomName};
 use ott_common::discovery::{ConnectionConfig, ServiceDiscoveryMsg};
+use rocket::futures::future::join_all;
 use rocket::futures::StreamExt;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 use tracing::{error, warn};
 use tungstenite::handshake::client::{generate_key, Request};
+use tungstenite::Message;
+use typeshare::typeshare;
 use uuid::Uuid;
 
+use crate::alerts::AlertMonitor;
+use crate::config::CollectorConfig;
+use crate::history::{now_millis, History, HistorySample};
+use crate::metrics;
 use crate::SystemState;
 
 pub static CURRENT_STATE: Lazy<Arc<Mutex<SystemState>>> =
     Lazy::new(|| Arc::new(Mutex::new(SystemState(vec![]))));
 
+/// The health of the event stream from each balancer, by [`metrics::balancer_label`].
+pub static STREAM_HEALTH: Lazy<Arc<Mutex<BTreeMap<String, TrackedStream>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(BTreeMap::new())));
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StreamsConfig {
+    /// How long to wait before reconnecting to a balancer's event stream the first time it fails.
+    #[serde(with = "humantime_serde")]
+    pub initial_backoff: Duration,
+    /// The wait doubles every time the stream fails to connect, up to this much.
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Duration,
+}
+
+impl Default for StreamsConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[typeshare]
+pub struct StreamHealth {
+    pub balancer: String,
+    pub connected: bool,
+    /// When the stream connected, in milliseconds since the Unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[typeshare(serialized_as = "Option<number>")]
+    pub connected_since: Option<u64>,
+    /// How many times the stream has ended and been restarted.
+    pub reconnects: u32,
+    /// Why the stream last ended.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// Events that were passed along to the event bus.
+    #[typeshare(serialized_as = "number")]
+    pub events: u64,
+    /// Messages that weren't recognized, and were dropped.
+    #[typeshare(serialized_as = "number")]
+    pub parse_failures: u64,
+}
+
+/// Counts what came through a stream. These change with every message, so they are atomics that the stream can update
+/// without locking [`STREAM_HEALTH`].
+#[derive(Debug, Default)]
+pub struct StreamCounters {
+    events: AtomicU64,
+    parse_failures: AtomicU64,
+}
+
+/// A stream in [`STREAM_HEALTH`]. The counters in `health` are filled in from `counters` by [`TrackedStream::health`].
+#[derive(Debug, Default)]
+pub struct TrackedStream {
+    health: StreamHealth,
+    counters: Arc<StreamCounters>,
+}
+
+impl TrackedStream {
+    pub fn health(&self) -> StreamHealth {
+        StreamHealth {
+            events: self.counters.events.load(Ordering::Relaxed),
+            parse_failures: self.counters.parse_failures.load(Ordering::Relaxed),
+            ..self.health.clone()
+        }
+    }
+}
+
+/// How long to wait between attempts to reconnect a stream, doubling after every attempt.
+#[derive(Debug)]
+struct Backoff {
+    next: Duration,
+    initial: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    fn new(config: &StreamsConfig) -> Self {
+        Self {
+            next: config.initial_backoff,
+            initial: config.initial_backoff,
+            max: config.max_backoff,
+        }
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
 pub struct Collector {
     discovery_rx: tokio::sync::mpsc::Receiver<ServiceDiscoveryMsg>,
     events_tx: tokio::sync::mpsc::Sender<String>,
     interval: tokio::time::Duration,
+    balancer_timeout: Duration,
     balancers: Vec<ConnectionConfig>,
-    stream_tasks: HashMap<ConnectionConfig, tokio::task::JoinHandle<anyhow::Result<()>>>,
+    /// The last state that was collected from each balancer.
+    last_known: HashMap<ConnectionConfig, BalancerState>,
+    stream_tasks: HashMap<ConnectionConfig, tokio::task::JoinHandle<()>>,
+    streams: StreamsConfig,
     balancer_api_key: String,
+    history: Arc<Mutex<History>>,
+    alerts: AlertMonitor,
 }
 
 impl Collector {
     pub fn new(
         discovery_rx: tokio::sync::mpsc::Receiver<ServiceDiscoveryMsg>,
         events_tx: tokio::sync::mpsc::Sender<String>,
-        interval: tokio::time::Duration,
-        balancer_api_key: impl ToOwned<Owned = String>,
+        config: &CollectorConfig,
+        history: Arc<Mutex<History>>,
     ) -> Self {
         Self {
             discovery_rx,
             events_tx,
-            interval,
+            interval: config.collect_interval,
+            balancer_timeout: config.balancer_timeout,
             balancers: Default::default(),
+            last_known: Default::default(),
             stream_tasks: Default::default(),
-            balancer_api_key: balancer_api_key.to_owned(),
+            streams: config.streams.clone(),
+            balancer_api_key: config.balancer_api_key.clone(),
+            history,
+            alerts: AlertMonitor::new(config.alerts.clone()),
         }
     }
 
@@ -60,11 +182,15 @@ impl Collector {
                             continue;
                         }
                     };
+                    let sample = HistorySample::new(now_millis(), &new_state);
+                    self.history.lock().await.record(sample);
+                    self.alerts.update(&new_state).await;
+                    metrics::update_state_gauges(&new_state);
                     let mut current = CURRENT_STATE.lock().await;
                     *current = new_state;
                 }
                 Some(msg) = self.discovery_rx.recv() => {
-                    self.handle_discovery(msg);
+                    self.handle_discovery(msg).await;
                 }
                 else => {
                     break;
@@ -73,74 +199,190 @@ impl Collector {
         }
     }
 
-    pub fn handle_discovery(&mut self, msg: ServiceDiscoveryMsg) {
+    pub async fn handle_discovery(&mut self, msg: ServiceDiscoveryMsg) {
         debug!(
             "Balancer discovery: {} added, {} removed",
             msg.added.len(),
             msg.removed.len()
         );
         self.balancers.retain(|conf| !msg.removed.contains(conf));
+        self.last_known
+            .retain(|conf, _| !msg.removed.contains(conf));
+        metrics::forget_balancers(&msg.removed);
+
+        let mut health = STREAM_HEALTH.lock().await;
+        for conf in &msg.removed {
+            if let Some(task) = self.stream_tasks.remove(conf) {
+                debug!("Stopping stream from balancer: {:?}", conf);
+                task.abort();
+            }
+            health.remove(&metrics::balancer_label(conf));
+        }
+        for conf in &msg.added {
+            if self.stream_tasks.contains_key(conf) {
+                continue;
+            }
+            let label = metrics::balancer_label(conf);
+            let counters = Arc::new(StreamCounters::default());
+            health.insert(
+                label.clone(),
+                TrackedStream {
+                    health: StreamHealth {
+                        balancer: label,
+                        ..Default::default()
+                    },
+                    counters: counters.clone(),
+                },
+            );
+            let task = Self::supervise_stream(
+                conf.clone(),
+                self.events_tx.clone(),
+                self.balancer_api_key.clone(),
+                self.streams.clone(),
+                counters,
+            );
+            self.stream_tasks.insert(conf.clone(), task);
+        }
+        drop(health);
+
         self.balancers.extend(msg.added);
     }
 
     pub async fn collect(&mut self) -> anyhow::Result<SystemState> {
         info!("Collecting system state");
         let client = reqwest::Client::new();
+        let balancer_api_key = &self.balancer_api_key;
+        let timeout = self.balancer_timeout;
+        let results = join_all(self.balancers.iter().map(|conf| {
+            let client = &client;
+            async move {
+                let result = tokio::time::timeout(
+                    timeout,
+                    Self::fetch_state(client, conf, balancer_api_key),
+                )
+                .await
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out after {:?}", timeout)));
+                (conf, result)
+            }
+        }))
+        .await;
+
+        // A balancer that fails keeps its last known state, marked with the error, until it recovers or is removed.
+        // One that has never answered gets a placeholder, so that it doesn't go unnoticed.
+        let now = now_millis();
         let mut states = vec![];
-        for conf in &self.balancers {
-            let mut url = conf.uri();
-            url.set_path("/api/state");
-            url.set_scheme("http").expect("scheme should be valid");
-
-            let resp = client
-                .get(url)
-                .header("Authorization", format!("Bearer {}", self.balancer_api_key))
-                .timeout(Duration::from_secs(3))
-                .send()
-                .await?;
-            if !resp.status().is_success() {
-                error!("Failed to fetch state from {:?}", &conf);
-                continue;
+        for (conf, result) in results {
+            match result {
+                Ok(mut state) => {
+                    metrics::record_collection(conf, true);
+                    state.collected_at = Some(now);
+                    state.error = None;
+                    self.last_known.insert(conf.clone(), state.clone());
+                    states.push(state);
+                }
+                Err(err) => {
+                    metrics::record_collection(conf, false);
+                    error!("Failed to fetch state from {:?}: {:#}", &conf, err);
+                    let error = Some(format!("{:#}", err));
+                    match self.last_known.get_mut(conf) {
+                        Some(state) => {
+                            state.error = error;
+                            states.push(state.clone());
+                        }
+                        None => states.push(BalancerState {
+                            id: placeholder_id(conf),
+                            region: Default::default(),
+                            monoliths: vec![],
+                            collected_at: None,
+                            error,
+                        }),
+                    }
+                }
             }
-            let state = resp.json::<BalancerState>().await?;
-            states.push(state);
         }
         info!("Collected state from {} balancers", states.len());
 
-        // start stream tasks for new balancers
-        for conf in &self.balancers {
-            if self.stream_tasks.contains_key(conf) {
-                continue;
-            }
-            debug!("Starting stream from balancer: {:?}", &conf);
-            let _conf = conf.clone();
-            let events_tx = self.events_tx.clone();
-            let _balancer_api_key = self.balancer_api_key.clone();
-            let task = tokio::spawn(async move {
-                let result =
-                    Self::start_stream_events_from_balancer(_conf, events_tx, _balancer_api_key)
-                        .await;
-                if let Err(err) = result {
-                    error!("Event stream failed: {}", err);
-                    return Err(err);
-                }
-                Ok(())
-            });
-            self.stream_tasks.insert(conf.clone(), task);
-        }
+        Ok(SystemState(states))
+    }
 
-        // cleanup stream tasks that have finished
-        self.stream_tasks.retain(|_conf, task| !task.is_finished());
+    async fn fetch_state(
+        client: &reqwest::Client,
+        conf: &ConnectionConfig,
+        balancer_api_key: &str,
+    ) -> anyhow::Result<BalancerState> {
+        let mut url = conf.uri();
+        url.set_path("/api/state");
+        url.set_scheme("http").expect("scheme should be valid");
 
-        Ok(SystemState(states))
+        let resp = client
+            .get(url)
+            .header("Authorization", format!("Bearer {}", balancer_api_key))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(resp.json::<BalancerState>().await?)
     }
 
-    async fn start_stream_events_from_balancer(
+    /// Keep the event stream from a balancer open, reconnecting whenever it ends, until the task is aborted.
+    fn supervise_stream(
         balancer: ConnectionConfig,
         events_tx: tokio::sync::mpsc::Sender<String>,
-        balancer_api_key: impl ToOwned<Owned = String>,
+        balancer_api_key: String,
+        config: StreamsConfig,
+        counters: Arc<StreamCounters>,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let label = metrics::balancer_label(&balancer);
+            let mut backoff = Backoff::new(&config);
+            loop {
+                let result = Self::start_stream_events_from_balancer(
+                    &balancer,
+                    &label,
+                    &events_tx,
+                    &balancer_api_key,
+                    &counters,
+                )
+                .await;
+                if events_tx.is_closed() {
+                    break;
+                }
+                let error = match result {
+                    Ok(()) => "stream ended".to_owned(),
+                    Err(err) => {
+                        error!("Event stream from {:?} failed: {:#}", &balancer, err);
+                        format!("{:#}", err)
+                    }
+                };
+
+                let mut health = STREAM_HEALTH.lock().await;
+                if let Some(health) = health.get_mut(&label).map(|s| &mut s.health) {
+                    // Only back off further if the stream didn't get anywhere.
+                    if health.connected {
+                        backoff.reset();
+                    }
+                    health.connected = false;
+                    health.connected_since = None;
+                    health.reconnects += 1;
+                    health.last_error = Some(error);
+                }
+                drop(health);
+                metrics::record_stream_disconnected(&balancer);
+
+                let delay = backoff.next_delay();
+                debug!("Reconnecting to {:?} in {:?}", &balancer, delay);
+                tokio::time::sleep(delay).await;
+            }
+        })
+    }
+
+    async fn start_stream_events_from_balancer(
+        balancer: &ConnectionConfig,
+        label: &str,
+        events_tx: &tokio::sync::mpsc::Sender<String>,
+        balancer_api_key: &str,
+        counters: &StreamCounters,
     ) -> anyhow::Result<()> {
-        info!("starting stream from balancer: {:?}", &balancer);
+        info!("starting stream from balancer: {:?}", balancer);
         let mut url = balancer.uri();
         url.set_path("/api/state/stream");
         let req = Request::builder()
@@ -150,25 +392,32 @@ impl Collector {
             .header("Upgrade", "websocket")
             .header("Sec-WebSocket-Version", "13")
             .header("Sec-WebSocket-Key", generate_key())
-            .header(
-                "Authorization",
-                format!("Bearer {}", balancer_api_key.to_owned()),
-            )
+            .header("Authorization", format!("Bearer {}", balancer_api_key))
             .body(())?;
         let (mut ws, _) = tokio_tungstenite::connect_async(req).await?;
 
+        if let Some(stream) = STREAM_HEALTH.lock().await.get_mut(label) {
+            stream.health.connected = true;
+            stream.health.connected_since = Some(now_millis());
+        }
+        metrics::record_stream_connected(balancer);
+
         loop {
             tokio::select! {
                 msg = ws.next() => {
                     match msg {
-                        Some(Ok(msg)) => {
-                            if msg.is_close() {
-                                break;
-                            }
-                            let msg = msg.to_string();
-                            if !should_send(&msg) {
-                                continue;
+                        Some(Ok(Message::Text(msg))) => {
+                            match serde_json::from_str::<StreamMessage>(&msg) {
+                                Ok(StreamMessage::Traffic(_) | StreamMessage::Lifecycle(_)) => {}
+                                // The stream also carries state syncs, which are collected separately.
+                                Ok(StreamMessage::State(_)) => continue,
+                                Err(_) => {
+                                    warn!("Dropping unrecognized event from {:?}: {}", balancer, msg);
+                                    Self::record_parse_failure(balancer, counters);
+                                    continue;
+                                }
                             }
+                            counters.events.fetch_add(1, Ordering::Relaxed);
                             if let Err(err) = events_tx.try_send(msg) {
                                 match err {
                                     tokio::sync::mpsc::error::TrySendError::Full(_) => {
@@ -181,7 +430,13 @@ impl Collector {
                                 }
                             }
                         }
-                        _ => break,
+                        Some(Ok(Message::Binary(_))) => {
+                            warn!("Dropping binary message from {:?}", balancer);
+                            Self::record_parse_failure(balancer, counters);
+                        }
+                        Some(Ok(Message::Close(_))) | None => break,
+                        Some(Ok(_)) => {}
+                        Some(Err(err)) => return Err(err.into()),
                     }
                 }
                 else => {
@@ -189,14 +444,32 @@ impl Collector {
                 }
             }
         }
-        warn!("stream from balancer {:?} ended", &balancer);
+        warn!("stream from balancer {:?} ended", balancer);
 
         Ok(())
     }
+
+    fn record_parse_failure(balancer: &ConnectionConfig, counters: &StreamCounters) {
+        counters.parse_failures.fetch_add(1, Ordering::Relaxed);
+        metrics::record_parse_failure(balancer);
+    }
 }
 
-fn should_send(event: &str) -> bool {
-    serde_json::from_str::<Event>(event).is_ok()
+/// Balancers that have never answered haven't told us their id, so they get one that is derived from their address.
+fn placeholder_id(conf: &ConnectionConfig) -> BalancerId {
+    let mut hasher = DefaultHasher::new();
+    conf.hash(&mut hasher);
+    Uuid::from_u64_pair(0, hasher.finish()).into()
+}
+
+/// Everything that a balancer's event stream carries, so that each message only has to be parsed once.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum StreamMessage {
+    Traffic(Event),
+    Lifecycle(BalancerEvent),
+    State(StateSync),
 }
 
 #[derive(Debug, Deserialize)]
@@ -242,9 +515,214 @@ enum MsgDirection {
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use std::sync::atomic::AtomicU8;
+
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use ott_common::discovery::HostOrIp;
     use uuid::uuid;
 
+    use super::*;
+    use crate::history::HistoryConfig;
+    use crate::test_util::{serve_http, serve_tcp};
+
+    const OK: u8 = 0;
+    const FAIL: u8 = 1;
+    const HANG: u8 = 2;
+
+    /// Stands in for a balancer's state endpoint, behaving according to `mode`.
+    async fn start_balancer(state: BalancerState, mode: Arc<AtomicU8>) -> ConnectionConfig {
+        let body = serde_json::to_vec(&state).unwrap();
+        let addr = serve_http(move |_req| {
+            let body = body.clone();
+            let mode = mode.load(Ordering::SeqCst);
+            async move {
+                let mut resp = hyper::Response::new(Full::new(Bytes::from(body)));
+                match mode {
+                    FAIL => *resp.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR,
+                    HANG => std::future::pending::<()>().await,
+                    _ => {}
+                }
+                resp
+            }
+        })
+        .await;
+        ConnectionConfig {
+            host: HostOrIp::Ip("127.0.0.1".parse().unwrap()),
+            port: addr.port(),
+        }
+    }
+
+    fn balancer_state(n: u128) -> BalancerState {
+        BalancerState {
+            id: Uuid::from_u128(n).into(),
+            region: "ord".into(),
+            monoliths: vec![],
+            collected_at: None,
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn should_keep_last_known_state_of_failing_balancers() {
+        let (_discovery_tx, discovery_rx) = tokio::sync::mpsc::channel(1);
+        let (events_tx, _events_rx) = tokio::sync::mpsc::channel(1);
+        let mut collector = Collector::new(
+            discovery_rx,
+            events_tx,
+            &CollectorConfig {
+                balancer_timeout: Duration::from_millis(200),
+                ..Default::default()
+            },
+            Arc::new(Mutex::new(
+                History::open(HistoryConfig::default(), 0).unwrap(),
+            )),
+        );
+
+        let mode_a = Arc::new(AtomicU8::new(OK));
+        let mode_b = Arc::new(AtomicU8::new(HANG));
+        let mode_c = Arc::new(AtomicU8::new(HANG));
+        let a = start_balancer(balancer_state(1), mode_a.clone()).await;
+        let b = start_balancer(balancer_state(2), mode_b.clone()).await;
+        let c = start_balancer(balancer_state(3), mode_c.clone()).await;
+        collector
+            .handle_discovery(ServiceDiscoveryMsg {
+                added: vec![a.clone(), b.clone(), c.clone()],
+                removed: vec![],
+                metadata: Default::default(),
+            })
+            .await;
+
+        // Balancers that never responded show up as placeholders, and don't hold up the others.
+        let started = tokio::time::Instant::now();
+        let state = collector.collect().await.unwrap();
+        assert!(started.elapsed() < Duration::from_millis(400));
+        assert_eq!(state.0.len(), 3);
+        assert_eq!(state.0[0].id, Uuid::from_u128(1).into());
+        assert!(state.0[0].error.is_none());
+        let collected_at = state.0[0].collected_at.expect("should be set");
+        for placeholder in &state.0[1..] {
+            assert!(placeholder.collected_at.is_none());
+            assert!(placeholder.monoliths.is_empty());
+            assert!(placeholder.error.as_deref().unwrap().contains("timed out"));
+        }
+        assert_ne!(state.0[1].id, state.0[2].id);
+        let placeholder_c = state.0[2].id;
+
+        mode_a.store(FAIL, Ordering::SeqCst);
+        mode_b.store(OK, Ordering::SeqCst);
+        let state = collector.collect().await.unwrap();
+        assert_eq!(state.0.len(), 3);
+        assert_eq!(state.0[0].id, Uuid::from_u128(1).into());
+        assert_eq!(state.0[0].collected_at, Some(collected_at));
+        assert!(state.0[0].error.is_some());
+        assert_eq!(state.0[1].id, Uuid::from_u128(2).into());
+        assert!(state.0[1].error.is_none());
+        assert_eq!(state.0[2].id, placeholder_c);
+
+        collector
+            .handle_discovery(ServiceDiscoveryMsg {
+                added: vec![],
+                removed: vec![a],
+                metadata: Default::default(),
+            })
+            .await;
+        mode_a.store(OK, Ordering::SeqCst);
+        let state = collector.collect().await.unwrap();
+        assert_eq!(state.0.len(), 2);
+        assert_eq!(state.0[0].id, Uuid::from_u128(2).into());
+    }
+
+    #[test]
+    fn backoff_should_double_up_to_max() {
+        let mut backoff = Backoff::new(&StreamsConfig {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+        });
+        let delays: Vec<_> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn should_restart_streams_until_balancer_is_removed() {
+        use rocket::futures::SinkExt;
+
+        let lifecycle = r#"{"balancer_id":"f47ac10b-58cc-4372-a567-0e02b2c3d479","timestamp":1700000000000,"event":{"type":"monolith_disconnected","payload":{"monolith_id":"f47ac10b-58cc-4372-a567-0e02b2c3d479"}}}"#;
+        let state_sync = serde_json::to_string(&StateSync {
+            balancer_id: Uuid::nil().into(),
+            version: 1,
+            state: ott_balancer_protocol::collector::StateUpdate::Diff(vec![]),
+        })
+        .unwrap();
+
+        // Every connection gets one event, one state sync and one bad message before being closed.
+        let addr = serve_tcp(move |stream| {
+            let state_sync = state_sync.clone();
+            async move {
+                let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                for msg in [lifecycle, &state_sync, "not an event"] {
+                    ws.send(Message::Text(msg.to_owned())).await.unwrap();
+                }
+                let _ = ws.close(None).await;
+            }
+        })
+        .await;
+        let conf = ConnectionConfig {
+            host: HostOrIp::Ip("127.0.0.1".parse().unwrap()),
+            port: addr.port(),
+        };
+        let label = metrics::balancer_label(&conf);
+
+        let (_discovery_tx, discovery_rx) = tokio::sync::mpsc::channel(1);
+        let (events_tx, mut events_rx) = tokio::sync::mpsc::channel(10);
+        let mut collector = Collector::new(
+            discovery_rx,
+            events_tx,
+            &CollectorConfig {
+                streams: StreamsConfig {
+                    initial_backoff: Duration::from_millis(10),
+                    max_backoff: Duration::from_millis(10),
+                },
+                ..Default::default()
+            },
+            Arc::new(Mutex::new(
+                History::open(HistoryConfig::default(), 0).unwrap(),
+            )),
+        );
+        collector
+            .handle_discovery(ServiceDiscoveryMsg {
+                added: vec![conf.clone()],
+                removed: vec![],
+                metadata: Default::default(),
+            })
+            .await;
+
+        for _ in 0..2 {
+            let event = tokio::time::timeout(Duration::from_secs(1), events_rx.recv())
+                .await
+                .expect("stream should be restarted")
+                .unwrap();
+            assert_eq!(event, lifecycle);
+        }
+        let health = STREAM_HEALTH.lock().await.get(&label).unwrap().health();
+        assert!(health.events >= 2);
+        assert!(health.parse_failures >= 1);
+        assert!(health.reconnects >= 1);
+        assert_eq!(health.last_error.as_deref(), Some("stream ended"));
+
+        collector
+            .handle_discovery(ServiceDiscoveryMsg {
+                added: vec![],
+                removed: vec![conf],
+                metadata: Default::default(),
+            })
+            .await;
+        assert!(collector.stream_tasks.is_empty());
+        assert!(!STREAM_HEALTH.lock().await.contains_key(&label));
+    }
+
     #[test]
     fn test_deserialize_event_ws() {
         let event =
@@ -322,4 +800,17 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn should_recognize_stream_messages() {
+        let parse = |msg: &str| serde_json::from_str::<StreamMessage>(msg);
+        let event = r#"{"balancer_id":"f47ac10b-58cc-4372-a567-0e02b2c3d479","timestamp":1700000000000,"event":{"type":"monolith_disconnected","payload":{"monolith_id":"f47ac10b-58cc-4372-a567-0e02b2c3d479"}}}"#;
+        assert!(matches!(parse(event), Ok(StreamMessage::Lifecycle(_))));
+        let event =
+            r#"{"event":"ws","node_id":"f47ac10b-58cc-4372-a567-0e02b2c3d479", "direction": "tx"}"#;
+        assert!(matches!(parse(event), Ok(StreamMessage::Traffic(_))));
+        let sync = r#"{"balancer_id":"f47ac10b-58cc-4372-a567-0e02b2c3d479","version":1,"state":{"type":"diff","payload":[]}}"#;
+        assert!(matches!(parse(sync), Ok(StreamMessage::State(_))));
+        assert!(parse(r#"{"timestamp":1700000000000}"#).is_err());
+    }
 }
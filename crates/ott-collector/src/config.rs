@@ -5,6 +5,15 @@ use figment::providers::Format;
 use ott_common::discovery::DiscoveryConfig;
 use serde::Deserialize;
 
+use crate::alerts::AlertsConfig;
+use crate::auth::AuthConfig;
+use crate::collector::StreamsConfig;
+use crate::cors::CorsConfig;
+use crate::event_log::EventLogConfig;
+use crate::history::HistoryConfig;
+use crate::rates::RollupConfig;
+use crate::redact::RedactConfig;
+
 #[derive(Debug, Deserialize)]
 pub struct CollectorConfig {
     #[serde(default)]
@@ -12,7 +21,27 @@ pub struct CollectorConfig {
     #[serde(default)]
     #[serde(with = "humantime_serde")]
     pub collect_interval: Duration,
+    /// How long to wait for each balancer to respond with its state.
+    #[serde(default = "default_balancer_timeout")]
+    #[serde(with = "humantime_serde")]
+    pub balancer_timeout: Duration,
     pub balancer_api_key: String,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default)]
+    pub event_log: EventLogConfig,
+    #[serde(default)]
+    pub rollup: RollupConfig,
+    #[serde(default)]
+    pub alerts: AlertsConfig,
+    #[serde(default)]
+    pub streams: StreamsConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub cors: CorsConfig,
+    #[serde(default)]
+    pub redact: RedactConfig,
 }
 
 impl Default for CollectorConfig {
@@ -20,11 +49,24 @@ impl Default for CollectorConfig {
         CollectorConfig {
             discovery: Default::default(),
             collect_interval: Duration::from_secs(5),
+            balancer_timeout: default_balancer_timeout(),
             balancer_api_key: "".to_owned(),
+            history: Default::default(),
+            event_log: Default::default(),
+            rollup: Default::default(),
+            alerts: Default::default(),
+            streams: Default::default(),
+            auth: Default::default(),
+            cors: Default::default(),
+            redact: Default::default(),
         }
     }
 }
 
+fn default_balancer_timeout() -> Duration {
+    Duration::from_secs(3)
+}
+
 impl CollectorConfig {
     pub fn load(path: &PathBuf) -> Result<Self, anyhow::Error> {
         let config: CollectorConfig = figment::Figment::new()
@@ -52,5 +94,6 @@ mod tests {
         });
         let conf = serde_json::from_value::<CollectorConfig>(json).expect("failed to parse json");
         assert_eq!(conf.collect_interval, Duration::from_secs(10));
+        assert_eq!(conf.balancer_timeout, Duration::from_secs(3));
     }
 }
@@ -2,8 +2,47 @@ use rocket::fairing::{Fairing, Info, Kind};
 use rocket::http::Header;
 use rocket::response::status::NoContent;
 use rocket::{Request, Response};
+use serde::Deserialize;
 
-pub struct Cors;
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins that browsers are allowed to make requests from, like `https://grafana.example.com`.
+    ///
+    /// `*` allows any origin, but then browsers won't send cookies or cached credentials along with their requests.
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec!["*".to_owned()],
+        }
+    }
+}
+
+pub struct Cors {
+    config: CorsConfig,
+}
+
+impl Cors {
+    pub fn new(config: CorsConfig) -> Self {
+        Self { config }
+    }
+
+    /// The value of `Access-Control-Allow-Origin` for a request from `origin`, if it's allowed at all.
+    fn allow_origin<'a>(&'a self, origin: Option<&'a str>) -> Option<&'a str> {
+        if let Some(origin) = origin {
+            if self.config.allowed_origins.iter().any(|o| o == origin) {
+                return Some(origin);
+            }
+        }
+        if self.config.allowed_origins.iter().any(|o| o == "*") {
+            return Some("*");
+        }
+        None
+    }
+}
 
 #[rocket::async_trait]
 impl Fairing for Cors {
@@ -14,12 +53,24 @@ impl Fairing for Cors {
         }
     }
 
-    async fn on_response<'r>(&self, _request: &'r Request<'_>, response: &mut Response<'r>) {
-        response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
-        response.set_header(Header::new("Access-Control-Allow-Methods", "*"));
-        response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
-        response.set_header(Header::new("Access-Control-Expose-Headers", "*"));
-        response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        response.set_header(Header::new("Vary", "Origin"));
+        let Some(allow_origin) = self.allow_origin(request.headers().get_one("Origin")) else {
+            return;
+        };
+        response.set_header(Header::new(
+            "Access-Control-Allow-Origin",
+            allow_origin.to_owned(),
+        ));
+        response.set_header(Header::new("Access-Control-Allow-Methods", "GET, OPTIONS"));
+        response.set_header(Header::new(
+            "Access-Control-Allow-Headers",
+            "Authorization, Content-Type",
+        ));
+        // Browsers refuse credentials when any origin is allowed, so only allow them for specific origins.
+        if allow_origin != "*" {
+            response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+        }
         response.set_header(Header::new("Access-Control-Max-Age", "7200"));
     }
 }
@@ -28,3 +79,61 @@ impl Fairing for Cors {
 pub fn handle_preflight() -> NoContent {
     NoContent
 }
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::{Header, Status};
+    use rocket::local::asynchronous::Client;
+
+    use super::*;
+
+    async fn client(allowed_origins: &[&str]) -> Client {
+        let rocket = rocket::build()
+            .attach(Cors::new(CorsConfig {
+                allowed_origins: allowed_origins.iter().map(|o| o.to_string()).collect(),
+            }))
+            .mount("/", routes![handle_preflight]);
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_not_allow_credentials_for_any_origin() {
+        let client = client(&["*"]).await;
+        let resp = client
+            .options("/state")
+            .header(Header::new("Origin", "https://example.com"))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::NoContent);
+        let headers = resp.headers();
+        assert_eq!(headers.get_one("Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(headers.get_one("Access-Control-Allow-Credentials"), None);
+    }
+
+    #[tokio::test]
+    async fn should_only_allow_configured_origins() {
+        let client = client(&["https://grafana.example.com"]).await;
+
+        let resp = client
+            .options("/state")
+            .header(Header::new("Origin", "https://grafana.example.com"))
+            .dispatch()
+            .await;
+        let headers = resp.headers();
+        assert_eq!(
+            headers.get_one("Access-Control-Allow-Origin"),
+            Some("https://grafana.example.com")
+        );
+        assert_eq!(
+            headers.get_one("Access-Control-Allow-Credentials"),
+            Some("true")
+        );
+
+        let resp = client
+            .options("/state")
+            .header(Header::new("Origin", "https://evil.example.com"))
+            .dispatch()
+            .await;
+        assert_eq!(resp.headers().get_one("Access-Control-Allow-Origin"), None);
+    }
+}
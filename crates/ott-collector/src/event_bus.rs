@@ -2,19 +2,35 @@ use rocket::{
     futures::{SinkExt, StreamExt},
     State,
 };
+use std::sync::Arc;
+
 use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+
+use crate::auth::Authenticated;
+use crate::event_log::EventLog;
+use crate::redact::Redactor;
 
 /// Handles all the raw events being streamed from balancers and parses and filters them into only the events we care about.
 pub struct EventBus {
     events_rx: tokio::sync::mpsc::Receiver<String>,
 
     bus_tx: tokio::sync::broadcast::Sender<EventBusEvent>,
+
+    event_log: Arc<Mutex<EventLog>>,
 }
 
 impl EventBus {
-    pub fn new(events_rx: tokio::sync::mpsc::Receiver<String>) -> Self {
+    pub fn new(
+        events_rx: tokio::sync::mpsc::Receiver<String>,
+        event_log: Arc<Mutex<EventLog>>,
+    ) -> Self {
         let (bus_tx, _) = tokio::sync::broadcast::channel(100);
-        Self { events_rx, bus_tx }
+        Self {
+            events_rx,
+            bus_tx,
+            event_log,
+        }
     }
 
     #[must_use]
@@ -29,7 +45,7 @@ impl EventBus {
         loop {
             tokio::select! {
                 Some(event) = self.events_rx.recv() => {
-                    self.handle_event(event);
+                    self.handle_event(event).await;
                 }
                 else => {
                     break;
@@ -38,14 +54,33 @@ impl EventBus {
         }
     }
 
-    fn handle_event(&self, event: String) {
-        info!("Received event: {}", event);
+    async fn handle_event(&self, event: String) {
+        debug!("Received event: {}", event);
+        let value = match serde_json::from_str::<serde_json::Value>(&event) {
+            Ok(value) => value,
+            Err(err) => {
+                crate::metrics::count_event(None);
+                warn!("Dropping invalid event: {}", err);
+                return;
+            }
+        };
+        crate::metrics::count_event(Some(&value));
+        let now = crate::history::now_millis();
+        crate::rates::TRAFFIC_RATES
+            .lock()
+            .await
+            .record_event(&value, now / 1000);
+        let is_traffic = matches!(value["event"].as_str(), Some("ws" | "proxy" | "broadcast"));
+        self.event_log.lock().await.record(value, now);
         if self.bus_tx.receiver_count() == 0 {
             return;
         }
-        match self.bus_tx.send(event) {
+        match self.bus_tx.send(EventBusEvent {
+            text: event,
+            is_traffic,
+        }) {
             Ok(count) => {
-                info!("Event sent to {count} subscribers");
+                debug!("Event sent to {count} subscribers");
             }
             Err(err) => {
                 error!("Error sending event to subscribers: {}", err);
@@ -58,7 +93,12 @@ impl EventBus {
     }
 }
 
-type EventBusEvent = String;
+#[derive(Debug, Clone)]
+pub struct EventBusEvent {
+    pub text: String,
+    /// Whether this is one of the high volume traffic events, rather than a lifecycle event.
+    pub is_traffic: bool,
+}
 
 /// Enables subscriptions to the event bus
 pub struct EventBusSubscriber {
@@ -75,12 +115,18 @@ impl EventBusSubscriber {
     }
 }
 
-#[get("/state/stream")]
+/// Stream lifecycle events as they happen. Traffic events are only included with `raw=true`, see `/rates/stream` for a rolled up view of them instead.
+#[get("/state/stream?<raw>")]
 pub fn event_stream(
+    _auth: Authenticated,
     ws: rocket_ws::WebSocket,
+    raw: Option<bool>,
     event_bus: &State<EventBusSubscriber>,
+    redactor: &State<Redactor>,
 ) -> rocket_ws::Channel<'static> {
     let mut bus_rx = event_bus.subscribe();
+    let redactor = redactor.inner().clone();
+    let raw = raw.unwrap_or(false);
     ws.channel(move |mut stream| {
         Box::pin(async move {
             loop {
@@ -97,6 +143,10 @@ pub fn event_stream(
                                 break;
                             }
                         };
+                        if event.is_traffic && !raw {
+                            continue;
+                        }
+                        let event = redactor.event(event.text);
                         if let Err(err) = stream.send(rocket_ws::Message::text(event)).await {
                             error!("Error sending event to Event bus WebSocket: {}", err);
                             break;
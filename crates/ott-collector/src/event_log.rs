@@ -0,0 +1,438 @@
+//! Keeps the events that came through the event bus, so that incidents can be looked into after the fact.
+//!
+//! Events are kept in memory for querying, and appended to a file so that they survive restarts.
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use ott_balancer_protocol::RoomName;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::auth::Authenticated;
+use crate::jsonl::{self, JsonlWriter};
+use crate::redact::Redactor;
+
+/// The most events that a single query returns.
+const MAX_QUERY_LIMIT: usize = 10_000;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct EventLogConfig {
+    /// How long to keep events for.
+    #[serde(with = "humantime_serde")]
+    pub retention: Duration,
+    /// The most events to keep, regardless of `retention`.
+    pub max_events: usize,
+    /// A file to append events to, so that they survive restarts.
+    pub persist_path: Option<PathBuf>,
+}
+
+impl Default for EventLogConfig {
+    fn default() -> Self {
+        Self {
+            retention: Duration::from_secs(60 * 60 * 24),
+            max_events: 200_000,
+            persist_path: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoggedEvent {
+    /// When the collector received the event, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub event: serde_json::Value,
+}
+
+impl LoggedEvent {
+    /// Look for a field at the top level of a traffic event, or in the payload of a lifecycle event.
+    fn field(&self, name: &str) -> Option<&str> {
+        self.event
+            .get(name)
+            .or_else(|| self.event.get("event")?.get("payload")?.get(name))
+            .and_then(|value| value.as_str())
+    }
+
+    /// Look for a field as it will be served, so that filters can't be used to find events about redacted rooms and
+    /// clients by their real names.
+    fn redacted_field(&self, name: &str, redactor: &Redactor) -> Option<String> {
+        let value = self.field(name)?;
+        let redact_room = |room: &str| redactor.room(&room.into()).to_string();
+        let redact_client = |id: &str| match id.parse::<Uuid>() {
+            Ok(id) => redactor.client_id(id.into()).to_string(),
+            Err(_) => id.to_owned(),
+        };
+        Some(match name {
+            "room" => redact_room(value),
+            "client_id" => redact_client(value),
+            // traffic events repeat the room or the client as the node
+            "node_id" if self.field("client_id") == Some(value) => redact_client(value),
+            "node_id" if self.field("room") == Some(value) => redact_room(value),
+            _ => value.to_owned(),
+        })
+    }
+
+    fn matches(&self, filter: &EventFilter, redactor: &Redactor) -> bool {
+        if self.timestamp < filter.from || self.timestamp > filter.to {
+            return false;
+        }
+        if let Some(room) = &filter.room {
+            let matches_room = self
+                .redacted_field("room", redactor)
+                .is_some_and(|r| RoomName::from(r) == *room);
+            if !matches_room {
+                return false;
+            }
+        }
+        if let Some(node) = &filter.node {
+            let is_node = ["node_id", "client_id", "monolith_id", "balancer_id"]
+                .iter()
+                .any(|name| self.redacted_field(name, redactor).as_ref() == Some(node));
+            if !is_node {
+                return false;
+            }
+        }
+        if let Some(direction) = &filter.direction {
+            if self.field("direction") != Some(direction.as_str()) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Rooms and nodes are matched against their redacted names and ids, which are the ones that the events are served with.
+#[derive(Debug, Default)]
+pub struct EventFilter {
+    pub from: u64,
+    pub to: u64,
+    pub room: Option<RoomName>,
+    /// Matches the id of the client, monolith or balancer that the event is about.
+    pub node: Option<String>,
+    pub direction: Option<String>,
+}
+
+pub struct EventLog {
+    config: EventLogConfig,
+    events: VecDeque<LoggedEvent>,
+    writer: Option<JsonlWriter>,
+    /// Lines in the persisted file, including ones for events that have since been dropped.
+    persisted_lines: usize,
+}
+
+impl EventLog {
+    /// Create an event log, loading any events that were persisted to `config.persist_path`.
+    pub fn open(config: EventLogConfig, now: u64) -> anyhow::Result<Self> {
+        let mut log = Self {
+            config,
+            events: VecDeque::new(),
+            writer: None,
+            persisted_lines: 0,
+        };
+        let Some(path) = log.config.persist_path.clone() else {
+            return Ok(log);
+        };
+        log.events = jsonl::load(&path)
+            .context("loading persisted events")?
+            .into();
+        log.prune(now);
+        let lines = jsonl::to_lines(&log.events)?;
+        log.persisted_lines = lines.len();
+        log.writer = Some(JsonlWriter::open(path, &lines).context("persisting events")?);
+        Ok(log)
+    }
+
+    pub fn record(&mut self, event: serde_json::Value, now: u64) {
+        self.events.push_back(LoggedEvent {
+            timestamp: now,
+            event,
+        });
+        self.prune(now);
+        if let Err(err) = self.persist() {
+            error!("Failed to persist event: {:#}", err);
+        }
+    }
+
+    /// The latest `limit` events that match `filter`, oldest first.
+    pub fn query(
+        &self,
+        filter: &EventFilter,
+        limit: usize,
+        redactor: &Redactor,
+    ) -> Vec<LoggedEvent> {
+        let mut events: Vec<_> = self
+            .events
+            .iter()
+            .rev()
+            .filter(|event| event.matches(filter, redactor))
+            .take(limit)
+            .cloned()
+            .collect();
+        events.reverse();
+        events
+    }
+
+    fn prune(&mut self, now: u64) {
+        let cutoff = now.saturating_sub(self.config.retention.as_millis() as u64);
+        while self
+            .events
+            .front()
+            .is_some_and(|e| e.timestamp < cutoff || self.events.len() > self.config.max_events)
+        {
+            self.events.pop_front();
+        }
+    }
+
+    /// Persist the latest event. The file is rewritten once most of it is events that have been dropped.
+    fn persist(&mut self) -> anyhow::Result<()> {
+        let Some(writer) = &self.writer else {
+            return Ok(());
+        };
+        if writer.needs_rewrite() || self.persisted_lines > self.events.len() * 2 + 1000 {
+            let lines = jsonl::to_lines(&self.events)?;
+            self.persisted_lines = lines.len();
+            writer.rewrite(lines);
+        } else if let Some(event) = self.events.back() {
+            writer.append(serde_json::to_string(event)?);
+            self.persisted_lines += 1;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, FromForm)]
+pub struct EventQuery {
+    from: Option<u64>,
+    to: Option<u64>,
+    room: Option<String>,
+    node: Option<String>,
+    direction: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Serve logged events that match all of the given filters.
+#[get("/events?<query..>")]
+pub async fn serve_events(
+    _auth: Authenticated,
+    log: &State<Arc<Mutex<EventLog>>>,
+    redactor: &State<Redactor>,
+    query: EventQuery,
+) -> Result<Json<Vec<LoggedEvent>>, (Status, String)> {
+    let limit = query.limit.unwrap_or(1000);
+    if limit > MAX_QUERY_LIMIT {
+        return Err((
+            Status::BadRequest,
+            format!("limit can't be more than {}", MAX_QUERY_LIMIT),
+        ));
+    }
+    let filter = EventFilter {
+        from: query.from.unwrap_or(0),
+        to: query.to.unwrap_or(u64::MAX),
+        room: query.room.map(RoomName::from),
+        node: query.node,
+        direction: query.direction,
+    };
+    let events = log.lock().await.query(&filter, limit, redactor);
+    let events = events
+        .into_iter()
+        .map(|mut logged| {
+            logged.event = serde_json::from_str(&redactor.event(logged.event.to_string()))
+                .unwrap_or(logged.event);
+            logged
+        })
+        .collect();
+    Ok(Json(events))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::redact::RedactConfig;
+
+    fn ws_event(room: &str, client: &str, direction: &str) -> serde_json::Value {
+        json!({
+            "event": "ws",
+            "node_id": client,
+            "client_id": client,
+            "room": room,
+            "direction": direction,
+        })
+    }
+
+    fn all() -> EventFilter {
+        EventFilter {
+            to: u64::MAX,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn should_filter_events() {
+        let mut log = EventLog::open(EventLogConfig::default(), 0).unwrap();
+        log.record(ws_event("foo", "a", "tx"), 1000);
+        log.record(ws_event("Foo", "b", "rx"), 2000);
+        log.record(ws_event("bar", "a", "rx"), 3000);
+        log.record(
+            json!({
+                "balancer_id": "x",
+                "timestamp": 4000,
+                "event": {
+                    "type": "room_loaded",
+                    "payload": {"room": "foo", "monolith_id": "m", "load_epoch": 1},
+                },
+            }),
+            4000,
+        );
+
+        let timestamps = |filter: EventFilter, limit: usize| -> Vec<u64> {
+            log.query(&filter, limit, &Redactor::default())
+                .iter()
+                .map(|e| e.timestamp)
+                .collect()
+        };
+        assert_eq!(timestamps(all(), 100), vec![1000, 2000, 3000, 4000]);
+        assert_eq!(timestamps(all(), 2), vec![3000, 4000]);
+        assert_eq!(
+            timestamps(
+                EventFilter {
+                    from: 1500,
+                    to: 3000,
+                    ..Default::default()
+                },
+                100
+            ),
+            vec![2000, 3000]
+        );
+        assert_eq!(
+            timestamps(
+                EventFilter {
+                    room: Some("foo".into()),
+                    ..all()
+                },
+                100
+            ),
+            vec![1000, 2000, 4000]
+        );
+        assert_eq!(
+            timestamps(
+                EventFilter {
+                    node: Some("a".into()),
+                    direction: Some("rx".into()),
+                    ..all()
+                },
+                100
+            ),
+            vec![3000]
+        );
+        assert_eq!(
+            timestamps(
+                EventFilter {
+                    node: Some("m".into()),
+                    ..all()
+                },
+                100
+            ),
+            vec![4000]
+        );
+    }
+
+    #[test]
+    fn should_drop_old_events() {
+        let mut log = EventLog::open(
+            EventLogConfig {
+                retention: Duration::from_secs(10),
+                max_events: 3,
+                persist_path: None,
+            },
+            0,
+        )
+        .unwrap();
+        for timestamp in [1000, 2000, 3000, 4000] {
+            log.record(ws_event("foo", "a", "tx"), timestamp);
+        }
+        let redactor = Redactor::default();
+        assert_eq!(log.query(&all(), 100, &redactor).len(), 3);
+        log.record(ws_event("foo", "a", "tx"), 13_500);
+        let timestamps: Vec<_> = log
+            .query(&all(), 100, &redactor)
+            .iter()
+            .map(|e| e.timestamp)
+            .collect();
+        assert_eq!(timestamps, vec![4000, 13_500]);
+    }
+
+    #[test]
+    fn should_load_persisted_events() {
+        let path = std::env::temp_dir().join(format!("events-{}.jsonl", uuid::Uuid::new_v4()));
+        let config = EventLogConfig {
+            retention: Duration::from_secs(10),
+            max_events: 100,
+            persist_path: Some(path.clone()),
+        };
+
+        let mut log = EventLog::open(config.clone(), 0).unwrap();
+        log.record(ws_event("foo", "a", "tx"), 1000);
+        log.record(ws_event("foo", "b", "tx"), 5000);
+        drop(log);
+
+        let log = EventLog::open(config, 12_000).unwrap();
+        let events = log.query(&all(), 100, &Redactor::default());
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].timestamp, 5000);
+        assert_eq!(events[0].event["client_id"], "b");
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn should_filter_by_redacted_names() {
+        let redactor = Redactor::new(RedactConfig {
+            client_ids: true,
+            room_names: true,
+            public_rooms: vec!["lobby".into()],
+        });
+        let client = Uuid::new_v4().to_string();
+        let mut log = EventLog::open(EventLogConfig::default(), 0).unwrap();
+        log.record(ws_event("secret", &client, "tx"), 1000);
+        log.record(ws_event("lobby", &client, "tx"), 2000);
+
+        let timestamps = |filter: EventFilter| -> Vec<u64> {
+            log.query(&filter, 100, &redactor)
+                .iter()
+                .map(|e| e.timestamp)
+                .collect()
+        };
+        let by_room = |room: RoomName| EventFilter {
+            room: Some(room),
+            ..all()
+        };
+        let by_node = |node: String| EventFilter {
+            node: Some(node),
+            ..all()
+        };
+        assert_eq!(timestamps(by_room("secret".into())), Vec::<u64>::new());
+        assert_eq!(
+            timestamps(by_room(redactor.room(&"secret".into()))),
+            vec![1000]
+        );
+        assert_eq!(timestamps(by_room("lobby".into())), vec![2000]);
+        assert_eq!(timestamps(by_node(client.clone())), Vec::<u64>::new());
+        let redacted_client = redactor.client_id(client.parse::<Uuid>().unwrap().into());
+        assert_eq!(
+            timestamps(by_node(redacted_client.to_string())),
+            vec![1000, 2000]
+        );
+    }
+}
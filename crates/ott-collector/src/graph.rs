@@ -0,0 +1,391 @@
+//! Shows the system as a graph in Grafana's Node Graph panel: edge regions → balancers → monoliths → rooms.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+
+use ott_balancer_protocol::{BalancerId, ClientId, MonolithId, Region, RoomName};
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::Mutex;
+
+use crate::auth::Authenticated;
+use crate::history::now_millis;
+use crate::rates::{NodeKind, TrafficRates};
+use crate::redact::Redactor;
+use crate::SystemState;
+
+/// Message rates are averaged over this many seconds.
+const RATE_WINDOW_SECS: u64 = 10;
+
+#[derive(Debug, Default)]
+struct Stats {
+    clients: BTreeSet<ClientId>,
+    rate: f64,
+}
+
+struct Node {
+    id: String,
+    title: String,
+    subtitle: &'static str,
+    stats: Stats,
+}
+
+/// The nodes and edges of the graph, in the order that they were first seen.
+#[derive(Default)]
+struct Graph {
+    nodes: Vec<Node>,
+    node_index: HashMap<String, usize>,
+    edges: BTreeMap<(String, String), Stats>,
+}
+
+impl Graph {
+    fn node(&mut self, id: String, title: impl ToString, subtitle: &'static str) -> &mut Stats {
+        let index = *self.node_index.entry(id.clone()).or_insert_with(|| {
+            self.nodes.push(Node {
+                id,
+                title: title.to_string(),
+                subtitle,
+                stats: Stats::default(),
+            });
+            self.nodes.len() - 1
+        });
+        &mut self.nodes[index].stats
+    }
+
+    fn stats(&mut self, id: &str) -> &mut Stats {
+        &mut self.nodes[self.node_index[id]].stats
+    }
+
+    fn edge(&mut self, source: &str, target: &str) -> &mut Stats {
+        self.edges
+            .entry((source.to_owned(), target.to_owned()))
+            .or_default()
+    }
+
+    fn into_frames(self) -> Vec<DataFrame> {
+        let mut nodes = DataFrame::new("nodes", &NODE_FIELDS);
+        for node in self.nodes {
+            nodes.push(vec![
+                node.id.into(),
+                node.title.into(),
+                node.subtitle.into(),
+                node.stats.clients.len().into(),
+                node.stats.rate.into(),
+            ]);
+        }
+        let mut edges = DataFrame::new("edges", &EDGE_FIELDS);
+        for ((source, target), stats) in self.edges {
+            edges.push(vec![
+                format!("{source}->{target}").into(),
+                source.into(),
+                target.into(),
+                stats.clients.len().into(),
+                stats.rate.into(),
+            ]);
+        }
+        vec![nodes, edges]
+    }
+}
+
+/// Build the graph of the system. Rooms that balancers disagree about show up under every monolith that they're said to be on.
+pub fn build_graph(
+    state: &SystemState,
+    rates: &TrafficRates,
+    redactor: &Redactor,
+    now_secs: u64,
+) -> Vec<DataFrame> {
+    let client_rate = |balancer: BalancerId, client: ClientId| {
+        rates.connection_rate(
+            balancer,
+            NodeKind::Client,
+            &client.to_string(),
+            now_secs,
+            RATE_WINDOW_SECS,
+        )
+    };
+    let mut graph = Graph::default();
+    let mut room_clients: BTreeMap<(String, String), BTreeSet<(BalancerId, ClientId)>> =
+        BTreeMap::new();
+
+    for balancer in &state.0 {
+        let balancer_node = format!("balancer:{}", balancer.id);
+        graph.node(balancer_node.clone(), balancer.id, "Balancer");
+        for monolith in &balancer.monoliths {
+            let monolith_node = format!("monolith:{}", monolith.id);
+            let monolith_rate = rates.connection_rate(
+                balancer.id,
+                NodeKind::Monolith,
+                &monolith.id.to_string(),
+                now_secs,
+                RATE_WINDOW_SECS,
+            );
+            graph
+                .node(monolith_node.clone(), monolith.id, "Monolith")
+                .rate += monolith_rate;
+            graph.edge(&balancer_node, &monolith_node).rate += monolith_rate;
+
+            for room in &monolith.rooms {
+                let room_name = redactor.room(&room.name);
+                let room_node = room_node_id(monolith.id, &room_name);
+                graph.node(room_node.clone(), &room_name, "Room");
+                graph.edge(&monolith_node, &room_node);
+
+                for client in &room.clients {
+                    let client_rate = client_rate(balancer.id, client.id);
+                    let region_node = region_node_id(&client.edge_region);
+                    let stats = graph.node(region_node.clone(), &client.edge_region, "Edge region");
+                    stats.clients.insert(client.id);
+                    stats.rate += client_rate;
+                    let stats = graph.stats(&balancer_node);
+                    stats.clients.insert(client.id);
+                    stats.rate += client_rate;
+                    graph.stats(&monolith_node).clients.insert(client.id);
+
+                    let stats = graph.edge(&region_node, &balancer_node);
+                    stats.clients.insert(client.id);
+                    stats.rate += client_rate;
+                    graph
+                        .edge(&balancer_node, &monolith_node)
+                        .clients
+                        .insert(client.id);
+                    room_clients
+                        .entry((monolith_node.clone(), room_node.clone()))
+                        .or_default()
+                        .insert((balancer.id, client.id));
+                }
+            }
+        }
+    }
+
+    // Every balancer reports the same rooms, so room stats are only added up once the duplicates are gone.
+    for ((monolith_node, room_node), clients) in room_clients {
+        let rate: f64 = clients
+            .iter()
+            .map(|(balancer, client)| client_rate(*balancer, *client))
+            .sum();
+        let clients: BTreeSet<_> = clients.into_iter().map(|(_, client)| client).collect();
+        let stats = graph.edge(&monolith_node, &room_node);
+        stats.clients.extend(&clients);
+        stats.rate += rate;
+        let stats = graph.stats(&room_node);
+        stats.clients.extend(&clients);
+        stats.rate += rate;
+    }
+
+    graph.into_frames()
+}
+
+fn region_node_id(region: &Region) -> String {
+    format!("region:{}", region)
+}
+
+fn room_node_id(monolith: MonolithId, room: &RoomName) -> String {
+    format!("room:{}:{}", monolith, room)
+}
+
+/// The fields of the nodes frame, named the way the Node Graph panel expects.
+const NODE_FIELDS: [(&str, &str, Option<&str>); 5] = [
+    ("id", "string", None),
+    ("title", "string", None),
+    ("subtitle", "string", None),
+    ("mainstat", "number", Some("Clients")),
+    ("secondarystat", "number", Some("Messages/s")),
+];
+
+const EDGE_FIELDS: [(&str, &str, Option<&str>); 5] = [
+    ("id", "string", None),
+    ("source", "string", None),
+    ("target", "string", None),
+    ("mainstat", "number", Some("Clients")),
+    ("secondarystat", "number", Some("Messages/s")),
+];
+
+/// A data frame in the JSON format that Grafana uses to send frames from backends.
+#[derive(Debug, Clone, Serialize)]
+pub struct DataFrame {
+    schema: serde_json::Value,
+    data: FrameData,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct FrameData {
+    /// One column per field.
+    values: Vec<Vec<serde_json::Value>>,
+}
+
+impl DataFrame {
+    fn new(name: &str, fields: &[(&str, &str, Option<&str>)]) -> Self {
+        let fields: Vec<_> = fields
+            .iter()
+            .map(|(name, kind, display_name)| match display_name {
+                Some(display_name) => json!({
+                    "name": name,
+                    "type": kind,
+                    "config": { "displayName": display_name },
+                }),
+                None => json!({ "name": name, "type": kind }),
+            })
+            .collect();
+        Self {
+            data: FrameData {
+                values: vec![vec![]; fields.len()],
+            },
+            schema: json!({
+                "name": name,
+                "meta": { "preferredVisualisationType": "nodeGraph" },
+                "fields": fields,
+            }),
+        }
+    }
+
+    fn push(&mut self, row: Vec<serde_json::Value>) {
+        for (column, value) in self.data.values.iter_mut().zip(row) {
+            column.push(value);
+        }
+    }
+}
+
+/// Serve the nodes and edges frames for Grafana's Node Graph panel.
+#[get("/state/graph")]
+pub async fn serve_graph(
+    _auth: Authenticated,
+    state: &State<Arc<Mutex<SystemState>>>,
+    rates: &State<Arc<Mutex<TrafficRates>>>,
+    redactor: &State<Redactor>,
+) -> Json<Vec<DataFrame>> {
+    let state = state.lock().await;
+    let rates = rates.lock().await;
+    Json(build_graph(&state, &rates, redactor, now_millis() / 1000))
+}
+
+#[cfg(test)]
+mod tests {
+    use ott_balancer_protocol::collector::{BalancerState, ClientState, MonolithState, RoomState};
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn id<T: From<Uuid>>(n: u128) -> T {
+        Uuid::from_u128(n).into()
+    }
+
+    fn balancer(n: u128, clients: &[(u128, &str)]) -> BalancerState {
+        BalancerState {
+            id: id(n),
+            region: "ord".into(),
+            monoliths: vec![MonolithState {
+                id: id(10),
+                region: "ord".into(),
+                rooms: vec![RoomState {
+                    name: "foo".into(),
+                    clients: clients
+                        .iter()
+                        .map(|(c, region)| ClientState {
+                            id: id(*c),
+                            edge_region: (*region).into(),
+                        })
+                        .collect(),
+                }],
+            }],
+            collected_at: None,
+            error: None,
+        }
+    }
+
+    fn ws_event(balancer: u128, field: &str, node: u128) -> serde_json::Value {
+        json!({
+            "event": "ws",
+            "balancer_id": Uuid::from_u128(balancer),
+            field: Uuid::from_u128(node),
+            "direction": "rx",
+        })
+    }
+
+    /// The rows of a frame, as `(id, mainstat, secondarystat)`.
+    fn rows(frame: &DataFrame) -> Vec<(String, u64, f64)> {
+        let values = &frame.data.values;
+        (0..values[0].len())
+            .map(|i| {
+                (
+                    values[0][i].as_str().unwrap().to_owned(),
+                    values[3][i].as_u64().unwrap(),
+                    values[4][i].as_f64().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_count_messages_by_connection() {
+        let mut rates = TrafficRates::default();
+        for second in [100, 100, 105, 109] {
+            rates.record_event(&ws_event(1, "client_id", 100), second);
+        }
+        rates.record_event(&ws_event(1, "monolith_id", 10), 109);
+        rates.record_event(&ws_event(2, "monolith_id", 10), 109);
+        rates.record_event(
+            &json!({"event": "broadcast", "node_id": "foo", "direction": "tx"}),
+            109,
+        );
+
+        let rate = |balancer: u128, kind: NodeKind, node: u128, now_secs: u64| {
+            rates.connection_rate(
+                id(balancer),
+                kind,
+                &Uuid::from_u128(node).to_string(),
+                now_secs,
+                RATE_WINDOW_SECS,
+            )
+        };
+        assert_eq!(rate(1, NodeKind::Client, 100, 109), 0.4);
+        assert_eq!(rate(1, NodeKind::Client, 100, 110), 0.2);
+        assert_eq!(rate(1, NodeKind::Monolith, 10, 109), 0.1);
+        assert_eq!(rate(1, NodeKind::Client, 10, 109), 0.0);
+        assert_eq!(rate(3, NodeKind::Client, 100, 109), 0.0);
+    }
+
+    #[test]
+    fn should_build_graph_from_state_and_rates() {
+        let state = SystemState(vec![
+            balancer(1, &[(100, "iad"), (101, "ord")]),
+            balancer(2, &[(102, "iad")]),
+        ]);
+        let mut rates = TrafficRates::default();
+        for _ in 0..10 {
+            rates.record_event(&ws_event(1, "client_id", 100), 100);
+        }
+        for _ in 0..20 {
+            rates.record_event(&ws_event(2, "monolith_id", 10), 100);
+        }
+
+        let frames = build_graph(&state, &rates, &Redactor::default(), 100);
+        let b1 = format!("balancer:{}", Uuid::from_u128(1));
+        let b2 = format!("balancer:{}", Uuid::from_u128(2));
+        let m = format!("monolith:{}", Uuid::from_u128(10));
+        let room = format!("room:{}:foo", Uuid::from_u128(10));
+        assert_eq!(
+            rows(&frames[0]),
+            vec![
+                (b1.clone(), 2, 1.0),
+                (m.clone(), 3, 2.0),
+                (room.clone(), 3, 1.0),
+                ("region:iad".to_owned(), 2, 1.0),
+                ("region:ord".to_owned(), 1, 0.0),
+                (b2.clone(), 1, 0.0),
+            ]
+        );
+        assert_eq!(
+            rows(&frames[1]),
+            vec![
+                (format!("{b1}->{m}"), 2, 0.0),
+                (format!("{b2}->{m}"), 1, 2.0),
+                (format!("{m}->{room}"), 3, 1.0),
+                (format!("region:iad->{b1}"), 1, 1.0),
+                (format!("region:iad->{b2}"), 1, 0.0),
+                (format!("region:ord->{b1}"), 1, 0.0),
+            ]
+        );
+    }
+}
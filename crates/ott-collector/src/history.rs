@@ -0,0 +1,380 @@
+//! Keeps a history of the system state, so that room and client counts can be charted over time.
+//!
+//! Counters are kept for [`HistoryConfig::retention`], but full snapshots only for [`HistoryConfig::snapshot_retention`],
+//! because they grow with the number of clients. Only counters are persisted to disk.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use ott_balancer_protocol::Region;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::error;
+use typeshare::typeshare;
+
+use crate::auth::Authenticated;
+use crate::jsonl::{self, JsonlWriter};
+use crate::redact::Redactor;
+use crate::SystemState;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// How long to keep counters for.
+    #[serde(with = "humantime_serde")]
+    pub retention: Duration,
+    /// How long to keep full snapshots of the system state for.
+    #[serde(with = "humantime_serde")]
+    pub snapshot_retention: Duration,
+    /// The most samples to keep, regardless of `retention`.
+    pub max_samples: usize,
+    /// A file to persist counters to, so that they survive restarts.
+    pub persist_path: Option<PathBuf>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            retention: Duration::from_secs(60 * 60 * 24),
+            snapshot_retention: Duration::from_secs(60 * 15),
+            max_samples: 50_000,
+            persist_path: None,
+        }
+    }
+}
+
+/// The system state at a point in time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[typeshare]
+pub struct HistorySample {
+    /// Milliseconds since the Unix epoch.
+    #[typeshare(serialized_as = "number")]
+    pub timestamp: u64,
+    pub balancers: u32,
+    pub monoliths: u32,
+    pub rooms: u32,
+    pub clients: u32,
+    /// Clients connected through each edge region.
+    pub clients_by_region: BTreeMap<Region, u32>,
+    /// Only present on recent samples, and only when asked for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<SystemState>,
+}
+
+impl HistorySample {
+    /// Count everything in `state`. Every balancer reports the same monoliths and rooms, so those are only counted once.
+    pub fn new(timestamp: u64, state: &SystemState) -> Self {
+        let mut monoliths = HashSet::new();
+        let mut rooms = HashSet::new();
+        let mut clients = HashMap::new();
+        for balancer in &state.0 {
+            for monolith in &balancer.monoliths {
+                monoliths.insert(monolith.id);
+                for room in &monolith.rooms {
+                    rooms.insert(&room.name);
+                    for client in &room.clients {
+                        clients.insert(client.id, &client.edge_region);
+                    }
+                }
+            }
+        }
+        let mut clients_by_region = BTreeMap::new();
+        for edge_region in clients.values() {
+            *clients_by_region.entry((*edge_region).clone()).or_default() += 1;
+        }
+        Self {
+            timestamp,
+            balancers: state.0.len() as u32,
+            monoliths: monoliths.len() as u32,
+            rooms: rooms.len() as u32,
+            clients: clients.len() as u32,
+            clients_by_region,
+            state: Some(state.clone()),
+        }
+    }
+
+    fn without_state(&self) -> Self {
+        Self {
+            state: None,
+            ..self.clone()
+        }
+    }
+}
+
+pub struct History {
+    config: HistoryConfig,
+    samples: VecDeque<HistorySample>,
+    writer: Option<JsonlWriter>,
+    /// Lines in the persisted file, including ones for samples that have since been dropped.
+    persisted_lines: usize,
+}
+
+impl History {
+    /// Create a history, loading any samples that were persisted to `config.persist_path`.
+    pub fn open(config: HistoryConfig, now: u64) -> anyhow::Result<Self> {
+        let mut history = Self {
+            config,
+            samples: VecDeque::new(),
+            writer: None,
+            persisted_lines: 0,
+        };
+        let Some(path) = history.config.persist_path.clone() else {
+            return Ok(history);
+        };
+        history.samples = jsonl::load(&path)
+            .context("loading persisted history")?
+            .into();
+        history.prune(now);
+        let lines = history.persisted_lines()?;
+        history.persisted_lines = lines.len();
+        history.writer = Some(JsonlWriter::open(path, &lines).context("persisting history")?);
+        Ok(history)
+    }
+
+    pub fn record(&mut self, sample: HistorySample) {
+        let now = sample.timestamp;
+        self.samples.push_back(sample);
+        self.prune(now);
+        if let Err(err) = self.persist() {
+            error!("Failed to persist history sample: {:#}", err);
+        }
+    }
+
+    /// Samples between `from` and `to`, inclusive. When `step` is given, only the last sample in each step is returned.
+    pub fn query(
+        &self,
+        from: Option<u64>,
+        to: Option<u64>,
+        step: Option<u64>,
+        include_state: bool,
+    ) -> Vec<HistorySample> {
+        let from = from.unwrap_or(0);
+        let to = to.unwrap_or(u64::MAX);
+        let mut result: Vec<HistorySample> = vec![];
+        let mut last_bucket = None;
+        for sample in &self.samples {
+            if sample.timestamp < from || sample.timestamp > to {
+                continue;
+            }
+            let sample = if include_state {
+                sample.clone()
+            } else {
+                sample.without_state()
+            };
+            let bucket = step
+                .filter(|s| *s > 0)
+                .map(|s| (sample.timestamp - from) / s);
+            if bucket.is_some() && bucket == last_bucket {
+                *result.last_mut().expect("bucket should have a sample") = sample;
+            } else {
+                result.push(sample);
+            }
+            last_bucket = bucket;
+        }
+        result
+    }
+
+    fn prune(&mut self, now: u64) {
+        let cutoff = now.saturating_sub(self.config.retention.as_millis() as u64);
+        while self
+            .samples
+            .front()
+            .is_some_and(|s| s.timestamp < cutoff || self.samples.len() > self.config.max_samples)
+        {
+            self.samples.pop_front();
+        }
+
+        // samples with a snapshot are always the most recent ones
+        let snapshot_cutoff = now.saturating_sub(self.config.snapshot_retention.as_millis() as u64);
+        for sample in self
+            .samples
+            .iter_mut()
+            .rev()
+            .skip_while(|s| s.timestamp >= snapshot_cutoff)
+            .take_while(|s| s.state.is_some())
+        {
+            sample.state = None;
+        }
+    }
+
+    /// Persist the latest sample. The file is rewritten once most of it is samples that have been dropped.
+    fn persist(&mut self) -> anyhow::Result<()> {
+        let Some(writer) = &self.writer else {
+            return Ok(());
+        };
+        if writer.needs_rewrite() || self.persisted_lines > self.samples.len() * 2 + 100 {
+            let lines = self.persisted_lines()?;
+            self.persisted_lines = lines.len();
+            writer.rewrite(lines);
+        } else if let Some(sample) = self.samples.back() {
+            writer.append(serde_json::to_string(&sample.without_state())?);
+            self.persisted_lines += 1;
+        }
+        Ok(())
+    }
+
+    /// Only counters are persisted.
+    fn persisted_lines(&self) -> anyhow::Result<Vec<String>> {
+        let samples: Vec<_> = self.samples.iter().map(|s| s.without_state()).collect();
+        jsonl::to_lines(&samples)
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Serve past samples of the system state. `from` and `to` are milliseconds since the Unix epoch, and `step` is in milliseconds.
+#[get("/state/history?<from>&<to>&<step>&<include_state>")]
+pub async fn serve_history(
+    _auth: Authenticated,
+    history: &State<Arc<Mutex<History>>>,
+    redactor: &State<Redactor>,
+    from: Option<u64>,
+    to: Option<u64>,
+    step: Option<u64>,
+    include_state: Option<bool>,
+) -> Json<Vec<HistorySample>> {
+    let samples = history
+        .lock()
+        .await
+        .query(from, to, step, include_state.unwrap_or(false));
+    Json(
+        samples
+            .into_iter()
+            .map(|sample| redactor.history_sample(sample))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use ott_balancer_protocol::collector::{BalancerState, ClientState, MonolithState, RoomState};
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn balancer(clients: &[(u128, &str)]) -> BalancerState {
+        BalancerState {
+            id: Uuid::new_v4().into(),
+            region: "ord".into(),
+            monoliths: vec![MonolithState {
+                id: Uuid::from_u128(1).into(),
+                region: "ord".into(),
+                rooms: vec![
+                    RoomState {
+                        name: "foo".into(),
+                        clients: clients
+                            .iter()
+                            .map(|(id, edge_region)| ClientState {
+                                id: Uuid::from_u128(*id).into(),
+                                edge_region: (*edge_region).into(),
+                            })
+                            .collect(),
+                    },
+                    RoomState {
+                        name: "bar".into(),
+                        clients: vec![],
+                    },
+                ],
+            }],
+            collected_at: None,
+            error: None,
+        }
+    }
+
+    fn config() -> HistoryConfig {
+        HistoryConfig {
+            retention: Duration::from_secs(100),
+            snapshot_retention: Duration::from_secs(10),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn should_count_monoliths_and_rooms_once() {
+        let state = SystemState(vec![
+            balancer(&[(10, "ord"), (11, "iad")]),
+            balancer(&[(12, "ord")]),
+        ]);
+        let sample = HistorySample::new(0, &state);
+        assert_eq!(sample.balancers, 2);
+        assert_eq!(sample.monoliths, 1);
+        assert_eq!(sample.rooms, 2);
+        assert_eq!(sample.clients, 3);
+        assert_eq!(
+            sample.clients_by_region,
+            BTreeMap::from([("iad".into(), 1), ("ord".into(), 2)])
+        );
+    }
+
+    #[test]
+    fn should_drop_old_samples_and_snapshots() {
+        let mut history = History::open(config(), 0).unwrap();
+        let state = SystemState(vec![balancer(&[])]);
+        for secs in [0, 50, 94, 101, 105] {
+            history.record(HistorySample::new(secs * 1000, &state));
+        }
+
+        let samples = history.query(None, None, None, true);
+        let timestamps: Vec<_> = samples.iter().map(|s| s.timestamp).collect();
+        assert_eq!(timestamps, vec![50_000, 94_000, 101_000, 105_000]);
+        let with_state: Vec<_> = samples.iter().map(|s| s.state.is_some()).collect();
+        assert_eq!(with_state, vec![false, false, true, true]);
+
+        assert!(history
+            .query(None, None, None, false)
+            .iter()
+            .all(|s| s.state.is_none()));
+    }
+
+    #[test]
+    fn should_take_last_sample_in_each_step() {
+        let mut history = History::open(config(), 0).unwrap();
+        for (secs, clients) in [(0, 1), (5, 2), (10, 3), (12, 4), (25, 5)] {
+            let clients: Vec<_> = (0..clients).map(|id| (id, "ord")).collect();
+            history.record(HistorySample::new(
+                secs * 1000,
+                &SystemState(vec![balancer(&clients)]),
+            ));
+        }
+
+        let samples = history.query(Some(1000), Some(20_000), Some(10_000), false);
+        let points: Vec<_> = samples.iter().map(|s| (s.timestamp, s.clients)).collect();
+        assert_eq!(points, vec![(10_000, 3), (12_000, 4)]);
+    }
+
+    #[test]
+    fn should_load_persisted_counters() {
+        let path = std::env::temp_dir().join(format!("ott-collector-history-{}", Uuid::new_v4()));
+        let config = HistoryConfig {
+            persist_path: Some(path.clone()),
+            ..config()
+        };
+        let state = SystemState(vec![balancer(&[(10, "ord")])]);
+
+        let mut history = History::open(config.clone(), 0).unwrap();
+        for secs in [0, 50, 90] {
+            history.record(HistorySample::new(secs * 1000, &state));
+        }
+        drop(history);
+
+        let history = History::open(config, 120_000).unwrap();
+        let samples = history.query(None, None, None, true);
+        let timestamps: Vec<_> = samples.iter().map(|s| s.timestamp).collect();
+        assert_eq!(timestamps, vec![50_000, 90_000]);
+        assert!(samples.iter().all(|s| s.clients == 1 && s.state.is_none()));
+        let persisted = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(persisted.lines().count(), 2);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
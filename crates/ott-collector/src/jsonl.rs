@@ -0,0 +1,330 @@
+//! Persists records as JSON lines, so that they survive restarts.
+//!
+//! Writes happen on a thread of their own, so that slow or failing disks never hold up the locks that the
+//! records are kept behind.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::{error, warn};
+
+/// How many lines can be waiting to be written before new ones are dropped.
+const QUEUE_SIZE: usize = 10_000;
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Load the records in a JSON lines file, skipping lines that can't be parsed.
+pub fn load<T: DeserializeOwned>(path: &Path) -> anyhow::Result<Vec<T>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err).with_context(|| format!("opening {}", path.display())),
+    };
+    let mut records = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("reading {}", path.display()))?;
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(err) => warn!("Skipping invalid line in {}: {}", path.display(), err),
+        }
+    }
+    Ok(records)
+}
+
+/// Serialize records into lines for [`JsonlWriter::rewrite`].
+pub fn to_lines<'a, T: Serialize + 'a>(
+    records: impl IntoIterator<Item = &'a T>,
+) -> anyhow::Result<Vec<String>> {
+    records
+        .into_iter()
+        .map(|record| Ok(serde_json::to_string(record)?))
+        .collect()
+}
+
+/// Replace the contents of the file with `lines`, and open it for appending.
+fn rewrite_file(path: &Path, lines: &[String]) -> anyhow::Result<BufWriter<File>> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = BufWriter::new(File::create(&tmp_path)?);
+    for line in lines {
+        writeln!(file, "{}", line)?;
+    }
+    file.flush()?;
+    drop(file);
+    std::fs::rename(&tmp_path, path)?;
+    let file = std::fs::OpenOptions::new().append(true).open(path)?;
+    Ok(BufWriter::new(file))
+}
+
+enum Command {
+    Append(String),
+    Rewrite(Vec<String>),
+}
+
+/// Tracks dropped lines, so that the file is rewritten at most once for however many were dropped.
+#[derive(Default)]
+struct RewriteState {
+    /// Lines were dropped since the last rewrite was asked for.
+    dropped: AtomicBool,
+    /// The writer is asking the owner for a rewrite.
+    requested: AtomicBool,
+    /// A rewrite was asked for, and the writer hasn't handled it yet.
+    pending: AtomicBool,
+}
+
+/// Appends lines to a file from a background thread.
+///
+/// If a write fails, or the writer falls behind, lines are dropped. Once the writer has caught up on everything that
+/// was queued (and any backoff has passed), [`JsonlWriter::needs_rewrite`] asks the owner for everything it still
+/// keeps, so that the file catches up on what was dropped. Only one rewrite is asked for at a time.
+///
+/// Dropping the writer waits for everything that was sent to it to be written.
+pub struct JsonlWriter {
+    /// Only `None` while dropping.
+    tx: Option<tokio::sync::mpsc::Sender<Command>>,
+    rewrites: Arc<RewriteState>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl JsonlWriter {
+    /// Replace the contents of `path` with `lines`, and start appending to it.
+    pub fn open(path: PathBuf, lines: &[String]) -> anyhow::Result<Self> {
+        let file =
+            rewrite_file(&path, lines).with_context(|| format!("rewriting {}", path.display()))?;
+        let (tx, rx) = tokio::sync::mpsc::channel(QUEUE_SIZE);
+        let rewrites = Arc::new(RewriteState::default());
+        let task = WriterTask {
+            path,
+            file: Some(file),
+            backoff: MIN_BACKOFF,
+            retry_at: Instant::now(),
+            rewrites: rewrites.clone(),
+        };
+        let thread = std::thread::Builder::new()
+            .name("jsonl writer".into())
+            .spawn(move || task.run(rx))?;
+        Ok(Self {
+            tx: Some(tx),
+            rewrites,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn append(&self, line: String) {
+        self.send(Command::Append(line));
+    }
+
+    /// Replace the contents of the file with `lines`.
+    pub fn rewrite(&self, lines: Vec<String>) {
+        self.send(Command::Rewrite(lines));
+    }
+
+    /// Whether lines were dropped, and the owner should [`JsonlWriter::rewrite`] the file. Only returns `true` once
+    /// per request.
+    pub fn needs_rewrite(&self) -> bool {
+        self.rewrites.requested.swap(false, Ordering::Relaxed)
+    }
+
+    fn send(&self, command: Command) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        match tx.try_send(command) {
+            Ok(()) => {}
+            Err(TrySendError::Full(command)) => {
+                if !self.rewrites.dropped.swap(true, Ordering::Relaxed) {
+                    warn!("Persisting is falling behind, dropping lines");
+                }
+                if let Command::Rewrite(_) = command {
+                    // the writer will ask again once it has caught up
+                    self.rewrites.pending.store(false, Ordering::Relaxed);
+                }
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+impl Drop for JsonlWriter {
+    fn drop(&mut self) {
+        // closing the channel stops the thread once it has written everything
+        self.tx = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct WriterTask {
+    path: PathBuf,
+    /// `None` after a write failed.
+    file: Option<BufWriter<File>>,
+    backoff: Duration,
+    retry_at: Instant,
+    rewrites: Arc<RewriteState>,
+}
+
+impl WriterTask {
+    fn run(mut self, mut rx: tokio::sync::mpsc::Receiver<Command>) {
+        while let Some(command) = rx.blocking_recv() {
+            self.handle(command);
+            // only flush once there's nothing else to write
+            while let Ok(command) = rx.try_recv() {
+                self.handle(command);
+            }
+            if let Some(file) = &mut self.file {
+                if let Err(err) = file.flush() {
+                    self.fail(err.into());
+                }
+            }
+            self.request_rewrite();
+        }
+    }
+
+    /// Ask for a rewrite if lines were dropped. Only called once the queue has been drained, so that the rewrite
+    /// doesn't just get dropped as well.
+    fn request_rewrite(&self) {
+        if self.file.is_none() && Instant::now() < self.retry_at {
+            return;
+        }
+        if self.rewrites.pending.load(Ordering::Relaxed) {
+            return;
+        }
+        if self.rewrites.dropped.swap(false, Ordering::Relaxed) {
+            self.rewrites.pending.store(true, Ordering::Relaxed);
+            self.rewrites.requested.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Append(line) => {
+                let Some(file) = &mut self.file else {
+                    self.rewrites.dropped.store(true, Ordering::Relaxed);
+                    return;
+                };
+                if let Err(err) = writeln!(file, "{}", line) {
+                    self.fail(err.into());
+                }
+            }
+            Command::Rewrite(lines) => {
+                self.rewrites.pending.store(false, Ordering::Relaxed);
+                if self.file.is_none() && Instant::now() < self.retry_at {
+                    self.rewrites.dropped.store(true, Ordering::Relaxed);
+                    return;
+                }
+                self.file = None;
+                match rewrite_file(&self.path, &lines) {
+                    Ok(file) => {
+                        self.file = Some(file);
+                        self.backoff = MIN_BACKOFF;
+                    }
+                    Err(err) => self.fail(err),
+                }
+            }
+        }
+    }
+
+    fn fail(&mut self, err: anyhow::Error) {
+        self.rewrites.dropped.store(true, Ordering::Relaxed);
+        error!(
+            "Failed to write to {}, retrying in {:?}: {:#}",
+            self.path.display(),
+            self.backoff,
+            err
+        );
+        self.file = None;
+        self.retry_at = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_append_and_rewrite() {
+        let path =
+            std::env::temp_dir().join(format!("ott-collector-jsonl-{}", uuid::Uuid::new_v4()));
+        let writer = JsonlWriter::open(path.clone(), &["1".into()]).unwrap();
+        writer.append("2".into());
+        writer.rewrite(vec!["3".into()]);
+        writer.append("4".into());
+        drop(writer);
+
+        assert_eq!(load::<u32>(&path).unwrap(), vec![3, 4]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn should_ask_for_rewrite_after_failing() {
+        let dir =
+            std::env::temp_dir().join(format!("ott-collector-jsonl-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("records.jsonl");
+        let mut task = WriterTask {
+            path: path.clone(),
+            file: None,
+            backoff: MIN_BACKOFF,
+            retry_at: Instant::now() + Duration::from_secs(60),
+            rewrites: Arc::new(RewriteState::default()),
+        };
+
+        // still backing off, so nothing is written and no rewrite is asked for
+        task.handle(Command::Append("1".into()));
+        task.handle(Command::Rewrite(vec!["1".into()]));
+        task.request_rewrite();
+        assert!(!task.rewrites.requested.load(Ordering::Relaxed));
+        assert!(!path.exists());
+
+        task.retry_at = Instant::now();
+        task.handle(Command::Append("2".into()));
+        task.request_rewrite();
+        assert!(task.rewrites.requested.load(Ordering::Relaxed));
+        task.handle(Command::Rewrite(vec!["1".into(), "2".into()]));
+        task.handle(Command::Append("3".into()));
+        task.file.as_mut().unwrap().flush().unwrap();
+
+        assert_eq!(load::<u32>(&path).unwrap(), vec![1, 2, 3]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_only_ask_for_one_rewrite_at_a_time() {
+        let path =
+            std::env::temp_dir().join(format!("ott-collector-jsonl-{}", uuid::Uuid::new_v4()));
+        let writer = JsonlWriter::open(path.clone(), &[]).unwrap();
+        let mut task = WriterTask {
+            path: path.clone(),
+            file: Some(rewrite_file(&path, &[]).unwrap()),
+            backoff: MIN_BACKOFF,
+            retry_at: Instant::now(),
+            rewrites: writer.rewrites.clone(),
+        };
+
+        writer.rewrites.dropped.store(true, Ordering::Relaxed);
+        task.request_rewrite();
+        assert!(writer.needs_rewrite());
+
+        // more lines get dropped before the rewrite makes it to the writer
+        writer.rewrites.dropped.store(true, Ordering::Relaxed);
+        task.request_rewrite();
+        assert!(!writer.needs_rewrite());
+
+        task.handle(Command::Rewrite(vec!["1".into()]));
+        task.request_rewrite();
+        assert!(writer.needs_rewrite());
+        assert!(!writer.needs_rewrite());
+
+        drop(writer);
+        std::fs::remove_file(path).unwrap();
+    }
+}
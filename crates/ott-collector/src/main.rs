@@ -1,35 +1,57 @@
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
 use anyhow::Context;
 use clap::Parser;
-use collector::{Collector, CURRENT_STATE};
+use collector::{Collector, StreamHealth, TrackedStream, CURRENT_STATE, STREAM_HEALTH};
+use history::History;
 use ott_balancer_protocol::collector::BalancerState;
-use ott_common::discovery::{
-    start_discovery_task, DiscoveryConfig, DnsServiceDiscoverer, FlyServiceDiscoverer,
-    HarnessServiceDiscoverer, ManualServiceDiscoverer,
-};
+use ott_common::discovery::start_discovery_task;
 use rocket::{serde::json::Json, State};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 use typeshare::typeshare;
 
 #[macro_use]
 extern crate rocket;
 
+mod aggregate;
+mod alerts;
+mod auth;
 mod collector;
 mod config;
 mod cors;
 mod event_bus;
+mod event_log;
+mod graph;
+mod history;
+mod jsonl;
+mod metrics;
+mod rates;
+mod redact;
+#[cfg(test)]
+mod test_util;
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[typeshare]
 pub struct SystemState(Vec<BalancerState>);
 
+/// The health of the collector itself.
+#[derive(Debug, Clone, Serialize)]
+#[typeshare]
+pub struct CollectorStatus {
+    pub streams: Vec<StreamHealth>,
+}
+
 /// Serve the current system state
 #[get("/state")]
-async fn serve_state(state: &State<Arc<Mutex<SystemState>>>) -> Json<SystemState> {
+async fn serve_state(
+    _auth: auth::Authenticated,
+    state: &State<Arc<Mutex<SystemState>>>,
+    redactor: &State<redact::Redactor>,
+) -> Json<SystemState> {
     let s = state.lock().await.clone();
-    Json(s)
+    Json(redactor.system_state(s))
 }
 
 #[rocket::main]
@@ -40,61 +62,117 @@ async fn main() -> anyhow::Result<()> {
 
     let (discovery_tx, discovery_rx) = tokio::sync::mpsc::channel(2);
 
-    let _discovery_handle = match &config.discovery {
-        DiscoveryConfig::Dns(config) => {
-            let discovery = DnsServiceDiscoverer::new(config.clone());
-            start_discovery_task(discovery, discovery_tx)
-        }
-        DiscoveryConfig::Fly(config) => {
-            let discovery = FlyServiceDiscoverer::new(config.clone());
-            start_discovery_task(discovery, discovery_tx)
-        }
-        DiscoveryConfig::Manual(config) => {
-            let discovery = ManualServiceDiscoverer::new(config.clone());
-            start_discovery_task(discovery, discovery_tx)
-        }
-        DiscoveryConfig::Harness(config) => {
-            let discovery = HarnessServiceDiscoverer::new(config.clone());
-            start_discovery_task(discovery, discovery_tx)
-        }
-    };
+    let _discovery_handle = start_discovery_task(config.discovery.discoverer(), discovery_tx);
+
+    let history = Arc::new(Mutex::new(
+        History::open(config.history.clone(), history::now_millis())
+            .context("loading state history")?,
+    ));
 
     let (events_tx, events_rx) = tokio::sync::mpsc::channel(100);
-    let _collector_handle = Collector::new(
-        discovery_rx,
-        events_tx,
-        config.collect_interval,
-        config.balancer_api_key,
-    )
-    .spawn();
-
-    let event_bus = event_bus::EventBus::new(events_rx);
+    let _collector_handle =
+        Collector::new(discovery_rx, events_tx, &config, history.clone()).spawn();
+
+    let event_log = Arc::new(Mutex::new(
+        event_log::EventLog::open(config.event_log.clone(), history::now_millis())
+            .context("loading event log")?,
+    ));
+
+    let event_bus = event_bus::EventBus::new(events_rx, event_log.clone());
     let event_subscriber = event_bus.subscriber();
     let _event_bus_task = event_bus.spawn();
 
     rocket::build()
-        .attach(cors::Cors)
+        .attach(cors::Cors::new(config.cors.clone()))
+        .attach(auth::StripAccessToken)
         .mount(
             "/",
             routes![
                 status,
+                stream_status,
                 cors::handle_preflight,
                 serve_state,
-                event_bus::event_stream
+                aggregate::serve_aggregate,
+                graph::serve_graph,
+                history::serve_history,
+                alerts::serve_alerts,
+                metrics::serve_metrics,
+                event_bus::event_stream,
+                event_log::serve_events,
+                rates::serve_rates,
+                rates::stream_rates,
             ],
         )
         .manage(CURRENT_STATE.clone())
+        .manage(history)
+        .manage(event_log)
+        .manage(alerts::CURRENT_ALERTS.clone())
+        .manage(STREAM_HEALTH.clone())
+        .manage(rates::TRAFFIC_RATES.clone())
+        .manage(config.rollup.clone())
         .manage(event_subscriber)
+        .manage(config.auth.clone())
+        .manage(redact::Redactor::new(config.redact.clone()))
         .launch()
         .await?;
 
     Ok(())
 }
 
+/// Whether the collector is up. This doesn't need credentials, so that health checks can use it.
 #[get("/status")]
 fn status() -> &'static str {
     "OK"
 }
 
+/// The health of the stream from each balancer.
+#[get("/status/streams")]
+async fn stream_status(
+    _auth: auth::Authenticated,
+    streams: &State<Arc<Mutex<BTreeMap<String, TrackedStream>>>>,
+) -> Json<CollectorStatus> {
+    let streams = streams
+        .lock()
+        .await
+        .values()
+        .map(TrackedStream::health)
+        .collect();
+    Json(CollectorStatus { streams })
+}
+
 #[cfg(test)]
-mod test {}
+mod test {
+    use rocket::http::{Header, Status};
+    use rocket::local::asynchronous::Client;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn should_only_serve_stream_health_with_credentials() {
+        let rocket = rocket::build()
+            .mount("/", routes![status, stream_status])
+            .manage(Arc::new(Mutex::new(
+                BTreeMap::<String, TrackedStream>::new(),
+            )))
+            .manage(auth::AuthConfig {
+                tokens: vec!["token".to_owned()],
+                users: vec![],
+            });
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let resp = client.get("/status").dispatch().await;
+        assert_eq!(resp.status(), Status::Ok);
+        assert_eq!(resp.into_string().await.unwrap(), "OK");
+
+        let resp = client.get("/status/streams").dispatch().await;
+        assert_eq!(resp.status(), Status::Unauthorized);
+
+        let resp = client
+            .get("/status/streams")
+            .header(Header::new("Authorization", "Bearer token"))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::Ok);
+        assert_eq!(resp.into_string().await.unwrap(), r#"{"streams":[]}"#);
+    }
+}
@@ -0,0 +1,289 @@
+//! Exports the collected state as Prometheus metrics.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use once_cell::sync::Lazy;
+use ott_balancer_protocol::{ClientId, MonolithId, Region, RoomName};
+use ott_common::discovery::ConnectionConfig;
+use prometheus::{
+    register_int_counter_vec, register_int_gauge_vec, Encoder, IntCounterVec, IntGaugeVec,
+    TextEncoder,
+};
+
+use crate::auth::Authenticated;
+use crate::SystemState;
+
+/// Set the gauges for the whole system from the latest collected state.
+///
+/// Monoliths, rooms and clients are counted once, even though every balancer reports them, and grouped by the region of their monolith.
+pub fn update_state_gauges(state: &SystemState) {
+    let mut monoliths: HashMap<MonolithId, &Region> = HashMap::new();
+    let mut rooms: HashMap<&RoomName, &Region> = HashMap::new();
+    let mut clients: HashMap<ClientId, &Region> = HashMap::new();
+    for balancer in &state.0 {
+        for monolith in &balancer.monoliths {
+            monoliths.insert(monolith.id, &monolith.region);
+            for room in &monolith.rooms {
+                rooms.insert(&room.name, &monolith.region);
+                for client in &room.clients {
+                    clients.insert(client.id, &monolith.region);
+                }
+            }
+        }
+    }
+
+    for (gauge, counts) in [
+        (&*GAUGE_MONOLITHS, count_by_region(monoliths.into_values())),
+        (&*GAUGE_ROOMS, count_by_region(rooms.into_values())),
+        (&*GAUGE_CLIENTS, count_by_region(clients.into_values())),
+    ] {
+        gauge.reset();
+        for (region, count) in counts {
+            gauge.with_label_values(&[region.as_str()]).set(count);
+        }
+    }
+}
+
+fn count_by_region<'a>(regions: impl Iterator<Item = &'a Region>) -> BTreeMap<&'a Region, i64> {
+    let mut counts = BTreeMap::new();
+    for region in regions {
+        *counts.entry(region).or_default() += 1;
+    }
+    counts
+}
+
+/// The label that identifies a balancer. Balancers that can't be reached haven't told us their id, so their address is used instead.
+pub fn balancer_label(conf: &ConnectionConfig) -> String {
+    let url = conf.uri();
+    format!("{}:{}", url.host_str().unwrap_or_default(), conf.port)
+}
+
+/// Record whether collecting state from a balancer succeeded.
+pub fn record_collection(conf: &ConnectionConfig, success: bool) {
+    let label = balancer_label(conf);
+    GAUGE_BALANCER_UP
+        .with_label_values(&[&label])
+        .set(success as i64);
+    if success {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        GAUGE_BALANCER_LAST_SUCCESS
+            .with_label_values(&[&label])
+            .set(now as i64);
+    }
+}
+
+/// Record that the event stream from a balancer connected.
+pub fn record_stream_connected(conf: &ConnectionConfig) {
+    GAUGE_STREAM_CONNECTED
+        .with_label_values(&[&balancer_label(conf)])
+        .set(1);
+}
+
+/// Record that the event stream from a balancer ended, and is going to be restarted.
+pub fn record_stream_disconnected(conf: &ConnectionConfig) {
+    let label = balancer_label(conf);
+    GAUGE_STREAM_CONNECTED.with_label_values(&[&label]).set(0);
+    COUNTER_STREAM_RECONNECTS.with_label_values(&[&label]).inc();
+}
+
+/// Record a message from a balancer's event stream that couldn't be parsed.
+pub fn record_parse_failure(conf: &ConnectionConfig) {
+    COUNTER_EVENT_PARSE_FAILURES
+        .with_label_values(&[&balancer_label(conf)])
+        .inc();
+}
+
+/// Stop reporting metrics for balancers that are gone.
+pub fn forget_balancers(removed: &[ConnectionConfig]) {
+    let removed: HashSet<_> = removed.iter().map(balancer_label).collect();
+    for label in &removed {
+        let _ = GAUGE_BALANCER_UP.remove_label_values(&[label]);
+        let _ = GAUGE_BALANCER_LAST_SUCCESS.remove_label_values(&[label]);
+        let _ = GAUGE_STREAM_CONNECTED.remove_label_values(&[label]);
+        let _ = COUNTER_STREAM_RECONNECTS.remove_label_values(&[label]);
+        let _ = COUNTER_EVENT_PARSE_FAILURES.remove_label_values(&[label]);
+    }
+}
+
+/// Count an event that came through the event bus, or one that couldn't be parsed.
+pub fn count_event(event: Option<&serde_json::Value>) {
+    COUNTER_EVENTS
+        .with_label_values(&[event.map_or("unknown", event_type)])
+        .inc();
+}
+
+/// The `event` field of a traffic event, or the `type` of a lifecycle event.
+fn event_type(event: &serde_json::Value) -> &str {
+    match event.get("event") {
+        Some(serde_json::Value::String(kind)) => kind,
+        Some(event) => event
+            .get("type")
+            .and_then(|t| t.as_str())
+            .unwrap_or("unknown"),
+        None => "unknown",
+    }
+}
+
+/// Serve metrics in the Prometheus text format.
+#[get("/metrics")]
+pub fn serve_metrics(
+    _auth: Authenticated,
+) -> Result<String, rocket::response::Debug<anyhow::Error>> {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(anyhow::Error::from)?;
+    Ok(String::from_utf8(buffer).map_err(anyhow::Error::from)?)
+}
+
+static GAUGE_MONOLITHS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "collector_monoliths",
+        "Number of monoliths in each region",
+        &["region"]
+    )
+    .unwrap()
+});
+
+static GAUGE_ROOMS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "collector_rooms",
+        "Number of rooms loaded on monoliths in each region",
+        &["region"]
+    )
+    .unwrap()
+});
+
+static GAUGE_CLIENTS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "collector_clients",
+        "Number of clients in rooms on monoliths in each region",
+        &["region"]
+    )
+    .unwrap()
+});
+
+static GAUGE_BALANCER_UP: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "collector_balancer_up",
+        "Whether the last attempt to collect state from a balancer succeeded",
+        &["balancer"]
+    )
+    .unwrap()
+});
+
+static GAUGE_BALANCER_LAST_SUCCESS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "collector_balancer_last_success_timestamp_seconds",
+        "When state was last collected from a balancer, in seconds since the Unix epoch",
+        &["balancer"]
+    )
+    .unwrap()
+});
+
+static GAUGE_STREAM_CONNECTED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "collector_stream_connected",
+        "Whether the event stream from a balancer is connected",
+        &["balancer"]
+    )
+    .unwrap()
+});
+
+static COUNTER_STREAM_RECONNECTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "collector_stream_reconnects_total",
+        "Number of times the event stream from a balancer has ended and been restarted",
+        &["balancer"]
+    )
+    .unwrap()
+});
+
+static COUNTER_EVENT_PARSE_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "collector_event_parse_failures_total",
+        "Number of messages from a balancer's event stream that couldn't be parsed",
+        &["balancer"]
+    )
+    .unwrap()
+});
+
+static COUNTER_EVENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "collector_events_total",
+        "Count of events received from balancers, by type",
+        &["type"]
+    )
+    .unwrap()
+});
+
+#[cfg(test)]
+mod tests {
+    use ott_balancer_protocol::collector::{BalancerState, ClientState, MonolithState, RoomState};
+    use ott_common::discovery::HostOrIp;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn should_count_each_monolith_room_and_client_once() {
+        let balancer = |clients: &[u128]| BalancerState {
+            id: Uuid::new_v4().into(),
+            region: "ord".into(),
+            monoliths: vec![
+                MonolithState {
+                    id: Uuid::from_u128(1).into(),
+                    region: "ord".into(),
+                    rooms: vec![RoomState {
+                        name: "foo".into(),
+                        clients: clients
+                            .iter()
+                            .map(|c| ClientState {
+                                id: Uuid::from_u128(*c).into(),
+                                edge_region: "iad".into(),
+                            })
+                            .collect(),
+                    }],
+                },
+                MonolithState {
+                    id: Uuid::from_u128(2).into(),
+                    region: "ams".into(),
+                    rooms: vec![],
+                },
+            ],
+            collected_at: None,
+            error: None,
+        };
+        update_state_gauges(&SystemState(vec![balancer(&[10, 11]), balancer(&[12])]));
+
+        assert_eq!(GAUGE_MONOLITHS.with_label_values(&["ord"]).get(), 1);
+        assert_eq!(GAUGE_MONOLITHS.with_label_values(&["ams"]).get(), 1);
+        assert_eq!(GAUGE_ROOMS.with_label_values(&["ord"]).get(), 1);
+        assert_eq!(GAUGE_CLIENTS.with_label_values(&["ord"]).get(), 3);
+    }
+
+    #[test]
+    fn should_label_balancers_by_address() {
+        let conf = ConnectionConfig {
+            host: HostOrIp::Ip("::1".parse().unwrap()),
+            port: 8081,
+        };
+        assert_eq!(balancer_label(&conf), "[::1]:8081");
+    }
+
+    #[test]
+    fn should_find_event_type() {
+        assert_eq!(event_type(&json!({"event": "ws", "direction": "tx"})), "ws");
+        assert_eq!(
+            event_type(
+                &json!({"balancer_id": "x", "event": {"type": "room_loaded", "payload": {}}})
+            ),
+            "room_loaded"
+        );
+        assert_eq!(event_type(&json!({"direction": "tx"})), "unknown");
+    }
+}
@@ -0,0 +1,507 @@
+//! Rolls traffic events up into rates, so that subscribers don't have to keep up with every event.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use ott_balancer_protocol::{BalancerId, RoomName};
+use rocket::futures::SinkExt;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use typeshare::typeshare;
+use uuid::Uuid;
+
+use crate::auth::Authenticated;
+use crate::history::now_millis;
+use crate::redact::Redactor;
+
+pub static TRAFFIC_RATES: Lazy<Arc<Mutex<TrafficRates>>> =
+    Lazy::new(|| Arc::new(Mutex::new(TrafficRates::default())));
+
+/// The longest window that rates can be asked for over.
+const MAX_WINDOW_SECS: u64 = 300;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RollupConfig {
+    /// The window that rates are averaged over, unless another one is asked for.
+    #[serde(with = "humantime_serde")]
+    pub window: Duration,
+    /// How often to send rollups to subscribers of the rates stream.
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+impl Default for RollupConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(10),
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Counts of things in each second, over a sliding window.
+#[derive(Debug)]
+pub struct SlidingCounts<K> {
+    window_secs: u64,
+    /// The count in each second, oldest first.
+    counts: HashMap<K, VecDeque<(u64, u32)>>,
+    last_pruned: u64,
+}
+
+impl<K: Eq + Hash> SlidingCounts<K> {
+    pub fn new(window_secs: u64) -> Self {
+        Self {
+            window_secs,
+            counts: HashMap::new(),
+            last_pruned: 0,
+        }
+    }
+
+    pub fn record(&mut self, key: K, now_secs: u64) {
+        let counts = self.counts.entry(key).or_default();
+        match counts.back_mut() {
+            Some((second, count)) if *second == now_secs => *count += 1,
+            _ => counts.push_back((now_secs, 1)),
+        }
+        if self.last_pruned < now_secs {
+            self.prune(now_secs);
+        }
+    }
+
+    /// Forget about counts that have left the window, so that keys that are gone don't pile up.
+    fn prune(&mut self, now_secs: u64) {
+        let cutoff = now_secs.saturating_sub(self.window_secs);
+        self.counts.retain(|_, counts| {
+            while counts.front().is_some_and(|(second, _)| *second <= cutoff) {
+                counts.pop_front();
+            }
+            !counts.is_empty()
+        });
+        self.last_pruned = now_secs;
+    }
+
+    /// The average count per second over the last `over_secs` seconds, which is capped to the window.
+    pub fn rate(&self, key: &K, now_secs: u64, over_secs: u64) -> f64 {
+        self.counts
+            .get(key)
+            .map(|counts| Self::rate_of(counts, now_secs, over_secs.min(self.window_secs)))
+            .unwrap_or_default()
+    }
+
+    /// The rates of every key that was counted in the last `over_secs` seconds.
+    pub fn rates(&self, now_secs: u64, over_secs: u64) -> impl Iterator<Item = (&K, f64)> {
+        let over_secs = over_secs.min(self.window_secs);
+        self.counts
+            .iter()
+            .map(move |(key, counts)| (key, Self::rate_of(counts, now_secs, over_secs)))
+            .filter(|(_, rate)| *rate > 0.0)
+    }
+
+    fn rate_of(counts: &VecDeque<(u64, u32)>, now_secs: u64, over_secs: u64) -> f64 {
+        if over_secs == 0 {
+            return 0.0;
+        }
+        let cutoff = now_secs.saturating_sub(over_secs);
+        let total: u32 = counts
+            .iter()
+            .rev()
+            .take_while(|(second, _)| *second > cutoff)
+            .map(|(_, count)| count)
+            .sum();
+        total as f64 / over_secs as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[typeshare]
+pub enum NodeKind {
+    Client,
+    Monolith,
+    /// Broadcasts are sent to every client in a room.
+    Room,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Direction {
+    Rx,
+    Tx,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum TrafficKey {
+    Node {
+        /// The balancer that saw the traffic.
+        balancer: Option<BalancerId>,
+        kind: NodeKind,
+        id: String,
+        event: String,
+        direction: Direction,
+    },
+    Room {
+        room: RoomName,
+        direction: Direction,
+    },
+}
+
+/// Messages per second, in each direction, as seen by the balancers.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[typeshare]
+pub struct DirectionRates {
+    /// Received by a balancer.
+    pub rx: f64,
+    /// Sent by a balancer.
+    pub tx: f64,
+}
+
+impl DirectionRates {
+    fn add(&mut self, direction: Direction, rate: f64) {
+        match direction {
+            Direction::Rx => self.rx += rate,
+            Direction::Tx => self.tx += rate,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[typeshare]
+pub struct NodeTraffic {
+    pub kind: NodeKind,
+    pub id: String,
+    /// The kind of traffic event, like `ws`, `proxy` or `broadcast`.
+    pub event: String,
+    pub rates: DirectionRates,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[typeshare]
+pub struct RoomTraffic {
+    pub room: RoomName,
+    pub rates: DirectionRates,
+}
+
+/// Traffic across the system, averaged over a window.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[typeshare]
+pub struct TrafficRollup {
+    /// Milliseconds since the Unix epoch.
+    #[typeshare(serialized_as = "number")]
+    pub timestamp: u64,
+    pub window_secs: u32,
+    pub total: DirectionRates,
+    pub nodes: Vec<NodeTraffic>,
+    pub rooms: Vec<RoomTraffic>,
+}
+
+impl TrafficRollup {
+    fn redact(mut self, redactor: &Redactor) -> Self {
+        for node in &mut self.nodes {
+            match node.kind {
+                NodeKind::Client => {
+                    if let Ok(id) = node.id.parse::<Uuid>() {
+                        node.id = redactor.client_id(id.into()).to_string();
+                    }
+                }
+                NodeKind::Room => node.id = redactor.room(&node.id.as_str().into()).to_string(),
+                NodeKind::Monolith => {}
+            }
+        }
+        for room in &mut self.rooms {
+            room.room = redactor.room(&room.room);
+        }
+        self
+    }
+}
+
+/// Counts the traffic events from balancers by node, room and direction.
+#[derive(Debug)]
+pub struct TrafficRates {
+    counts: SlidingCounts<TrafficKey>,
+}
+
+impl Default for TrafficRates {
+    fn default() -> Self {
+        Self {
+            counts: SlidingCounts::new(MAX_WINDOW_SECS),
+        }
+    }
+}
+
+impl TrafficRates {
+    pub fn record_event(&mut self, event: &serde_json::Value, now_secs: u64) {
+        let Some(kind @ ("ws" | "proxy" | "broadcast")) = event["event"].as_str() else {
+            return;
+        };
+        let direction = match event["direction"].as_str() {
+            Some("rx") => Direction::Rx,
+            Some("tx") => Direction::Tx,
+            _ => return,
+        };
+        let node = if let Some(client_id) = event["client_id"].as_str() {
+            Some((NodeKind::Client, client_id))
+        } else if let Some(monolith_id) = event["monolith_id"].as_str() {
+            Some((NodeKind::Monolith, monolith_id))
+        } else if kind == "broadcast" {
+            event["node_id"].as_str().map(|room| (NodeKind::Room, room))
+        } else {
+            None
+        };
+        if let Some((node_kind, id)) = node {
+            let balancer = event["balancer_id"]
+                .as_str()
+                .and_then(|id| id.parse::<Uuid>().ok())
+                .map(BalancerId::from);
+            self.counts.record(
+                TrafficKey::Node {
+                    balancer,
+                    kind: node_kind,
+                    id: id.to_owned(),
+                    event: kind.to_owned(),
+                    direction,
+                },
+                now_secs,
+            );
+        }
+        if let Some(room) = event["room"].as_str() {
+            self.counts.record(
+                TrafficKey::Room {
+                    room: room.into(),
+                    direction,
+                },
+                now_secs,
+            );
+        }
+    }
+
+    /// Messages per second between a balancer and a client or monolith over the last `over_secs` seconds,
+    /// counting `ws` and `proxy` events in both directions.
+    pub fn connection_rate(
+        &self,
+        balancer: BalancerId,
+        kind: NodeKind,
+        id: &str,
+        now_secs: u64,
+        over_secs: u64,
+    ) -> f64 {
+        let mut rate = 0.0;
+        for event in ["ws", "proxy"] {
+            for direction in [Direction::Rx, Direction::Tx] {
+                let key = TrafficKey::Node {
+                    balancer: Some(balancer),
+                    kind,
+                    id: id.to_owned(),
+                    event: event.to_owned(),
+                    direction,
+                };
+                rate += self.counts.rate(&key, now_secs, over_secs);
+            }
+        }
+        rate
+    }
+
+    /// Roll the counts up into rates over the last `window_secs` seconds, busiest first.
+    pub fn rollup(&self, now_millis: u64, window_secs: u64) -> TrafficRollup {
+        let now_secs = now_millis / 1000;
+        let mut total = DirectionRates::default();
+        let mut nodes: BTreeMap<(NodeKind, &str, &str), DirectionRates> = BTreeMap::new();
+        let mut rooms: HashMap<&RoomName, DirectionRates> = HashMap::new();
+        for (key, rate) in self.counts.rates(now_secs, window_secs) {
+            match key {
+                TrafficKey::Node {
+                    kind,
+                    id,
+                    event,
+                    direction,
+                    ..
+                } => {
+                    total.add(*direction, rate);
+                    nodes
+                        .entry((*kind, id, event))
+                        .or_default()
+                        .add(*direction, rate);
+                }
+                TrafficKey::Room { room, direction } => {
+                    rooms.entry(room).or_default().add(*direction, rate);
+                }
+            }
+        }
+
+        let busiest_first =
+            |a: &DirectionRates, b: &DirectionRates| (b.rx + b.tx).total_cmp(&(a.rx + a.tx));
+        let mut nodes: Vec<_> = nodes
+            .into_iter()
+            .map(|((kind, id, event), rates)| NodeTraffic {
+                kind,
+                id: id.to_owned(),
+                event: event.to_owned(),
+                rates,
+            })
+            .collect();
+        nodes.sort_by(|a, b| busiest_first(&a.rates, &b.rates));
+        let mut rooms: Vec<_> = rooms
+            .into_iter()
+            .map(|(room, rates)| RoomTraffic {
+                room: room.clone(),
+                rates,
+            })
+            .collect();
+        rooms.sort_by(|a, b| busiest_first(&a.rates, &b.rates).then_with(|| a.room.cmp(&b.room)));
+
+        TrafficRollup {
+            timestamp: now_millis,
+            window_secs: window_secs.min(MAX_WINDOW_SECS) as u32,
+            total,
+            nodes,
+            rooms,
+        }
+    }
+}
+
+fn window_secs(config: &RollupConfig, window: Option<u64>) -> Result<u64, (Status, String)> {
+    match window {
+        Some(0) => Err((Status::BadRequest, "window must be positive".to_owned())),
+        Some(window) if window > MAX_WINDOW_SECS => Err((
+            Status::BadRequest,
+            format!("window can't be more than {} seconds", MAX_WINDOW_SECS),
+        )),
+        Some(window) => Ok(window),
+        None => Ok(config.window.as_secs().clamp(1, MAX_WINDOW_SECS)),
+    }
+}
+
+/// Serve the current traffic rates, averaged over `window` seconds.
+#[get("/rates?<window>")]
+pub async fn serve_rates(
+    _auth: Authenticated,
+    rates: &State<Arc<Mutex<TrafficRates>>>,
+    config: &State<RollupConfig>,
+    redactor: &State<Redactor>,
+    window: Option<u64>,
+) -> Result<Json<TrafficRollup>, (Status, String)> {
+    let window = window_secs(config, window)?;
+    let rollup = rates.lock().await.rollup(now_millis(), window);
+    Ok(Json(rollup.redact(redactor)))
+}
+
+/// Stream the traffic rates, sending a new rollup every [`RollupConfig::interval`].
+#[get("/rates/stream?<window>")]
+pub fn stream_rates(
+    _auth: Authenticated,
+    ws: rocket_ws::WebSocket,
+    rates: &State<Arc<Mutex<TrafficRates>>>,
+    config: &State<RollupConfig>,
+    redactor: &State<Redactor>,
+    window: Option<u64>,
+) -> Result<rocket_ws::Channel<'static>, (Status, String)> {
+    let window = window_secs(config, window)?;
+    let rates = rates.inner().clone();
+    let interval = config.interval;
+    let redactor = redactor.inner().clone();
+    Ok(ws.channel(move |mut stream| {
+        Box::pin(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let rollup = rates.lock().await.rollup(now_millis(), window);
+                let rollup = serde_json::to_string(&rollup.redact(&redactor))
+                    .expect("rollups should serialize");
+                if let Err(err) = stream.send(rocket_ws::Message::text(rollup)).await {
+                    debug!("Rates stream closed: {}", err);
+                    break;
+                }
+            }
+            Ok(())
+        })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn sliding_counts_should_average_over_window() {
+        let mut counts = SlidingCounts::new(10);
+        for second in [100, 100, 105, 109] {
+            counts.record("a", second);
+        }
+        assert_eq!(counts.rate(&"a", 109, 10), 0.4);
+        assert_eq!(counts.rate(&"a", 110, 10), 0.2);
+        assert_eq!(counts.rate(&"a", 109, 5), 0.4);
+        assert_eq!(counts.rate(&"a", 109, 60), 0.4);
+
+        counts.record("b", 120);
+        assert!(!counts.counts.contains_key(&"a"));
+        assert_eq!(counts.rates(120, 10).collect::<Vec<_>>(), vec![(&"b", 0.1)]);
+    }
+
+    #[test]
+    fn should_roll_up_traffic() {
+        let client = Uuid::new_v4().to_string();
+        let monolith = Uuid::new_v4().to_string();
+        let mut rates = TrafficRates::default();
+        for _ in 0..20 {
+            rates.record_event(
+                &json!({"event": "ws", "node_id": client, "client_id": client, "room": "foo", "direction": "rx"}),
+                100,
+            );
+        }
+        for _ in 0..10 {
+            rates.record_event(
+                &json!({"event": "ws", "node_id": monolith, "monolith_id": monolith, "direction": "tx"}),
+                100,
+            );
+        }
+        rates.record_event(
+            &json!({"event": "broadcast", "node_id": "foo", "room": "foo", "direction": "tx"}),
+            100,
+        );
+        rates.record_event(
+            &json!({"balancer_id": "x", "event": {"type": "room_loaded"}}),
+            100,
+        );
+
+        let rollup = rates.rollup(100_500, 10);
+        assert_eq!(rollup.window_secs, 10);
+        assert_eq!(rollup.total, DirectionRates { rx: 2.0, tx: 1.1 });
+        assert_eq!(
+            rollup.nodes,
+            vec![
+                NodeTraffic {
+                    kind: NodeKind::Client,
+                    id: client,
+                    event: "ws".to_owned(),
+                    rates: DirectionRates { rx: 2.0, tx: 0.0 },
+                },
+                NodeTraffic {
+                    kind: NodeKind::Monolith,
+                    id: monolith,
+                    event: "ws".to_owned(),
+                    rates: DirectionRates { rx: 0.0, tx: 1.0 },
+                },
+                NodeTraffic {
+                    kind: NodeKind::Room,
+                    id: "foo".to_owned(),
+                    event: "broadcast".to_owned(),
+                    rates: DirectionRates { rx: 0.0, tx: 0.1 },
+                },
+            ]
+        );
+        assert_eq!(
+            rollup.rooms,
+            vec![RoomTraffic {
+                room: "foo".into(),
+                rates: DirectionRates { rx: 2.0, tx: 0.1 },
+            }]
+        );
+    }
+}
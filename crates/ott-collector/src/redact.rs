@@ -0,0 +1,245 @@
+//! Hides client ids and room names from the collector's responses.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+use ott_balancer_protocol::{ClientId, RoomName};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::aggregate::RoomConflict;
+use crate::alerts::Alert;
+use crate::history::HistorySample;
+use crate::SystemState;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RedactConfig {
+    /// Replace client ids with made up ones.
+    pub client_ids: bool,
+    /// Replace room names with made up ones, except for the rooms in `public_rooms`.
+    pub room_names: bool,
+    pub public_rooms: Vec<RoomName>,
+}
+
+/// Replaces client ids and room names in everything the collector serves.
+///
+/// The same id or name is always replaced with the same value, so that clients and rooms can still be followed across balancers and responses. The replacements are keyed hashes, with a key that is picked every time the collector starts, so they can't be reversed by guessing names.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    config: RedactConfig,
+    key: RandomState,
+}
+
+impl Redactor {
+    pub fn new(config: RedactConfig) -> Self {
+        Self {
+            config,
+            key: RandomState::new(),
+        }
+    }
+
+    pub fn client_id(&self, id: ClientId) -> ClientId {
+        if !self.config.client_ids {
+            return id;
+        }
+        let high = self.key.hash_one((0u8, id));
+        let low = self.key.hash_one((1u8, id));
+        Uuid::from_u64_pair(high, low).into()
+    }
+
+    pub fn room(&self, room: &RoomName) -> RoomName {
+        if !self.config.room_names || self.config.public_rooms.contains(room) {
+            return room.clone();
+        }
+        format!("room-{:016x}", self.key.hash_one(room)).into()
+    }
+
+    pub fn system_state(&self, mut state: SystemState) -> SystemState {
+        for balancer in &mut state.0 {
+            for monolith in &mut balancer.monoliths {
+                for room in &mut monolith.rooms {
+                    room.name = self.room(&room.name);
+                    for client in &mut room.clients {
+                        client.id = self.client_id(client.id);
+                    }
+                }
+            }
+        }
+        state
+    }
+
+    pub fn history_sample(&self, mut sample: HistorySample) -> HistorySample {
+        sample.state = sample.state.map(|state| self.system_state(state));
+        sample
+    }
+
+    pub fn alerts(&self, alerts: Vec<Alert>) -> Vec<Alert> {
+        alerts
+            .into_iter()
+            .map(|alert| match alert {
+                Alert::RoomConflict(conflict) => Alert::RoomConflict(RoomConflict {
+                    room: self.room(&conflict.room),
+                    ..conflict
+                }),
+                Alert::OrphanedClient(mut orphaned) => {
+                    orphaned.client_id = self.client_id(orphaned.client_id);
+                    Alert::OrphanedClient(orphaned)
+                }
+                alert @ Alert::PartialMonolith(_) => alert,
+            })
+            .collect()
+    }
+
+    /// Redact an event from the event bus, which is either a traffic event or a lifecycle event.
+    ///
+    /// Both kinds name rooms with `room` and clients with `client_id`. Traffic events also repeat one of them as `node_id`.
+    pub fn event(&self, event: String) -> String {
+        if !self.config.client_ids && !self.config.room_names {
+            return event;
+        }
+        let Ok(mut value) = serde_json::from_str::<serde_json::Value>(&event) else {
+            return event;
+        };
+        self.redact_value(&mut value);
+        value.to_string()
+    }
+
+    fn redact_value(&self, value: &mut serde_json::Value) {
+        let serde_json::Value::Object(object) = value else {
+            return;
+        };
+
+        let mut replacements = HashMap::new();
+        if let Some(room) = object.get("room").and_then(|r| r.as_str()) {
+            replacements.insert(room.to_owned(), self.room(&room.into()).to_string());
+        }
+        if let Some(client_id) = object.get("client_id").and_then(|c| c.as_str()) {
+            if let Ok(id) = client_id.parse::<Uuid>() {
+                replacements.insert(client_id.to_owned(), self.client_id(id.into()).to_string());
+            }
+        }
+        for key in ["room", "client_id", "node_id"] {
+            if let Some(field) = object.get_mut(key) {
+                if let Some(replacement) = field.as_str().and_then(|f| replacements.get(f)) {
+                    *field = replacement.clone().into();
+                }
+            }
+        }
+
+        for child in object.values_mut() {
+            self.redact_value(child);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ott_balancer_protocol::collector::{BalancerState, ClientState, MonolithState, RoomState};
+
+    use super::*;
+
+    fn redactor() -> Redactor {
+        Redactor::new(RedactConfig {
+            client_ids: true,
+            room_names: true,
+            public_rooms: vec!["lobby".into()],
+        })
+    }
+
+    #[test]
+    fn should_redact_consistently() {
+        let redactor = redactor();
+        let client = ClientId::from(Uuid::new_v4());
+        assert_ne!(redactor.client_id(client), client);
+        assert_eq!(redactor.client_id(client), redactor.client_id(client));
+
+        let room = RoomName::from("secret");
+        assert_ne!(redactor.room(&room), room);
+        assert_eq!(redactor.room(&room), redactor.room(&"Secret".into()));
+        assert_eq!(redactor.room(&"Lobby".into()), "Lobby".into());
+    }
+
+    #[test]
+    fn should_not_redact_by_default() {
+        let redactor = Redactor::new(RedactConfig::default());
+        let client = ClientId::from(Uuid::new_v4());
+        assert_eq!(redactor.client_id(client), client);
+        assert_eq!(redactor.room(&"secret".into()), "secret".into());
+        let event = r#"{"event":"ws","room":"secret"}"#.to_owned();
+        assert_eq!(redactor.event(event.clone()), event);
+    }
+
+    #[test]
+    fn should_redact_state() {
+        let redactor = redactor();
+        let client = ClientId::from(Uuid::new_v4());
+        let state = SystemState(vec![BalancerState {
+            id: Uuid::new_v4().into(),
+            region: "ord".into(),
+            monoliths: vec![MonolithState {
+                id: Uuid::new_v4().into(),
+                region: "ord".into(),
+                rooms: vec![RoomState {
+                    name: "secret".into(),
+                    clients: vec![ClientState {
+                        id: client,
+                        edge_region: "ord".into(),
+                    }],
+                }],
+            }],
+            collected_at: None,
+            error: None,
+        }]);
+
+        let redacted = redactor.system_state(state);
+        let room = &redacted.0[0].monoliths[0].rooms[0];
+        assert_eq!(room.name, redactor.room(&"secret".into()));
+        assert_eq!(room.clients[0].id, redactor.client_id(client));
+    }
+
+    #[test]
+    fn should_redact_events() {
+        let redactor = redactor();
+        let client = Uuid::new_v4();
+        let monolith = Uuid::new_v4();
+
+        let event = serde_json::json!({
+            "event": "ws",
+            "node_id": client,
+            "client_id": client,
+            "room": "secret",
+            "direction": "tx",
+        });
+        let redacted: serde_json::Value =
+            serde_json::from_str(&redactor.event(event.to_string())).unwrap();
+        let client_id = redactor.client_id(client.into()).to_string();
+        assert_eq!(redacted["client_id"], client_id.as_str());
+        assert_eq!(redacted["node_id"], client_id.as_str());
+        assert_eq!(
+            redacted["room"],
+            redactor.room(&"secret".into()).to_string().as_str()
+        );
+
+        let event = serde_json::json!({
+            "balancer_id": Uuid::new_v4(),
+            "timestamp": 1700000000000u64,
+            "event": {
+                "type": "client_joined",
+                "payload": {
+                    "client_id": client,
+                    "room": "secret",
+                    "monolith_id": monolith,
+                    "edge_region": "ord",
+                },
+            },
+        });
+        let redacted: serde_json::Value =
+            serde_json::from_str(&redactor.event(event.to_string())).unwrap();
+        let payload = &redacted["event"]["payload"];
+        assert_eq!(payload["client_id"], client_id.as_str());
+        assert_ne!(payload["room"], "secret");
+        assert_eq!(payload["monolith_id"], monolith.to_string().as_str());
+    }
+}
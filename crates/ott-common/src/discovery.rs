@@ -1,33 +1,48 @@
 //! Handles discovery of Services.
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::time::Duration;
 use tracing::{debug, error, warn};
 
+mod composite;
+mod consul;
 mod dns;
+mod file;
 mod fly;
 mod harness;
+mod kubernetes;
 mod manual;
 
+pub use composite::*;
+pub use consul::*;
 pub use dns::*;
+pub use file::*;
 pub use fly::*;
 pub use harness::*;
+pub use kubernetes::*;
 pub use manual::*;
 
 use async_trait::async_trait;
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use tokio::task::JoinHandle;
 use url::Url;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 #[serde(tag = "method", rename_all = "lowercase")]
 pub enum DiscoveryConfig {
-    Dns(DnsDiscoveryConfig),
+    Composite(CompositeDiscoveryConfig),
+    Consul(ConsulDiscoveryConfig),
+    Dns(
+        #[serde(deserialize_with = "DnsDiscoveryConfig::deserialize_validated")] DnsDiscoveryConfig,
+    ),
+    File(FileDiscoveryConfig),
     Fly(FlyDiscoveryConfig),
     Manual(ManualDiscoveryConfig),
     Harness(HarnessDiscoveryConfig),
+    Kubernetes(KubernetesDiscoveryConfig),
 }
 
 impl Default for DiscoveryConfig {
@@ -36,15 +51,52 @@ impl Default for DiscoveryConfig {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize)]
+impl DiscoveryConfig {
+    /// The name of the discovery method, as it's written in config files.
+    pub fn method(&self) -> &'static str {
+        match self {
+            Self::Composite(_) => "composite",
+            Self::Consul(_) => "consul",
+            Self::Dns(_) => "dns",
+            Self::File(_) => "file",
+            Self::Fly(_) => "fly",
+            Self::Manual(_) => "manual",
+            Self::Harness(_) => "harness",
+            Self::Kubernetes(_) => "kubernetes",
+        }
+    }
+
+    pub fn discoverer(&self) -> Box<dyn ServiceDiscoverer + Send + Sync> {
+        match self {
+            Self::Composite(config) => Box::new(CompositeServiceDiscoverer::new(config.clone())),
+            Self::Consul(config) => Box::new(ConsulServiceDiscoverer::new(config.clone())),
+            Self::Dns(config) => Box::new(DnsServiceDiscoverer::new(config.clone())),
+            Self::File(config) => Box::new(FileServiceDiscoverer::new(config.clone())),
+            Self::Fly(config) => Box::new(FlyServiceDiscoverer::new(config.clone())),
+            Self::Manual(config) => Box::new(ManualServiceDiscoverer::new(config.clone())),
+            Self::Harness(config) => Box::new(HarnessServiceDiscoverer::new(config.clone())),
+            Self::Kubernetes(config) => Box::new(KubernetesServiceDiscoverer::new(config.clone())),
+        }
+    }
+}
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize, JsonSchema,
+)]
+#[serde(deny_unknown_fields)]
 pub struct ConnectionConfig {
+    #[schemars(with = "String")]
     pub host: HostOrIp,
     pub port: u16,
 }
 
 impl ConnectionConfig {
     pub fn uri(&self) -> Url {
-        let mut url = Url::parse("ws://localhost").unwrap();
+        self.uri_with_scheme("ws")
+    }
+
+    pub fn uri_with_scheme(&self, scheme: &str) -> Url {
+        let mut url = Url::parse(&format!("{scheme}://localhost")).unwrap();
         match self.host {
             HostOrIp::Host(ref host) => {
                 url.set_host(Some(host)).unwrap();
@@ -68,12 +120,41 @@ impl From<SocketAddr> for ConnectionConfig {
     }
 }
 
+/// Extra information about an instance, from discovery methods that have it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InstanceMetadata {
+    pub tags: Vec<String>,
+    /// Key/value metadata that the instance was registered with, like `region`. This is only informational, monoliths
+    /// report their own region when they connect.
+    pub meta: BTreeMap<String, String>,
+    /// How many rooms the instance should get relative to others. Used by the balancer's monolith selection.
+    pub weight: Option<u32>,
+}
+
+impl InstanceMetadata {
+    pub fn region(&self) -> Option<&str> {
+        self.meta.get("region").map(String::as_str)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum HostOrIp {
     Host(String),
     Ip(IpAddr),
 }
 
+impl Serialize for HostOrIp {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            HostOrIp::Host(host) => serializer.serialize_str(host),
+            HostOrIp::Ip(ip) => serializer.collect_str(ip),
+        }
+    }
+}
+
 impl<'de> Deserialize<'de> for HostOrIp {
     fn deserialize<D>(deserializer: D) -> Result<HostOrIp, D::Error>
     where
@@ -93,16 +174,85 @@ pub trait ServiceDiscoverer {
     /// In polling mode, this function should immediately return the current list of instances. In continuous mode, this function should wait until the list of instances changes, then return the new list.
     async fn discover(&mut self) -> anyhow::Result<Vec<ConnectionConfig>>;
     fn mode(&self) -> DiscoveryMode;
+    /// Metadata for the instances returned by the last call to `discover`, if this discovery method has any.
+    fn metadata(&self) -> HashMap<ConnectionConfig, InstanceMetadata> {
+        HashMap::new()
+    }
+}
+
+#[async_trait]
+impl ServiceDiscoverer for Box<dyn ServiceDiscoverer + Send + Sync> {
+    async fn discover(&mut self) -> anyhow::Result<Vec<ConnectionConfig>> {
+        (**self).discover().await
+    }
+
+    fn mode(&self) -> DiscoveryMode {
+        (**self).mode()
+    }
+
+    fn metadata(&self) -> HashMap<ConnectionConfig, InstanceMetadata> {
+        (**self).metadata()
+    }
 }
 
 pub enum DiscoveryMode {
     Polling(Duration),
     Continuous,
 }
-pub struct DiscoveryTask {
+
+/// How long to wait before trying again after discovery fails.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Runs a discoverer over and over, waiting between polls and retrying after failures.
+pub(crate) struct DiscoveryLoop {
     discovery: Box<dyn ServiceDiscoverer + Send + Sync>,
+    /// What to call the discoverer in logs.
+    name: String,
+    /// Whether the next call has to wait out the polling interval first.
+    polled: bool,
+}
+
+impl DiscoveryLoop {
+    pub(crate) fn new(discovery: Box<dyn ServiceDiscoverer + Send + Sync>, name: String) -> Self {
+        Self {
+            discovery,
+            name,
+            polled: false,
+        }
+    }
+
+    /// Wait for the next list of instances, along with their metadata.
+    pub(crate) async fn next(
+        &mut self,
+    ) -> (
+        Vec<ConnectionConfig>,
+        HashMap<ConnectionConfig, InstanceMetadata>,
+    ) {
+        loop {
+            if std::mem::take(&mut self.polled) {
+                if let DiscoveryMode::Polling(d) = self.discovery.mode() {
+                    tokio::time::sleep(d).await;
+                }
+            }
+            match self.discovery.discover().await {
+                Ok(instances) => {
+                    self.polled = true;
+                    return (instances, self.discovery.metadata());
+                }
+                Err(e) => {
+                    error!("{} failed: {:?}", self.name, e);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+}
+
+pub struct DiscoveryTask {
+    discovery: DiscoveryLoop,
 
     monoliths: HashSet<ConnectionConfig>,
+    metadata: HashMap<ConnectionConfig, InstanceMetadata>,
     discovery_tx: tokio::sync::mpsc::Sender<ServiceDiscoveryMsg>,
 }
 
@@ -112,26 +262,38 @@ impl DiscoveryTask {
         discovery_tx: tokio::sync::mpsc::Sender<ServiceDiscoveryMsg>,
     ) -> Self {
         Self {
-            discovery: Box::new(discovery),
+            discovery: DiscoveryLoop::new(Box::new(discovery), "Monolith Discovery".to_owned()),
             monoliths: Default::default(),
+            metadata: Default::default(),
             discovery_tx,
         }
     }
 
+    /// Keep discovering until the receiving end of the channel is gone.
     pub async fn do_continuous_discovery(&mut self) {
         loop {
-            if let Err(e) = self.do_discovery().await {
-                error!("Monolith Discovery failed: {:?}", e);
-                tokio::time::sleep(Duration::from_secs(5)).await;
+            let (monoliths, metadata) = self.discovery.next().await;
+            if let Err(e) = self.apply_discovery(monoliths, metadata).await {
+                error!("Monolith Discovery stopped: {:?}", e);
+                return;
             }
         }
     }
 
-    async fn do_discovery(&mut self) -> anyhow::Result<()> {
-        let monoliths = self.discovery.discover().await?;
+    async fn apply_discovery(
+        &mut self,
+        monoliths: Vec<ConnectionConfig>,
+        metadata: HashMap<ConnectionConfig, InstanceMetadata>,
+    ) -> anyhow::Result<()> {
         debug!("Discovered monoliths: {:?}", monoliths);
         let monoliths_new: HashSet<_> = monoliths.into_iter().collect();
-        let msg = build_discovery_msg(&self.monoliths, &monoliths_new);
+        let mut msg = build_discovery_msg(&self.monoliths, &monoliths_new);
+        msg.metadata = metadata
+            .iter()
+            .filter(|(conf, meta)| self.metadata.get(*conf) != Some(*meta))
+            .map(|(conf, meta)| (conf.clone(), meta.clone()))
+            .collect();
+        self.metadata = metadata;
         // apply the changes to our state
         for m in &msg.removed {
             self.monoliths.remove(m);
@@ -144,10 +306,6 @@ impl DiscoveryTask {
             warn!("No monoliths discovered");
         }
 
-        if let DiscoveryMode::Polling(d) = self.discovery.mode() {
-            tokio::time::sleep(d).await;
-        }
-
         Ok(())
     }
 }
@@ -161,6 +319,7 @@ fn build_discovery_msg(
     ServiceDiscoveryMsg {
         added: instances_added,
         removed: instances_removed,
+        metadata: HashMap::new(),
     }
 }
 
@@ -181,4 +340,6 @@ pub fn start_discovery_task(
 pub struct ServiceDiscoveryMsg {
     pub added: Vec<ConnectionConfig>,
     pub removed: Vec<ConnectionConfig>,
+    /// Metadata for instances that were just added, or whose metadata changed.
+    pub metadata: HashMap<ConnectionConfig, InstanceMetadata>,
 }
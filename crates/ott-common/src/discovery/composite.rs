@@ -0,0 +1,320 @@
+use std::collections::BTreeSet;
+
+use anyhow::Context;
+use tracing::info;
+
+use super::*;
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CompositeDiscoveryConfig {
+    /// The discovery methods to combine. Instances found by any of them are used.
+    pub sources: Vec<DiscoveryConfig>,
+}
+
+/// Combines the instances found by several discovery methods, like while migrating from one method to another.
+///
+/// Each source runs on its own, so a source that fails keeps the instances it found last, and doesn't hold up the others.
+pub struct CompositeServiceDiscoverer {
+    updates_rx: tokio::sync::mpsc::Receiver<SourceUpdate>,
+    sources: Vec<SourceState>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct SourceState {
+    method: &'static str,
+    instances: Vec<ConnectionConfig>,
+    metadata: HashMap<ConnectionConfig, InstanceMetadata>,
+}
+
+struct SourceUpdate {
+    source: usize,
+    instances: Vec<ConnectionConfig>,
+    metadata: HashMap<ConnectionConfig, InstanceMetadata>,
+}
+
+impl CompositeServiceDiscoverer {
+    pub fn new(config: CompositeDiscoveryConfig) -> Self {
+        info!(
+            "Creating CompositeServiceDiscoverer, sources: {:?}",
+            config
+                .sources
+                .iter()
+                .map(|source| source.method())
+                .collect::<Vec<_>>()
+        );
+        Self::from_sources(
+            config
+                .sources
+                .iter()
+                .map(|source| (source.method(), source.discoverer()))
+                .collect(),
+        )
+    }
+
+    fn from_sources(
+        discoverers: Vec<(&'static str, Box<dyn ServiceDiscoverer + Send + Sync>)>,
+    ) -> Self {
+        let (updates_tx, updates_rx) = tokio::sync::mpsc::channel(discoverers.len().max(1));
+        let mut sources = vec![];
+        let mut tasks = vec![];
+        for (index, (method, discovery)) in discoverers.into_iter().enumerate() {
+            sources.push(SourceState {
+                method,
+                ..Default::default()
+            });
+            let discovery =
+                DiscoveryLoop::new(discovery, format!("Discovery source {index} ({method})"));
+            let updates_tx = updates_tx.clone();
+            let task = tokio::task::Builder::new()
+                .name("discovery source")
+                .spawn(run_source(index, discovery, updates_tx))
+                .expect("failed to spawn discovery source task");
+            tasks.push(task);
+        }
+        Self {
+            updates_rx,
+            sources,
+            tasks,
+        }
+    }
+
+    fn apply(&mut self, update: SourceUpdate) {
+        let source = &mut self.sources[update.source];
+        info!(
+            "Discovery source {} ({}) found {} instances",
+            update.source,
+            source.method,
+            update.instances.len()
+        );
+        source.instances = update.instances;
+        source.metadata = update.metadata;
+    }
+}
+
+async fn run_source(
+    index: usize,
+    mut discovery: DiscoveryLoop,
+    updates_tx: tokio::sync::mpsc::Sender<SourceUpdate>,
+) {
+    loop {
+        let (instances, metadata) = discovery.next().await;
+        let update = SourceUpdate {
+            source: index,
+            instances,
+            metadata,
+        };
+        if updates_tx.send(update).await.is_err() {
+            // the composite discoverer is gone
+            return;
+        }
+    }
+}
+
+#[async_trait]
+impl ServiceDiscoverer for CompositeServiceDiscoverer {
+    async fn discover(&mut self) -> anyhow::Result<Vec<ConnectionConfig>> {
+        let update = self
+            .updates_rx
+            .recv()
+            .await
+            .context("no discovery sources")?;
+        self.apply(update);
+        // report updates that arrived together all at once
+        while let Ok(update) = self.updates_rx.try_recv() {
+            self.apply(update);
+        }
+
+        let instances: BTreeSet<_> = self
+            .sources
+            .iter()
+            .flat_map(|source| source.instances.iter().cloned())
+            .collect();
+        Ok(instances.into_iter().collect())
+    }
+
+    fn mode(&self) -> DiscoveryMode {
+        DiscoveryMode::Continuous
+    }
+
+    fn metadata(&self) -> HashMap<ConnectionConfig, InstanceMetadata> {
+        let mut metadata = HashMap::new();
+        // sources listed first win when more than one has metadata for an instance
+        for source in &self.sources {
+            for (conf, meta) in &source.metadata {
+                metadata.entry(conf.clone()).or_insert_with(|| meta.clone());
+            }
+        }
+        metadata
+    }
+}
+
+impl Drop for CompositeServiceDiscoverer {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn conf(port: u16) -> ConnectionConfig {
+        ConnectionConfig {
+            host: HostOrIp::Ip([127, 0, 0, 1].into()),
+            port,
+        }
+    }
+
+    type Discovered = anyhow::Result<Vec<(ConnectionConfig, InstanceMetadata)>>;
+
+    /// A source that finds whatever is sent to it.
+    struct ChannelDiscoverer {
+        rx: tokio::sync::mpsc::Receiver<Discovered>,
+        metadata: HashMap<ConnectionConfig, InstanceMetadata>,
+    }
+
+    #[async_trait]
+    impl ServiceDiscoverer for ChannelDiscoverer {
+        async fn discover(&mut self) -> anyhow::Result<Vec<ConnectionConfig>> {
+            let Some(discovered) = self.rx.recv().await else {
+                return std::future::pending().await;
+            };
+            let discovered = discovered?;
+            self.metadata = discovered.iter().cloned().collect();
+            Ok(discovered.into_iter().map(|(conf, _)| conf).collect())
+        }
+
+        fn mode(&self) -> DiscoveryMode {
+            DiscoveryMode::Continuous
+        }
+
+        fn metadata(&self) -> HashMap<ConnectionConfig, InstanceMetadata> {
+            self.metadata.clone()
+        }
+    }
+
+    fn channel_sources(
+        count: usize,
+    ) -> (
+        CompositeServiceDiscoverer,
+        Vec<tokio::sync::mpsc::Sender<Discovered>>,
+    ) {
+        let mut senders = vec![];
+        let mut discoverers: Vec<(&'static str, Box<dyn ServiceDiscoverer + Send + Sync>)> = vec![];
+        for _ in 0..count {
+            let (tx, rx) = tokio::sync::mpsc::channel(10);
+            senders.push(tx);
+            discoverers.push((
+                "channel",
+                Box::new(ChannelDiscoverer {
+                    rx,
+                    metadata: HashMap::new(),
+                }),
+            ));
+        }
+        (
+            CompositeServiceDiscoverer::from_sources(discoverers),
+            senders,
+        )
+    }
+
+    fn found(ports: &[u16]) -> Discovered {
+        Ok(ports
+            .iter()
+            .map(|port| (conf(*port), InstanceMetadata::default()))
+            .collect())
+    }
+
+    async fn next(discovery: &mut CompositeServiceDiscoverer) -> Vec<ConnectionConfig> {
+        tokio::time::timeout(Duration::from_secs(5), discovery.discover())
+            .await
+            .expect("sources should be reported")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_merge_sources() {
+        let config: DiscoveryConfig = serde_json::from_value(json!({
+            "method": "composite",
+            "sources": [
+                {"method": "manual", "monoliths": [conf(3002), conf(3003)]},
+                {"method": "manual", "monoliths": [conf(3003), conf(3004)]},
+                {"method": "file", "path": "/nonexistent/monoliths.toml"},
+            ],
+        }))
+        .unwrap();
+        let mut discovery = config.discoverer();
+
+        let mut instances = vec![];
+        while instances.len() < 3 {
+            instances = tokio::time::timeout(Duration::from_secs(5), discovery.discover())
+                .await
+                .expect("the failing source shouldn't hold up the others")
+                .unwrap();
+        }
+        assert_eq!(instances, vec![conf(3002), conf(3003), conf(3004)]);
+    }
+
+    #[tokio::test]
+    async fn shrinking_source_should_only_remove_its_own_instances() {
+        let (mut discovery, sources) = channel_sources(2);
+        sources[0].send(found(&[3002, 3003])).await.unwrap();
+        assert_eq!(next(&mut discovery).await, vec![conf(3002), conf(3003)]);
+        sources[1].send(found(&[3003, 3004])).await.unwrap();
+        assert_eq!(
+            next(&mut discovery).await,
+            vec![conf(3002), conf(3003), conf(3004)]
+        );
+
+        sources[0].send(found(&[3002])).await.unwrap();
+        assert_eq!(
+            next(&mut discovery).await,
+            vec![conf(3002), conf(3003), conf(3004)]
+        );
+        sources[0].send(found(&[])).await.unwrap();
+        assert_eq!(next(&mut discovery).await, vec![conf(3003), conf(3004)]);
+    }
+
+    #[tokio::test]
+    async fn failing_source_should_keep_its_last_instances() {
+        let (mut discovery, sources) = channel_sources(2);
+        sources[0].send(found(&[3002])).await.unwrap();
+        assert_eq!(next(&mut discovery).await, vec![conf(3002)]);
+
+        sources[0]
+            .send(Err(anyhow::anyhow!("source is down")))
+            .await
+            .unwrap();
+        sources[1].send(found(&[3003])).await.unwrap();
+        assert_eq!(next(&mut discovery).await, vec![conf(3002), conf(3003)]);
+    }
+
+    #[tokio::test]
+    async fn earlier_sources_should_win_metadata() {
+        let (mut discovery, sources) = channel_sources(2);
+        let weight = |weight| InstanceMetadata {
+            weight: Some(weight),
+            ..Default::default()
+        };
+        sources[1]
+            .send(Ok(vec![(conf(3002), weight(2)), (conf(3003), weight(3))]))
+            .await
+            .unwrap();
+        next(&mut discovery).await;
+        sources[0]
+            .send(Ok(vec![(conf(3002), weight(1))]))
+            .await
+            .unwrap();
+        next(&mut discovery).await;
+
+        let metadata = discovery.metadata();
+        assert_eq!(metadata[&conf(3002)], weight(1));
+        assert_eq!(metadata[&conf(3003)], weight(3));
+    }
+}
@@ -0,0 +1,354 @@
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::*;
+
+#[derive(Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ConsulDiscoveryConfig {
+    /// The name of the monolith service in the Consul catalog.
+    pub service: String,
+    /// The Consul agent to query.
+    #[serde(default = "default_consul_address")]
+    pub address: String,
+    /// Only discover instances that have this tag. Optional.
+    #[serde(default)]
+    pub tag: Option<String>,
+    /// The datacenter to discover instances in. Optional. If not provided, the agent's datacenter is used.
+    #[serde(default)]
+    pub datacenter: Option<String>,
+    /// The ACL token to query Consul with. Optional.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// The port that monoliths should be listening on for load balancer connections. Optional. If not provided, the port that the service was registered with is used.
+    #[serde(default)]
+    pub service_port: Option<u16>,
+    /// How long each blocking query waits for the instances to change.
+    #[serde(default = "default_wait")]
+    #[serde(with = "humantime_serde")]
+    #[schemars(with = "String")]
+    pub wait: Duration,
+}
+
+impl std::fmt::Debug for ConsulDiscoveryConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // the token itself is left out so that it doesn't end up in the logs
+        f.debug_struct("ConsulDiscoveryConfig")
+            .field("service", &self.service)
+            .field("address", &self.address)
+            .field("tag", &self.tag)
+            .field("datacenter", &self.datacenter)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("service_port", &self.service_port)
+            .field("wait", &self.wait)
+            .finish()
+    }
+}
+
+fn default_consul_address() -> String {
+    "http://127.0.0.1:8500".to_owned()
+}
+
+fn default_wait() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
+/// Discovers passing instances of a service with blocking queries against Consul's health endpoint.
+pub struct ConsulServiceDiscoverer {
+    config: ConsulDiscoveryConfig,
+    client: reqwest::Client,
+    /// The `X-Consul-Index` of the last response, which the next query blocks on.
+    index: u64,
+    instances: HashMap<ConnectionConfig, InstanceMetadata>,
+}
+
+impl ConsulServiceDiscoverer {
+    pub fn new(config: ConsulDiscoveryConfig) -> Self {
+        info!(
+            "Creating ConsulServiceDiscoverer, service: {}, address: {}",
+            &config.service, &config.address
+        );
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            index: 0,
+            instances: HashMap::new(),
+        }
+    }
+
+    /// Query the health endpoint, blocking until the results change if there were results before.
+    async fn query(&self) -> anyhow::Result<(u64, Vec<ServiceEntry>)> {
+        let url = format!(
+            "{}/v1/health/service/{}",
+            self.config.address.trim_end_matches('/'),
+            self.config.service
+        );
+        let mut query = vec![("passing", "true".to_owned())];
+        if self.index > 0 {
+            query.push(("index", self.index.to_string()));
+            query.push(("wait", format!("{}s", self.config.wait.as_secs())));
+        }
+        if let Some(tag) = &self.config.tag {
+            query.push(("tag", tag.clone()));
+        }
+        if let Some(datacenter) = &self.config.datacenter {
+            query.push(("dc", datacenter.clone()));
+        }
+
+        let mut request = self
+            .client
+            .get(url)
+            .query(&query)
+            // leave Consul time to answer a query that waited the whole time
+            .timeout(self.config.wait + self.config.wait / 16 + Duration::from_secs(5));
+        if let Some(token) = &self.config.token {
+            request = request.header("X-Consul-Token", token);
+        }
+        let response = request.send().await?.error_for_status()?;
+        let index = response
+            .headers()
+            .get("X-Consul-Index")
+            .and_then(|index| index.to_str().ok())
+            .and_then(|index| index.parse().ok())
+            .context("missing X-Consul-Index header")?;
+        let entries = response
+            .json()
+            .await
+            .context("parsing Consul health response")?;
+        Ok((index, entries))
+    }
+
+    fn instance(&self, entry: ServiceEntry) -> Option<(ConnectionConfig, InstanceMetadata)> {
+        // `passing` is asked for, but double check in case the agent ignored it
+        if entry.checks.iter().any(|check| check.status != "passing") {
+            return None;
+        }
+        let address = if entry.service.address.is_empty() {
+            entry.node.address
+        } else {
+            entry.service.address
+        };
+        if address.is_empty() {
+            warn!("Consul service {} has no address", entry.service.id);
+            return None;
+        }
+        let conf = ConnectionConfig {
+            host: match address.parse::<IpAddr>() {
+                Ok(ip) => HostOrIp::Ip(ip),
+                Err(_) => HostOrIp::Host(address),
+            },
+            port: self.config.service_port.unwrap_or(entry.service.port),
+        };
+        let metadata = InstanceMetadata {
+            tags: entry.service.tags,
+            meta: entry.service.meta,
+            weight: entry.service.weights.map(|weights| weights.passing),
+        };
+        Some((conf, metadata))
+    }
+}
+
+#[async_trait]
+impl ServiceDiscoverer for ConsulServiceDiscoverer {
+    async fn discover(&mut self) -> anyhow::Result<Vec<ConnectionConfig>> {
+        loop {
+            let (index, entries) = match self.query().await {
+                Ok(result) => result,
+                Err(err) => {
+                    // start over, in case the error was caused by the index
+                    self.index = 0;
+                    return Err(err);
+                }
+            };
+            let unchanged = index == self.index;
+            // the index can go backwards, like when the Consul servers are restored from a snapshot
+            self.index = if index < self.index { 0 } else { index };
+            if unchanged {
+                // the query timed out without any changes
+                continue;
+            }
+
+            self.instances = entries
+                .into_iter()
+                .filter_map(|entry| self.instance(entry))
+                .collect();
+            let mut instances: Vec<_> = self.instances.keys().cloned().collect();
+            instances.sort();
+            return Ok(instances);
+        }
+    }
+
+    fn mode(&self) -> DiscoveryMode {
+        DiscoveryMode::Continuous
+    }
+
+    fn metadata(&self) -> HashMap<ConnectionConfig, InstanceMetadata> {
+        self.instances.clone()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ServiceEntry {
+    node: Node,
+    service: Service,
+    #[serde(default)]
+    checks: Vec<Check>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Node {
+    #[serde(default)]
+    address: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Service {
+    #[serde(rename = "ID", default)]
+    id: String,
+    #[serde(default)]
+    address: String,
+    port: u16,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    meta: BTreeMap<String, String>,
+    #[serde(default)]
+    weights: Option<Weights>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Weights {
+    passing: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Check {
+    status: String,
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    use http_body_util::Full;
+    use hyper::body::Bytes;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_util::serve_http;
+
+    fn entry(id: &str, address: &str, region: &str, status: &str) -> serde_json::Value {
+        json!({
+            "Node": {"Node": "node", "Address": "192.168.0.1"},
+            "Service": {
+                "ID": id,
+                "Service": "monolith",
+                "Address": address,
+                "Port": 3002,
+                "Tags": ["monolith", region],
+                "Meta": {"region": region},
+                "Weights": {"Passing": 3, "Warning": 1},
+            },
+            "Checks": [{"Status": "passing"}, {"Status": status}],
+        })
+    }
+
+    /// Stands in for a Consul agent. The instances change once the index is set to 12.
+    async fn start_consul(index: Arc<AtomicU64>) -> String {
+        let addr = serve_http(move |req| {
+            let index = index.clone();
+            async move {
+                assert_eq!(req.uri().path(), "/v1/health/service/monolith");
+                assert_eq!(req.headers()["X-Consul-Token"], "secret");
+                let query = req.uri().query().unwrap_or_default().to_owned();
+                assert!(query.contains("passing=true"));
+                let mut current = index.load(Ordering::SeqCst);
+                if query.contains(&format!("index={current}")) {
+                    // block until something changes, or give up like a query that waited too long
+                    for _ in 0..20 {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                        if index.load(Ordering::SeqCst) != current {
+                            break;
+                        }
+                    }
+                    current = index.load(Ordering::SeqCst);
+                }
+                let entries = if current < 12 {
+                    json!([
+                        entry("a", "10.0.0.1", "ord", "passing"),
+                        entry("b", "10.0.0.2", "ord", "warning"),
+                        entry("c", "", "ewr", "passing"),
+                    ])
+                } else {
+                    json!([entry("d", "10.0.0.4", "ewr", "passing")])
+                };
+                let mut resp = hyper::Response::new(Full::new(Bytes::from(entries.to_string())));
+                resp.headers_mut()
+                    .insert("X-Consul-Index", current.to_string().parse().unwrap());
+                resp
+            }
+        })
+        .await;
+        format!("http://{addr}")
+    }
+
+    fn conf(ip: &str) -> ConnectionConfig {
+        ConnectionConfig {
+            host: HostOrIp::Ip(ip.parse().unwrap()),
+            port: 3002,
+        }
+    }
+
+    #[tokio::test]
+    async fn should_discover_passing_instances() {
+        let index = Arc::new(AtomicU64::new(10));
+        let address = start_consul(index.clone()).await;
+        let mut discoverer = ConsulServiceDiscoverer::new(ConsulDiscoveryConfig {
+            service: "monolith".into(),
+            address,
+            tag: None,
+            datacenter: None,
+            token: Some("secret".into()),
+            service_port: None,
+            wait: Duration::from_secs(1),
+        });
+
+        let instances = discoverer.discover().await.unwrap();
+        assert_eq!(instances, vec![conf("10.0.0.1"), conf("192.168.0.1")]);
+        let metadata = discoverer.metadata();
+        let a = &metadata[&conf("10.0.0.1")];
+        assert_eq!(a.region(), Some("ord"));
+        assert_eq!(a.tags, vec!["monolith", "ord"]);
+        assert_eq!(a.weight, Some(3));
+        assert_eq!(metadata[&conf("192.168.0.1")].region(), Some("ewr"));
+
+        // queries that time out without changes aren't reported
+        let changer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            index.store(12, Ordering::SeqCst);
+        });
+        let instances = discoverer.discover().await.unwrap();
+        assert_eq!(instances, vec![conf("10.0.0.4")]);
+        assert_eq!(discoverer.index, 12);
+        changer.await.unwrap();
+    }
+
+    #[test]
+    fn should_not_debug_token() {
+        let config: ConsulDiscoveryConfig = serde_json::from_value(json!({
+            "service": "monolith",
+            "token": "hunter2",
+        }))
+        .unwrap();
+        assert_eq!(config.address, "http://127.0.0.1:8500");
+        assert!(!format!("{:?}", config).contains("hunter2"));
+    }
+}
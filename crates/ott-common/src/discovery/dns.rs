@@ -1,31 +1,81 @@
 use std::str::FromStr;
+use std::time::Instant;
 
 use async_trait::async_trait;
 use hickory_resolver::{
     config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
     TokioAsyncResolver,
 };
 use serde::Deserializer;
+use serde::Serialize;
 use tracing::info;
 
 use super::*;
 
-#[derive(Debug, Clone, Deserialize)]
+/// Polls are never closer together than this, even if records expire sooner.
+const MIN_POLLING_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct DnsDiscoveryConfig {
-    /// The port that monoliths should be listening on for load balancer connections.
-    pub service_port: u16,
+    /// The port that monoliths should be listening on for load balancer connections. Required, except for SRV records, which have their own ports.
+    #[serde(default)]
+    pub service_port: Option<u16>,
     /// The DNS server to query. Optional. If not provided, the system configuration will be used instead.
     #[serde(deserialize_with = "deserialize_dns_server")]
     #[serde(default)]
+    #[schemars(with = "Option<String>")]
     pub dns_server: Option<SocketAddr>,
-    /// The A record to query. If using docker-compose, this should be the service name for the monolith.
+    /// The record to query. If using docker-compose, this should be the service name for the monolith.
     pub query: String,
-    /// The polling mode discovery interval.
+    /// The type of record to query.
+    #[serde(default)]
+    pub record_type: DnsRecordType,
+    /// The longest time between polls. Polls happen sooner when the records expire sooner.
     #[serde(default = "default_polling_interval")]
     #[serde(with = "humantime_serde")]
+    #[schemars(with = "String")]
     pub polling_interval: Duration,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsRecordType {
+    /// IPv4 addresses.
+    #[default]
+    A,
+    /// IPv6 addresses.
+    Aaaa,
+    /// Both IPv4 and IPv6 addresses.
+    Dual,
+    /// Service records, which have the port, priority and weight of each instance.
+    Srv,
+}
+
+impl DnsDiscoveryConfig {
+    /// Deserialize the config, and check that it has everything that its record type needs, so that bad configs fail
+    /// when they're loaded instead of on every poll.
+    pub(crate) fn deserialize_validated<'de, D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let config = Self::deserialize(deserializer)?;
+        config.validate().map_err(serde::de::Error::custom)?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.record_type != DnsRecordType::Srv && self.service_port.is_none() {
+            anyhow::bail!(
+                "service_port is required for {:?} records",
+                self.record_type
+            );
+        }
+        Ok(())
+    }
+}
+
 fn default_polling_interval() -> Duration {
     Duration::from_secs(5)
 }
@@ -47,6 +97,11 @@ where
 
 pub struct DnsServiceDiscoverer {
     config: DnsDiscoveryConfig,
+    /// Reused across polls, so that its cache can answer until the records expire.
+    resolver: Option<TokioAsyncResolver>,
+    /// How long to wait before the next poll, based on when the last records expire.
+    next_poll: Duration,
+    metadata: HashMap<ConnectionConfig, InstanceMetadata>,
 }
 
 impl DnsServiceDiscoverer {
@@ -55,17 +110,21 @@ impl DnsServiceDiscoverer {
             "Creating DnsServiceDiscoverer, DNS server: {:?}",
             config.dns_server
         );
-        Self { config }
+        let next_poll = config.polling_interval;
+        Self {
+            config,
+            resolver: None,
+            next_poll,
+            metadata: HashMap::new(),
+        }
     }
-}
 
-#[async_trait]
-impl ServiceDiscoverer for DnsServiceDiscoverer {
-    async fn discover(&mut self) -> anyhow::Result<Vec<ConnectionConfig>> {
+    fn resolver(&mut self) -> anyhow::Result<TokioAsyncResolver> {
+        if let Some(resolver) = &self.resolver {
+            return Ok(resolver.clone());
+        }
         let resolver = match self.config.dns_server {
-            None => {
-                TokioAsyncResolver::tokio_from_system_conf().expect("failed to create resolver")
-            }
+            None => TokioAsyncResolver::tokio_from_system_conf()?,
             Some(server) => {
                 let mut resolver_config = ResolverConfig::new();
                 resolver_config.add_name_server(NameServerConfig::new(server, Protocol::Udp));
@@ -73,28 +132,288 @@ impl ServiceDiscoverer for DnsServiceDiscoverer {
                 TokioAsyncResolver::tokio(resolver_config, ResolverOpts::default())
             }
         };
+        self.resolver = Some(resolver.clone());
+        Ok(resolver)
+    }
+
+    fn service_port(&self) -> anyhow::Result<u16> {
+        self.config.validate()?;
+        Ok(self
+            .config
+            .service_port
+            .expect("validated configs have a service port"))
+    }
+
+    async fn lookup_ips(
+        &self,
+        resolver: &TokioAsyncResolver,
+    ) -> anyhow::Result<(Vec<IpAddr>, Instant)> {
+        let query = &self.config.query;
+        let v4 = || async {
+            let lookup = resolver.ipv4_lookup(query).await?;
+            let ips: Vec<_> = lookup.iter().map(|ip| IpAddr::from(ip.0)).collect();
+            Ok::<_, ResolveError>((ips, lookup.valid_until()))
+        };
+        let v6 = || async {
+            let lookup = resolver.ipv6_lookup(query).await?;
+            let ips: Vec<_> = lookup.iter().map(|ip| IpAddr::from(ip.0)).collect();
+            Ok::<_, ResolveError>((ips, lookup.valid_until()))
+        };
+        let result = match self.config.record_type {
+            DnsRecordType::A => v4().await?,
+            DnsRecordType::Aaaa => v6().await?,
+            _ => {
+                let (v4, v6) = tokio::join!(v4(), v6());
+                match (v4, v6) {
+                    (Ok((mut ips, v4_valid_until)), Ok((v6_ips, v6_valid_until))) => {
+                        ips.extend(v6_ips);
+                        (ips, v4_valid_until.min(v6_valid_until))
+                    }
+                    // it's fine for instances to only have one kind of address
+                    (Ok(result), Err(err)) | (Err(err), Ok(result)) if is_no_records(&err) => {
+                        result
+                    }
+                    (Err(err), _) | (_, Err(err)) => return Err(err.into()),
+                }
+            }
+        };
+        Ok(result)
+    }
 
-        let lookup = resolver.ipv4_lookup(&self.config.query).await?;
-        let instances = lookup
-            .iter()
-            .map(|ip| ConnectionConfig {
-                host: HostOrIp::Ip(ip.0.into()),
-                port: self.config.service_port,
-            })
-            .collect::<Vec<_>>();
+    /// Look up SRV records, keeping only the ones with the best priority. The others are only meant to be used when none of those are available.
+    async fn lookup_srv(
+        &mut self,
+        resolver: &TokioAsyncResolver,
+    ) -> anyhow::Result<(Vec<ConnectionConfig>, Instant)> {
+        let lookup = resolver.srv_lookup(&self.config.query).await?;
+        self.metadata.clear();
+        let Some(priority) = lookup.iter().map(|srv| srv.priority()).min() else {
+            return Ok((vec![], lookup.as_lookup().valid_until()));
+        };
+        let mut instances = vec![];
+        for srv in lookup.iter().filter(|srv| srv.priority() == priority) {
+            let target = srv.target().to_utf8();
+            let target = target.trim_end_matches('.');
+            // a target of "." means that the service is decidedly not available at this domain
+            if target.is_empty() {
+                continue;
+            }
+            let conf = ConnectionConfig {
+                host: HostOrIp::Host(target.to_owned()),
+                port: srv.port(),
+            };
+            self.metadata.insert(
+                conf.clone(),
+                InstanceMetadata {
+                    weight: Some(srv.weight().into()),
+                    ..Default::default()
+                },
+            );
+            instances.push(conf);
+        }
+        Ok((instances, lookup.as_lookup().valid_until()))
+    }
+}
+
+fn is_no_records(err: &ResolveError) -> bool {
+    matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. })
+}
+
+#[async_trait]
+impl ServiceDiscoverer for DnsServiceDiscoverer {
+    async fn discover(&mut self) -> anyhow::Result<Vec<ConnectionConfig>> {
+        let resolver = self.resolver()?;
+
+        let (instances, valid_until) = if self.config.record_type == DnsRecordType::Srv {
+            self.lookup_srv(&resolver).await?
+        } else {
+            let port = self.service_port()?;
+            let (ips, valid_until) = self.lookup_ips(&resolver).await?;
+            let instances = ips
+                .into_iter()
+                .map(|ip| ConnectionConfig {
+                    host: HostOrIp::Ip(ip),
+                    port,
+                })
+                .collect::<Vec<_>>();
+            (instances, valid_until)
+        };
+
+        self.next_poll = valid_until.saturating_duration_since(Instant::now()).clamp(
+            MIN_POLLING_INTERVAL,
+            self.config.polling_interval.max(MIN_POLLING_INTERVAL),
+        );
 
         Ok(instances)
     }
 
     fn mode(&self) -> DiscoveryMode {
-        DiscoveryMode::Polling(self.config.polling_interval)
+        DiscoveryMode::Polling(self.next_poll)
+    }
+
+    fn metadata(&self) -> HashMap<ConnectionConfig, InstanceMetadata> {
+        self.metadata.clone()
     }
 }
 #[cfg(test)]
 mod test {
     use super::*;
+    use hickory_resolver::proto::op::{Message, MessageType};
+    use hickory_resolver::proto::rr::rdata::{A, AAAA, SRV};
+    use hickory_resolver::proto::rr::{Name, RData, Record, RecordType};
     use serde_json::json;
 
+    /// Stands in for a DNS server, answering every query with the same records, except for SRV queries for `unavailable.monolith.test.`, which are answered with a `.` target.
+    async fn start_dns_server() -> SocketAddr {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let query = request.queries()[0].clone();
+                let name = query.name().clone();
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_available(true)
+                    .add_query(query.clone());
+                let target = |host: &str| Name::from_ascii(host).unwrap();
+                let answers = match query.query_type() {
+                    RecordType::A => vec![RData::A(A([10, 0, 0, 1].into()))],
+                    RecordType::AAAA => vec![RData::AAAA(AAAA("fd00::1".parse().unwrap()))],
+                    RecordType::SRV if name == target("unavailable.monolith.test.") => {
+                        vec![RData::SRV(SRV::new(0, 0, 0, Name::root()))]
+                    }
+                    RecordType::SRV => vec![
+                        RData::SRV(SRV::new(10, 5, 3002, target("a.monolith.test."))),
+                        RData::SRV(SRV::new(10, 1, 3003, target("b.monolith.test."))),
+                        RData::SRV(SRV::new(20, 1, 3002, target("backup.monolith.test."))),
+                    ],
+                    _ => vec![],
+                };
+                for rdata in answers {
+                    response.add_answer(Record::from_rdata(name.clone(), 2, rdata));
+                }
+                socket
+                    .send_to(&response.to_vec().unwrap(), from)
+                    .await
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    fn dns_discoverer(dns_server: SocketAddr, record_type: DnsRecordType) -> DnsServiceDiscoverer {
+        DnsServiceDiscoverer::new(DnsDiscoveryConfig {
+            service_port: Some(8080),
+            dns_server: Some(dns_server),
+            query: "monolith.test.".into(),
+            record_type,
+            polling_interval: Duration::from_secs(30),
+        })
+    }
+
+    #[tokio::test]
+    async fn should_look_up_addresses_and_follow_ttl() {
+        let dns_server = start_dns_server().await;
+
+        let mut discoverer = dns_discoverer(dns_server, DnsRecordType::Dual);
+        let mut instances = discoverer.discover().await.unwrap();
+        instances.sort();
+        assert_eq!(
+            instances,
+            vec![
+                ConnectionConfig::from(SocketAddr::from(([10, 0, 0, 1], 8080))),
+                ConnectionConfig::from(SocketAddr::new("fd00::1".parse().unwrap(), 8080)),
+            ]
+        );
+        // the records only live for 2 seconds
+        let DiscoveryMode::Polling(next_poll) = discoverer.mode() else {
+            panic!("dns discovery should poll");
+        };
+        assert!(next_poll >= MIN_POLLING_INTERVAL && next_poll <= Duration::from_secs(2));
+
+        let mut discoverer = dns_discoverer(dns_server, DnsRecordType::Aaaa);
+        let instances = discoverer.discover().await.unwrap();
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances[0].host, HostOrIp::Ip("fd00::1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn should_use_best_srv_records() {
+        let dns_server = start_dns_server().await;
+        let mut discoverer = dns_discoverer(dns_server, DnsRecordType::Srv);
+        let mut instances = discoverer.discover().await.unwrap();
+        instances.sort();
+        let a = ConnectionConfig {
+            host: HostOrIp::Host("a.monolith.test".into()),
+            port: 3002,
+        };
+        let b = ConnectionConfig {
+            host: HostOrIp::Host("b.monolith.test".into()),
+            port: 3003,
+        };
+        assert_eq!(instances, vec![a.clone(), b.clone()]);
+        let metadata = discoverer.metadata();
+        assert_eq!(metadata[&a].weight, Some(5));
+        assert_eq!(metadata[&b].weight, Some(1));
+    }
+
+    #[tokio::test]
+    async fn should_skip_srv_records_for_unavailable_services() {
+        let dns_server = start_dns_server().await;
+        let mut discoverer = DnsServiceDiscoverer::new(DnsDiscoveryConfig {
+            service_port: None,
+            dns_server: Some(dns_server),
+            query: "unavailable.monolith.test.".into(),
+            record_type: DnsRecordType::Srv,
+            polling_interval: Duration::from_secs(30),
+        });
+        let instances = discoverer.discover().await.unwrap();
+        assert_eq!(instances, vec![]);
+        assert!(discoverer.metadata().is_empty());
+    }
+
+    #[test]
+    fn service_port_should_only_be_required_without_srv() {
+        let config: DnsDiscoveryConfig = serde_json::from_value(json!({
+            "query": "monolith.test",
+            "record_type": "srv",
+        }))
+        .expect("Failed to deserialize json");
+        assert_eq!(config.record_type, DnsRecordType::Srv);
+
+        let config: DnsDiscoveryConfig = serde_json::from_value(json!({
+            "query": "monolith.test",
+        }))
+        .expect("Failed to deserialize json");
+        assert!(DnsServiceDiscoverer::new(config).service_port().is_err());
+    }
+
+    #[test]
+    fn missing_service_port_should_fail_on_load() {
+        let err = serde_json::from_value::<DiscoveryConfig>(json!({
+            "method": "dns",
+            "query": "monolith.test",
+            "record_type": "aaaa",
+        }))
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("service_port is required for Aaaa records"));
+
+        let config: DiscoveryConfig = serde_json::from_value(json!({
+            "method": "dns",
+            "query": "monolith.test",
+            "record_type": "srv",
+        }))
+        .expect("Failed to deserialize json");
+        assert!(matches!(config, DiscoveryConfig::Dns(_)));
+    }
+
     #[test]
     fn dns_server_deserializes_correctly() {
         let json = json!({
@@ -107,6 +426,7 @@ mod test {
             serde_json::from_value(json).expect("Failed to deserialize json");
 
         assert_eq!(config.dns_server, Some(([127, 0, 0, 1], 100).into()));
+        assert_eq!(config.record_type, DnsRecordType::A);
     }
 
     #[test]
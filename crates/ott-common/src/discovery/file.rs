@@ -0,0 +1,230 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tracing::{info, warn};
+
+use super::*;
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct FileDiscoveryConfig {
+    /// A JSON or TOML file that lists the monoliths, like `monoliths = [{ host = "10.0.0.1", port = 3002 }]`. Files ending in `.json` are read as JSON, and anything else as TOML.
+    pub path: PathBuf,
+}
+
+/// Reads monoliths from a file, and reads it again whenever it changes.
+pub struct FileServiceDiscoverer {
+    config: FileDiscoveryConfig,
+    /// Kept around so that it keeps watching.
+    watcher: Option<RecommendedWatcher>,
+    changed_rx: tokio::sync::mpsc::Receiver<()>,
+    changed_tx: tokio::sync::mpsc::Sender<()>,
+    monoliths: Option<Vec<ConnectionConfig>>,
+}
+
+impl FileServiceDiscoverer {
+    pub fn new(config: FileDiscoveryConfig) -> Self {
+        info!(
+            "Creating FileServiceDiscoverer, path: {}",
+            config.path.display()
+        );
+        // one pending notification is enough, because the whole file is read again either way
+        let (changed_tx, changed_rx) = tokio::sync::mpsc::channel(1);
+        Self {
+            config,
+            watcher: None,
+            changed_rx,
+            changed_tx,
+            monoliths: None,
+        }
+    }
+
+    fn watch(&mut self) -> anyhow::Result<()> {
+        let changed_tx = self.changed_tx.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+                // reading the file is an event too
+                Ok(event) if event.kind.is_access() => {}
+                // any change in the directory counts, because the file can be a symlink that changes without being
+                // touched, like when Kubernetes swaps the `..data` symlink of a mounted ConfigMap. Reading it again
+                // when nothing changed is harmless.
+                Ok(_) => {
+                    let _ = changed_tx.try_send(());
+                }
+                Err(err) => warn!("Error watching discovery file: {}", err),
+            })?;
+        // editors often replace files instead of writing to them, so watch the directory instead of the file
+        let dir = match self.config.path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .with_context(|| format!("watching {}", dir.display()))?;
+        self.watcher = Some(watcher);
+        Ok(())
+    }
+}
+
+fn read_monoliths(path: &Path) -> anyhow::Result<Vec<ConnectionConfig>> {
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let config: ManualDiscoveryConfig = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&contents)?
+    } else {
+        toml::from_str(&contents)?
+    };
+    Ok(config.monoliths)
+}
+
+#[async_trait]
+impl ServiceDiscoverer for FileServiceDiscoverer {
+    async fn discover(&mut self) -> anyhow::Result<Vec<ConnectionConfig>> {
+        if self.watcher.is_none() {
+            // start watching before reading, so that edits in between aren't missed
+            self.watch()?;
+        }
+        let Some(current) = &self.monoliths else {
+            let monoliths = read_monoliths(&self.config.path)?;
+            self.monoliths = Some(monoliths.clone());
+            return Ok(monoliths);
+        };
+        let current = current.clone();
+
+        loop {
+            self.changed_rx
+                .recv()
+                .await
+                .context("discovery file watcher stopped")?;
+            // let the rest of the edit land before reading the file
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            while self.changed_rx.try_recv().is_ok() {}
+
+            match read_monoliths(&self.config.path) {
+                Ok(monoliths) if monoliths != current => {
+                    info!("Discovery file changed, {} monoliths", monoliths.len());
+                    self.monoliths = Some(monoliths.clone());
+                    return Ok(monoliths);
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    warn!(
+                        "Ignoring invalid discovery file, keeping the current monoliths: {:#}",
+                        err
+                    );
+                }
+            }
+        }
+    }
+
+    fn mode(&self) -> DiscoveryMode {
+        DiscoveryMode::Continuous
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn conf(port: u16) -> ConnectionConfig {
+        ConnectionConfig {
+            host: HostOrIp::Ip([127, 0, 0, 1].into()),
+            port,
+        }
+    }
+
+    /// Replace the file like an editor would, by writing a new one and moving it into place.
+    fn replace(path: &Path, contents: &str) {
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, contents).unwrap();
+        std::fs::rename(tmp, path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_keep_monoliths_through_invalid_edits() {
+        let dir = std::env::temp_dir().join(format!("ott-discovery-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("monoliths.json");
+        std::fs::write(
+            &path,
+            r#"{"monoliths": [{"host": "127.0.0.1", "port": 3002}]}"#,
+        )
+        .unwrap();
+
+        let mut discoverer = FileServiceDiscoverer::new(FileDiscoveryConfig { path: path.clone() });
+        assert_eq!(discoverer.discover().await.unwrap(), vec![conf(3002)]);
+
+        let edits = tokio::spawn({
+            let path = path.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                replace(&path, r#"{"monoliths": [{"host": "127.0.0.1", "port": "#);
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                replace(
+                    &path,
+                    r#"{"monoliths": [{"host": "127.0.0.1", "port": 3002}, {"host": "127.0.0.1", "port": 3003}]}"#,
+                );
+            }
+        });
+        let monoliths = tokio::time::timeout(Duration::from_secs(5), discoverer.discover())
+            .await
+            .expect("changes should be picked up")
+            .unwrap();
+        assert_eq!(monoliths, vec![conf(3002), conf(3003)]);
+        edits.await.unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Update a directory the way Kubernetes updates a mounted ConfigMap: the files live in a directory for each
+    /// version, and the `..data` symlink is swapped to the new one.
+    #[cfg(unix)]
+    fn update_config_map(dir: &Path, version: &str, port: u16) {
+        std::fs::create_dir_all(dir.join(version)).unwrap();
+        std::fs::write(
+            dir.join(version).join("monoliths.toml"),
+            format!("[[monoliths]]\nhost = \"127.0.0.1\"\nport = {port}\n"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(version, dir.join("..data_tmp")).unwrap();
+        std::fs::rename(dir.join("..data_tmp"), dir.join("..data")).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn should_follow_config_map_updates() {
+        let dir =
+            std::env::temp_dir().join(format!("ott-discovery-configmap-{}", std::process::id()));
+        update_config_map(&dir, "..v1", 3002);
+        let path = dir.join("monoliths.toml");
+        std::os::unix::fs::symlink("..data/monoliths.toml", &path).unwrap();
+
+        let mut discoverer = FileServiceDiscoverer::new(FileDiscoveryConfig { path });
+        assert_eq!(discoverer.discover().await.unwrap(), vec![conf(3002)]);
+
+        let edits = tokio::spawn({
+            let dir = dir.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                update_config_map(&dir, "..v2", 3003);
+            }
+        });
+        let monoliths = tokio::time::timeout(Duration::from_secs(5), discoverer.discover())
+            .await
+            .expect("changes should be picked up")
+            .unwrap();
+        assert_eq!(monoliths, vec![conf(3003)]);
+        edits.await.unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_read_toml() {
+        let path = std::env::temp_dir().join(format!("ott-discovery-{}.toml", std::process::id()));
+        std::fs::write(&path, "[[monoliths]]\nhost = \"127.0.0.1\"\nport = 3002\n").unwrap();
+        assert_eq!(read_monoliths(&path).unwrap(), vec![conf(3002)]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,11 +1,12 @@
 use async_trait::async_trait;
 use hickory_resolver::TokioAsyncResolver;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tracing::info;
 
 use super::*;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct FlyDiscoveryConfig {
     /// The port that monoliths should be listening on for load balancer connections.
     pub service_port: u16,
@@ -14,6 +15,7 @@ pub struct FlyDiscoveryConfig {
     ///
     #[serde(default = "default_polling_interval")]
     #[serde(with = "humantime_serde")]
+    #[schemars(with = "String")]
     pub polling_interval: Duration,
 }
 
@@ -2,13 +2,14 @@ use std::sync::Arc;
 
 use futures_util::StreamExt;
 use ott_balancer_protocol::harness::HarnessMonoliths;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 use tracing::{error, info, warn};
 
 use super::*;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct HarnessDiscoveryConfig {
     /// The port to listen on for the harness to connect to.
     pub port: u16,
@@ -0,0 +1,618 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::*;
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct KubernetesDiscoveryConfig {
+    /// The port that monoliths should be listening on for load balancer connections.
+    pub service_port: u16,
+    /// The name of the Kubernetes Service that selects the monolith pods.
+    pub service: String,
+    /// The namespace of the Service. Optional. If not provided, the namespace of the service account will be used instead.
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// The URL of the Kubernetes API server. Optional. If not provided, the in-cluster API server will be used.
+    #[serde(default)]
+    pub api_server: Option<String>,
+    /// The directory that has the service account's `token`, `ca.crt` and `namespace` files.
+    #[serde(default = "default_service_account_dir")]
+    #[schemars(with = "String")]
+    pub service_account_dir: PathBuf,
+}
+
+/// How long the API server should keep a watch open before ending it.
+const WATCH_TIMEOUT_SECS: &str = "290";
+/// How long to wait for anything on a watch before assuming the connection is dead. The API server sends
+/// bookmarks about once a minute, so a healthy watch is never quiet for this long.
+const WATCH_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// How long to wait before watching again when a watch ends without any events, so that an API server (or proxy)
+/// that keeps ending watches right away isn't hammered with new ones.
+const EMPTY_WATCH_BACKOFF: Duration = Duration::from_secs(1);
+
+fn default_service_account_dir() -> PathBuf {
+    PathBuf::from("/var/run/secrets/kubernetes.io/serviceaccount")
+}
+
+/// Watches the EndpointSlices of a Service, so that monolith pods show up as soon as they are ready.
+pub struct KubernetesServiceDiscoverer {
+    config: KubernetesDiscoveryConfig,
+    client: Option<KubernetesClient>,
+    /// The addresses of ready endpoints in each EndpointSlice, by name.
+    slices: HashMap<String, Vec<ConnectionConfig>>,
+    /// Where to start watching from. `None` until the EndpointSlices have been listed.
+    resource_version: Option<String>,
+    watch: Option<Watch>,
+    idle_timeout: Duration,
+    empty_watch_backoff: Duration,
+}
+
+impl KubernetesServiceDiscoverer {
+    pub fn new(config: KubernetesDiscoveryConfig) -> Self {
+        info!(
+            "Creating KubernetesServiceDiscoverer, service: {}",
+            &config.service
+        );
+        Self {
+            config,
+            client: None,
+            slices: HashMap::new(),
+            resource_version: None,
+            watch: None,
+            idle_timeout: WATCH_IDLE_TIMEOUT,
+            empty_watch_backoff: EMPTY_WATCH_BACKOFF,
+        }
+    }
+
+    fn instances(&self) -> Vec<ConnectionConfig> {
+        self.slices
+            .values()
+            .flatten()
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    fn endpoints(&self, slice: &EndpointSlice) -> Vec<ConnectionConfig> {
+        slice
+            .endpoints
+            .iter()
+            // endpoints are ready unless they say otherwise
+            .filter(|endpoint| endpoint.conditions.ready != Some(false))
+            .flat_map(|endpoint| &endpoint.addresses)
+            .map(|address| ConnectionConfig {
+                host: match address.parse::<IpAddr>() {
+                    Ok(ip) => HostOrIp::Ip(ip),
+                    Err(_) => HostOrIp::Host(address.clone()),
+                },
+                port: self.config.service_port,
+            })
+            .collect()
+    }
+
+    async fn list(&mut self, client: &KubernetesClient) -> anyhow::Result<()> {
+        let list: EndpointSliceList = client
+            .request(&self.config.service, &[])
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .context("parsing EndpointSlices")?;
+        self.slices = list
+            .items
+            .iter()
+            .map(|slice| (slice.metadata.name.clone(), self.endpoints(slice)))
+            .collect();
+        self.resource_version = Some(list.metadata.resource_version);
+        Ok(())
+    }
+
+    /// Apply the next event from the watch, opening it if needed. Returns whether the instances changed.
+    async fn next_event(&mut self, client: &KubernetesClient) -> anyhow::Result<bool> {
+        let resource_version = self
+            .resource_version
+            .clone()
+            .expect("EndpointSlices should be listed before watching");
+        if self.watch.is_none() {
+            let response = client
+                .request(
+                    &self.config.service,
+                    &[
+                        ("watch", "true"),
+                        ("allowWatchBookmarks", "true"),
+                        ("timeoutSeconds", WATCH_TIMEOUT_SECS),
+                        ("resourceVersion", &resource_version),
+                    ],
+                )
+                .await?;
+            if response.status() == reqwest::StatusCode::GONE {
+                return self.relist(client).await;
+            }
+            self.watch = Some(Watch {
+                response: response.error_for_status()?,
+                buf: Vec::new(),
+                idle_timeout: self.idle_timeout,
+                events: 0,
+            });
+        }
+        let watch = self.watch.as_mut().expect("watch was just opened");
+
+        let line = match watch.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => {
+                // watches end after their timeout, or when they go quiet, so pick up where it left off
+                let watch = self.watch.take().expect("watch should be open");
+                if watch.events == 0 {
+                    tokio::time::sleep(self.empty_watch_backoff).await;
+                }
+                return Ok(false);
+            }
+            Err(err) => {
+                self.watch = None;
+                return Err(err);
+            }
+        };
+        let event: WatchEvent = serde_json::from_slice(&line).context("parsing watch event")?;
+
+        let before = self.instances();
+        match event {
+            WatchEvent::Added(slice) | WatchEvent::Modified(slice) => {
+                let endpoints = self.endpoints(&slice);
+                self.slices.insert(slice.metadata.name, endpoints);
+                self.resource_version = Some(slice.metadata.resource_version);
+            }
+            WatchEvent::Deleted(slice) => {
+                self.slices.remove(&slice.metadata.name);
+                self.resource_version = Some(slice.metadata.resource_version);
+            }
+            WatchEvent::Bookmark(slice) => {
+                self.resource_version = Some(slice.metadata.resource_version);
+            }
+            WatchEvent::Error(status) => {
+                if status.code == reqwest::StatusCode::GONE.as_u16() {
+                    return self.relist(client).await;
+                }
+                self.watch = None;
+                anyhow::bail!("watching EndpointSlices failed: {}", status.message);
+            }
+        }
+        Ok(self.instances() != before)
+    }
+
+    /// Start over when the API server no longer has the history to continue the watch from.
+    async fn relist(&mut self, client: &KubernetesClient) -> anyhow::Result<bool> {
+        warn!("EndpointSlice watch expired, listing again");
+        self.watch = None;
+        let before = self.instances();
+        self.resource_version = None;
+        self.list(client).await?;
+        Ok(self.instances() != before)
+    }
+}
+
+#[async_trait]
+impl ServiceDiscoverer for KubernetesServiceDiscoverer {
+    async fn discover(&mut self) -> anyhow::Result<Vec<ConnectionConfig>> {
+        let client = match self.client.take() {
+            Some(client) => client,
+            None => KubernetesClient::new(&self.config).await?,
+        };
+        let result = async {
+            if self.resource_version.is_none() {
+                self.list(&client).await?;
+                return Ok(());
+            }
+            while !self.next_event(&client).await? {}
+            Ok(())
+        }
+        .await;
+        self.client = Some(client);
+        result.map(|_| self.instances())
+    }
+
+    fn mode(&self) -> DiscoveryMode {
+        DiscoveryMode::Continuous
+    }
+}
+
+struct KubernetesClient {
+    http: reqwest::Client,
+    /// The URL of the EndpointSlices in the Service's namespace.
+    url: String,
+    token_path: PathBuf,
+}
+
+impl KubernetesClient {
+    async fn new(config: &KubernetesDiscoveryConfig) -> anyhow::Result<Self> {
+        let dir = &config.service_account_dir;
+        let api_server = match &config.api_server {
+            Some(api_server) => api_server.trim_end_matches('/').to_owned(),
+            None => in_cluster_api_server(),
+        };
+        let namespace = match &config.namespace {
+            Some(namespace) => namespace.clone(),
+            None => tokio::fs::read_to_string(dir.join("namespace"))
+                .await
+                .context("reading service account namespace")?
+                .trim()
+                .to_owned(),
+        };
+
+        let mut http = reqwest::Client::builder();
+        if api_server.starts_with("https://") {
+            let ca = tokio::fs::read(dir.join("ca.crt"))
+                .await
+                .context("reading service account CA")?;
+            http = http.add_root_certificate(
+                reqwest::Certificate::from_pem(&ca).context("parsing service account CA")?,
+            );
+        }
+
+        Ok(Self {
+            http: http.build()?,
+            url: format!(
+                "{}/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices",
+                api_server, namespace
+            ),
+            token_path: dir.join("token"),
+        })
+    }
+
+    async fn request(
+        &self,
+        service: &str,
+        query: &[(&str, &str)],
+    ) -> anyhow::Result<reqwest::Response> {
+        // service account tokens are rotated, so always use the latest one
+        let token = tokio::fs::read_to_string(&self.token_path)
+            .await
+            .context("reading service account token")?;
+        let label_selector = format!("kubernetes.io/service-name={service}");
+        let response = self
+            .http
+            .get(&self.url)
+            .bearer_auth(token.trim())
+            .query(&[("labelSelector", label_selector.as_str())])
+            .query(query)
+            .send()
+            .await?;
+        Ok(response)
+    }
+}
+
+fn in_cluster_api_server() -> String {
+    match (
+        std::env::var("KUBERNETES_SERVICE_HOST"),
+        std::env::var("KUBERNETES_SERVICE_PORT"),
+    ) {
+        (Ok(host), Ok(port)) if host.contains(':') => format!("https://[{host}]:{port}"),
+        (Ok(host), Ok(port)) => format!("https://{host}:{port}"),
+        _ => "https://kubernetes.default.svc".to_owned(),
+    }
+}
+
+/// A watch response, which has one event per line.
+struct Watch {
+    response: reqwest::Response,
+    buf: Vec<u8>,
+    idle_timeout: Duration,
+    /// How many events the watch has delivered.
+    events: usize,
+}
+
+impl Watch {
+    async fn next_line(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(end) = self.buf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=end).collect();
+                if line.iter().all(|b| b.is_ascii_whitespace()) {
+                    continue;
+                }
+                self.events += 1;
+                return Ok(Some(line));
+            }
+            match tokio::time::timeout(self.idle_timeout, self.response.chunk()).await {
+                Ok(chunk) => match chunk? {
+                    Some(chunk) => self.buf.extend_from_slice(&chunk),
+                    None => return Ok(None),
+                },
+                Err(_) => {
+                    warn!(
+                        "EndpointSlice watch was quiet for {:?}, reconnecting",
+                        self.idle_timeout
+                    );
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct EndpointSliceList {
+    metadata: ObjectMeta,
+    items: Vec<EndpointSlice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EndpointSlice {
+    metadata: ObjectMeta,
+    #[serde(default)]
+    endpoints: Vec<Endpoint>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectMeta {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    resource_version: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Endpoint {
+    addresses: Vec<String>,
+    #[serde(default)]
+    conditions: EndpointConditions,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct EndpointConditions {
+    ready: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "object", rename_all = "UPPERCASE")]
+enum WatchEvent {
+    Added(EndpointSlice),
+    Modified(EndpointSlice),
+    Deleted(EndpointSlice),
+    Bookmark(EndpointSlice),
+    Error(WatchStatus),
+}
+
+#[derive(Debug, Deserialize)]
+struct WatchStatus {
+    #[serde(default)]
+    code: u16,
+    #[serde(default)]
+    message: String,
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use http_body_util::{BodyExt, Full, StreamBody};
+    use hyper::body::{Bytes, Frame};
+    use serde_json::json;
+    use tokio::sync::Mutex;
+
+    use super::*;
+    use crate::test_util::serve_http;
+
+    fn slice(
+        name: &str,
+        resource_version: &str,
+        endpoints: serde_json::Value,
+    ) -> serde_json::Value {
+        json!({
+            "metadata": {"name": name, "resourceVersion": resource_version},
+            "addressType": "IPv4",
+            "endpoints": endpoints,
+        })
+    }
+
+    struct ApiServer {
+        url: String,
+        /// Events to send on the open watch.
+        events_tx: tokio::sync::mpsc::Sender<serde_json::Value>,
+        /// The query of every watch that was opened.
+        watches: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    /// Stands in for the Kubernetes API server.
+    async fn start_api_server() -> ApiServer {
+        let (events_tx, events_rx) = tokio::sync::mpsc::channel(10);
+        let events_rx = Arc::new(Mutex::new(events_rx));
+        let watches = Arc::new(std::sync::Mutex::new(vec![]));
+        let server_watches = watches.clone();
+        let addr = serve_http(move |req| {
+            let events_rx = events_rx.clone();
+            let watches = server_watches.clone();
+            async move {
+                let query = req.uri().query().unwrap_or_default().to_owned();
+                assert_eq!(
+                    req.uri().path(),
+                    "/apis/discovery.k8s.io/v1/namespaces/ott/endpointslices"
+                );
+                assert!(query.contains("labelSelector=kubernetes.io%2Fservice-name%3Dmonolith"));
+                if req.headers()["authorization"] != "Bearer test-token" {
+                    let body = Full::new(Bytes::new()).map_err(|e| match e {}).boxed();
+                    let mut resp = hyper::Response::new(body);
+                    *resp.status_mut() = hyper::StatusCode::UNAUTHORIZED;
+                    return resp;
+                }
+
+                if !query.contains("watch=true") {
+                    let list = json!({
+                        "metadata": {"resourceVersion": "1"},
+                        "items": [slice("monolith-a", "1", json!([
+                            {"addresses": ["10.0.0.1"], "conditions": {"ready": true}},
+                            {"addresses": ["10.0.0.2"], "conditions": {"ready": false}},
+                        ]))],
+                    });
+                    let body = Full::new(Bytes::from(list.to_string()))
+                        .map_err(|e| match e {})
+                        .boxed();
+                    return hyper::Response::new(body);
+                }
+
+                watches.lock().unwrap().push(query);
+                let events = futures_util::stream::unfold(events_rx, |rx| async {
+                    let event = rx.lock().await.recv().await?;
+                    let frame = Frame::data(Bytes::from(format!("{event}\n")));
+                    Some((Ok::<_, Infallible>(frame), rx))
+                });
+                hyper::Response::new(StreamBody::new(events).boxed())
+            }
+        })
+        .await;
+        ApiServer {
+            url: format!("http://{addr}"),
+            events_tx,
+            watches,
+        }
+    }
+
+    fn service_account_dir(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ott-kubernetes-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("token"), "test-token\n").unwrap();
+        std::fs::write(dir.join("namespace"), "ott").unwrap();
+        dir
+    }
+
+    fn discoverer(api_server: &ApiServer, dir: &Path) -> KubernetesServiceDiscoverer {
+        KubernetesServiceDiscoverer::new(KubernetesDiscoveryConfig {
+            service_port: 3002,
+            service: "monolith".into(),
+            namespace: None,
+            api_server: Some(api_server.url.clone()),
+            service_account_dir: dir.to_owned(),
+        })
+    }
+
+    fn ips(instances: Vec<ConnectionConfig>) -> Vec<String> {
+        instances
+            .into_iter()
+            .map(|instance| match instance.host {
+                HostOrIp::Ip(ip) => ip.to_string(),
+                HostOrIp::Host(host) => host,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn should_watch_endpoint_slices() {
+        let dir = service_account_dir("watch");
+        let api_server = start_api_server().await;
+        let events_tx = &api_server.events_tx;
+        let mut discoverer = discoverer(&api_server, &dir);
+
+        let instances = discoverer.discover().await.unwrap();
+        assert_eq!(instances[0].port, 3002);
+        assert_eq!(ips(instances), vec!["10.0.0.1"]);
+
+        events_tx
+            .send(
+                json!({"type": "ADDED", "object": slice("monolith-b", "2", json!([
+                    {"addresses": ["10.0.0.3"]},
+                ]))}),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            ips(discoverer.discover().await.unwrap()),
+            vec!["10.0.0.1", "10.0.0.3"]
+        );
+
+        // changes that don't affect ready endpoints aren't reported
+        events_tx
+            .send(json!({"type": "BOOKMARK", "object": {"metadata": {"resourceVersion": "3"}}}))
+            .await
+            .unwrap();
+        events_tx
+            .send(
+                json!({"type": "MODIFIED", "object": slice("monolith-a", "4", json!([
+                    {"addresses": ["10.0.0.1"], "conditions": {"ready": true}},
+                    {"addresses": ["10.0.0.2"], "conditions": {"ready": false}},
+                    {"addresses": ["10.0.0.4"], "conditions": {"ready": false}},
+                ]))}),
+            )
+            .await
+            .unwrap();
+        events_tx
+            .send(json!({"type": "DELETED", "object": slice("monolith-a", "5", json!([]))}))
+            .await
+            .unwrap();
+        assert_eq!(ips(discoverer.discover().await.unwrap()), vec!["10.0.0.3"]);
+        assert_eq!(discoverer.resource_version.as_deref(), Some("5"));
+
+        let watches = api_server.watches.lock().unwrap().clone();
+        assert_eq!(watches.len(), 1);
+        assert!(watches[0].contains("resourceVersion=1"));
+        assert!(watches[0].contains("timeoutSeconds=290"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_rewatch_quiet_watches_from_last_resource_version() {
+        let dir = service_account_dir("idle");
+        let api_server = start_api_server().await;
+        let events_tx = &api_server.events_tx;
+        let mut discoverer = discoverer(&api_server, &dir);
+        discoverer.idle_timeout = Duration::from_millis(100);
+        discoverer.empty_watch_backoff = Duration::from_millis(10);
+
+        discoverer.discover().await.unwrap();
+        events_tx
+            .send(
+                json!({"type": "ADDED", "object": slice("monolith-b", "2", json!([
+                    {"addresses": ["10.0.0.3"]},
+                ]))}),
+            )
+            .await
+            .unwrap();
+        discoverer.discover().await.unwrap();
+
+        // nothing happens for long enough that the watch is given up on, then the next watch gets the event
+        let (instances, _) = tokio::join!(discoverer.discover(), async {
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            events_tx
+                .send(json!({"type": "DELETED", "object": slice("monolith-a", "3", json!([]))}))
+                .await
+                .unwrap();
+        });
+        assert_eq!(ips(instances.unwrap()), vec!["10.0.0.3"]);
+
+        let watches = api_server.watches.lock().unwrap().clone();
+        assert!(watches.len() > 1);
+        assert!(watches[0].contains("resourceVersion=1"));
+        for watch in &watches[1..] {
+            assert!(watch.contains("resourceVersion=2"));
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    #[tokio::test]
+    async fn should_back_off_when_watches_end_without_events() {
+        let dir = service_account_dir("empty");
+        let api_server = start_api_server().await;
+        let mut discoverer = discoverer(&api_server, &dir);
+        discoverer.empty_watch_backoff = Duration::from_millis(100);
+        discoverer.discover().await.unwrap();
+
+        // without anything to send, every watch ends right away
+        drop(api_server.events_tx);
+        let result = tokio::time::timeout(Duration::from_millis(350), discoverer.discover()).await;
+        assert!(result.is_err(), "nothing should have changed");
+
+        let watches = api_server.watches.lock().unwrap().len();
+        assert!((2..=5).contains(&watches), "opened {watches} watches");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -2,7 +2,8 @@ use tracing::info;
 
 use super::*;
 
-#[derive(Debug, Clone, Default, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct ManualDiscoveryConfig {
     pub monoliths: Vec<ConnectionConfig>,
 }
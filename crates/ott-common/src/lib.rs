@@ -1,2 +1,5 @@
 pub mod discovery;
 pub mod websocket;
+
+#[cfg(test)]
+mod test_util;
@@ -13,9 +13,9 @@ e2e.scenario({
 	describeName: "Smoke: Datasource makes outbound request",
 	itName: "Smoke: Datasource save and test",
 	scenario: () => {
-		cy.intercept("http://localhost:8000/status", {
+		cy.intercept("http://localhost:8000/status/streams", {
 			statusCode: 200,
-			body: "OK",
+			body: { streams: [] },
 		}).as("healthCheck");
 		e2e.pages.DataSources.visit();
 		e2e().contains("vis-datasource").click();
@@ -1,7 +1,7 @@
 import React, { type ChangeEvent } from "react";
-import { InlineField, Input, SecretInput } from "@grafana/ui";
+import { InlineField, Input } from "@grafana/ui";
 import type { DataSourcePluginOptionsEditorProps } from "@grafana/data";
-import type { MyDataSourceOptions, MySecureJsonData } from "../types";
+import type { MyDataSourceOptions } from "../types";
 
 interface Props extends DataSourcePluginOptionsEditorProps<MyDataSourceOptions> {}
 
@@ -15,32 +15,15 @@ export function ConfigEditor(props: Props) {
 		onOptionsChange({ ...options, jsonData });
 	};
 
-	// Secure field (only sent to the backend)
-	const onAPIKeyChange = (event: ChangeEvent<HTMLInputElement>) => {
-		onOptionsChange({
-			...options,
-			secureJsonData: {
-				apiKey: event.target.value,
-			},
-		});
-	};
-
-	const onResetAPIKey = () => {
-		onOptionsChange({
-			...options,
-			secureJsonFields: {
-				...options.secureJsonFields,
-				apiKey: false,
-			},
-			secureJsonData: {
-				...options.secureJsonData,
-				apiKey: "",
-			},
-		});
+	const onAccessTokenChange = (event: ChangeEvent<HTMLInputElement>) => {
+		const jsonData = {
+			...options.jsonData,
+			accessToken: event.target.value || undefined,
+		};
+		onOptionsChange({ ...options, jsonData });
 	};
 
-	const { jsonData, secureJsonFields } = options;
-	const secureJsonData = (options.secureJsonData || {}) as MySecureJsonData;
+	const { jsonData } = options;
 
 	return (
 		<div className="gf-form-group">
@@ -52,14 +35,17 @@ export function ConfigEditor(props: Props) {
 					width={40}
 				/>
 			</InlineField>
-			<InlineField label="API Key" labelWidth={12}>
-				<SecretInput
-					isConfigured={secureJsonFields?.apiKey as boolean}
-					value={secureJsonData.apiKey || ""}
-					placeholder="secure json field (backend only)"
+			<InlineField
+				label="Access Token"
+				labelWidth={12}
+				tooltip="One of the collector's auth tokens. It's sent from the browser, so anyone who can use this datasource can see it."
+			>
+				<Input
+					type="password"
+					onChange={onAccessTokenChange}
+					value={jsonData.accessToken || ""}
+					placeholder="only needed if the collector requires auth"
 					width={40}
-					onReset={onResetAPIKey}
-					onChange={onAPIKeyChange}
 				/>
 			</InlineField>
 		</div>
@@ -21,7 +21,16 @@ export function QueryEditor({ query, onChange, onRunQuery }: Props) {
 		onChange({ ...query, stream: event.target.checked });
 	};
 
-	const { queryText, constant, stream } = query;
+	const onQueryRawEventsChange = (event: ChangeEvent<HTMLInputElement>) => {
+		onChange({ ...query, rawEvents: event.target.checked });
+	};
+
+	const onQueryGraphChange = (event: ChangeEvent<HTMLInputElement>) => {
+		onChange({ ...query, graph: event.target.checked });
+		onRunQuery();
+	};
+
+	const { queryText, constant, stream, rawEvents, graph } = query;
 
 	return (
 		<div className="gf-form">
@@ -50,6 +59,23 @@ export function QueryEditor({ query, onChange, onRunQuery }: Props) {
 					data-testid="vis-stream"
 				/>
 			</InlineField>
+			<InlineField label="Raw events" tooltip="Stream every event instead of rates, for debugging">
+				<Input
+					type="checkbox"
+					onChange={onQueryRawEventsChange}
+					checked={rawEvents}
+					disabled={!stream}
+					data-testid="vis-raw-events"
+				/>
+			</InlineField>
+			<InlineField label="Node graph">
+				<Input
+					type="checkbox"
+					onChange={onQueryGraphChange}
+					checked={graph}
+					data-testid="vis-graph"
+				/>
+			</InlineField>
 		</div>
 	);
 }
@@ -7,105 +7,194 @@ import {
 	FieldType,
 	CircularDataFrame,
 	LoadingState,
+	dataFrameFromJSON,
+	type DataFrameJSON,
 } from "@grafana/data";
 
 import type { MyQuery, MyDataSourceOptions } from "./types";
-import { getBackendSrv } from "@grafana/runtime";
+import { type FetchResponse, getBackendSrv } from "@grafana/runtime";
 import type { SystemState } from "ott-vis";
+import type { TrafficRollup } from "ott-vis/generated";
 import { Observable, lastValueFrom, merge } from "rxjs";
 
 const HTTP_PROTOCOL_REGEX = /^http/;
 
 export class DataSource extends DataSourceApi<MyQuery, MyDataSourceOptions> {
 	baseUrl: string;
+	accessToken?: string;
 
 	constructor(instanceSettings: DataSourceInstanceSettings<MyDataSourceOptions>) {
 		super(instanceSettings);
 		this.baseUrl = instanceSettings.jsonData.baseUrl;
+		this.accessToken = instanceSettings.jsonData.accessToken;
 		console.log("Datasource created");
 	}
 
-	query(options: DataQueryRequest<MyQuery>): Observable<DataQueryResponse> {
-		const observables = options.targets.map(target => {
-			if (target.stream) {
-				return new Observable<DataQueryResponse>(subscriber => {
-					const frame = new CircularDataFrame({
-						append: "tail",
-						capacity: 1000,
-					});
-
-					frame.refId = target.refId;
-					frame.addField({ name: "timestamp", type: FieldType.time });
-					frame.addField({ name: "event", type: FieldType.string });
-					frame.addField({ name: "balancer_id", type: FieldType.string });
-					frame.addField({ name: "node_id", type: FieldType.string });
-					frame.addField({ name: "direction", type: FieldType.string });
-					frame.addField({ name: "room", type: FieldType.string });
-
-					const base = this.baseUrl.replace(HTTP_PROTOCOL_REGEX, "ws");
+	/** Headers that authenticate requests to the collector, if there is a token. */
+	headers(): Record<string, string> {
+		return this.accessToken ? { Authorization: `Bearer ${this.accessToken}` } : {};
+	}
 
-					const open = (e: Event) => {
-						console.log("WebSocket opened", e);
-					};
+	/** The URL to open a websocket to. Browsers can't set headers on websockets, so the token goes in the query. */
+	websocketUrl(path: string): string {
+		const url = new URL(`${this.baseUrl.replace(HTTP_PROTOCOL_REGEX, "ws")}${path}`);
+		if (this.accessToken) {
+			url.searchParams.set("access_token", this.accessToken);
+		}
+		return url.toString();
+	}
 
-					const message = (msg: MessageEvent) => {
-						const event = JSON.parse(msg.data);
-						frame.add(event);
+	/**
+	 * Stream the traffic rates, as one row per node and direction with traffic in each rollup, so that the panel can
+	 * show where the traffic is without getting every message that the balancers see.
+	 */
+	streamRates(target: MyQuery): Observable<DataQueryResponse> {
+		const frame = new CircularDataFrame({
+			append: "tail",
+			capacity: 1000,
+		});
+		frame.refId = target.refId;
+		frame.addField({ name: "timestamp", type: FieldType.time });
+		frame.addField({ name: "event", type: FieldType.string });
+		frame.addField({ name: "node_id", type: FieldType.string });
+		frame.addField({ name: "direction", type: FieldType.string });
+		frame.addField({ name: "rate", type: FieldType.number });
 
-						subscriber.next({
-							data: [frame],
-							key: frame.refId,
-							state: LoadingState.Streaming,
+		return this.streamWebsocket("/rates/stream", frame, data => {
+			const rollup: TrafficRollup = JSON.parse(data);
+			for (const node of rollup.nodes) {
+				for (const direction of ["rx", "tx"] as const) {
+					if (node.rates[direction] > 0) {
+						frame.add({
+							timestamp: rollup.timestamp,
+							event: node.event,
+							node_id: node.id,
+							direction,
+							rate: node.rates[direction],
 						});
-					};
-
-					const error = (err: Event) => {
-						console.error("WebSocket error", err);
-					};
-
-					const close = (e: CloseEvent) => {
-						// subscriber.complete();
-						console.warn("WebSocket closed", e);
-						if (e.code === 4132) {
-							console.info("We aborted the connection.");
-							return;
-						}
-						console.log("Datasource reconnecting...");
-						setTimeout(() => {
-							if (socket) {
-								removeListeners(socket);
-							}
-							socket = new WebSocket(`${base}/state/stream`);
-							addListeners(socket);
-						}, 1000);
-					};
-
-					function addListeners(ws: WebSocket) {
-						ws.addEventListener("open", open);
-						ws.addEventListener("message", message);
-						ws.addEventListener("error", error);
-						ws.addEventListener("close", close);
-					}
-					function removeListeners(ws: WebSocket) {
-						ws.removeEventListener("open", open);
-						ws.removeEventListener("message", message);
-						ws.removeEventListener("error", error);
-						ws.removeEventListener("close", close);
 					}
+				}
+			}
+		});
+	}
+
+	/** Stream every event that the balancers send. This is a lot of data, so it's only meant for debugging. */
+	streamRawEvents(target: MyQuery): Observable<DataQueryResponse> {
+		const frame = new CircularDataFrame({
+			append: "tail",
+			capacity: 1000,
+		});
+		frame.refId = target.refId;
+		frame.addField({ name: "timestamp", type: FieldType.time });
+		frame.addField({ name: "event", type: FieldType.string });
+		frame.addField({ name: "balancer_id", type: FieldType.string });
+		frame.addField({ name: "node_id", type: FieldType.string });
+		frame.addField({ name: "direction", type: FieldType.string });
+		frame.addField({ name: "room", type: FieldType.string });
+
+		return this.streamWebsocket("/state/stream?raw=true", frame, data => {
+			frame.add(JSON.parse(data));
+		});
+	}
+
+	/** Keep a websocket to the collector open, reconnecting when it closes, and send `frame` after each message. */
+	streamWebsocket(
+		path: string,
+		frame: CircularDataFrame,
+		onMessage: (data: string) => void,
+	): Observable<DataQueryResponse> {
+		return new Observable<DataQueryResponse>(subscriber => {
+			const streamUrl = this.websocketUrl(path);
+
+			const open = (e: Event) => {
+				console.log("WebSocket opened", e);
+			};
 
-					function teardown(this: DataSource) {
-						if (!socket) {
-							return;
-						}
-						socket.close(4132);
+			const message = (msg: MessageEvent) => {
+				onMessage(msg.data);
+
+				subscriber.next({
+					data: [frame],
+					key: frame.refId,
+					state: LoadingState.Streaming,
+				});
+			};
+
+			const error = (err: Event) => {
+				console.error("WebSocket error", err);
+			};
+
+			const close = (e: CloseEvent) => {
+				// subscriber.complete();
+				console.warn("WebSocket closed", e);
+				if (e.code === 4132) {
+					console.info("We aborted the connection.");
+					return;
+				}
+				console.log("Datasource reconnecting...");
+				setTimeout(() => {
+					if (socket) {
 						removeListeners(socket);
-						window.removeEventListener("beforeunload", teardown);
 					}
-					subscriber.add(teardown.bind(this));
-					window.addEventListener("beforeunload", teardown);
-
-					let socket = new WebSocket(`${base}/state/stream`);
+					socket = new WebSocket(streamUrl);
 					addListeners(socket);
+				}, 1000);
+			};
+
+			function addListeners(ws: WebSocket) {
+				ws.addEventListener("open", open);
+				ws.addEventListener("message", message);
+				ws.addEventListener("error", error);
+				ws.addEventListener("close", close);
+			}
+			function removeListeners(ws: WebSocket) {
+				ws.removeEventListener("open", open);
+				ws.removeEventListener("message", message);
+				ws.removeEventListener("error", error);
+				ws.removeEventListener("close", close);
+			}
+
+			function teardown(this: DataSource) {
+				if (!socket) {
+					return;
+				}
+				socket.close(4132);
+				removeListeners(socket);
+				window.removeEventListener("beforeunload", teardown);
+			}
+			subscriber.add(teardown.bind(this));
+			window.addEventListener("beforeunload", teardown);
+
+			let socket = new WebSocket(streamUrl);
+			addListeners(socket);
+		});
+	}
+
+	query(options: DataQueryRequest<MyQuery>): Observable<DataQueryResponse> {
+		const observables = options.targets.map(target => {
+			if (target.stream) {
+				return target.rawEvents ? this.streamRawEvents(target) : this.streamRates(target);
+			}
+
+			if (target.graph) {
+				return new Observable<DataQueryResponse>(subscriber => {
+					getBackendSrv()
+						.fetch<DataFrameJSON[]>({
+							url: `${this.baseUrl}/state/graph`,
+							headers: this.headers(),
+						})
+						.subscribe(resp => {
+							const frames = resp.data.map(json => {
+								const frame = dataFrameFromJSON(json);
+								frame.refId = target.refId;
+								return frame;
+							});
+							subscriber.next({
+								data: frames,
+								state: LoadingState.Done,
+							});
+							subscriber.complete();
+						});
 				});
 			}
 
@@ -117,6 +206,7 @@ export class DataSource extends DataSourceApi<MyQuery, MyDataSourceOptions> {
 				getBackendSrv()
 					.fetch<SystemState>({
 						url: `${this.baseUrl}/state`,
+						headers: this.headers(),
 					})
 					.subscribe(resp => {
 						const systemState: SystemState = resp.data;
@@ -139,11 +229,25 @@ export class DataSource extends DataSourceApi<MyQuery, MyDataSourceOptions> {
 	}
 
 	async testDatasource() {
+		// this needs credentials when the collector requires them, so it also checks the access token
 		const obs = getBackendSrv().fetch({
-			url: `${this.baseUrl}/status`,
+			url: `${this.baseUrl}/status/streams`,
+			headers: this.headers(),
+			showErrorAlert: false,
 		});
-		const resp = await lastValueFrom(obs);
+		let resp: FetchResponse;
+		try {
+			resp = await lastValueFrom(obs);
+		} catch (err) {
+			resp = err as FetchResponse;
+		}
 
+		if (resp.status === 401) {
+			return {
+				status: "error",
+				message: "The collector requires authentication, check the access token",
+			};
+		}
 		if (resp.status !== 200) {
 			return {
 				status: "error",
@@ -4,7 +4,12 @@ import type { DataQuery } from "@grafana/schema";
 export interface MyQuery extends DataQuery {
 	queryText?: string;
 	constant: number;
+	/** Stream the traffic rates. */
 	stream: boolean;
+	/** Stream every raw event instead of the traffic rates. Only meant for debugging. */
+	rawEvents?: boolean;
+	/** Query the nodes and edges for the Node Graph panel. */
+	graph?: boolean;
 }
 
 export const DEFAULT_QUERY: Partial<MyQuery> = {
@@ -16,11 +21,10 @@ export const DEFAULT_QUERY: Partial<MyQuery> = {
  */
 export interface MyDataSourceOptions extends DataSourceJsonData {
 	baseUrl: string;
-}
-
-/**
- * Value that is used in the backend, but never sent over HTTP to the frontend
- */
-export interface MySecureJsonData {
-	apiKey?: string;
+	/**
+	 * A token from the collector's `auth.tokens`, if it requires authentication.
+	 *
+	 * The collector is queried straight from the browser, so this is visible to anyone who can use the datasource.
+	 */
+	accessToken?: string;
 }
@@ -12,4 +12,6 @@ export type BusEvent = {
 	node_id: string;
 	direction: "tx" | "rx";
 	room?: string;
+	/** Messages per second, when the datasource is streaming traffic rates instead of raw events. */
+	rate?: number;
 };
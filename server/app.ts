@@ -23,6 +23,7 @@ import session, { type SessionOptions } from "express-session";
 import RedisStore from "connect-redis";
 import { setupPostgresMetricsCollection } from "./storage.metrics.js";
 import cookieparser from "cookie-parser";
+import { setupTracing, shutdownTracing } from "./tracing.js";
 
 const app = express();
 
@@ -44,6 +45,8 @@ export async function main() {
 		}
 	}
 
+	setupTracing();
+
 	const env = conf.get("env");
 	const heroku = conf.get("heroku");
 	const docker = conf.get("docker");
@@ -253,6 +256,7 @@ async function shutdown() {
 	// let the clients disconnect
 	await new Promise(resolve => setTimeout(resolve, 1000));
 	roommanager.shutdown();
+	await shutdownTracing();
 	process.exit(0);
 }
 
@@ -1,5 +1,8 @@
 import { v4 as uuidv4 } from "uuid";
 import EventEmitter from "node:events";
+import { createHmac, randomBytes, timingSafeEqual } from "node:crypto";
+import fs from "node:fs";
+import https from "node:https";
 import WebSocket from "ws";
 
 import { getLogger } from "./logger.js";
@@ -9,7 +12,14 @@ import { replacer } from "ott-common/serialize.js";
 import roommanager from "./roommanager.js";
 import type { RoomListItem } from "ott-common/models/rest-api.js";
 import _ from "lodash";
-import type { MsgB2M, MsgM2B, UnloadReason } from "./generated.js";
+import type {
+	B2MInit,
+	LinkAuth,
+	M2BInit,
+	MsgB2M,
+	MsgM2B,
+	UnloadReason,
+} from "./generated.js";
 import { Gauge } from "prom-client";
 export type { MsgB2M, MsgM2B };
 
@@ -30,9 +40,24 @@ export function initBalancerConnections() {
 
 	gossipDebounced();
 
-	wss = new WebSocket.Server({
-		port: conf.get("balancing.port"),
-	});
+	const certPath = conf.get("balancing.tls.cert_path");
+	const keyPath = conf.get("balancing.tls.key_path");
+	const caPath = conf.get("balancing.tls.ca_path");
+	if (certPath && keyPath) {
+		const server = https.createServer({
+			cert: fs.readFileSync(certPath),
+			key: fs.readFileSync(keyPath),
+			ca: caPath ? fs.readFileSync(caPath) : undefined,
+			requestCert: !!caPath,
+			rejectUnauthorized: !!caPath,
+		});
+		wss = new WebSocket.Server({ server });
+		server.listen(conf.get("balancing.port"));
+	} else {
+		wss = new WebSocket.Server({
+			port: conf.get("balancing.port"),
+		});
+	}
 	wss.on("connection", ws => {
 		log.debug("New balancer connection");
 		const conn = new BalancerConnectionReal(ws);
@@ -55,7 +80,7 @@ class BalancerManager {
 	bus = new EventEmitter();
 
 	async addBalancerConnection(conn: BalancerConnection) {
-		this.onBalancerPreconnect(conn);
+		const init = this.onBalancerPreconnect(conn);
 		conn.on("connect", () => this.onBalancerConnect(conn));
 		conn.on("disconnect", (code, reason) => this.onBalancerDisconnect(conn, code, reason));
 		conn.on("error", error => this.onBalancerError(conn, error));
@@ -65,14 +90,37 @@ class BalancerManager {
 				conn.disconnect(1002, "Balancer did not send init message");
 				reject(new Error("Balancer did not send init message"));
 			}, 1000 * 10);
+			let balancerInit: B2MInit | null = null;
 			const handler = (msg: MsgB2M) => {
-				if (msg.type === "init") {
-					conn.id = msg.payload.id;
-					conn.off("message", handler);
-					conn.off("disconnect", disconnectHandler);
-					clearTimeout(timeout);
-					resolve();
+				if (balancerInit === null) {
+					if (msg.type !== "init") {
+						return;
+					}
+					balancerInit = msg.payload;
+					const auth = authenticateToBalancer(init, balancerInit);
+					if (auth) {
+						conn.send({ type: "auth", payload: auth });
+					}
+					if (conf.get("balancing.secret")) {
+						// wait for the balancer to answer our challenge
+						return;
+					}
+				}
+				conn.off("message", handler);
+				conn.off("disconnect", disconnectHandler);
+				clearTimeout(timeout);
+				const authError = verifyBalancerAuth(
+					init,
+					balancerInit,
+					msg.type === "auth" ? msg.payload : undefined,
+				);
+				if (authError) {
+					conn.disconnect(1008, "authentication failed");
+					reject(new Error(`Balancer failed authentication: ${authError}`));
+					return;
 				}
+				conn.id = balancerInit.id;
+				resolve();
 			};
 			const disconnectHandler = () => {
 				clearTimeout(timeout);
@@ -86,7 +134,7 @@ class BalancerManager {
 			log.debug("Waiting for balancer init message");
 			await waitForInit;
 		} catch (e) {
-			log.error(`Balancer did not send init message in time: ${e}`);
+			log.error(`Balancer did not complete init: ${e}`);
 			return;
 		}
 
@@ -99,16 +147,10 @@ class BalancerManager {
 		return this.balancerConnections.find(conn => conn.id === id);
 	}
 
-	private onBalancerPreconnect(conn: BalancerConnection) {
-		const init: MsgM2B = {
-			type: "init",
-			payload: {
-				port: conf.get("port"),
-				region: conf.get("balancing.region"),
-				id: monolithId,
-			},
-		};
-		conn.send(init);
+	private onBalancerPreconnect(conn: BalancerConnection): M2BInit {
+		const msg = buildInitMessage();
+		conn.send(msg);
+		return msg.payload;
 	}
 
 	private onBalancerConnect(conn: BalancerConnection) {
@@ -278,15 +320,7 @@ export class BalancerConnectionReal extends BalancerConnection {
 	}
 
 	private onSocketConnect(event: WebSocket.OpenEvent) {
-		const init: MsgM2B = {
-			type: "init",
-			payload: {
-				port: conf.get("port"),
-				region: conf.get("balancing.region"),
-				id: monolithId,
-			},
-		};
-		this.send(init);
+		this.send(buildInitMessage());
 		this.emit("connect");
 	}
 
@@ -352,11 +386,77 @@ function validateB2M(message: unknown): message is MsgB2M {
 			return typeof msg.payload.room === "string";
 		case "init":
 			return typeof msg.payload.id === "string";
+		case "auth":
+			return typeof msg.payload.signature === "string";
 		default:
 			return false;
 	}
 }
 
+function signLink(secret: string, direction: string, balancer: B2MInit, monolith: M2BInit): Buffer {
+	return createHmac("sha256", secret)
+		.update(
+			[
+				direction,
+				balancer.id,
+				balancer.challenge ?? "",
+				monolith.id,
+				monolith.port,
+				monolith.region,
+				monolith.challenge ?? "",
+			].join(":"),
+		)
+		.digest();
+}
+
+export function buildInitMessage(): Extract<MsgM2B, { type: "init" }> {
+	const init: M2BInit = {
+		port: conf.get("port"),
+		region: conf.get("balancing.region"),
+		id: monolithId,
+	};
+	if (conf.get("balancing.secret")) {
+		init.challenge = randomBytes(16).toString("hex");
+	}
+	return { type: "init", payload: init };
+}
+
+/**
+ * Answers the balancer's challenge, proving that this server knows the shared secret.
+ * @returns The answer, or null if there is no secret or the balancer didn't send a challenge.
+ */
+export function authenticateToBalancer(init: M2BInit, balancer: B2MInit): LinkAuth | null {
+	const secret = conf.get("balancing.secret");
+	if (!secret || !balancer.challenge) {
+		return null;
+	}
+	return { signature: signLink(secret, "m2b", balancer, init).toString("hex") };
+}
+
+/**
+ * Checks that a balancer answered the challenge in `init` with the shared secret, if one is configured.
+ * @returns A description of the problem, or null if the balancer is allowed to connect.
+ */
+export function verifyBalancerAuth(
+	init: M2BInit,
+	balancer: B2MInit,
+	auth: LinkAuth | undefined,
+): string | null {
+	const secret = conf.get("balancing.secret");
+	if (!secret) {
+		return null;
+	}
+	if (!auth) {
+		return "peer did not authenticate";
+	}
+	const expected = signLink(secret, "b2m", balancer, init);
+	const actual = Buffer.from(auth.signature, "hex");
+	if (actual.length !== expected.length || !timingSafeEqual(actual, expected)) {
+		return "peer sent an invalid signature";
+	}
+	return null;
+}
+
 function broadcastToBalancers(message: MsgM2B) {
 	for (const conn of balancerManager.balancerConnections) {
 		conn.send(message);
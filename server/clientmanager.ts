@@ -36,9 +36,11 @@ import {
 import usermanager from "./usermanager.js";
 import { OttException } from "ott-common/exceptions.js";
 import { conf } from "./ott-config.js";
-import { UnloadReason } from "./generated.js";
+import { type TraceContext, UnloadReason } from "./generated.js";
+import { type Context, context, propagation, trace } from "@opentelemetry/api";
 
 const log = getLogger("clientmanager");
+const tracer = trace.getTracer("ott-server");
 
 const connections: Client[] = [];
 const roomJoins: Map<string, Client[]> = new Map();
@@ -326,12 +328,23 @@ async function onBalancerMessage(conn: BalancerConnection, message: MsgB2M) {
 		},
 		join: async message => {
 			const msg = message.payload;
-			const client = new BalancerClient(msg.room, msg.client, conn);
-			connections.push(client);
-			client.on("auth", onClientAuth);
-			client.on("message", onClientMessage);
-			client.on("disconnect", onClientDisconnect);
-			client.auth(msg.token);
+			await tracer.startActiveSpan(
+				"balancer join",
+				{ attributes: { "ott.room": msg.room, "ott.client": msg.client } },
+				extractTraceContext(msg.trace),
+				async span => {
+					try {
+						const client = new BalancerClient(msg.room, msg.client, conn);
+						connections.push(client);
+						client.on("auth", onClientAuth);
+						client.on("message", onClientMessage);
+						client.on("disconnect", onClientDisconnect);
+						await client.auth(msg.token);
+					} finally {
+						span.end();
+					}
+				},
+			);
 		},
 		leave: async message => {
 			const msg = message.payload;
@@ -348,7 +361,18 @@ async function onBalancerMessage(conn: BalancerConnection, message: MsgB2M) {
 			const msg = message.payload;
 			const client = connections.find(c => c.id === msg.client_id);
 			if (client instanceof BalancerClient) {
-				client.receiveMessage(msg.payload as ClientMessage);
+				tracer.startActiveSpan(
+					"balancer client message",
+					{ attributes: { "ott.room": client.room, "ott.client": client.id } },
+					extractTraceContext(msg.trace),
+					span => {
+						try {
+							client.receiveMessage(msg.payload as ClientMessage);
+						} finally {
+							span.end();
+						}
+					},
+				);
 			} else {
 				log.error(
 					`Balancer sent message for client that does not exist or is not a balancer client`,
@@ -360,6 +384,9 @@ async function onBalancerMessage(conn: BalancerConnection, message: MsgB2M) {
 			conn.id = msg.id;
 			log.info(`Received init message: ${JSON.stringify(msg.id)}`);
 		},
+		auth: async () => {
+			log.warn(`Balancer ${conn.id} sent auth when it wasn't expected, ignoring`);
+		},
 	};
 
 	const handler = handlers[message.type];
@@ -370,6 +397,16 @@ async function onBalancerMessage(conn: BalancerConnection, message: MsgB2M) {
 	await handler(message as any); // this cast is safe because the type is checked and narrowed above
 }
 
+/**
+ * Continue the trace that the balancer started, if it sent one. The trace context uses the same names as the W3C headers, so it can be used as the carrier directly.
+ */
+function extractTraceContext(traceContext: TraceContext | undefined): Context {
+	if (!traceContext) {
+		return context.active();
+	}
+	return propagation.extract(context.active(), traceContext);
+}
+
 function onBalancerError(conn: BalancerConnection, error: WebSocket.ErrorEvent) {
 	log.error(`Error from balancer ${conn.id}: ${error}`);
 }
@@ -448,6 +485,7 @@ const roomUnloadReasons: Record<UnloadReason, OttWebsocketError> = {
 	[UnloadReason.Admin]: OttWebsocketError.ROOM_UNLOADED,
 	[UnloadReason.Commanded]: OttWebsocketError.AWAY,
 	[UnloadReason.Shutdown]: OttWebsocketError.AWAY,
+	[UnloadReason.Gossip]: OttWebsocketError.AWAY,
 };
 
 function onRoomUnload(roomName: string, reason: UnloadReason) {
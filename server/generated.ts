@@ -32,25 +32,305 @@ export interface BalancerState {
 	id: BalancerId;
 	region: Region;
 	monoliths: MonolithState[];
+	/** When the collector last got this state from the balancer, in milliseconds since the Unix epoch. Only set by the collector. */
+	collected_at?: number;
+	/** Why the collector's latest attempt to get state from the balancer failed. When set, this state is the last one that the collector got, and may be out of date. If the collector has never got state from the balancer, this is a placeholder without `collected_at` or monoliths. */
+	error?: string;
 }
 
 export type SystemState = BalancerState[];
 
+export interface AggregatedClient {
+	id: ClientId;
+	edge_region: Region;
+	/** The balancer that the client is connected to. */
+	balancer_id: BalancerId;
+}
+
+export interface AggregatedRoom {
+	name: RoomName;
+	/** The clients in this room on every balancer. */
+	clients: AggregatedClient[];
+}
+
+export interface AggregatedMonolith {
+	id: MonolithId;
+	region: Region;
+	rooms: AggregatedRoom[];
+}
+
+export interface RoomLocation {
+	monolith_id: MonolithId;
+	/** The balancers that think the room is on this monolith. */
+	balancers: BalancerId[];
+}
+
+/** A room that different balancers think is on different monoliths. */
+export interface RoomConflict {
+	room: RoomName;
+	locations: RoomLocation[];
+}
+
+/** The state of the whole system, where each monolith and room only shows up once. */
+export interface AggregatedState {
+	balancers: BalancerId[];
+	monoliths: AggregatedMonolith[];
+	/** Clients connected through each edge region, across all balancers. */
+	clients_by_edge_region: Record<Region, number>;
+	/** Rooms that balancers disagree about the location of. */
+	conflicts: RoomConflict[];
+}
+
+/** Sent to the webhook when alerts start or stop firing. */
+export interface AlertNotification {
+	/** Alerts that started firing. */
+	firing: Alert[];
+	/** Alerts that stopped firing. */
+	resolved: Alert[];
+}
+
+/**
+ * A client that more than one balancer thinks is connected to it.
+ * 
+ * Clients only ever connect to one balancer, so the others are holding on to a client that has already left.
+ */
+export interface OrphanedClient {
+	client_id: ClientId;
+	balancers: BalancerId[];
+}
+
+/** A monolith that only some of the balancers are connected to. */
+export interface PartialMonolith {
+	monolith_id: MonolithId;
+	region: Region;
+	seen_by: BalancerId[];
+	missing_from: BalancerId[];
+}
+
+/** The system state at a point in time. */
+export interface HistorySample {
+	/** Milliseconds since the Unix epoch. */
+	timestamp: number;
+	balancers: number;
+	monoliths: number;
+	rooms: number;
+	clients: number;
+	/** Clients connected through each edge region. */
+	clients_by_region: Record<Region, number>;
+	/** Only present on recent samples, and only when asked for. */
+	state?: SystemState;
+}
+
+export interface StreamHealth {
+	balancer: string;
+	connected: boolean;
+	/** When the stream connected, in milliseconds since the Unix epoch. */
+	connected_since?: number;
+	/** How many times the stream has ended and been restarted. */
+	reconnects: number;
+	/** Why the stream last ended. */
+	last_error?: string;
+	/** Events that were passed along to the event bus. */
+	events: number;
+	/** Messages that weren't recognized, and were dropped. */
+	parse_failures: number;
+}
+
+/** The health of the collector itself. */
+export interface CollectorStatus {
+	streams: StreamHealth[];
+}
+
+export enum NodeKind {
+	Client = "client",
+	Monolith = "monolith",
+	/** Broadcasts are sent to every client in a room. */
+	Room = "room",
+}
+
+/** Messages per second, in each direction, as seen by the balancers. */
+export interface DirectionRates {
+	/** Received by a balancer. */
+	rx: number;
+	/** Sent by a balancer. */
+	tx: number;
+}
+
+export interface NodeTraffic {
+	kind: NodeKind;
+	id: string;
+	/** The kind of traffic event, like `ws`, `proxy` or `broadcast`. */
+	event: string;
+	rates: DirectionRates;
+}
+
+export interface RoomTraffic {
+	room: RoomName;
+	rates: DirectionRates;
+}
+
+/** Traffic across the system, averaged over a window. */
+export interface TrafficRollup {
+	/** Milliseconds since the Unix epoch. */
+	timestamp: number;
+	window_secs: number;
+	total: DirectionRates;
+	nodes: NodeTraffic[];
+	rooms: RoomTraffic[];
+}
+
+/** A lifecycle event that a balancer sends on its state stream. */
+export interface BalancerEvent {
+	/** The balancer that the event happened on. */
+	balancer_id: BalancerId;
+	/** When the event happened, in milliseconds since the Unix epoch. */
+	timestamp: number;
+	event: Event;
+}
+
+/** A client was authenticated and handed off to a monolith. */
+export interface EClientJoined {
+	client_id: ClientId;
+	room: RoomName;
+	monolith_id: MonolithId;
+	edge_region: Region;
+}
+
+/** A client disconnected from the balancer. */
+export interface EClientLeft {
+	client_id: ClientId;
+	room: RoomName;
+}
+
+/** The balancer connected to a monolith. */
+export interface EMonolithConnected {
+	monolith_id: MonolithId;
+	region: Region;
+}
+
+/** The balancer lost its connection to a monolith, or dropped it. */
+export interface EMonolithDisconnected {
+	monolith_id: MonolithId;
+}
+
+/** A room was loaded on two monoliths at once, and one of them was told to unload it. */
+export interface ERoomConflictResolved {
+	room: RoomName;
+	/** The monolith that keeps the room, because it loaded the room first. */
+	kept_monolith_id: MonolithId;
+	/** The monolith that was told to unload the room. */
+	unloaded_monolith_id: MonolithId;
+}
+
+/** A monolith reported that it loaded a room. */
+export interface ERoomLoaded {
+	room: RoomName;
+	monolith_id: MonolithId;
+	load_epoch: number;
+}
+
+/** A monolith reported that it unloaded a room. */
+export interface ERoomUnloaded {
+	room: RoomName;
+	monolith_id: MonolithId;
+	reason: UnloadReason;
+}
+
+export interface DClientAdded {
+	monolith_id: MonolithId;
+	room: RoomName;
+	client_id: ClientId;
+	edge_region: Region;
+}
+
+export interface DClientRemoved {
+	monolith_id: MonolithId;
+	room: RoomName;
+	client_id: ClientId;
+}
+
+export interface DMonolithAdded {
+	monolith_id: MonolithId;
+	region: Region;
+}
+
+export interface DMonolithRemoved {
+	monolith_id: MonolithId;
+}
+
+export interface DRoomAdded {
+	monolith_id: MonolithId;
+	room: RoomName;
+}
+
+export interface DRoomRemoved {
+	monolith_id: MonolithId;
+	room: RoomName;
+}
+
+/**
+ * Sent on the state stream so that subscribers can keep an exact copy of a balancer's state.
+ * 
+ * The stream starts with a snapshot, which is followed by diffs. Each diff has a version one higher than the one before it. Diffs with a version at or below the version of the last snapshot should be ignored.
+ */
+export interface StateSync {
+	balancer_id: BalancerId;
+	/** The version of the state after this message has been applied. */
+	version: number;
+	state: StateUpdate;
+}
+
+/**
+ * Limits which events a subscriber of the state stream receives.
+ * 
+ * Each list that isn't empty must match the event, and an event matches a list if it matches any item in it. For example, `{ "events": ["ws"], "rooms": ["foo", "bar"] }` only lets through websocket messages in the rooms `foo` and `bar`.
+ */
+export interface StreamFilter {
+	/** Event types, like `ws`, `proxy`, `broadcast`, or the `type` of an [`Event`], like `room_loaded`. */
+	events?: string[];
+	rooms?: RoomName[];
+	monoliths?: MonolithId[];
+	clients?: ClientId[];
+}
+
+/** [W3C trace context](https://www.w3.org/TR/trace-context/), so that a monolith can continue a trace that was started by the balancer. */
+export interface TraceContext {
+	/** The value of the `traceparent` header, eg. `00-<trace id>-<parent span id>-01`. */
+	traceparent: string;
+	/** The value of the `tracestate` header, if there is any vendor specific state. */
+	tracestate?: string;
+}
+
 export interface B2MClientMsg<T = unknown> {
 	/** The client that sent the message. */
 	client_id: ClientId;
 	/** The message that was received from the client, verbatim. */
 	payload: T;
+	/** The trace that the message is part of, if the balancer is exporting traces. */
+	trace?: TraceContext;
+}
+
+/**
+ * Proves that the sender knows the secret that is shared between the balancers and the monoliths. Sent in response to the peer's init message.
+ * 
+ * `signature` is the hex encoded HMAC-SHA256 of `{direction}:{balancer id}:{balancer challenge}:{monolith id}:{port}:{region}:{monolith challenge}`, keyed with the shared secret. `direction` is `b2m` or `m2b`, and a missing challenge is an empty string. Both challenges are fresh for every connection, so a captured [`LinkAuth`] can't be used on another connection.
+ */
+export interface LinkAuth {
+	signature: string;
 }
 
 export interface B2MInit {
 	id: BalancerId;
+	/** A random value that the monolith must include in its [`LinkAuth`]. Only sent if the balancer is configured with a shared secret. */
+	challenge?: string;
 }
 
 export interface B2MJoin {
 	room: RoomName;
 	client: ClientId;
 	token: string;
+	/** The trace that the join is part of, if the balancer is exporting traces. */
+	trace?: TraceContext;
 }
 
 export interface B2MLeave {
@@ -98,6 +378,8 @@ export interface M2BInit {
 	port: number;
 	region: Region;
 	id: MonolithId;
+	/** A random value that the balancer must include in its [`LinkAuth`]. Only sent if the monolith is configured with a shared secret. */
+	challenge?: string;
 }
 
 export interface M2BKick {
@@ -152,6 +434,8 @@ export enum UnloadReason {
 	Commanded = "Commanded",
 	/** The room was unloaded because the Monolith is shutting down. */
 	Shutdown = "Shutdown",
+	/** The Balancer stopped tracking the room because the Monolith's gossip no longer included it, or it could not be synced. */
+	Gossip = "Gossip",
 }
 
 export interface M2BUnloaded {
@@ -159,13 +443,28 @@ export interface M2BUnloaded {
 	reason: UnloadReason;
 }
 
+export type Alert = 
+	| { type: "room_conflict", payload: RoomConflict }
+	| { type: "orphaned_client", payload: OrphanedClient }
+	| { type: "partial_monolith", payload: PartialMonolith };
+
+export type Event = 
+	| { type: "room_loaded", payload: ERoomLoaded }
+	| { type: "room_unloaded", payload: ERoomUnloaded }
+	| { type: "client_joined", payload: EClientJoined }
+	| { type: "client_left", payload: EClientLeft }
+	| { type: "monolith_connected", payload: EMonolithConnected }
+	| { type: "monolith_disconnected", payload: EMonolithDisconnected }
+	| { type: "room_conflict_resolved", payload: ERoomConflictResolved };
+
 export type MsgB2M = 
 	| { type: "load", payload: B2MLoad }
 	| { type: "unload", payload: B2MUnload }
 	| { type: "join", payload: B2MJoin }
 	| { type: "leave", payload: B2MLeave }
 	| { type: "client_msg", payload: B2MClientMsg }
-	| { type: "init", payload: B2MInit };
+	| { type: "init", payload: B2MInit }
+	| { type: "auth", payload: LinkAuth };
 
 export type MsgM2B<T = unknown> = 
 	| { type: "init", payload: M2BInit }
@@ -173,5 +472,26 @@ export type MsgM2B<T = unknown> =
 	| { type: "unloaded", payload: M2BUnloaded }
 	| { type: "gossip", payload: M2BGossip }
 	| { type: "room_msg", payload: M2BRoomMsg<T> }
-	| { type: "kick", payload: M2BKick };
+	| { type: "kick", payload: M2BKick }
+	| { type: "auth", payload: LinkAuth };
+
+export type StateChange = 
+	| { type: "monolith_added", payload: DMonolithAdded }
+	/** Also removes all of the monolith's rooms. */
+	| { type: "monolith_removed", payload: DMonolithRemoved }
+	| { type: "room_added", payload: DRoomAdded }
+	/** Also removes all of the room's clients. */
+	| { type: "room_removed", payload: DRoomRemoved }
+	| { type: "client_added", payload: DClientAdded }
+	| { type: "client_removed", payload: DClientRemoved };
+
+export type StateUpdate = 
+	/** The whole state. Replaces any copy that the subscriber had. */
+	| { type: "snapshot", payload: BalancerState }
+	/** The changes since the previous version, in the order that they should be applied. */
+	| { type: "diff", payload: StateChange[] };
 
+/** Messages that a subscriber can send on the state stream. */
+export type StreamControl = 
+	/** Replace the current filter. */
+	| { type: "subscribe", payload: StreamFilter };
@@ -391,6 +391,58 @@ export const conf = convict({
 			default: "unknown",
 			env: "BALANCING_REGION",
 		},
+		secret: {
+			doc: "A secret shared with the load balancers. When set, this server and each balancer challenge each other to prove that they know it, and balancers that don't are disconnected.",
+			format: String,
+			default: null as string | null,
+			env: "BALANCING_SECRET",
+			nullable: true,
+			sensitive: true,
+		},
+		tls: {
+			cert_path: {
+				doc: "PEM encoded certificate chain to serve. When set, load balancers must connect with wss://.",
+				format: String,
+				default: null as string | null,
+				env: "BALANCING_TLS_CERT_PATH",
+				nullable: true,
+			},
+			key_path: {
+				doc: "PEM encoded private key for balancing.tls.cert_path.",
+				format: String,
+				default: null as string | null,
+				env: "BALANCING_TLS_KEY_PATH",
+				nullable: true,
+			},
+			ca_path: {
+				doc: "PEM encoded CA certificates. When set, load balancers must present a client certificate signed by one of them.",
+				format: String,
+				default: null as string | null,
+				env: "BALANCING_TLS_CA_PATH",
+				nullable: true,
+			},
+		},
+	},
+	tracing: {
+		otlp_endpoint: {
+			doc: "The OTLP/HTTP endpoint to export traces to, eg. http://localhost:4318/v1/traces. Traces are not exported when unset.",
+			format: String,
+			default: null as string | null,
+			env: "TRACING_OTLP_ENDPOINT",
+			nullable: true,
+		},
+		service_name: {
+			doc: "The service name to report traces under.",
+			format: String,
+			default: "ott-server",
+			env: "TRACING_SERVICE_NAME",
+		},
+		sample_ratio: {
+			doc: "The fraction of traces to sample when they don't continue a trace from a load balancer.",
+			format: Number,
+			default: 1.0,
+			env: "TRACING_SAMPLE_RATIO",
+		},
 	},
 	mail: {
 		enabled: {
@@ -9,7 +9,9 @@ import {
 	type BalancerConnectionEvents,
 	type MsgM2B,
 	balancerManager,
+	verifyBalancerAuth,
 } from "../../balancer.js";
+import { createHmac } from "node:crypto";
 import { BalancerClient, Client } from "../../client.js";
 import type { OttWebsocketError } from "ott-common/models/types.js";
 import { buildClients } from "../../redisclient.js";
@@ -18,7 +20,15 @@ import roommanager from "../../roommanager.js";
 import { loadModels } from "../../models/index.js";
 import type { Request } from "express";
 import { loadConfigFile, conf } from "../../ott-config.js";
-import { type M2BInit, UnloadReason, type MsgB2M } from "../../generated.js";
+import { type B2MInit, type M2BInit, UnloadReason, type MsgB2M } from "../../generated.js";
+import tokens from "../../auth/tokens.js";
+import { setupTracing } from "../../tracing.js";
+import { trace } from "@opentelemetry/api";
+import {
+	BasicTracerProvider,
+	InMemorySpanExporter,
+	SimpleSpanProcessor,
+} from "@opentelemetry/sdk-trace-base";
 
 class TestClient extends Client {
 	sendRawMock = vi.fn();
@@ -75,11 +85,18 @@ class BalancerConnectionMock extends BalancerConnection {
 }
 
 describe("ClientManager", () => {
+	const spanExporter = new InMemorySpanExporter();
+
 	beforeAll(async () => {
 		loadConfigFile();
 		loadModels();
 		await buildClients();
 		await clientmanager.setup();
+
+		setupTracing();
+		const provider = new BasicTracerProvider();
+		provider.addSpanProcessor(new SimpleSpanProcessor(spanExporter));
+		trace.setGlobalTracerProvider(provider);
 	});
 
 	beforeEach(async () => {
@@ -204,6 +221,34 @@ describe("ClientManager", () => {
 		const joins2 = clientmanager.getClientsInRoom("foo");
 		expect(joins2).toHaveLength(2);
 	});
+
+	it("should continue the trace that the balancer sent with a join", async () => {
+		const mockBalancerCon = new BalancerConnectionMock();
+		balancerManager.addBalancerConnection(mockBalancerCon);
+		await mockBalancerCon.emitInit();
+		const token = await tokens.mint();
+		await tokens.setSessionInfo(token, { isLoggedIn: false, username: "foo" });
+
+		mockBalancerCon.emit("message", {
+			type: "join",
+			payload: {
+				room: "foo",
+				client: "5a1d3c8e-7f2b-4c6a-9e0d-1b2c3d4e5f60",
+				token,
+				trace: {
+					traceparent: "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+				},
+			},
+		});
+		await new Promise(resolve => setTimeout(resolve, 100));
+
+		const span = spanExporter.getFinishedSpans().find(s => s.name === "balancer join");
+		expect(span).toBeDefined();
+		expect(span?.spanContext().traceId).toEqual("0af7651916cd43dd8448eb211c80319c");
+		expect(span?.parentSpanId).toEqual("b7ad6b7169203331");
+
+		mockBalancerCon.emit("disconnect", 1000, "reason");
+	});
 });
 
 describe("BalancerManager", () => {
@@ -260,6 +305,92 @@ describe("BalancerManager", () => {
 		expect(id1).toEqual(id2);
 	});
 
+	describe("with a shared secret", () => {
+		beforeEach(() => {
+			conf.set("balancing.secret", "secret");
+		});
+
+		afterEach(() => {
+			conf.set("balancing.secret", null);
+		});
+
+		function sign(secret: string, direction: string, balancer: B2MInit, monolith: M2BInit) {
+			return createHmac("sha256", secret)
+				.update(
+					`${direction}:${balancer.id}:${balancer.challenge}:${monolith.id}:${monolith.port}:${monolith.region}:${monolith.challenge}`,
+				)
+				.digest("hex");
+		}
+
+		const balancerInit: B2MInit = {
+			id: "a7b6e7e2-1c4a-4a1e-9a0b-4bfbb1d6c9a1",
+			challenge: "balancer-challenge",
+		};
+
+		it("should challenge balancers and accept ones that know the secret", async () => {
+			const mock = new BalancerConnectionMock();
+			const added = balancerManager.addBalancerConnection(mock);
+			const init = mock.sendMock.mock.calls[0][0].payload as M2BInit;
+			expect(init.challenge).toBeDefined();
+
+			mock.emit("message", { type: "init", payload: balancerInit });
+			expect(mock.sendMock).toHaveBeenLastCalledWith({
+				type: "auth",
+				payload: { signature: sign("secret", "m2b", balancerInit, init) },
+			});
+			expect(balancerManager.balancerConnections).not.toContain(mock);
+
+			mock.emit("message", {
+				type: "auth",
+				payload: { signature: sign("secret", "b2m", balancerInit, init) },
+			});
+			await added;
+			expect(balancerManager.balancerConnections).toContain(mock);
+			expect(mock.disconnectMock).not.toHaveBeenCalled();
+		});
+
+		it("should reject balancers that don't know the secret", async () => {
+			const mock = new BalancerConnectionMock();
+			const added = balancerManager.addBalancerConnection(mock);
+			const init = mock.sendMock.mock.calls[0][0].payload as M2BInit;
+
+			mock.emit("message", { type: "init", payload: balancerInit });
+			mock.emit("message", {
+				type: "auth",
+				payload: { signature: sign("wrong", "b2m", balancerInit, init) },
+			});
+			await added;
+			expect(balancerManager.balancerConnections).not.toContain(mock);
+			expect(mock.disconnectMock).toHaveBeenCalled();
+		});
+
+		it("should reject balancers that don't answer the challenge", async () => {
+			const mock = new BalancerConnectionMock();
+			const added = balancerManager.addBalancerConnection(mock);
+
+			mock.emit("message", { type: "init", payload: { id: balancerInit.id } });
+			mock.emit("message", { type: "load", payload: { room: "foo" } });
+			await added;
+			expect(balancerManager.balancerConnections).not.toContain(mock);
+			expect(mock.disconnectMock).toHaveBeenCalled();
+		});
+
+		it("should reject answers to another connection's challenge", () => {
+			const init: M2BInit = {
+				port: 3000,
+				region: "ord",
+				id: "0b3f5e0e-5d0e-4e0f-8b3a-8a4f1c6a3f7e",
+				challenge: "first",
+			};
+			const auth = { signature: sign("secret", "b2m", balancerInit, init) };
+			expect(verifyBalancerAuth(init, balancerInit, auth)).toBeNull();
+			expect(
+				verifyBalancerAuth({ ...init, challenge: "second" }, balancerInit, auth),
+			).not.toBeNull();
+			expect(verifyBalancerAuth(init, balancerInit, undefined)).not.toBeNull();
+		});
+	});
+
 	it("should unload rooms when a balancer tells it to", async () => {
 		const mock = new BalancerConnectionMock();
 		await Promise.all([balancerManager.addBalancerConnection(mock), mock.emitInit()]);
@@ -0,0 +1,50 @@
+import { propagation } from "@opentelemetry/api";
+import { W3CTraceContextPropagator } from "@opentelemetry/core";
+import { OTLPTraceExporter } from "@opentelemetry/exporter-trace-otlp-http";
+import { Resource } from "@opentelemetry/resources";
+import {
+	BatchSpanProcessor,
+	ParentBasedSampler,
+	TraceIdRatioBasedSampler,
+} from "@opentelemetry/sdk-trace-base";
+import { NodeTracerProvider } from "@opentelemetry/sdk-trace-node";
+import { getLogger } from "./logger.js";
+import { conf } from "./ott-config.js";
+
+const log = getLogger("tracing");
+
+let provider: NodeTracerProvider | null = null;
+
+/**
+ * Sets up tracing so that spans continue the traces that load balancers send with their messages.
+ *
+ * The W3C propagator is always registered so that trace context can be read. Spans are only recorded and exported when `tracing.otlp_endpoint` is set.
+ */
+export function setupTracing(): void {
+	propagation.setGlobalPropagator(new W3CTraceContextPropagator());
+
+	const endpoint = conf.get("tracing.otlp_endpoint");
+	if (!endpoint) {
+		return;
+	}
+
+	provider = new NodeTracerProvider({
+		resource: new Resource({ "service.name": conf.get("tracing.service_name") }),
+		sampler: new ParentBasedSampler({
+			root: new TraceIdRatioBasedSampler(conf.get("tracing.sample_ratio")),
+		}),
+	});
+	provider.addSpanProcessor(new BatchSpanProcessor(new OTLPTraceExporter({ url: endpoint })));
+	provider.register({ propagator: new W3CTraceContextPropagator() });
+	log.info(`Exporting traces to ${endpoint}`);
+}
+
+/**
+ * Flushes any spans that haven't been exported yet.
+ */
+export async function shutdownTracing(): Promise<void> {
+	if (provider) {
+		await provider.shutdown();
+		provider = null;
+	}
+}